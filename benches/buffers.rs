@@ -0,0 +1,118 @@
+//! Counts the allocations made per request under load, which the buffers each worker thread
+//! reuses across requests keep down. Logging is off, so only the serving itself is counted,
+//! along with the little the clients allocate.
+//!
+//! Run with `cargo bench --bench buffers`.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use wwwdaanlubbersnl::trace::{self, LogLevel};
+use wwwdaanlubbersnl::webserver::*;
+
+const CLIENTS: usize = 8;
+const REQUESTS: usize = 5_000;
+
+/// The system allocator, counting what goes through it.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// A request with headers like those a browser sends.
+const REQUEST: &[u8] = b"GET /page HTTP/1.1\r\n\
+    Host: www.daanlubbers.nl\r\n\
+    User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0\r\n\
+    Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n\
+    Accept-Language: en-US,en;q=0.5\r\n\
+    Accept-Encoding: gzip, deflate, br\r\n\
+    Referer: https://www.daanlubbers.nl/\r\n\
+    Connection: close\r\n\
+    Upgrade-Insecure-Requests: 1\r\n\
+    Sec-Fetch-Dest: document\r\n\
+    Sec-Fetch-Mode: navigate\r\n\
+    Sec-Fetch-Site: same-origin\r\n\r\n";
+
+fn request(addr: SocketAddr, buffer: &mut Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(REQUEST).unwrap();
+    buffer.clear();
+    stream.read_to_end(buffer).unwrap();
+    assert!(buffer.starts_with(b"HTTP/1.1 200 OK\r\n"));
+}
+
+fn main() {
+    trace::set_level(LogLevel::Off);
+    let addr: SocketAddr = "127.0.0.1:7782".parse().unwrap();
+    let mut app = create_app(AppConfig::new(addr, 4, 5));
+    let page = "<p>hello</p>".repeat(200);
+    app.register_resource(Resource::new(
+        RequestType::GET,
+        "/page".to_string(),
+        ResourceType::TEXT,
+        Box::new(move |_| Ok(Response::text(StatusCode::OK, page.as_str()))),
+    ));
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let stop_flag_clone = stop_flag.clone();
+    let server = thread::spawn(move || app.run(Some(stop_flag_clone)));
+    thread::sleep(Duration::from_millis(100));
+
+    let mut buffer = Vec::with_capacity(16 * 1024);
+    request(addr, &mut buffer); // Let the workers fill their buffers
+    let (allocations, allocated) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED.load(Ordering::Relaxed),
+    );
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut buffer = Vec::with_capacity(16 * 1024);
+                for _ in 0..REQUESTS {
+                    request(addr, &mut buffer);
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let requests = (CLIENTS * REQUESTS) as f64;
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) as f64 / requests;
+    let allocated = (ALLOCATED.load(Ordering::Relaxed) - allocated) as f64 / requests;
+
+    stop_flag.store(true, Ordering::SeqCst);
+    request(addr, &mut buffer);
+    server.join().unwrap().unwrap();
+    println!("{:.0} requests/s", requests / elapsed);
+    println!("{allocations:.1} allocations and {allocated:.0} bytes allocated per request");
+}
@@ -0,0 +1,50 @@
+//! Compares finding a route in a `RouteTrie` with scanning a list of routes, as the server did
+//! before, for a site with a few hundred routes.
+//!
+//! Run with `cargo bench --bench routing`.
+use std::{hint::black_box, time::Instant};
+use wwwdaanlubbersnl::router::RouteTrie;
+use wwwdaanlubbersnl::webserver::RequestType;
+
+const ROUTES: usize = 500;
+const LOOKUPS: usize = 1_000_000;
+
+fn paths() -> Vec<String> {
+    (0..ROUTES)
+        .map(|i| match i % 3 {
+            0 => format!("/blog/{}/post-{i}", 2000 + i % 25),
+            1 => format!("/images/gallery-{}/photo-{i}.jpg", i % 10),
+            _ => format!("/page-{i}"),
+        })
+        .collect()
+}
+
+/// Nanoseconds per lookup, looking up every route in turn.
+fn bench(paths: &[String], lookup: impl Fn(&str) -> Option<usize>) -> f64 {
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        let path = &paths[i % paths.len()];
+        assert_eq!(black_box(lookup(black_box(path))), Some(i % paths.len()));
+    }
+    start.elapsed().as_nanos() as f64 / LOOKUPS as f64
+}
+
+fn main() {
+    let paths = paths();
+    let list: Vec<(RequestType, String)> = paths
+        .iter()
+        .map(|path| (RequestType::GET, path.clone()))
+        .collect();
+    let mut trie = RouteTrie::new();
+    for (i, path) in paths.iter().enumerate() {
+        trie.insert(RequestType::GET, path, i);
+    }
+
+    let scan = bench(&paths, |path| {
+        list.iter()
+            .position(|(method, route)| *method == RequestType::GET && route == path)
+    });
+    let lookup = bench(&paths, |path| trie.get(RequestType::GET, path).copied());
+    println!("    scan: {scan:.0} ns per lookup");
+    println!("    trie: {lookup:.0} ns per lookup");
+}
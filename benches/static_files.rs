@@ -0,0 +1,69 @@
+//! Compares serving a large static file with and without `sendfile(2)`.
+//!
+//! Run with `cargo bench --bench static_files`.
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use wwwdaanlubbersnl::webserver::*;
+
+const FILE_SIZE: usize = 64 * 1024 * 1024;
+const DOWNLOADS: usize = 20;
+
+fn download(addr: SocketAddr, buffer: &mut Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /large HTTP/1.1\r\n\r\n").unwrap();
+    buffer.clear();
+    stream.read_to_end(buffer).unwrap();
+    assert!(buffer.len() > FILE_SIZE);
+}
+
+/// Seconds to download the file `DOWNLOADS` times, one after another.
+fn bench(port: u16, zero_copy: bool, path: &str) -> f64 {
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    let mut app = create_app(AppConfig::new(addr, 4, 5).with_zero_copy(zero_copy));
+    let path = path.to_string();
+    app.register_resource(Resource::new(
+        RequestType::GET,
+        "/large".to_string(),
+        ResourceType::BINARY,
+        Box::new(move |_| Ok(Response::file(StatusCode::OK, path.clone()))),
+    ));
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let stop_flag_clone = stop_flag.clone();
+    let server = thread::spawn(move || app.run(Some(stop_flag_clone)));
+    thread::sleep(Duration::from_millis(100));
+
+    let mut buffer = Vec::with_capacity(FILE_SIZE + 1024);
+    download(addr, &mut buffer); // Warm up the page cache
+    let start = Instant::now();
+    for _ in 0..DOWNLOADS {
+        download(addr, &mut buffer);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    stop_flag.store(true, Ordering::SeqCst);
+    download(addr, &mut buffer);
+    server.join().unwrap().unwrap();
+    elapsed
+}
+
+fn main() {
+    let path = std::env::temp_dir().join("wwwdaanlubbersnl_bench_large");
+    fs::write(&path, vec![0x5a; FILE_SIZE]).unwrap();
+    let path = path.to_str().unwrap();
+
+    let megabytes = (FILE_SIZE * DOWNLOADS) as f64 / (1024.0 * 1024.0);
+    for (name, port, zero_copy) in [("chunked", 7780, false), ("sendfile", 7781, true)] {
+        let seconds = bench(port, zero_copy, path);
+        println!("{name:>8}: {:.0} MiB/s", megabytes / seconds);
+    }
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,252 @@
+use crate::concurrency::{ConnectionCounter, GracefulShutdown, PoolCounters};
+use crate::log;
+use crate::metrics::Metrics;
+use crate::router::{RouteList, Router};
+use crate::search_notify::json_string;
+use crate::trace::{self, LogLevel};
+use crate::webserver::{Request, Response, StatusCode};
+use std::{sync::Arc, time::Instant};
+
+/// Empties a cache, returning how many entries it dropped.
+pub type CacheFlush = Box<dyn Fn() -> usize + Send + Sync>;
+
+/// JSON endpoints for looking into and controlling the running server, registered under a
+/// scope with `register`:
+///
+/// - `GET /stats`: uptime, open connections, the thread pool and, with metrics, the number of
+///   requests and the 50th, 90th and 99th percentile of their duration in milliseconds.
+/// - `GET /log-level` and `POST /log-level` with a `level` form field of `off`, `warn` or
+///   `info`, see `trace::set_level`.
+/// - `POST /cache/flush`: empties the caches given to `with_cache`.
+/// - `GET /routes`: the registered resources.
+/// - `POST /shutdown`: stops the server gracefully, see `GracefulShutdown`.
+///
+/// These are as powerful as they sound, so register `Auth` for the prefix on the router, or on
+/// the app, first.
+pub struct Admin {
+    metrics: Option<Arc<Metrics>>,
+    caches: Vec<(String, CacheFlush)>,
+    started: Instant,
+}
+
+impl Default for Admin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Admin {
+    /// Counts uptime from now.
+    pub fn new() -> Self {
+        Self {
+            metrics: None,
+            caches: vec![],
+            started: Instant::now(),
+        }
+    }
+
+    /// Report request counts and durations from `metrics` in the stats.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Empty the cache with `flush` on `POST /cache/flush`, reported under `name`, such as
+    /// `Box::new(move || cache.purge_all())` for a `ResponseCache`.
+    pub fn with_cache(mut self, name: &str, flush: CacheFlush) -> Self {
+        self.caches.push((name.to_string(), flush));
+        self
+    }
+
+    /// Register the endpoints on `router`, relative to its prefix.
+    pub fn register(self, router: &mut Router) {
+        let admin = Arc::new(self);
+        let stats = Arc::clone(&admin);
+        router.get("/stats", move |request| Ok(json(stats.stats(request))));
+        router.get("/log-level", |_| Ok(json(log_level())));
+        router.post("/log-level", |request| {
+            let level = request.form().ok().and_then(|form| {
+                let level = form.get("level")?;
+                level.parse::<LogLevel>().ok()
+            });
+            let Some(level) = level else {
+                return Ok(Response::text(
+                    StatusCode::BadRequest,
+                    "Expected a level of off, warn or info\n",
+                ));
+            };
+            // Logged before switching, so turning logging off still shows up.
+            log!("Log level set to {}", level.as_str());
+            trace::set_level(level);
+            Ok(json(log_level()))
+        });
+        let caches = Arc::clone(&admin);
+        router.post("/cache/flush", move |_| Ok(json(caches.flush_caches())));
+        router.get("/routes", |request| {
+            let routes = request.state::<RouteList>().map(|list| list.all());
+            let routes: Vec<String> = routes
+                .unwrap_or_default()
+                .iter()
+                .map(|(method, path)| {
+                    format!(
+                        "{{\"method\":\"{method:?}\",\"path\":{}}}",
+                        json_string(path)
+                    )
+                })
+                .collect();
+            Ok(json(format!("[{}]", routes.join(","))))
+        });
+        router.post("/shutdown", |request| {
+            let Some(shutdown) = request.state::<GracefulShutdown>() else {
+                return Ok(Response::empty(StatusCode::ServiceUnavailable));
+            };
+            log!("Shutdown requested");
+            shutdown.request();
+            Ok(
+                Response::text(StatusCode::Accepted, "{\"shutting_down\":true}")
+                    .with_header("Content-Type", "application/json"),
+            )
+        });
+    }
+
+    fn stats(&self, request: &Request) -> String {
+        let mut fields = vec![format!(
+            "\"uptime_seconds\":{}",
+            self.started.elapsed().as_secs()
+        )];
+        if let Some(connections) = request.state::<ConnectionCounter>() {
+            fields.push(format!("\"connections_open\":{}", connections.open()));
+        }
+        if let Some(pool) = request.state::<PoolCounters>() {
+            let stats = pool.stats();
+            fields.push(format!(
+                "\"thread_pool\":{{\"queued\":{},\"busy\":{},\"completed\":{},\"panicked\":{}}}",
+                stats.queued, stats.busy, stats.completed, stats.panicked
+            ));
+        }
+        if let Some(metrics) = &self.metrics {
+            let durations = &metrics.request_duration_ms;
+            let percentile = |quantile| match durations.quantile(quantile) {
+                Some(bound) => bound.to_string(),
+                None => "null".to_string(),
+            };
+            fields.push(format!("\"requests\":{}", durations.count()));
+            fields.push(format!(
+                "\"request_duration_ms\":{{\"p50\":{},\"p90\":{},\"p99\":{}}}",
+                percentile(0.5),
+                percentile(0.9),
+                percentile(0.99)
+            ));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+
+    fn flush_caches(&self) -> String {
+        let flushed: Vec<String> = self
+            .caches
+            .iter()
+            .map(|(name, flush)| {
+                let entries = flush();
+                log!("Flushed {entries} entries from the {name} cache");
+                format!("{}:{entries}", json_string(name))
+            })
+            .collect();
+        format!("{{\"flushed\":{{{}}}}}", flushed.join(","))
+    }
+}
+
+fn log_level() -> String {
+    format!("{{\"level\":\"{}\"}}", trace::level().as_str())
+}
+
+/// A 200 with `body`, which is JSON, and mustn't be cached.
+fn json(body: String) -> Response {
+    Response::text(StatusCode::OK, body)
+        .with_header("Content-Type", "application/json")
+        .with_header("Cache-Control", "no-store")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Auth;
+    use crate::testing::TestClient;
+    use crate::webserver::{App, AppConfig};
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn client(admin: Admin) -> TestClient {
+        let mut app = App::new(
+            AppConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), 1, 5)
+                .with_date_header(false)
+                .with_server_header(None),
+        );
+        app.scope("/admin", |router| {
+            router.register_middleware(Box::new(
+                Auth::new("/admin", "Admin").with_users(&[("admin", "secret")]),
+            ));
+            admin.register(router);
+        });
+        TestClient::new(app)
+    }
+
+    /// `admin:secret`
+    const CREDENTIALS: (&str, &str) = ("Authorization", "Basic YWRtaW46c2VjcmV0");
+
+    #[test]
+    fn endpoints() {
+        let flushed = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&flushed);
+        let metrics = Arc::new(Metrics::new());
+        metrics.request_duration_ms.observe(3);
+        let client = client(Admin::new().with_metrics(metrics).with_cache(
+            "pages",
+            Box::new(move || counter.fetch_add(1, Ordering::SeqCst) + 2),
+        ));
+        let get = |path: &str| client.request("GET", path, &[CREDENTIALS], &[]);
+        let post = |path: &str, body: &str| {
+            let content_type = ("Content-Type", "application/x-www-form-urlencoded");
+            client.request("POST", path, &[CREDENTIALS, content_type], body.as_bytes())
+        };
+
+        assert_eq!(client.get("/admin/stats").status(), 401);
+        let stats = get("/admin/stats");
+        assert_eq!(stats.status(), 200);
+        assert_eq!(stats.header("Content-Type"), Some("application/json"));
+        assert!(stats
+            .text()
+            .starts_with("{\"uptime_seconds\":0,\"connections_open\":0,"));
+        assert!(stats
+            .text()
+            .ends_with(",\"requests\":1,\"request_duration_ms\":{\"p50\":5,\"p90\":5,\"p99\":5}}"));
+
+        assert_eq!(
+            get("/admin/routes").text(),
+            "[{\"method\":\"GET\",\"path\":\"/admin/stats\"},\
+             {\"method\":\"GET\",\"path\":\"/admin/log-level\"},\
+             {\"method\":\"POST\",\"path\":\"/admin/log-level\"},\
+             {\"method\":\"POST\",\"path\":\"/admin/cache/flush\"},\
+             {\"method\":\"GET\",\"path\":\"/admin/routes\"},\
+             {\"method\":\"POST\",\"path\":\"/admin/shutdown\"}]"
+        );
+
+        assert_eq!(
+            post("/admin/cache/flush", "").text(),
+            "{\"flushed\":{\"pages\":2}}"
+        );
+        assert_eq!(flushed.load(Ordering::SeqCst), 1);
+
+        assert_eq!(get("/admin/log-level").text(), "{\"level\":\"info\"}");
+        assert_eq!(post("/admin/log-level", "level=loud").status(), 400);
+        // Left at info, as the level is shared with the other tests.
+        assert_eq!(
+            post("/admin/log-level", "level=INFO").text(),
+            "{\"level\":\"info\"}"
+        );
+
+        assert!(!client.app().shutdown().requested());
+        let response = post("/admin/shutdown", "");
+        assert_eq!(response.status(), 202);
+        assert!(client.app().shutdown().requested());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::cache::CachePolicy;
+use crate::digest::{hex, sha256};
+use crate::webserver::{Middleware, Request, RequestType, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// How long clients may keep fingerprinted files, which is as long as caches keep anything.
+const ONE_YEAR: u64 = 365 * 24 * 60 * 60;
+
+/// Middleware serving the files in a directory under names with a hash of their contents, such
+/// as `/static/app-3f2a9c1b0d.css` for `app.css`, with an immutable `Cache-Control`. A changed
+/// file gets another name, so browsers never use an old version.
+///
+/// The files are hashed once, when created, so the server has to restart for changed files.
+/// Pages link them with `url`, or with `{{asset app.css}}` in templates, see
+/// `Templates::with_assets`.
+pub struct Assets {
+    prefix: String,
+    /// Fingerprinted URLs by the path of the file in the directory.
+    urls: HashMap<String, String>,
+    /// Files by their fingerprinted URL.
+    files: HashMap<String, PathBuf>,
+}
+
+impl Assets {
+    /// Hash the files in `dir` and its subdirectories, to serve under `prefix`. Hidden files
+    /// are left out.
+    pub fn new(prefix: &str, dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let mut assets = Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            urls: HashMap::new(),
+            files: HashMap::new(),
+        };
+        let dir = dir.into();
+        assets.add_dir(&dir, "")?;
+        Ok(assets)
+    }
+
+    fn add_dir(&mut self, dir: &Path, relative: &str) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            let relative = format!("{relative}{name}");
+            if entry.file_type()?.is_dir() {
+                self.add_dir(&path, &format!("{relative}/"))?;
+                continue;
+            }
+            let hash = hex(&sha256(&fs::read(&path)?)[..5]);
+            let url = format!("{}/{}", self.prefix, fingerprinted(&relative, &hash));
+            self.files.insert(url.clone(), path);
+            self.urls.insert(relative, url);
+        }
+        Ok(())
+    }
+
+    /// The fingerprinted URL of the file at `name` in the directory, such as `css/app.css`.
+    pub fn url(&self, name: &str) -> Option<&str> {
+        self.urls
+            .get(name.trim_start_matches('/'))
+            .map(String::as_str)
+    }
+}
+
+/// The name with the hash before the extension, such as `css/app-3f2a9c1b0d.css`.
+fn fingerprinted(name: &str, hash: &str) -> String {
+    let file_start = name.rfind('/').map_or(0, |index| index + 1);
+    match name[file_start..].find('.') {
+        Some(0) | None => format!("{name}-{hash}"),
+        Some(dot) => {
+            let (stem, extension) = name.split_at(file_start + dot);
+            format!("{stem}-{hash}{extension}")
+        }
+    }
+}
+
+impl Middleware for Assets {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if request.request_type() != RequestType::GET {
+            return None;
+        }
+        let path = self.files.get(request.path())?;
+        let mut response = Response::file(StatusCode::OK, path);
+        CachePolicy::Immutable(ONE_YEAR).apply(&mut response, SystemTime::now());
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::templates::{Context, Templates};
+    use crate::webserver::Body;
+    use std::sync::Arc;
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(fingerprinted("app.css", "abc"), "app-abc.css");
+        assert_eq!(fingerprinted("js/app.min.js", "abc"), "js/app-abc.min.js");
+        assert_eq!(fingerprinted("LICENSE", "abc"), "LICENSE-abc");
+        assert_eq!(fingerprinted("v1.0/.env", "abc"), "v1.0/.env-abc");
+
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_assets");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("css")).unwrap();
+        fs::write(dir.join("css").join("app.css"), "body {}").unwrap();
+        fs::write(dir.join(".secret"), "").unwrap();
+        let assets = Arc::new(Assets::new("/static/", &dir).unwrap());
+        let hash = hex(&sha256(b"body {}")[..5]);
+        let url = format!("/static/css/app-{hash}.css");
+        assert_eq!(assets.url("css/app.css"), Some(url.as_str()));
+        assert_eq!(assets.url("/css/app.css"), Some(url.as_str()));
+        assert_eq!(assets.url(".secret"), None);
+
+        let mut request =
+            Request::from_reader(&mut format!("GET {url} HTTP/1.1\r\n\r\n").as_bytes()).unwrap();
+        let response = assets.before(&mut request).unwrap();
+        assert!(matches!(&response.body, Body::File(path) if path.ends_with("app.css")));
+        assert_eq!(
+            response.header("Cache-Control"),
+            Some("public, max-age=31536000, immutable")
+        );
+        let mut request =
+            Request::from_reader(&mut "GET /static/css/app.css HTTP/1.1\r\n\r\n".as_bytes())
+                .unwrap();
+        assert!(assets.before(&mut request).is_none());
+
+        fs::write(
+            dir.join("page.html"),
+            "<link href=\"{{asset css/app.css}}\">",
+        )
+        .unwrap();
+        fs::write(dir.join("broken.html"), "{{asset missing.css}}").unwrap();
+        let templates = Templates::new(&dir).with_assets(Arc::clone(&assets));
+        assert_eq!(
+            templates.render("page.html", &Context::new()).unwrap(),
+            format!("<link href=\"{url}\">")
+        );
+        assert!(templates.render("broken.html", &Context::new()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
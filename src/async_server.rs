@@ -0,0 +1,523 @@
+use crate::log;
+use crate::proxy;
+use crate::trace::Scope;
+use crate::webserver::{
+    chunk_size, App, Error, Output, ReadError, Request, RequestLimits, RequestType, ResourceType,
+    Response, StatusCode, CHUNK_SIZE, MAX_CHUNK_LINE,
+};
+use crate::websocket;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::{timeout, timeout_at, Instant},
+};
+
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response, Error>> + Send>>;
+pub type AsyncResourceHandler = Box<dyn Fn(Request) -> ResponseFuture + Send + Sync>;
+
+/// A resource with an async handler, served by `App::run_async`.
+pub struct AsyncResource {
+    pub(crate) request_type: RequestType,
+    pub(crate) path: String,
+    pub(crate) resource_type: ResourceType,
+    handler: AsyncResourceHandler,
+}
+
+impl AsyncResource {
+    /// The handler is an `async fn(Request) -> Result<Response, Error>`, or a closure returning
+    /// such a future.
+    pub fn new<F, Fut>(
+        request_type: RequestType,
+        path: String,
+        resource_type: ResourceType,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response, Error>> + Send + 'static,
+    {
+        Self {
+            request_type,
+            path,
+            resource_type,
+            handler: Box::new(move |request| Box::pin(handler(request))),
+        }
+    }
+
+    pub(crate) fn matches(&self, request_type: RequestType, path: &str) -> bool {
+        self.request_type == request_type && self.path == path
+    }
+}
+
+/// Accept connections and serve each on its own task, until the app is stopped or the
+/// listener fails.
+pub(crate) async fn run(
+    app: Arc<App>,
+    listener: TcpListener,
+    stop_flag: Option<Arc<AtomicBool>>,
+) -> io::Result<()> {
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) if out_of_files(&e) => {
+                log!("Connection Failed: {e:?}");
+                // Only connections closing free them up, so don't try again right away.
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+            Err(e) if is_connection_error(&e) => {
+                log!("Connection Failed: {e:?}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        // As in `run`, the request that follows setting the flag is the last one handled.
+        let stop = app.shutdown_requested()
+            || stop_flag
+                .as_ref()
+                .is_some_and(|stop_flag| stop_flag.load(Ordering::SeqCst));
+
+        let task = tokio::spawn(serve(Arc::clone(&app), stream, remote_addr, !stop));
+        if stop {
+            let _ = task.await;
+            return Ok(());
+        }
+    }
+}
+
+/// Whether an accept error is about the connection being accepted, rather than the listener.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// Whether the process or system is out of file descriptors, EMFILE or ENFILE.
+fn out_of_files(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(23 | 24))
+}
+
+/// Answer requests on the connection until the client closes it, or for just one request
+/// without `keep_alive`.
+async fn serve(app: Arc<App>, stream: TcpStream, remote_addr: SocketAddr, keep_alive: bool) {
+    let _connection = app.track_connection();
+    let config = &app.config;
+    let idle_timeout = Duration::from_secs(config.read_timeout);
+    let mut stream = BufReader::new(stream);
+    let mut remote_addr = remote_addr;
+    if config.proxy_protocol {
+        match timeout(config.header_timeout, read_proxy_header(&mut stream)).await {
+            Ok(Ok(client)) => remote_addr = client.unwrap_or(remote_addr),
+            Ok(Err(e)) => {
+                log!("{e}");
+                return;
+            }
+            Err(_) => {
+                log!("Request timed out");
+                return;
+            }
+        }
+    }
+    if let Err(e) = app.check_ip(Some(remote_addr), None) {
+        if let Some(response) = app.rejection(e) {
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+        return;
+    }
+    loop {
+        // Wait for the next request, which also only has to finish its headers in time once
+        // it starts arriving.
+        match timeout(idle_timeout, stream.fill_buf()).await {
+            Ok(Ok(buffer)) if !buffer.is_empty() => {}
+            _ => return,
+        }
+        let start = Instant::now();
+        let request_deadline = start + config.request_timeout;
+        let result = match timeout_at(
+            request_deadline.min(start + config.header_timeout),
+            read_head(&mut stream, &app),
+        )
+        .await
+        {
+            Ok(Ok(request)) => match app.check_ip(Some(remote_addr), Some(request.path())) {
+                Ok(()) => {
+                    timeout_at(
+                        request_deadline.min(start + config.body_timeout),
+                        read_body(&mut stream, &app, request),
+                    )
+                    .await
+                }
+                Err(e) => Ok(Err(e)),
+            },
+            Ok(Err(e)) => Ok(Err(e)),
+            Err(elapsed) => Err(elapsed),
+        };
+        let mut request = match result {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                let Some(response) = app.rejection(e) else {
+                    return;
+                };
+                if let Err(e) = stream.write_all(response.as_bytes()).await {
+                    log!("Failed to write to stream: {e:?}");
+                }
+                // Read a little of what the client is still sending, as `App::serve` does.
+                let _ = stream.get_mut().shutdown().await;
+                let mut rest = (&mut stream).take(64 * 1024);
+                let _ = timeout_at(request_deadline, io::copy(&mut rest, &mut io::sink())).await;
+                return;
+            }
+            Err(_) => {
+                log!("Request timed out");
+                return;
+            }
+        };
+        let keep_alive = keep_alive && request.keep_alive();
+        app.attach(&mut request, Some(remote_addr));
+        request.set_closing(!keep_alive);
+
+        let Some((mut response, request)) = respond(&app, request).await else {
+            return;
+        };
+        let keep_alive = keep_alive && response.keeps_alive();
+        let upgrade = response.upgrade.take();
+        // A stream can go on for as long as it has more to send, such as events.
+        let body = response.stream.take();
+        let chunked = response.chunked;
+        match timeout_at(request_deadline, write_output(stream.get_mut(), response)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                log!("Failed to write to stream: {e:?}");
+                return;
+            }
+            Err(_) => {
+                log!("Request timed out");
+                return;
+            }
+        }
+        if let Some(body) = body {
+            if let Err(e) = write_stream(stream.get_mut(), body, chunked).await {
+                log!("Failed to write to stream: {e:?}");
+                return;
+            }
+        }
+        // WebSocket connections are blocking, like handlers, so they get a thread of their own.
+        if let Some(handler) = upgrade {
+            let buffered = stream.buffer().to_vec();
+            let Ok(mut stream) = stream.into_inner().into_std() else {
+                return;
+            };
+            if stream.set_nonblocking(false).is_ok() {
+                let _ = tokio::task::spawn_blocking(move || {
+                    websocket::run(&handler, &request, &mut stream, buffered)
+                })
+                .await;
+            }
+            return;
+        }
+        if !keep_alive {
+            // Half-close, so the client reads the response to the end, as `App::serve` does.
+            let _ = stream.get_mut().flush().await;
+            let _ = stream.get_mut().shutdown().await;
+            return;
+        }
+    }
+}
+
+/// Answer async resources on the runtime, and everything else on a blocking thread. Returns
+/// `None` if a handler panicked, to close the connection like the thread pool does. The
+/// request is handed back for a WebSocket handler.
+async fn respond(app: &Arc<App>, mut request: Request) -> Option<(Output, Request)> {
+    let Some(resource) = app.get_async_resource(&request) else {
+        let app = Arc::clone(app);
+        return tokio::task::spawn_blocking(move || (app.respond(&mut request), request))
+            .await
+            .ok();
+    };
+
+    // The request ID is only logged with outside of the handler, which may move between threads.
+    let preflight = {
+        let _scope = Scope::enter(request.id());
+        app.preflight(&mut request)
+    };
+    if let Some(response) = preflight {
+        return Some((response, request));
+    }
+    let result = (resource.handler)(request.clone()).await;
+    let _scope = Scope::enter(request.id());
+    let output = app.handle_result(&resource.resource_type, &request, result);
+    Some((output, request))
+}
+
+async fn write_output(stream: &mut TcpStream, output: Output) -> io::Result<()> {
+    stream.write_all(&output.bytes).await?;
+    if let Some((file, length)) = output.file {
+        let mut file = File::from_std(file).take(length);
+        if io::copy(&mut file, stream).await? < length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "File shrunk while streaming",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Write a stream body, with chunked transfer coding if `chunked`. It is read on a blocking
+/// thread, a chunk at a time.
+async fn write_stream(
+    stream: &mut TcpStream,
+    mut body: Box<dyn std::io::Read + Send>,
+    chunked: bool,
+) -> io::Result<()> {
+    loop {
+        let (returned, chunk) = tokio::task::spawn_blocking(move || {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let read = std::io::Read::read(&mut body, &mut chunk);
+            (
+                body,
+                read.map(|read| {
+                    chunk.truncate(read);
+                    chunk
+                }),
+            )
+        })
+        .await
+        .map_err(io::Error::other)?;
+        body = returned;
+        let chunk = chunk?;
+        match (chunk.is_empty(), chunked) {
+            (true, true) => return stream.write_all(b"0\r\n\r\n").await,
+            (true, false) => return Ok(()),
+            (false, true) => {
+                stream
+                    .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                    .await?;
+                stream.write_all(&chunk).await?;
+                stream.write_all(b"\r\n").await?;
+            }
+            (false, false) => stream.write_all(&chunk).await?,
+        }
+    }
+}
+
+/// Read the PROXY protocol header as `proxy::read_header` does, which can read ahead here as
+/// the rest stays in the buffer.
+async fn read_proxy_header(
+    stream: &mut BufReader<TcpStream>,
+) -> Result<Option<SocketAddr>, String> {
+    let read_error = |e: io::Error| format!("Failed to read PROXY header: {e}");
+    let mut head = [0; 16];
+    stream
+        .read_exact(&mut head[..8])
+        .await
+        .map_err(read_error)?;
+    if head.starts_with(proxy::V1_PREFIX) {
+        let mut line = head[..8].to_vec();
+        let mut rest = stream.take((proxy::V1_MAX_LENGTH - line.len()) as u64);
+        rest.read_until(b'\n', &mut line)
+            .await
+            .map_err(read_error)?;
+        return proxy::parse_v1(&line);
+    }
+    stream
+        .read_exact(&mut head[8..])
+        .await
+        .map_err(read_error)?;
+    let mut addresses = vec![0; proxy::v2_length(&head)?];
+    stream
+        .read_exact(&mut addresses)
+        .await
+        .map_err(read_error)?;
+    proxy::parse_v2(&head, &addresses)
+}
+
+/// Read the request line and headers within the limits, and parse them as `App::serve` does.
+async fn read_head(stream: &mut BufReader<TcpStream>, app: &App) -> Result<Request, ReadError> {
+    let limits = &app.config.limits;
+    let mut head = vec![];
+    let mut max_line = limits.max_request_line;
+    let mut header_bytes = 0;
+    loop {
+        let start = head.len();
+        let length = (&mut *stream)
+            .take(max_line as u64 + 1)
+            .read_until(b'\n', &mut head)
+            .await
+            .map_err(|e| ReadError::Incomplete(format!("Failed to read request: {e:?}")))?;
+        // Stop at the end of the headers, or once the limits are exceeded, leaving the error
+        // to the parser.
+        if length == 0 || length > max_line || (start > 0 && head[start..].trim_ascii().is_empty())
+        {
+            break;
+        }
+        if start > 0 {
+            header_bytes += length;
+        }
+        max_line = limits.max_header_bytes - header_bytes;
+    }
+    Request::read_head(&mut head.as_slice(), limits)
+}
+
+async fn read_body(
+    stream: &mut BufReader<TcpStream>,
+    app: &App,
+    mut request: Request,
+) -> Result<Request, ReadError> {
+    let limits = &app.config.limits;
+    let body = match request.is_chunked()? {
+        true => read_chunked(stream, limits).await?,
+        false => {
+            let mut body = vec![0; request.content_length(limits)?];
+            stream
+                .read_exact(&mut body)
+                .await
+                .map_err(|e| ReadError::Incomplete(format!("Failed to read body: {e:?}")))?;
+            body
+        }
+    };
+    request.read_body(&mut body.as_slice(), limits)?;
+    Ok(request)
+}
+
+/// Read a chunked body as it was sent, for `Request::read_body` to decode. Only the chunk sizes
+/// are parsed here, to find where the body ends within the limits.
+async fn read_chunked(
+    stream: &mut BufReader<TcpStream>,
+    limits: &RequestLimits,
+) -> Result<Vec<u8>, ReadError> {
+    let mut raw = vec![];
+    let mut length = 0;
+    loop {
+        let size = chunk_size(&read_line(stream, &mut raw, MAX_CHUNK_LINE).await?)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_bytes - length {
+            return Err(ReadError::TooLarge(
+                StatusCode::PayloadTooLarge,
+                "Chunked request body too large".to_string(),
+            ));
+        }
+        length += size;
+        // The chunk and the line break after it.
+        let start = raw.len();
+        raw.resize(start + size + 2, 0);
+        stream
+            .read_exact(&mut raw[start..])
+            .await
+            .map_err(|e| ReadError::Incomplete(format!("Failed to read body: {e:?}")))?;
+    }
+    let mut trailer_bytes = 0;
+    loop {
+        let max = limits.max_header_bytes.saturating_sub(trailer_bytes);
+        let line = read_line(stream, &mut raw, max).await?;
+        if line.is_empty() {
+            return Ok(raw);
+        }
+        trailer_bytes += line.len() + 2;
+    }
+}
+
+/// Read a line of at most `max` bytes onto `raw`, returning it without its line break.
+async fn read_line(
+    stream: &mut BufReader<TcpStream>,
+    raw: &mut Vec<u8>,
+    max: usize,
+) -> Result<String, ReadError> {
+    let start = raw.len();
+    (&mut *stream)
+        .take(max as u64 + 2)
+        .read_until(b'\n', raw)
+        .await
+        .map_err(|e| ReadError::Incomplete(format!("Failed to read body: {e:?}")))?;
+    let line = &raw[start..];
+    match line.ends_with(b"\n") {
+        true => Ok(String::from_utf8_lossy(line)
+            .trim_end_matches(['\r', '\n'])
+            .to_string()),
+        false if line.len() <= max + 1 => Err(ReadError::Incomplete(
+            "Chunked body ended early".to_string(),
+        )),
+        false => Err(ReadError::TooLarge(
+            StatusCode::PayloadTooLarge,
+            "Chunk line too long".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::{create_app, AppConfig, Response, StatusCode};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn matches() {
+        let resource = AsyncResource::new(
+            RequestType::GET,
+            "/async".to_string(),
+            ResourceType::TEXT,
+            |_| async { Ok(Response::text(StatusCode::OK, "async")) },
+        );
+        assert!(resource.matches(RequestType::GET, "/async"));
+        assert!(!resource.matches(RequestType::POST, "/async"));
+        assert!(!resource.matches(RequestType::GET, "/async/"));
+        assert!(!resource.matches(RequestType::GET, "/"));
+    }
+
+    #[test]
+    fn shutdown() {
+        let addr: SocketAddr = "127.0.0.1:7730".parse().unwrap();
+        let mut app = create_app(AppConfig::new(addr, 1, 5));
+        app.register_async_resource(AsyncResource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            |_| async { Ok(Response::text(StatusCode::OK, "last")) },
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let server = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(app.run_async(Some(stop_flag_clone)))
+        });
+        std::thread::sleep(Duration::from_millis(100)); // Give the app time to start up
+
+        // The request after the flag is set is answered, and closes the connection.
+        stop_flag.store(true, Ordering::SeqCst);
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("Connection: close\r\nContent-Length: 4\r\n\r\nlast"));
+        assert!(server.join().unwrap().is_ok());
+
+        // Accept errors about a single connection don't stop the loop, others do.
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(is_connection_error(&reset));
+        assert!(out_of_files(&io::Error::from_raw_os_error(24)));
+        assert!(!is_connection_error(&io::Error::other("listener closed")));
+    }
+}
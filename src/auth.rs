@@ -0,0 +1,202 @@
+use crate::digest::{base64_decode, constant_time_eq};
+use crate::log;
+use crate::webserver::{Middleware, Request, Response, StatusCode};
+
+/// Checks a username and password sent with `Authorization: Basic`.
+pub type BasicVerifier = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// Checks a token sent with `Authorization: Bearer`.
+pub type BearerVerifier = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// The username of Basic credentials `Auth` accepted, in the request's extensions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthenticatedUser(pub String);
+
+/// Middleware that answers 401 for requests under `prefix` without valid credentials.
+///
+/// Basic credentials and Bearer tokens are each only accepted once a verifier for them is set.
+/// Requests with accepted Basic credentials get an `AuthenticatedUser` in their extensions.
+pub struct Auth {
+    prefix: String,
+    realm: String,
+    basic: Option<BasicVerifier>,
+    bearer: Option<BearerVerifier>,
+}
+
+impl Auth {
+    pub fn new(prefix: &str, realm: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            realm: realm.replace('"', ""),
+            basic: None,
+            bearer: None,
+        }
+    }
+
+    /// Accept Basic credentials for any of these usernames and passwords.
+    pub fn with_users(self, users: &[(&str, &str)]) -> Self {
+        let users: Vec<(String, String)> = users
+            .iter()
+            .map(|(user, password)| (user.to_string(), password.to_string()))
+            .collect();
+        self.with_basic(Box::new(move |user, password| {
+            // Check every entry, so the time taken doesn't reveal which usernames exist.
+            users.iter().fold(false, |found, (u, p)| {
+                found
+                    | (constant_time_eq(u.as_bytes(), user.as_bytes())
+                        & constant_time_eq(p.as_bytes(), password.as_bytes()))
+            })
+        }))
+    }
+
+    pub fn with_basic(mut self, verifier: BasicVerifier) -> Self {
+        self.basic = Some(verifier);
+        self
+    }
+
+    pub fn with_bearer(mut self, verifier: BearerVerifier) -> Self {
+        self.bearer = Some(verifier);
+        self
+    }
+
+    /// Whether the request carries credentials accepted by one of the verifiers.
+    pub fn verify(&self, request: &Request) -> bool {
+        let Some((scheme, credentials)) = request
+            .header("Authorization")
+            .and_then(|value| value.trim().split_once(' '))
+        else {
+            return false;
+        };
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let Some(basic) = &self.basic else {
+                return false;
+            };
+            match basic_credentials(credentials) {
+                Some((user, password)) => basic(&user, &password),
+                None => false,
+            }
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            self.bearer
+                .as_ref()
+                .is_some_and(|bearer| bearer(credentials))
+        } else {
+            false
+        }
+    }
+
+    fn unauthorized(&self) -> Response {
+        let mut response = Response::empty(StatusCode::Unauthorized);
+        if self.basic.is_some() {
+            response = response.with_header(
+                "WWW-Authenticate",
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+            );
+        }
+        if self.bearer.is_some() {
+            response = response.with_header(
+                "WWW-Authenticate",
+                format!("Bearer realm=\"{}\"", self.realm),
+            );
+        }
+        response
+    }
+}
+
+/// The username and password of `Authorization: Basic` credentials.
+fn basic_credentials(credentials: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(base64_decode(credentials)?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+impl Middleware for Auth {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if !request.path().starts_with(&self.prefix) {
+            return None;
+        }
+        if self.verify(request) {
+            let user = request
+                .header("Authorization")
+                .and_then(|value| value.trim().split_once(' '))
+                .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Basic"))
+                .and_then(|(_, credentials)| basic_credentials(credentials.trim()));
+            if let Some((user, _)) = user {
+                request.extensions_mut().insert(AuthenticatedUser(user));
+            }
+            return None;
+        }
+        log!("Rejected unauthorized request: {}", request.path());
+        Some(self.unauthorized())
+    }
+
+    fn names(&self, path: &str) -> Vec<String> {
+        match path.starts_with(&self.prefix) {
+            true => vec!["Auth".to_string()],
+            false => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::base64_encode;
+
+    fn request(path: &str, authorization: Option<&str>) -> Request {
+        let raw = match authorization {
+            Some(value) => format!("GET {path} HTTP/1.1\r\nAuthorization: {value}\r\n\r\n"),
+            None => format!("GET {path} HTTP/1.1\r\n\r\n"),
+        };
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64_encode(credentials.as_bytes()))
+    }
+
+    #[test]
+    fn basic_auth() {
+        let auth = Auth::new("/admin", "Admin").with_users(&[("daan", "hunter2"), ("guest", "")]);
+        assert!(auth.verify(&request("/admin", Some(&basic("daan:hunter2")))));
+        assert!(auth.verify(&request("/admin", Some(&basic("guest:")))));
+        assert!(!auth.verify(&request("/admin", Some(&basic("daan:hunter3")))));
+        assert!(!auth.verify(&request("/admin", Some(&basic("nobody:hunter2")))));
+        assert!(!auth.verify(&request("/admin", Some("Basic not-base64"))));
+        assert!(!auth.verify(&request("/admin", Some("Bearer hunter2"))));
+        assert!(!auth.verify(&request("/admin", None)));
+    }
+
+    #[test]
+    fn bearer_auth() {
+        let auth = Auth::new("/api", "API").with_bearer(Box::new(|token| token == "secret"));
+        assert!(auth.verify(&request("/api", Some("Bearer secret"))));
+        assert!(auth.verify(&request("/api", Some("bearer  secret"))));
+        assert!(!auth.verify(&request("/api", Some("Bearer wrong"))));
+        assert!(!auth.verify(&request("/api", Some(&basic("secret:secret")))));
+    }
+
+    #[test]
+    fn auth_middleware() {
+        let auth = Auth::new("/admin", "Admin")
+            .with_users(&[("daan", "hunter2")])
+            .with_bearer(Box::new(|token| token == "secret"));
+        assert!(auth.before(&mut request("/public", None)).is_none());
+        let mut bearer = request("/admin/page", Some("Bearer secret"));
+        assert!(auth.before(&mut bearer).is_none());
+        assert!(!bearer.extensions().contains::<AuthenticatedUser>());
+        let mut basic = request("/admin/page", Some(&basic("daan:hunter2")));
+        assert!(auth.before(&mut basic).is_none());
+        assert_eq!(
+            basic.extensions().get(),
+            Some(&AuthenticatedUser("daan".to_string()))
+        );
+
+        let response = auth.before(&mut request("/admin/page", None)).unwrap();
+        assert_eq!(
+            response.header("WWW-Authenticate").unwrap(),
+            "Basic realm=\"Admin\", charset=\"UTF-8\""
+        );
+    }
+}
@@ -0,0 +1,463 @@
+use crate::log;
+use crate::pagination::PageRequest;
+use crate::security::safe_path;
+use crate::templates::{Context, Templates, Value};
+use crate::webserver::{
+    Error, Middleware, Request, RequestType, Response, StatusCode, UtcDateTime,
+};
+use pulldown_cmark::{html, Options, Parser};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// A post converted to HTML, with when its file was last modified.
+#[derive(Clone)]
+struct Post {
+    title: String,
+    /// As `YYYY-MM-DD`, from the front matter or else when the file was last modified.
+    date: String,
+    tags: Vec<String>,
+    html: String,
+    modified: SystemTime,
+}
+
+impl Post {
+    /// The values the post is rendered with, in its own page and in the index.
+    fn context(&self) -> Context {
+        let tags = self
+            .tags
+            .iter()
+            .map(|tag| Context::new().with("tag", tag.as_str()))
+            .collect::<Vec<_>>();
+        Context::new()
+            .with("title", self.title.as_str())
+            .with("date", self.date.as_str())
+            .with("tags", tags)
+    }
+}
+
+/// The fields of the front matter a post can start with, between lines of `---`:
+///
+/// ```text
+/// ---
+/// title: Serving a website from scratch
+/// date: 2024-05-01
+/// tags: rust, http
+/// ---
+/// ```
+#[derive(Default, PartialEq, Debug)]
+struct FrontMatter {
+    title: Option<String>,
+    date: Option<String>,
+    tags: Vec<String>,
+}
+
+/// The front matter of a post, if it has any, and the Markdown after it. Unknown fields and
+/// dates not formatted as `YYYY-MM-DD` are left out.
+fn front_matter(markdown: &str) -> (FrontMatter, &str) {
+    let mut front_matter = FrontMatter::default();
+    let Some(rest) = markdown
+        .strip_prefix("---\n")
+        .or_else(|| markdown.strip_prefix("---\r\n"))
+    else {
+        return (front_matter, markdown);
+    };
+    let mut offset = markdown.len() - rest.len();
+    for line in rest.split_inclusive('\n') {
+        offset += line.len();
+        let line = line.trim();
+        if line == "---" {
+            return (front_matter, &markdown[offset..]);
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "title" => front_matter.title = Some(value.to_string()),
+            "date" if is_date(value) => front_matter.date = Some(value.to_string()),
+            "tags" => {
+                front_matter.tags = value
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            _ => {}
+        }
+    }
+    // Without a closing line it wasn't front matter after all.
+    (FrontMatter::default(), markdown)
+}
+
+/// Whether the text is a date formatted as `YYYY-MM-DD`.
+fn is_date(text: &str) -> bool {
+    let parts = text.split('-').collect::<Vec<_>>();
+    let number = |part: &str, length: usize, range: std::ops::RangeInclusive<u32>| {
+        part.len() == length
+            && part.bytes().all(|byte| byte.is_ascii_digit())
+            && part.parse().is_ok_and(|value| range.contains(&value))
+    };
+    parts.len() == 3
+        && number(parts[0], 4, 0..=9999)
+        && number(parts[1], 2, 1..=12)
+        && number(parts[2], 2, 1..=31)
+}
+
+/// Blog posts written in Markdown, served for GET requests to `<prefix>/<name>` from
+/// `<dir>/<name>.md`, so publishing a post is dropping a file in the directory.
+///
+/// Posts are rendered with the `layout` template, which gets the post as `{{content}}`, along
+/// with the `title`, `date` and `tags` (each as `{{tag}}`) from its front matter, see
+/// `FrontMatter`. Without a title there, the first `# heading` is used, and without a date,
+/// the day the file was last modified. With `with_index`, `<prefix>/` lists the posts, newest
+/// first. Converted posts are cached until their file changes.
+pub struct Blog {
+    prefix: String,
+    dir: PathBuf,
+    templates: Arc<Templates>,
+    layout: String,
+    index: Option<String>,
+    per_page: usize,
+    cache: Mutex<HashMap<String, Post>>,
+}
+
+impl Blog {
+    pub fn new(
+        prefix: &str,
+        dir: impl Into<PathBuf>,
+        templates: Arc<Templates>,
+        layout: &str,
+    ) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            dir: dir.into(),
+            templates,
+            layout: layout.to_string(),
+            index: None,
+            per_page: 10,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Answer `<prefix>/` with the `index` template, which gets a page of posts as
+    /// `{{#each posts}}`, each with its `name`, `url`, `title`, `date` and `tags`.
+    ///
+    /// The page is chosen with the `page` and `per_page` query parameters, see `PageRequest`,
+    /// and `tag` only lists the posts with that tag. The template also gets `page`,
+    /// `total_pages`, and `prev_url` and `next_url` where there are such pages.
+    pub fn with_index(mut self, index: &str) -> Self {
+        self.index = Some(index.to_string());
+        self
+    }
+
+    /// The number of posts on a page of the index, unless the request asks for another number
+    /// up to 100. Defaults to 10.
+    pub fn with_per_page(mut self, per_page: usize) -> Self {
+        self.per_page = per_page;
+        self
+    }
+
+    /// The post at `<dir>/<name>.md`, converted to HTML, or `None` if there is no such post.
+    fn post(&self, name: &str) -> Result<Option<Post>, Error> {
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Ok(None);
+        }
+        let path = match safe_path(&self.dir, &format!("{name}.md")) {
+            Ok(path) => path,
+            Err(e) => {
+                log!("Rejected path: {e}");
+                return Ok(None);
+            }
+        };
+        let Ok(modified) = fs::metadata(&path).and_then(|metadata| metadata.modified()) else {
+            return Ok(None);
+        };
+        if let Some(post) = self.cache.lock().unwrap().get(name) {
+            if post.modified == modified {
+                return Ok(Some(post.clone()));
+            }
+        }
+        let markdown = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read post {}: {e}", path.display()))?;
+        let (front_matter, markdown) = front_matter(&markdown);
+        let post = Post {
+            title: front_matter
+                .title
+                .unwrap_or_else(|| title(markdown).unwrap_or(name).to_string()),
+            date: front_matter.date.unwrap_or_else(|| {
+                let t = UtcDateTime::new(modified);
+                format!("{:04}-{:02}-{:02}", t.year, t.month, t.day)
+            }),
+            tags: front_matter.tags,
+            html: to_html(markdown),
+            modified,
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), post.clone());
+        Ok(Some(post))
+    }
+
+    fn post_page(&self, name: &str) -> Result<Option<Response>, Error> {
+        let Some(post) = self.post(name)? else {
+            return Ok(None);
+        };
+        let context = post.context().with("content", Value::Html(post.html));
+        self.templates.response(&self.layout, &context).map(Some)
+    }
+
+    fn index_page(&self, request: &Request) -> Result<Option<Response>, Error> {
+        let Some(index) = &self.index else {
+            return Ok(None);
+        };
+        let mut posts = vec![];
+        for entry in fs::read_dir(&self.dir).map_err(|e| e.to_string())? {
+            let file_name = entry.map_err(|e| e.to_string())?.file_name();
+            let Some(name) = file_name
+                .to_string_lossy()
+                .strip_suffix(".md")
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            if let Some(post) = self.post(&name)? {
+                posts.push((name, post));
+            }
+        }
+        if let Some(tag) = request.query_param_decoded("tag") {
+            posts.retain(|(_, post)| post.tags.contains(&tag));
+        }
+        posts.sort_by(|(_, a), (_, b)| (&b.date, b.modified).cmp(&(&a.date, a.modified)));
+
+        let page = PageRequest::from_request(request, self.per_page, 100);
+        let info = page.info(posts.len());
+        let items = page
+            .slice(&posts)
+            .iter()
+            .map(|(name, post)| {
+                post.context()
+                    .with("name", name.as_str())
+                    .with("url", format!("{}/{name}", self.prefix))
+            })
+            .collect::<Vec<_>>();
+        let mut context = Context::new()
+            .with("posts", items)
+            .with("page", info.page.to_string())
+            .with("total_pages", info.total_pages.to_string());
+        if let Some(prev) = info.prev {
+            context.insert("prev_url", info.url(request, prev));
+        }
+        if let Some(next) = info.next {
+            context.insert("next_url", info.url(request, next));
+        }
+        let mut response = self.templates.response(index, &context)?;
+        info.apply(request, &mut response);
+        Ok(Some(response))
+    }
+}
+
+impl Middleware for Blog {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if request.request_type() != RequestType::GET {
+            return None;
+        }
+        let result = match request.path().strip_prefix(&self.prefix)? {
+            "" | "/" => self.index_page(request),
+            rest => self.post_page(rest.strip_prefix('/')?),
+        };
+        match result {
+            Ok(response) => response,
+            Err(e) => {
+                log!("Failed to render blog page {}: {e}", request.path());
+                Some(Response::empty(StatusCode::InternalServerError))
+            }
+        }
+    }
+}
+
+/// The text of the first `# heading`.
+fn title(markdown: &str) -> Option<&str> {
+    markdown
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(str::trim)
+}
+
+/// Convert Markdown, with tables, footnotes and strikethrough, to HTML.
+pub fn to_html(markdown: &str) -> String {
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES | Options::ENABLE_STRIKETHROUGH;
+    let mut output = String::new();
+    html::push_html(&mut output, Parser::new_ext(markdown, options));
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::Body;
+    use std::{fs::File, io::BufReader, time::Duration};
+
+    fn request(path: &str) -> Request {
+        let text = format!("GET {path} HTTP/1.1\r\n\r\n");
+        Request::from_reader(&mut BufReader::new(text.as_bytes())).unwrap()
+    }
+
+    fn body(response: Response) -> String {
+        match response.body {
+            Body::Text(text) => text,
+            _ => panic!("Expected a text body"),
+        }
+    }
+
+    #[test]
+    fn markdown() {
+        assert_eq!(
+            to_html("# Title\n\nSome *text* & ~~more~~."),
+            "<h1>Title</h1>\n<p>Some <em>text</em> &amp; <del>more</del>.</p>\n"
+        );
+        assert_eq!(title("Intro\n#  Post title \n# Other"), Some("Post title"));
+        assert_eq!(title("No heading"), None);
+    }
+
+    #[test]
+    fn posts() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_blog");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("posts")).unwrap();
+        fs::create_dir_all(dir.join("templates")).unwrap();
+        fs::write(
+            dir.join("templates").join("post.html"),
+            "<title>{{title}}</title>{{content}}",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("templates").join("index.html"),
+            "{{#each posts}}<a href=\"{{url}}\">{{title}}</a>{{/each}}",
+        )
+        .unwrap();
+        let first = dir.join("posts").join("first.md");
+        fs::write(&first, "# First & best\n\nHello").unwrap();
+        fs::write(dir.join("posts").join("second.md"), "No title").unwrap();
+        fs::write(dir.join("posts").join(".draft.md"), "# Draft").unwrap();
+        File::options()
+            .write(true)
+            .open(&first)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+
+        let templates = Arc::new(Templates::new(dir.join("templates")));
+        let blog = Blog::new(
+            "/blog/",
+            dir.join("posts"),
+            Arc::clone(&templates),
+            "post.html",
+        );
+        let page = |blog: &Blog, path: &str| blog.before(&mut request(path));
+
+        assert_eq!(
+            body(page(&blog, "/blog/first").unwrap()),
+            "<title>First &amp; best</title><h1>First &amp; best</h1>\n<p>Hello</p>\n"
+        );
+        assert!(body(page(&blog, "/blog/second").unwrap()).starts_with("<title>second</title>"));
+        assert!(page(&blog, "/blog/missing").is_none());
+        assert!(page(&blog, "/blog/.draft").is_none());
+        assert!(page(&blog, "/blog/%2e%2e%2fsecret").is_none());
+        assert!(page(&blog, "/blogs/first").is_none());
+        assert!(page(&blog, "/blog/").is_none());
+
+        let blog = blog.with_index("index.html");
+        assert_eq!(
+            body(page(&blog, "/blog").unwrap()),
+            "<a href=\"/blog/second\">second</a><a href=\"/blog/first\">First &amp; best</a>"
+        );
+        let response = page(&blog, "/blog/?per_page=1&page=2").unwrap();
+        assert_eq!(response.header("X-Total-Count"), Some("2"));
+        assert_eq!(
+            body(response),
+            "<a href=\"/blog/first\">First &amp; best</a>"
+        );
+
+        // A changed post is converted again.
+        fs::write(&first, "# Updated").unwrap();
+        assert!(body(page(&blog, "/blog/first").unwrap()).starts_with("<title>Updated</title>"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_front_matter() {
+        let (parsed, rest) = front_matter(
+            "---\ntitle: Hello: world\ndate: 2024-05-01\ntags: [rust, http, ]\nlayout: x\n---\n# Body",
+        );
+        assert_eq!(
+            parsed,
+            FrontMatter {
+                title: Some("Hello: world".to_string()),
+                date: Some("2024-05-01".to_string()),
+                tags: vec!["rust".to_string(), "http".to_string()],
+            }
+        );
+        assert_eq!(rest, "# Body");
+        assert_eq!(
+            front_matter("---\r\ndate: 1 May\r\n---\r\n").0,
+            FrontMatter::default()
+        );
+        assert_eq!(front_matter("# No front matter").1, "# No front matter");
+        assert_eq!(front_matter("---\ntitle: x\n").1, "---\ntitle: x\n");
+        assert!(is_date("2024-12-31") && !is_date("2024-13-01") && !is_date("24-01-01"));
+    }
+
+    #[test]
+    fn index_pages() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_blog_index");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("posts")).unwrap();
+        fs::create_dir_all(dir.join("templates")).unwrap();
+        fs::write(
+            dir.join("templates").join("index.html"),
+            "{{#each posts}}{{date}} {{title}} [{{#each tags}}{{tag}};{{/each}}]\n{{/each}}\
+             {{page}}/{{total_pages}} {{prev_url}} {{next_url}}",
+        )
+        .unwrap();
+        for (name, date, tags) in [
+            ("old", "2023-01-10", "rust"),
+            ("new", "2024-03-01", "rust, http"),
+            ("middle", "2023-06-15", "http"),
+        ] {
+            fs::write(
+                dir.join("posts").join(format!("{name}.md")),
+                format!("---\ntitle: {name}\ndate: {date}\ntags: {tags}\n---\nText"),
+            )
+            .unwrap();
+        }
+
+        let templates = Arc::new(Templates::new(dir.join("templates")));
+        let blog = Blog::new("/blog", dir.join("posts"), templates, "post.html")
+            .with_index("index.html")
+            .with_per_page(2);
+        let index = |path: &str| body(blog.before(&mut request(path)).unwrap());
+        assert_eq!(
+            index("/blog/"),
+            "2024-03-01 new [rust;http;]\n2023-06-15 middle [http;]\n1/2  /blog/?page=2"
+        );
+        assert_eq!(
+            index("/blog/?page=2"),
+            "2023-01-10 old [rust;]\n2/2 /blog/?page=1 "
+        );
+        assert_eq!(
+            index("/blog?tag=rust"),
+            "2024-03-01 new [rust;http;]\n2023-01-10 old [rust;]\n1/1  "
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    cell::RefCell,
+    io::{self, BufRead, Read},
+    thread::LocalKey,
+};
+
+/// Bytes read from a connection at a time, as many as a `BufReader` reads.
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+/// Buffers that grew larger than this, such as for a large response, are dropped rather than
+/// kept, so one of those doesn't hold on to its memory.
+const MAX_KEPT_CAPACITY: usize = 64 * 1024;
+/// Buffers of each kind a thread keeps. A request only needs a few at a time.
+const MAX_KEPT: usize = 4;
+
+thread_local! {
+    /// Strings for the lines of requests and the heads of responses.
+    static STRINGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    /// Bytes for reading connections and assembling responses.
+    static BYTES: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An empty string, reusing one of those this thread gave back with `recycle_string`, so the
+/// worker threads don't allocate them for every request.
+pub(crate) fn string() -> String {
+    STRINGS.with_borrow_mut(Vec::pop).unwrap_or_default()
+}
+
+/// Keep `string` for the next `string` on this thread.
+pub(crate) fn recycle_string(mut string: String) {
+    string.clear();
+    let capacity = string.capacity();
+    keep(&STRINGS, string, capacity);
+}
+
+/// An empty byte buffer, see `string`.
+pub(crate) fn bytes() -> Vec<u8> {
+    BYTES.with_borrow_mut(Vec::pop).unwrap_or_default()
+}
+
+/// Keep `bytes` for the next `bytes` on this thread.
+pub(crate) fn recycle_bytes(mut bytes: Vec<u8>) {
+    bytes.clear();
+    let capacity = bytes.capacity();
+    keep(&BYTES, bytes, capacity);
+}
+
+fn keep<T>(pool: &'static LocalKey<RefCell<Vec<T>>>, buffer: T, capacity: usize) {
+    if capacity == 0 || capacity > MAX_KEPT_CAPACITY {
+        return;
+    }
+    // The buffers are already gone while the thread exits.
+    let _ = pool.try_with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < MAX_KEPT {
+            pool.push(buffer);
+        }
+    });
+}
+
+/// Like a `BufReader`, but with a buffer from `bytes`, which is given back once it's dropped.
+pub(crate) struct Reader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    position: usize,
+    filled: usize,
+}
+
+impl<R: Read> Reader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        let mut buffer = bytes();
+        buffer.resize(READ_BUFFER_SIZE, 0);
+        Self {
+            inner,
+            buffer,
+            position: 0,
+            filled: 0,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// What was read from the inner reader, but not from this one yet.
+    pub(crate) fn buffer(&self) -> &[u8] {
+        &self.buffer[self.position..self.filled]
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        // Large reads skip the buffer, like they do with a `BufReader`.
+        if self.position == self.filled && out.len() >= self.buffer.len() {
+            return self.inner.read(out);
+        }
+        let read = self.fill_buf()?.read(out)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<R: Read> BufRead for Reader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.filled {
+            self.filled = self.inner.read(&mut self.buffer)?;
+            self.position = 0;
+        }
+        Ok(self.buffer())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = (self.position + amount).min(self.filled);
+    }
+}
+
+impl<R> Drop for Reader<R> {
+    fn drop(&mut self) {
+        recycle_bytes(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused() {
+        let mut string = string();
+        string.push_str("GET / HTTP/1.1");
+        let address = string.as_ptr();
+        recycle_string(string);
+        let string = super::string();
+        assert!(string.is_empty());
+        assert_eq!(string.as_ptr(), address);
+        recycle_string(string);
+
+        // Too large to keep.
+        recycle_bytes(vec![0; MAX_KEPT_CAPACITY + 1]);
+        assert_eq!(bytes().capacity(), 0);
+        for _ in 0..MAX_KEPT + 1 {
+            recycle_bytes(Vec::with_capacity(16));
+        }
+        assert_eq!(BYTES.with_borrow(Vec::len), MAX_KEPT);
+    }
+
+    #[test]
+    fn reader() {
+        // Lines across the end of the buffer, and a read larger than it.
+        let mut text = "a".repeat(READ_BUFFER_SIZE - 2);
+        text.push_str("\r\nline\r\n");
+        text.push_str(&"b".repeat(2 * READ_BUFFER_SIZE));
+        let mut reader = Reader::new(text.as_bytes());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line.len(), READ_BUFFER_SIZE);
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "line\r\n");
+        assert_eq!(reader.buffer().len(), READ_BUFFER_SIZE - 6);
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest.len(), 2 * READ_BUFFER_SIZE);
+
+        let buffer = reader.buffer.as_ptr();
+        drop(reader);
+        let reused = bytes();
+        assert_eq!(reused.as_ptr(), buffer);
+    }
+}
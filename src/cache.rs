@@ -0,0 +1,312 @@
+use crate::log;
+use crate::webserver::{
+    http_date, Body, Middleware, Request, RequestType, Resource, ResourceType, Response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Entries are only added while the cache holds fewer than this many, by default.
+const MAX_ENTRIES: usize = 1_000;
+
+struct Entry {
+    status_code: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    keys: Vec<String>,
+    expires: Instant,
+}
+
+/// Middleware that caches GET responses tagged with surrogate keys, see
+/// `Response::with_surrogate_keys`, and serves them again until they expire or are purged.
+///
+/// Responses that set cookies, and requests with credentials, are never cached. Register the
+/// cache before other middleware, so the headers that middleware adds aren't cached as well.
+/// Register it as `Box::new(Arc::clone(&cache))` to share it with its `purge_resource`.
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: AtomicUsize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            max_entries: AtomicUsize::new(MAX_ENTRIES),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Only add entries while the cache holds fewer than `max_entries`. Defaults to 1000.
+    pub fn with_max_entries(self, max_entries: usize) -> Self {
+        self.set_max_entries(max_entries);
+        self
+    }
+
+    /// Like `with_max_entries`, but while the cache is in use, such as after the configuration
+    /// changed. Entries over the new maximum are dropped once they expire.
+    pub fn set_max_entries(&self, max_entries: usize) {
+        self.max_entries.store(max_entries, Ordering::Relaxed);
+    }
+
+    /// Remove every entry tagged with `key`. Returns how many were removed.
+    pub fn purge(&self, key: &str) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| !entry.keys.iter().any(|k| k == key));
+        before - entries.len()
+    }
+
+    pub fn purge_all(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A POST resource at `path` that purges the surrogate keys in the request body, separated
+    /// by whitespace, or everything for `*`. Protect the path with `Auth`.
+    pub fn purge_resource(self: &Arc<Self>, path: &str) -> Resource {
+        let cache = Arc::clone(self);
+        Resource::new(
+            RequestType::POST,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(move |request| {
+                let body = String::from_utf8_lossy(request.body());
+                let keys: Vec<&str> = body.split_whitespace().collect();
+                if keys.is_empty() {
+                    return Ok(Response::empty(StatusCode::BadRequest));
+                }
+                let purged: usize = match keys[..] {
+                    ["*"] => cache.purge_all(),
+                    _ => keys.iter().map(|key| cache.purge(key)).sum(),
+                };
+                log!("Purged {purged} cached responses for {}", keys.join(" "));
+                Ok(Response::text(StatusCode::OK, format!("Purged {purged}\n")))
+            }),
+        )
+    }
+
+    fn cache_key(request: &Request) -> Option<String> {
+        if request.request_type() != RequestType::GET || request.header("Authorization").is_some() {
+            return None;
+        }
+        Some(match request.query() {
+            Some(query) => format!("{}?{query}", request.path()),
+            None => request.path().to_string(),
+        })
+    }
+}
+
+impl Middleware for ResponseCache {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let key = Self::cache_key(request)?;
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        if entry.expires <= Instant::now() {
+            entries.remove(&key);
+            return None;
+        }
+        let mut response = Response::new(entry.status_code, Body::Bytes(entry.body.clone()));
+        response.headers = entry.headers.clone();
+        response.add_header("X-Cache", "HIT");
+        Some(response)
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        let Some(key) = Self::cache_key(request) else {
+            return;
+        };
+        if !matches!(response.status_code, StatusCode::OK)
+            || response.header("X-Cache").is_some()
+            || response.header("Set-Cookie").is_some()
+        {
+            return;
+        }
+        let Some(keys) = response.header("Surrogate-Key") else {
+            return;
+        };
+        let keys: Vec<String> = keys.split_whitespace().map(String::from).collect();
+
+        let body = match &response.body {
+            Body::File(path) => match fs::read(path) {
+                Ok(content) => content,
+                // Left for `serialize_response` to answer with a 404.
+                Err(_) => return,
+            },
+            Body::Text(text) => text.clone().into_bytes(),
+            Body::Bytes(bytes) => bytes.clone(),
+            // Reading it here would defeat streaming it.
+            Body::Stream(_) => return,
+            Body::Empty => vec![],
+        };
+        // Don't read the file twice.
+        if matches!(response.body, Body::File(_)) {
+            response.body = Body::Bytes(body.clone());
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let max_entries = self.max_entries.load(Ordering::Relaxed);
+        if entries.len() >= max_entries {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= max_entries {
+                return;
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                status_code: response.status_code,
+                headers: response.headers.clone(),
+                body,
+                keys,
+                expires: now + self.ttl,
+            },
+        );
+        response.add_header("X-Cache", "MISS");
+    }
+}
+
+/// How long clients and proxies may keep a resource's responses, set with
+/// `Resource::with_cache`.
+///
+/// The policy is added as `Cache-Control` and `Expires` headers to successful and redirect
+/// responses that don't have a `Cache-Control` header of their own.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CachePolicy {
+    /// Don't keep the response at all, such as for pages with personal data.
+    NoStore,
+    /// Keep the response, but check with the server before every use, such as for HTML pages.
+    NoCache,
+    /// Use the response for this many seconds without checking.
+    MaxAge(u64),
+    /// Use the response for this many seconds, without checking even when the user reloads.
+    /// Only for files whose contents never change under their name.
+    Immutable(u64),
+}
+
+impl CachePolicy {
+    /// The `Cache-Control` header value.
+    pub fn header(&self) -> String {
+        match self {
+            CachePolicy::NoStore => "no-store".to_string(),
+            CachePolicy::NoCache => "no-cache".to_string(),
+            CachePolicy::MaxAge(seconds) => format!("public, max-age={seconds}"),
+            CachePolicy::Immutable(seconds) => format!("public, max-age={seconds}, immutable"),
+        }
+    }
+
+    pub(crate) fn apply(&self, response: &mut Response, now: SystemTime) {
+        if response.status_code.code() >= 400 || response.header("Cache-Control").is_some() {
+            return;
+        }
+        response.add_header("Cache-Control", self.header());
+        if let CachePolicy::MaxAge(seconds) | CachePolicy::Immutable(seconds) = self {
+            // For HTTP/1.0 caches, which don't know Cache-Control.
+            response.add_header("Expires", http_date(now + Duration::from_secs(*seconds)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(raw: &str) -> Request {
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn cache_and_purge() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        let mut get = request("GET /blog?page=2 HTTP/1.1\r\n\r\n");
+        assert!(cache.before(&mut get).is_none());
+
+        let mut response =
+            Response::text(StatusCode::OK, "posts").with_surrogate_keys(&["blog", "post-1"]);
+        cache.after(&get, &mut response);
+        assert_eq!(response.header("X-Cache"), Some("MISS"));
+        assert_eq!(response.header("Cache-Tag"), Some("blog,post-1"));
+        assert_eq!(cache.len(), 1);
+
+        let cached = cache.before(&mut get).unwrap();
+        assert_eq!(cached.header("X-Cache"), Some("HIT"));
+        assert_eq!(cached.header("Surrogate-Key"), Some("blog post-1"));
+
+        assert_eq!(cache.purge("post-2"), 0);
+        assert_eq!(cache.purge("post-1"), 1);
+        assert!(cache.before(&mut get).is_none());
+    }
+
+    #[test]
+    fn not_cached() {
+        let cache = ResponseCache::new(Duration::from_secs(60));
+        let tagged = || Response::text(StatusCode::OK, "posts").with_surrogate_keys(&["blog"]);
+
+        let get = request("GET /blog HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n");
+        cache.after(&get, &mut tagged());
+        let post = request("POST /blog HTTP/1.1\r\n\r\n");
+        cache.after(&post, &mut tagged());
+
+        let get = request("GET /blog HTTP/1.1\r\n\r\n");
+        cache.after(&get, &mut Response::text(StatusCode::OK, "untagged"));
+        cache.after(&get, &mut tagged().with_header("Set-Cookie", "session=1"));
+        assert!(cache.is_empty());
+
+        let cache = ResponseCache::new(Duration::ZERO);
+        let mut get = request("GET /blog HTTP/1.1\r\n\r\n");
+        cache.after(&get, &mut tagged());
+        assert!(cache.before(&mut get).is_none());
+        assert!(cache.is_empty());
+
+        let cache = ResponseCache::new(Duration::from_secs(60)).with_max_entries(1);
+        cache.after(&get, &mut tagged());
+        cache.after(&request("GET /other HTTP/1.1\r\n\r\n"), &mut tagged());
+        assert_eq!(cache.len(), 1);
+        cache.set_max_entries(2);
+        cache.after(&request("GET /other HTTP/1.1\r\n\r\n"), &mut tagged());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn cache_policy() {
+        let now = std::time::UNIX_EPOCH + Duration::from_secs(784111777);
+        let mut response = Response::empty(StatusCode::OK);
+        CachePolicy::MaxAge(60).apply(&mut response, now);
+        assert_eq!(response.header("Cache-Control"), Some("public, max-age=60"));
+        assert_eq!(
+            response.header("Expires"),
+            Some("Sun, 06 Nov 1994 08:50:37 GMT")
+        );
+
+        let mut response = Response::empty(StatusCode::OK);
+        CachePolicy::NoCache.apply(&mut response, now);
+        assert_eq!(response.header("Cache-Control"), Some("no-cache"));
+        assert_eq!(response.header("Expires"), None);
+
+        // Errors and responses with their own policy are left alone.
+        let mut response = Response::empty(StatusCode::NotFound);
+        CachePolicy::MaxAge(60).apply(&mut response, now);
+        assert_eq!(response.header("Cache-Control"), None);
+        let mut response = Response::empty(StatusCode::OK).with_header("Cache-Control", "private");
+        CachePolicy::NoStore.apply(&mut response, now);
+        assert_eq!(response.header("Cache-Control"), Some("private"));
+    }
+}
@@ -0,0 +1,247 @@
+use crate::webserver::{Response, StatusCode, UtcDateTime};
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+pub const CONTENT_TYPE: &str = "text/calendar; charset=utf-8";
+
+/// Whether a calendar is shown by the client or downloaded as a file.
+pub enum Disposition {
+    /// Let the client display or subscribe to the calendar.
+    Inline,
+    /// Ask the client to save the calendar under this file name.
+    Attachment(String),
+}
+
+impl Disposition {
+    fn header(&self) -> String {
+        match self {
+            Disposition::Inline => "inline".to_string(),
+            Disposition::Attachment(name) => {
+                format!("attachment; filename=\"{}\"", name.replace('"', ""))
+            }
+        }
+    }
+}
+
+/// Serve an `.ics` file with the calendar content type. With `refresh` set, clients and caches
+/// are told how long the file can be used before fetching it again.
+pub fn file_response(
+    path: impl Into<PathBuf>,
+    disposition: Disposition,
+    refresh: Option<Duration>,
+) -> Response {
+    with_headers(Response::file(StatusCode::OK, path), disposition, refresh)
+}
+
+fn with_headers(
+    response: Response,
+    disposition: Disposition,
+    refresh: Option<Duration>,
+) -> Response {
+    let response = response
+        .with_header("Content-Type", CONTENT_TYPE)
+        .with_header("Content-Disposition", disposition.header());
+    match refresh {
+        Some(refresh) => {
+            response.with_header("Cache-Control", format!("max-age={}", refresh.as_secs()))
+        }
+        None => response,
+    }
+}
+
+/// A single appointment in a `Calendar`.
+pub struct Event {
+    uid: String,
+    start: SystemTime,
+    end: SystemTime,
+    summary: String,
+    description: Option<String>,
+    location: Option<String>,
+}
+
+impl Event {
+    /// The uid identifies the event across updates of the feed, so it should stay the same
+    /// when the event is changed.
+    pub fn new(uid: &str, start: SystemTime, end: SystemTime, summary: &str) -> Self {
+        Self {
+            uid: uid.to_string(),
+            start,
+            end,
+            summary: summary.to_string(),
+            description: None,
+            location: None,
+        }
+    }
+
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn with_location(mut self, location: &str) -> Self {
+        self.location = Some(location.to_string());
+        self
+    }
+}
+
+/// Builds an iCalendar (RFC 5545) feed, such as a list of times I'm available.
+pub struct Calendar {
+    name: String,
+    refresh: Option<Duration>,
+    events: Vec<Event>,
+}
+
+impl Calendar {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            refresh: None,
+            events: vec![],
+        }
+    }
+
+    /// Ask subscribed clients to fetch the feed again after this long.
+    pub fn with_refresh(mut self, refresh: Duration) -> Self {
+        self.refresh = Some(refresh);
+        self
+    }
+
+    pub fn with_event(mut self, event: Event) -> Self {
+        self.events.push(event);
+        self
+    }
+
+    /// The feed in iCalendar format. `now` is used as the timestamp of every event.
+    pub fn to_ics(&self, now: SystemTime) -> String {
+        let mut lines = vec![
+            "BEGIN:VCALENDAR".to_string(),
+            "VERSION:2.0".to_string(),
+            "PRODID:-//wwwdaanlubbersnl//calendar//EN".to_string(),
+            "CALSCALE:GREGORIAN".to_string(),
+            format!("NAME:{}", escape(&self.name)),
+            format!("X-WR-CALNAME:{}", escape(&self.name)),
+        ];
+        if let Some(refresh) = self.refresh {
+            let duration = format!("PT{}S", refresh.as_secs());
+            lines.push(format!("REFRESH-INTERVAL;VALUE=DURATION:{duration}"));
+            lines.push(format!("X-PUBLISHED-TTL:{duration}"));
+        }
+        for event in &self.events {
+            lines.push("BEGIN:VEVENT".to_string());
+            lines.push(format!("UID:{}", escape(&event.uid)));
+            lines.push(format!("DTSTAMP:{}", utc(now)));
+            lines.push(format!("DTSTART:{}", utc(event.start)));
+            lines.push(format!("DTEND:{}", utc(event.end)));
+            lines.push(format!("SUMMARY:{}", escape(&event.summary)));
+            if let Some(description) = &event.description {
+                lines.push(format!("DESCRIPTION:{}", escape(description)));
+            }
+            if let Some(location) = &event.location {
+                lines.push(format!("LOCATION:{}", escape(location)));
+            }
+            lines.push("END:VEVENT".to_string());
+        }
+        lines.push("END:VCALENDAR".to_string());
+
+        lines.iter().map(|line| fold(line)).collect()
+    }
+
+    pub fn response(&self, disposition: Disposition) -> Response {
+        with_headers(
+            Response::text(StatusCode::OK, self.to_ics(SystemTime::now())),
+            disposition,
+            self.refresh,
+        )
+    }
+}
+
+/// Format a time as a UTC date-time, such as `19970714T173000Z`.
+fn utc(time: SystemTime) -> String {
+    let t = UtcDateTime::new(time);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        t.year, t.month, t.day, t.hour, t.minute, t.second
+    )
+}
+
+/// Escape a TEXT value. vCards use the same rules.
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Terminate a content line with CRLF, splitting it so no line is longer than 75 octets.
+/// Continuation lines start with a space.
+pub(crate) fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn calendar_ics() {
+        let start = UNIX_EPOCH + Duration::from_secs(868901400);
+        let calendar = Calendar::new("Availability")
+            .with_refresh(Duration::from_secs(3600))
+            .with_event(
+                Event::new(
+                    "1@example.com",
+                    start,
+                    start + Duration::from_secs(3600),
+                    "Free",
+                )
+                .with_description("Coffee, or a call; either works\nReply first")
+                .with_location("Utrecht"),
+            );
+        assert_eq!(
+            calendar.to_ics(start),
+            "BEGIN:VCALENDAR\r\n\
+             VERSION:2.0\r\n\
+             PRODID:-//wwwdaanlubbersnl//calendar//EN\r\n\
+             CALSCALE:GREGORIAN\r\n\
+             NAME:Availability\r\n\
+             X-WR-CALNAME:Availability\r\n\
+             REFRESH-INTERVAL;VALUE=DURATION:PT3600S\r\n\
+             X-PUBLISHED-TTL:PT3600S\r\n\
+             BEGIN:VEVENT\r\n\
+             UID:1@example.com\r\n\
+             DTSTAMP:19970714T173000Z\r\n\
+             DTSTART:19970714T173000Z\r\n\
+             DTEND:19970714T183000Z\r\n\
+             SUMMARY:Free\r\n\
+             DESCRIPTION:Coffee\\, or a call\\; either works\\nReply first\r\n\
+             LOCATION:Utrecht\r\n\
+             END:VEVENT\r\n\
+             END:VCALENDAR\r\n"
+        );
+    }
+
+    #[test]
+    fn fold_long_lines() {
+        let line = format!("SUMMARY:{}", "é".repeat(40));
+        let folded = fold(&line);
+        let lines: Vec<&str> = folded.trim_end().split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.len() <= 75));
+        assert_eq!(lines.concat().replacen(' ', "", 1), line);
+    }
+}
@@ -0,0 +1,466 @@
+use crate::http_client::ParsedResponse;
+use crate::webserver::parse_http_date;
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+/// Every read or write of a check fails after this long.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A check of how the server handles one part of HTTP/1.1, from RFC 9110 and RFC 9112.
+pub struct Check {
+    pub name: &'static str,
+    /// Sends requests to the server at the address, for the path of a resource answering GET
+    /// requests with a 200.
+    run: fn(SocketAddr, &str) -> Result<(), String>,
+}
+
+/// The outcome of a check, with why it failed.
+pub struct CheckResult {
+    pub name: &'static str,
+    pub result: Result<(), String>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// All checks: malformed requests, header edge cases, conditional requests and keep-alive.
+///
+/// A server may close the connection instead of answering a request it can't parse, so checks
+/// of malformed requests accept either that or an error status.
+pub fn checks() -> Vec<Check> {
+    vec![
+        Check {
+            name: "GET is answered with an HTTP/1.1 status line",
+            run: status_line,
+        },
+        Check {
+            name: "Content-Length matches the body",
+            run: content_length,
+        },
+        Check {
+            name: "Responses have a valid Date header",
+            run: date_header,
+        },
+        Check {
+            name: "Connection: close closes the connection",
+            run: connection_close,
+        },
+        Check {
+            name: "HTTP/1.0 request is answered and closed",
+            run: http_1_0,
+        },
+        Check {
+            name: "Header names are case-insensitive",
+            run: header_case,
+        },
+        Check {
+            name: "Query string doesn't change the resource",
+            run: query_string,
+        },
+        Check {
+            name: "Unknown path is a 404",
+            run: unknown_path,
+        },
+        Check {
+            name: "Malformed request line is rejected",
+            run: malformed_request_line,
+        },
+        Check {
+            name: "Unsupported method is rejected",
+            run: unsupported_method,
+        },
+        Check {
+            name: "Header without a colon is rejected",
+            run: malformed_header,
+        },
+        Check {
+            name: "Invalid Content-Length is rejected",
+            run: invalid_content_length,
+        },
+        Check {
+            name: "Chunked request body is read",
+            run: chunked_request,
+        },
+        Check {
+            name: "Long request line is a 414",
+            run: long_request_line,
+        },
+        Check {
+            name: "Large headers are a 431",
+            run: large_headers,
+        },
+        Check {
+            name: "Non-matching If-None-Match gets the full response",
+            run: if_none_match_mismatch,
+        },
+        Check {
+            name: "Invalid If-Modified-Since is ignored",
+            run: invalid_if_modified_since,
+        },
+        Check {
+            name: "If-Modified-Since of Last-Modified gets a 304 without a body",
+            run: if_modified_since,
+        },
+        Check {
+            name: "Connection is kept alive or closed cleanly",
+            run: keep_alive,
+        },
+        Check {
+            name: "Pipelined requests are answered in order or closed cleanly",
+            run: pipelining,
+        },
+    ]
+}
+
+/// Run every check against the server at `addr`, with `path` a resource answering GET
+/// requests with a 200.
+pub fn run(addr: SocketAddr, path: &str) -> Vec<CheckResult> {
+    checks()
+        .into_iter()
+        .map(|check| CheckResult {
+            name: check.name,
+            result: (check.run)(addr, path),
+        })
+        .collect()
+}
+
+/// A line per check with whether it passed, followed by the totals.
+pub fn report(results: &[CheckResult]) -> String {
+    let mut report = String::new();
+    for result in results {
+        match &result.result {
+            Ok(()) => report.push_str(&format!("PASS {}\n", result.name)),
+            Err(e) => report.push_str(&format!("FAIL {}: {e}\n", result.name)),
+        }
+    }
+    let passed = results.iter().filter(|result| result.passed()).count();
+    report.push_str(&format!("{passed} of {} checks passed", results.len()));
+    report
+}
+
+fn connect(addr: SocketAddr) -> Result<BufReader<TcpStream>, String> {
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .map_err(|e| format!("Failed to connect to {addr}: {e}"))?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+    Ok(BufReader::new(stream))
+}
+
+/// Send the request, which fails if the server already closed the connection.
+fn send(connection: &mut BufReader<TcpStream>, request: &[u8]) -> Result<(), String> {
+    connection
+        .get_mut()
+        .write_all(request)
+        .map_err(|e| format!("Failed to send request: {e}"))
+}
+
+/// The next response, or `None` if the server closed the connection without one.
+fn receive(connection: &mut BufReader<TcpStream>) -> Result<Option<ParsedResponse>, String> {
+    match connection.fill_buf() {
+        Ok([]) => return Ok(None),
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::ConnectionReset => return Ok(None),
+        Err(e) => return Err(format!("Failed to read response: {e}")),
+    }
+    ParsedResponse::from_reader(connection).map(Some)
+}
+
+fn exchange(addr: SocketAddr, request: &[u8]) -> Result<Option<ParsedResponse>, String> {
+    let mut connection = connect(addr)?;
+    send(&mut connection, request)?;
+    receive(&mut connection)
+}
+
+/// Send a complete GET request for `target` with these extra headers, closing the connection.
+fn get(addr: SocketAddr, target: &str, headers: &str) -> Result<ParsedResponse, String> {
+    let request =
+        format!("GET {target} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{headers}\r\n");
+    exchange(addr, request.as_bytes())?.ok_or_else(|| "No response".to_string())
+}
+
+fn expect_status(response: &ParsedResponse, status: u16) -> Result<(), String> {
+    match response.status() {
+        actual if actual == status => Ok(()),
+        actual => Err(format!("Expected {status}, got {actual}")),
+    }
+}
+
+/// The request is rejected with one of the statuses, or by closing the connection.
+fn expect_rejected(addr: SocketAddr, request: &[u8], statuses: &[u16]) -> Result<(), String> {
+    match exchange(addr, request)? {
+        None => Ok(()),
+        Some(response) if statuses.contains(&response.status()) => Ok(()),
+        Some(response) => Err(format!("Expected rejection, got {}", response.status())),
+    }
+}
+
+/// Nothing follows the response before the server closes the connection.
+fn expect_closed(connection: &mut BufReader<TcpStream>) -> Result<(), String> {
+    let mut rest = vec![];
+    match connection.read_to_end(&mut rest) {
+        Ok(0) => Ok(()),
+        Ok(length) => Err(format!("{length} bytes after the response")),
+        Err(e) if e.kind() == ErrorKind::ConnectionReset => Ok(()),
+        Err(e) => Err(format!("Connection not closed: {e}")),
+    }
+}
+
+fn status_line(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let mut connection = connect(addr)?;
+    send(
+        &mut connection,
+        format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").as_bytes(),
+    )?;
+    let buffer = connection
+        .fill_buf()
+        .map_err(|e| format!("Failed to read response: {e}"))?;
+    if !buffer.starts_with(b"HTTP/1.1 ") {
+        return Err(format!(
+            "Status line starts with {:?}",
+            String::from_utf8_lossy(&buffer[..buffer.len().min(9)])
+        ));
+    }
+    let response = receive(&mut connection)?.ok_or("No response")?;
+    expect_status(&response, 200)
+}
+
+fn content_length(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let mut connection = connect(addr)?;
+    send(
+        &mut connection,
+        format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").as_bytes(),
+    )?;
+    let response = receive(&mut connection)?.ok_or("No response")?;
+    if response.header("Content-Length").is_none() {
+        return Err("No Content-Length".to_string());
+    }
+    expect_closed(&mut connection)
+}
+
+fn date_header(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let response = get(addr, path, "")?;
+    let date = response.header("Date").ok_or("No Date header")?;
+    match parse_http_date(date) {
+        Some(_) => Ok(()),
+        None => Err(format!("Invalid Date header: {date}")),
+    }
+}
+
+fn connection_close(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let mut connection = connect(addr)?;
+    send(
+        &mut connection,
+        format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").as_bytes(),
+    )?;
+    receive(&mut connection)?.ok_or("No response")?;
+    expect_closed(&mut connection)?;
+    // A request after the server closed may still be written, but is never answered.
+    let _ = send(&mut connection, b"GET / HTTP/1.1\r\n\r\n");
+    match receive(&mut connection) {
+        Ok(Some(_)) => Err("Answered a request after closing".to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn http_1_0(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let mut connection = connect(addr)?;
+    send(
+        &mut connection,
+        format!("GET {path} HTTP/1.0\r\n\r\n").as_bytes(),
+    )?;
+    let buffer = connection
+        .fill_buf()
+        .map_err(|e| format!("Failed to read response: {e}"))?;
+    if !buffer.starts_with(b"HTTP/1.0 ") && !buffer.starts_with(b"HTTP/1.1 ") {
+        return Err(format!(
+            "Status line starts with {:?}",
+            String::from_utf8_lossy(&buffer[..buffer.len().min(9)])
+        ));
+    }
+    let response = receive(&mut connection)?.ok_or("No response")?;
+    expect_status(&response, 200)?;
+    // Without keep-alive, an HTTP/1.0 client reads the body until the connection closes.
+    expect_closed(&mut connection)
+}
+
+fn header_case(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let mut connection = connect(addr)?;
+    send(
+        &mut connection,
+        format!("GET {path} HTTP/1.1\r\nhost: localhost\r\nconnection: CLOSE\r\n\r\n").as_bytes(),
+    )?;
+    let response = receive(&mut connection)?.ok_or("No response")?;
+    expect_status(&response, 200)?;
+    expect_closed(&mut connection)
+}
+
+fn query_string(addr: SocketAddr, path: &str) -> Result<(), String> {
+    expect_status(&get(addr, &format!("{path}?compliance=1"), "")?, 200)
+}
+
+fn unknown_path(addr: SocketAddr, _path: &str) -> Result<(), String> {
+    expect_status(&get(addr, "/compliance-check-unknown-path", "")?, 404)
+}
+
+fn malformed_request_line(addr: SocketAddr, _path: &str) -> Result<(), String> {
+    expect_rejected(addr, b"GARBAGE\r\n\r\n", &[400])
+}
+
+fn unsupported_method(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let request = format!("BREW {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    expect_rejected(addr, request.as_bytes(), &[400, 405, 501])
+}
+
+fn malformed_header(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nNo colon\r\n\r\n");
+    expect_rejected(addr, request.as_bytes(), &[400])
+}
+
+fn invalid_content_length(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let request = format!("POST {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1x\r\n\r\n");
+    expect_rejected(addr, request.as_bytes(), &[400])
+}
+
+fn chunked_request(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+         5;ext=1\r\nhello\r\n0\r\n\r\n"
+    );
+    let mut connection = connect(addr)?;
+    send(&mut connection, request.as_bytes())?;
+    let response = receive(&mut connection)?.ok_or("No response")?;
+    expect_status(&response, 200)?;
+    // A server reading the chunks as the next request would answer them with an error.
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    if send(&mut connection, request.as_bytes()).is_err() {
+        return Ok(());
+    }
+    match receive(&mut connection)? {
+        Some(response) => expect_status(&response, 200),
+        None => Ok(()),
+    }
+}
+
+fn long_request_line(addr: SocketAddr, _path: &str) -> Result<(), String> {
+    let target = format!("/{}", "a".repeat(16 * 1024));
+    expect_status(&get(addr, &target, "")?, 414)
+}
+
+fn large_headers(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let headers = format!("X-Padding: {}\r\n", "a".repeat(48 * 1024));
+    expect_status(&get(addr, path, &headers)?, 431)
+}
+
+fn if_none_match_mismatch(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let response = get(addr, path, "If-None-Match: \"compliance-check\"\r\n")?;
+    expect_status(&response, 200)
+}
+
+fn invalid_if_modified_since(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let response = get(addr, path, "If-Modified-Since: not a date\r\n")?;
+    expect_status(&response, 200)
+}
+
+fn if_modified_since(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let response = get(
+        addr,
+        path,
+        "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n",
+    )?;
+    expect_status(&response, 200)?;
+    // Resources without a modification time have nothing to compare to.
+    let Some(last_modified) = response.header("Last-Modified") else {
+        return Ok(());
+    };
+    let mut connection = connect(addr)?;
+    send(
+        &mut connection,
+        format!(
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             If-Modified-Since: {last_modified}\r\n\r\n"
+        )
+        .as_bytes(),
+    )?;
+    let response = receive(&mut connection)?.ok_or("No response")?;
+    expect_status(&response, 304)?;
+    expect_closed(&mut connection)
+}
+
+fn keep_alive(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let mut connection = connect(addr)?;
+    send(&mut connection, request.as_bytes())?;
+    let response = receive(&mut connection)?.ok_or("No response")?;
+    expect_status(&response, 200)?;
+    // The server may close the connection after any response, but then without sending more.
+    if send(&mut connection, request.as_bytes()).is_err() {
+        return Ok(());
+    }
+    match receive(&mut connection)? {
+        Some(response) => expect_status(&response, 200),
+        None => Ok(()),
+    }
+}
+
+fn pipelining(addr: SocketAddr, path: &str) -> Result<(), String> {
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         GET /compliance-check-unknown-path HTTP/1.1\r\nHost: localhost\r\n\r\n"
+    );
+    let mut connection = connect(addr)?;
+    send(&mut connection, request.as_bytes())?;
+    let response = receive(&mut connection)?.ok_or("No response")?;
+    expect_status(&response, 200)?;
+    match receive(&mut connection)? {
+        Some(response) => expect_status(&response, 404),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::*;
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn app_compliance() {
+        const TEST_ADDR: SocketAddr =
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 7698));
+        let mut app = create_app(AppConfig::new(TEST_ADDR, 2, 5));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.html"))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || app.run(Some(stop_flag_clone)));
+        thread::sleep(Duration::from_millis(100));
+
+        let results = run(TEST_ADDR, "/");
+        let report = report(&results);
+        assert!(results.iter().all(CheckResult::passed), "{report}");
+        assert!(report.ends_with(&format!("{0} of {0} checks passed", checks().len())));
+
+        stop_flag.store(true, Ordering::SeqCst);
+        drop(TcpStream::connect(TEST_ADDR).unwrap());
+        thread.join().unwrap().unwrap();
+    }
+}
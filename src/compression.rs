@@ -0,0 +1,411 @@
+#[cfg(feature = "gzip")]
+use crate::log;
+use crate::webserver::Response;
+#[cfg(feature = "gzip")]
+use crate::webserver::{Body, Middleware, Request};
+#[cfg(feature = "gzip")]
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// A content coding responses can be sent with.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Both, brotli first as it compresses better.
+    pub const ALL: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    /// The name in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The extension of a file compressed with it, such as `app.css.br`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    /// Of `available`, the encoding `Accept-Encoding` prefers, see `best_encoding`.
+    pub fn negotiate(accept_encoding: Option<&str>, available: &[Encoding]) -> Option<Encoding> {
+        let names: Vec<&str> = available.iter().map(Encoding::name).collect();
+        let name = crate::negotiation::best_encoding(accept_encoding?, &names)?;
+        available
+            .iter()
+            .find(|encoding| encoding.name() == name)
+            .copied()
+    }
+
+    /// Compress `data`, with a level that is quick enough to do for every response.
+    #[cfg(feature = "gzip")]
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.compress_with(data, false)
+    }
+
+    /// Compress `data` as small as possible, which takes a lot longer, for files compressed once.
+    #[cfg(feature = "gzip")]
+    pub fn compress_best(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.compress_with(data, true)
+    }
+
+    #[cfg(feature = "gzip")]
+    fn compress_with(&self, data: &[u8], best: bool) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => {
+                let quality = if best { 11 } else { 5 };
+                let mut compressed = vec![];
+                let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, quality, 22);
+                writer.write_all(data)?;
+                drop(writer);
+                Ok(compressed)
+            }
+            #[cfg(not(feature = "brotli"))]
+            Encoding::Brotli => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Brotli needs the brotli feature",
+            )),
+            Encoding::Gzip => {
+                let level = match best {
+                    true => flate2::Compression::best(),
+                    false => flate2::Compression::default(),
+                };
+                let mut encoder = flate2::write::GzEncoder::new(vec![], level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Whether responses of this `Content-Type` get smaller from compressing them. Images, video
+/// and archives are compressed already.
+pub fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || matches!(
+            media_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/manifest+json"
+                | "image/svg+xml"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+/// Whether files with this extension, such as `css`, get smaller from compressing them.
+pub fn is_compressible_extension(extension: &str) -> bool {
+    matches!(
+        extension.to_ascii_lowercase().as_str(),
+        "html"
+            | "htm"
+            | "css"
+            | "js"
+            | "mjs"
+            | "map"
+            | "json"
+            | "webmanifest"
+            | "xml"
+            | "svg"
+            | "txt"
+            | "md"
+            | "csv"
+            | "ics"
+            | "vcf"
+            | "ttf"
+            | "otf"
+    )
+}
+
+/// The encodings this build can compress with.
+#[cfg(feature = "gzip")]
+fn supported() -> Vec<Encoding> {
+    Encoding::ALL
+        .into_iter()
+        .filter(|encoding| cfg!(feature = "brotli") || *encoding != Encoding::Brotli)
+        .collect()
+}
+
+/// Write compressed versions of the compressible files in `dir` and its subdirectories next
+/// to them, such as `app.css.gz`, for `StaticDir::precompressed`. Versions newer than their
+/// file are kept, and hidden files skipped. Returns how many were written.
+///
+/// A version is only written if it is smaller than the file, so tiny files are served as they
+/// are.
+#[cfg(feature = "gzip")]
+pub fn precompress_dir(dir: &Path) -> io::Result<usize> {
+    let mut written = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name().as_encoded_bytes().starts_with(b".") {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            written += precompress_dir(&path)?;
+            continue;
+        }
+        let compressible = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(is_compressible_extension);
+        if !file_type.is_file() || !compressible {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        let mut data = None;
+        for encoding in supported() {
+            let mut target = path.clone().into_os_string();
+            target.push(format!(".{}", encoding.extension()));
+            let target = PathBuf::from(target);
+            let up_to_date = fs::metadata(&target)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|compressed| compressed >= modified);
+            if up_to_date {
+                continue;
+            }
+            let data = match &mut data {
+                Some(data) => data,
+                None => data.insert(fs::read(&path)?),
+            };
+            let compressed = encoding.compress_best(data)?;
+            if compressed.len() >= data.len() {
+                let _ = fs::remove_file(&target);
+                continue;
+            }
+            // Written next to it first, so the server never serves half a file.
+            let mut temporary = target.clone().into_os_string();
+            temporary.push(".tmp");
+            fs::write(&temporary, compressed)?;
+            fs::rename(&temporary, &target)?;
+            written += 1;
+        }
+    }
+    Ok(written)
+}
+
+/// Middleware compressing response bodies with brotli or gzip, whichever `Accept-Encoding`
+/// prefers. Gzip needs the gzip feature and brotli the brotli feature.
+///
+/// Only text and byte bodies of a compressible `Content-Type`, or text bodies without one, are
+/// compressed. Files are streamed as they are, see `StaticDir::precompressed` for those.
+/// Register it after other middleware, so it compresses what they produce.
+#[cfg(feature = "gzip")]
+pub struct Compression {
+    min_size: usize,
+    encodings: Vec<Encoding>,
+}
+
+#[cfg(feature = "gzip")]
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Compression {
+    /// Compress bodies of 1 KiB and more, as smaller ones barely shrink.
+    pub fn new() -> Self {
+        Self {
+            min_size: 1024,
+            encodings: supported(),
+        }
+    }
+
+    pub fn with_min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Middleware for Compression {
+    fn after(&self, request: &Request, response: &mut Response) {
+        if response.header("Content-Encoding").is_some()
+            || matches!(response.status_code.code(), 101 | 204 | 304)
+        {
+            return;
+        }
+        let data = match (&response.body, response.header("Content-Type")) {
+            (Body::Text(text), None) => text.as_bytes(),
+            (Body::Text(text), Some(content_type)) if is_compressible(content_type) => {
+                text.as_bytes()
+            }
+            (Body::Bytes(bytes), Some(content_type)) if is_compressible(content_type) => bytes,
+            _ => return,
+        };
+        if data.len() < self.min_size {
+            return;
+        }
+        let encoding = Encoding::negotiate(request.header("Accept-Encoding"), &self.encodings);
+        let compressed = match encoding.map(|encoding| encoding.compress(data)) {
+            Some(Ok(compressed)) => Some(compressed),
+            Some(Err(e)) => {
+                log!("Failed to compress response: {e}");
+                None
+            }
+            None => None,
+        };
+        // Whether or not this client gets it compressed, caches have to tell clients apart.
+        add_vary(response);
+        let (Some(encoding), Some(compressed)) = (encoding, compressed) else {
+            return;
+        };
+        response.body = Body::Bytes(compressed);
+        response.add_header("Content-Encoding", encoding.name());
+        // The compressed body is a different representation, which a strong ETag would claim
+        // to be byte for byte the same as the uncompressed one.
+        for (name, value) in &mut response.headers {
+            if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                *value = format!("W/{value}");
+            }
+        }
+    }
+}
+
+/// Add `Vary: Accept-Encoding`, unless the response varies by it already.
+pub(crate) fn add_vary(response: &mut Response) {
+    let varies = response.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("Vary")
+            && value
+                .split(',')
+                .any(|header| header.trim().eq_ignore_ascii_case("Accept-Encoding"))
+    });
+    if !varies {
+        response.add_header("Vary", "Accept-Encoding");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "gzip")]
+    use std::io::Read;
+
+    #[test]
+    fn encodings() {
+        assert_eq!(
+            Encoding::negotiate(Some("gzip, br"), &Encoding::ALL),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("gzip"), &Encoding::ALL),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(Encoding::negotiate(None, &Encoding::ALL), None);
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/ld+json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/octet-stream"));
+        assert!(is_compressible_extension("CSS"));
+        assert!(!is_compressible_extension("png"));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn precompress() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_precompress");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("js")).unwrap();
+        let css = "body { color: red; }\n".repeat(100);
+        fs::write(dir.join("app.css"), &css).unwrap();
+        fs::write(dir.join("tiny.txt"), "a").unwrap();
+        fs::write(dir.join("photo.png"), "x".repeat(1000)).unwrap();
+        fs::write(dir.join(".hidden.css"), &css).unwrap();
+        fs::write(dir.join("js").join("app.js"), "let x = 1;\n".repeat(100)).unwrap();
+
+        let versions = supported().len();
+        assert_eq!(precompress_dir(&dir).unwrap(), 2 * versions);
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(dir.join("app.css.gz")).unwrap())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, css);
+        assert!(dir.join("js").join("app.js.gz").is_file());
+        assert_eq!(dir.join("app.css.br").is_file(), cfg!(feature = "brotli"));
+        assert!(!dir.join("tiny.txt.gz").exists());
+        assert!(!dir.join("photo.png.gz").exists());
+        assert!(!dir.join(".hidden.css.gz").exists());
+        // Up to date now.
+        assert_eq!(precompress_dir(&dir).unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn middleware() {
+        use crate::webserver::StatusCode;
+
+        let text = "<p>Hello</p>".repeat(200);
+        let compress = |accept: &str, response: Response| {
+            let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {accept}\r\n\r\n");
+            let request = Request::from_reader(&mut raw.as_bytes()).unwrap();
+            let mut response = response;
+            Compression::new().after(&request, &mut response);
+            response
+        };
+
+        let response = compress(
+            "gzip",
+            Response::text(StatusCode::OK, text.clone()).with_header("ETag", "\"1\""),
+        );
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.header("ETag"), Some("W/\"1\""));
+        let Body::Bytes(compressed) = &response.body else {
+            panic!("Expected a compressed body");
+        };
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, text);
+
+        #[cfg(feature = "brotli")]
+        {
+            let response = compress("gzip, br", Response::text(StatusCode::OK, text.clone()));
+            assert_eq!(response.header("Content-Encoding"), Some("br"));
+            let Body::Bytes(compressed) = &response.body else {
+                panic!("Expected a compressed body");
+            };
+            let mut decompressed = String::new();
+            brotli::Decompressor::new(compressed.as_slice(), 4096)
+                .read_to_string(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, text);
+        }
+
+        let response = compress("identity", Response::text(StatusCode::OK, text.clone()));
+        assert!(response.header("Content-Encoding").is_none());
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        let response = compress("gzip", Response::text(StatusCode::OK, "short"));
+        assert!(response.header("Content-Encoding").is_none());
+        let png =
+            Response::bytes(StatusCode::OK, vec![0; 4096]).with_header("Content-Type", "image/png");
+        assert!(compress("gzip", png).header("Content-Encoding").is_none());
+    }
+}
@@ -1,11 +1,120 @@
+use crate::log;
+use crate::webserver::ServerError;
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
     thread,
+    time::Duration,
 };
 
+/// A snapshot of how busy a `ThreadPool` is.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct PoolStats {
+    /// Jobs waiting for a free worker.
+    pub queued: usize,
+    /// Workers running a job.
+    pub busy: usize,
+    pub completed: u64,
+    /// Jobs that panicked. The worker survives and picks up the next job.
+    pub panicked: u64,
+}
+
+/// Live counters of a `ThreadPool`, shared with the `App` state so handlers can report them.
+#[derive(Default)]
+pub struct PoolCounters {
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+}
+
+impl PoolCounters {
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            queued: self.queued.load(Ordering::SeqCst),
+            busy: self.busy.load(Ordering::SeqCst),
+            completed: self.completed.load(Ordering::SeqCst),
+            panicked: self.panicked.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// The number of connections the server has open, shared with the `App` state like
+/// `PoolCounters`. With `App::run` that includes accepted connections waiting for a worker.
+#[derive(Default)]
+pub struct ConnectionCounter {
+    open: AtomicUsize,
+    /// Held while checking the count before waiting on `closed`, so no close is missed.
+    lock: Mutex<()>,
+    closed: Condvar,
+}
+
+impl ConnectionCounter {
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Wait until fewer than `max` connections are open, for at most `timeout`, and return
+    /// whether they are.
+    pub(crate) fn wait_below(&self, max: usize, timeout: Duration) -> bool {
+        let lock = self.lock.lock().unwrap();
+        let _lock = self
+            .closed
+            .wait_timeout_while(lock, timeout, |_| self.open() >= max)
+            .unwrap();
+        self.open() < max
+    }
+
+    /// Count a connection as open until the returned guard is dropped.
+    pub(crate) fn track(self: Arc<Self>) -> OpenConnection {
+        self.open.fetch_add(1, Ordering::SeqCst);
+        OpenConnection(self)
+    }
+}
+
+pub(crate) struct OpenConnection(Arc<ConnectionCounter>);
+
+/// Stops the server gracefully from within, such as from an admin endpoint, like `SIGTERM`
+/// does. Shared with the `App` state like `PoolCounters`.
+#[derive(Default)]
+pub struct GracefulShutdown {
+    requested: AtomicBool,
+}
+
+impl GracefulShutdown {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// What `App::run` does with new connections while `AppConfig::with_max_connections` are open.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum OverLimit {
+    /// Stop accepting, leaving them in the listen backlog until a connection closes.
+    Wait,
+    /// Accept them, answer with a 503 and close them, so clients don't wait.
+    Reject,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+        drop(self.0.lock.lock().unwrap());
+        self.0.closed.notify_all();
+    }
+}
+
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: Option<mpsc::Sender<Job>>,
+    counters: Arc<PoolCounters>,
 }
 
 impl ThreadPool {
@@ -17,6 +126,15 @@ impl ThreadPool {
     ///
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> Self {
+        Self::with_counters(size, Arc::default())
+    }
+
+    /// Create a new ThreadPool that keeps its statistics in `counters`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size is zero.
+    pub fn with_counters(size: usize, counters: Arc<PoolCounters>) -> Self {
         assert!(size > 0);
 
         let (sender, receiver) = mpsc::channel();
@@ -26,22 +144,41 @@ impl ThreadPool {
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(
+                id,
+                Arc::clone(&receiver),
+                Arc::clone(&counters),
+            ));
         }
 
         Self {
             workers,
             sender: Some(sender),
+            counters,
         }
     }
 
-    pub fn execute<F>(&self, f: F)
+    pub fn stats(&self) -> PoolStats {
+        self.counters.stats()
+    }
+
+    /// Queue `f` for the next free worker. Fails if the workers are gone.
+    pub fn execute<F>(&self, f: F) -> Result<(), ServerError>
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+        let sent = self
+            .sender
+            .as_ref()
+            .is_some_and(|sender| sender.send(job).is_ok());
+        if !sent {
+            self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(ServerError::PoolStopped);
+        }
+        Ok(())
     }
 }
 
@@ -50,7 +187,7 @@ impl Drop for ThreadPool {
         drop(self.sender.take());
 
         for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+            log!("Shutting down worker {}", worker.id);
 
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
@@ -68,9 +205,12 @@ impl Worker {
     /// Create a new Worker.
     ///
     /// The id is the id of the worker and thread is the thread that the worker is running on.
-    ///
-    /// Todo: use std::thread::Builder and handle panics
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Self {
+    /// A job that panics is counted and the worker carries on with the next one.
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+        counters: Arc<PoolCounters>,
+    ) -> Self {
         let thread = thread::Builder::new()
             .name(format!("Worker {}", id))
             .spawn(move || loop {
@@ -78,12 +218,21 @@ impl Worker {
 
                 match message {
                     Ok(job) => {
-                        println!("Worker {id} got a job; executing.");
+                        log!("Worker {id} got a job; executing.");
+                        counters.queued.fetch_sub(1, Ordering::SeqCst);
+                        counters.busy.fetch_add(1, Ordering::SeqCst);
 
-                        job();
+                        match panic::catch_unwind(AssertUnwindSafe(job)) {
+                            Ok(()) => counters.completed.fetch_add(1, Ordering::SeqCst),
+                            Err(_) => {
+                                log!("Worker {id} job panicked.");
+                                counters.panicked.fetch_add(1, Ordering::SeqCst)
+                            }
+                        };
+                        counters.busy.fetch_sub(1, Ordering::SeqCst);
                     }
                     Err(_) => {
-                        println!("Worker {id} disconnected; shutting down.");
+                        log!("Worker {id} disconnected; shutting down.");
                         break;
                     }
                 }
@@ -130,7 +279,8 @@ mod tests {
         for _ in 0..99 {
             pool.execute(|| {
                 thread::sleep(time::Duration::from_millis(10));
-            });
+            })
+            .unwrap();
         }
     }
 
@@ -139,10 +289,60 @@ mod tests {
         let (sender, receiver) = mpsc::channel();
 
         let receiver = Arc::new(Mutex::new(receiver));
-        let mut worker = Worker::new(0, receiver);
+        let mut worker = Worker::new(0, receiver, Arc::default());
         drop(sender);
         if let Some(thread) = worker.thread.take() {
             thread.join().unwrap();
         }
     }
+
+    #[test]
+    fn threadpool_stats() {
+        let mut pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel::<()>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..3 {
+            let receiver = Arc::clone(&receiver);
+            pool.execute(move || receiver.lock().unwrap().recv().unwrap())
+                .unwrap();
+        }
+        pool.execute(|| panic!("Job failed")).unwrap();
+        thread::sleep(time::Duration::from_millis(50));
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.busy), (2, 2));
+
+        for _ in 0..3 {
+            sender.send(()).unwrap();
+        }
+        drop(pool.sender.take());
+        for worker in &mut pool.workers {
+            worker.thread.take().unwrap().join().unwrap();
+        }
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                queued: 0,
+                busy: 0,
+                completed: 3,
+                panicked: 1
+            }
+        );
+    }
+
+    #[test]
+    fn wait_for_connections() {
+        let counter = Arc::new(ConnectionCounter::default());
+        let connections = [counter.clone().track(), counter.clone().track()];
+        assert!(!counter.wait_below(2, Duration::from_millis(10)));
+        let closing = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(connections);
+        });
+        // Woken by the connections closing, long before the timeout.
+        let start = time::Instant::now();
+        assert!(counter.wait_below(1, Duration::from_secs(5)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(counter.open(), 0);
+        closing.join().unwrap();
+    }
 }
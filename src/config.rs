@@ -0,0 +1,711 @@
+#[cfg(feature = "tls")]
+use crate::acme::CertificateStore;
+use crate::concurrency::OverLimit;
+use crate::ip_filter::{Cidr, FilterAction, IpFilter};
+use crate::router::TrailingSlash;
+#[cfg(feature = "tls")]
+use crate::tls::TlsCertificates;
+use crate::webserver::AppConfig;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{self, Display},
+    fs,
+    net::SocketAddr,
+    path::Path,
+    time::Duration,
+};
+
+/// A value in a configuration file.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Display for Value {
+    /// The value as it is written in a file.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(value) => write!(f, "{value:?}"),
+            Value::Integer(value) => write!(f, "{value}"),
+            Value::Boolean(value) => write!(f, "{value}"),
+            Value::Array(values) => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+        }
+    }
+}
+
+/// The settings `AppConfig::from_config` reads, which only take effect on a restart.
+pub const SERVER_KEYS: &[&str] = &[
+    "host",
+    "port",
+    "addrs",
+    "threads",
+    "max_connections",
+    "max_connections_action",
+    "read_timeout",
+    "header_timeout",
+    "body_timeout",
+    "request_timeout",
+    "unix_socket",
+    "trusted_proxies",
+    "proxy_protocol",
+    "server_header",
+    "date_header",
+    "request_id_header",
+    "zero_copy",
+    "bad_request_responses",
+    "trace",
+    "trailing_slash",
+    "case_insensitive_paths",
+    "https_redirect",
+    "https_port",
+    "tls.listen",
+    "tls.certificates",
+    "tls.default",
+    "tls.http2",
+    "ip_filter.deny",
+    "ip_filter.allow",
+    "ip_filter.allow_paths",
+    "ip_filter.action",
+    "limits.max_request_line",
+    "limits.max_header_bytes",
+    "limits.max_body_bytes",
+];
+
+/// Settings read from a TOML file, such as `server.toml`, and the environment.
+///
+/// Only the part of TOML configuration needs is read: `[sections]` and `key = value` lines
+/// with strings, integers, booleans and arrays of them on a single line. Keys in a section are
+/// looked up as `section.key`.
+#[derive(Clone, Default, Debug)]
+pub struct ConfigFile {
+    values: HashMap<String, Value>,
+}
+
+impl ConfigFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        Self::parse(&source).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut values = HashMap::new();
+        let mut section = String::new();
+        for (number, line) in source.lines().enumerate() {
+            let error = |e: &str| format!("line {}: {e}", number + 1);
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[') {
+                let name = name
+                    .strip_suffix(']')
+                    .ok_or_else(|| error("Unclosed section"))?;
+                let name = name.trim();
+                if !is_key(name) {
+                    return Err(error("Invalid section name"));
+                }
+                section = format!("{name}.");
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("Expected key = value"))?;
+            let key = key.trim();
+            if !is_key(key) {
+                return Err(error("Invalid key"));
+            }
+            let value = parse_value(value.trim()).map_err(|e| error(&e))?;
+            if values.insert(format!("{section}{key}"), value).is_some() {
+                return Err(error(&format!("{key} is set twice")));
+            }
+        }
+        Ok(Self { values })
+    }
+
+    /// Override settings with environment variables named after them, such as `WWW_PORT` for
+    /// `port` and `WWW_LIMITS_MAX_BODY_BYTES` for `limits.max_body_bytes` with `prefix` `WWW`.
+    /// Values are read as TOML where they can be, and as plain strings otherwise.
+    pub fn with_env(mut self, prefix: &str) -> Self {
+        let prefix = format!("{prefix}_");
+        for (name, value) in env::vars_os() {
+            let (Some(name), Ok(value)) = (name.to_str(), value.into_string()) else {
+                continue;
+            };
+            let Some(key) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+            let value = parse_value(&value).unwrap_or(Value::String(value));
+            self.set(
+                &key.to_lowercase().replacen('_', ".", section_count(key)),
+                value,
+            );
+        }
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: Value) {
+        self.values.insert(key.to_string(), value);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(key)
+    }
+
+    /// What changed in `other`, a line such as `port: 8080 -> 9000` per setting, sorted by key.
+    pub fn changes(&self, other: &ConfigFile) -> Vec<String> {
+        let mut keys: Vec<&String> = self.values.keys().chain(other.values.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        keys.into_iter()
+            .filter_map(|key| match (self.get(key), other.get(key)) {
+                (old, new) if old == new => None,
+                (Some(old), Some(new)) => Some(format!("{key}: {old} -> {new}")),
+                (Some(old), None) => Some(format!("{key}: {old} -> unset")),
+                (None, Some(new)) => Some(format!("{key}: unset -> {new}")),
+                (None, None) => None,
+            })
+            .collect()
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<&str>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(_) => Err(format!("{key} should be a string")),
+        }
+    }
+
+    pub fn integer(&self, key: &str) -> Result<Option<i64>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(value)) => Ok(Some(*value)),
+            Some(_) => Err(format!("{key} should be an integer")),
+        }
+    }
+
+    /// A non-negative integer, such as a count or a number of seconds.
+    pub fn unsigned(&self, key: &str) -> Result<Option<u64>, String> {
+        self.integer(key)?
+            .map(|value| u64::try_from(value).map_err(|_| format!("{key} can't be negative")))
+            .transpose()
+    }
+
+    pub fn boolean(&self, key: &str) -> Result<Option<bool>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Boolean(value)) => Ok(Some(*value)),
+            Some(_) => Err(format!("{key} should be true or false")),
+        }
+    }
+
+    /// An array of strings. A single string is split on commas, as environment variables
+    /// can't hold arrays.
+    pub fn strings(&self, key: &str) -> Result<Option<Vec<String>>, String> {
+        let invalid = || format!("{key} should be an array of strings");
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            )),
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    Value::String(value) => Ok(value.clone()),
+                    _ => Err(invalid()),
+                })
+                .collect::<Result<_, _>>()
+                .map(Some),
+            Some(_) => Err(invalid()),
+        }
+    }
+}
+
+impl AppConfig {
+    /// Read the server settings from the TOML file at `path`, overridden by `WWW_` environment
+    /// variables, see `ConfigFile::with_env`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        Self::from_config(&ConfigFile::load(path)?.with_env("WWW"))
+    }
+
+    /// The server settings in `file`. Settings that aren't set keep their defaults, which are
+    /// shown here where they have one:
+    ///
+    /// ```toml
+    /// host = "127.0.0.1"
+    /// port = 8080
+    /// # Further addresses to listen on.
+    /// addrs = ["[::]:8080"]
+    /// # A worker per CPU by default.
+    /// threads = 4
+    /// # Unlimited by default. "wait" leaves further connections waiting, "reject" answers them
+    /// # with a 503.
+    /// max_connections = 256
+    /// max_connections_action = "wait"
+    /// # Seconds.
+    /// read_timeout = 5
+    /// header_timeout = 10
+    /// body_timeout = 60
+    /// request_timeout = 120
+    /// unix_socket = "/run/www.sock"
+    /// trusted_proxies = ["127.0.0.1"]
+    /// proxy_protocol = false
+    /// # An empty string leaves the header out.
+    /// server_header = "wwwdaanlubbersnl"
+    /// date_header = true
+    /// request_id_header = true
+    /// zero_copy = true
+    /// bad_request_responses = true
+    /// trace = false
+    /// # "strict", "ignore" or "redirect".
+    /// trailing_slash = "strict"
+    /// case_insensitive_paths = false
+    /// # Plain HTTP redirected to HTTPS on `https_port`.
+    /// https_redirect = "0.0.0.0:80"
+    /// https_port = 443
+    ///
+    /// # HTTPS, which needs the tls feature.
+    /// [tls]
+    /// listen = "0.0.0.0:443"
+    /// # A directory per hostname, such as `daanlubbers.nl/cert.pem` and `daanlubbers.nl/key.pem`.
+    /// certificates = "/etc/www/certificates"
+    /// # The hostname whose certificate clients without a known hostname get.
+    /// default = "daanlubbers.nl"
+    /// # Offer HTTP/2 to clients that support it.
+    /// http2 = true
+    ///
+    /// # Addresses turned away, and paths only served to the `allow` ranges.
+    /// [ip_filter]
+    /// deny = ["203.0.113.0/24"]
+    /// allow = ["10.0.0.0/8", "::1"]
+    /// allow_paths = ["/admin"]
+    /// # "forbidden" or "drop".
+    /// action = "forbidden"
+    ///
+    /// [limits]
+    /// max_request_line = 8192
+    /// max_header_bytes = 32768
+    /// max_body_bytes = 16777216
+    /// ```
+    ///
+    /// Other settings, such as those of the application itself, are left for the caller.
+    pub fn from_config(file: &ConfigFile) -> Result<Self, String> {
+        let host = file.string("host")?.unwrap_or("127.0.0.1");
+        let port = match file.unsigned("port")? {
+            Some(port) => u16::try_from(port).map_err(|_| "port is out of range".to_string())?,
+            None => 8080,
+        };
+        let addr = parse_addr(&format!("{host}:{port}"))
+            .or_else(|_| parse_addr(&format!("[{host}]:{port}")))?;
+        let mut builder = AppConfig::builder().addr(addr);
+        if let Some(threads) = file.unsigned("threads")? {
+            builder = builder.threads(threads as usize);
+        }
+        if let Some(seconds) = file.unsigned("read_timeout")? {
+            builder = builder.read_timeout(seconds);
+        }
+        let mut config = builder.build()?;
+
+        for addr in file.strings("addrs")?.unwrap_or_default() {
+            config = config.with_addr(parse_addr(&addr)?);
+        }
+        if let Some(max) = file.unsigned("max_connections")? {
+            let over_limit = match file.string("max_connections_action")? {
+                None | Some("wait") => OverLimit::Wait,
+                Some("reject") => OverLimit::Reject,
+                Some(action) => return Err(format!("Invalid max_connections_action: {action}")),
+            };
+            config = config.with_max_connections(max as usize, over_limit);
+        }
+        if let Some(seconds) = file.unsigned("header_timeout")? {
+            config = config.with_header_timeout(Duration::from_secs(seconds));
+        }
+        if let Some(seconds) = file.unsigned("body_timeout")? {
+            config = config.with_body_timeout(Duration::from_secs(seconds));
+        }
+        if let Some(seconds) = file.unsigned("request_timeout")? {
+            config = config.with_request_timeout(Duration::from_secs(seconds));
+        }
+        #[cfg(unix)]
+        if let Some(path) = file.string("unix_socket")? {
+            config = config.with_unix_socket(path);
+        }
+        for proxy in file.strings("trusted_proxies")?.unwrap_or_default() {
+            let ip = proxy
+                .parse()
+                .map_err(|_| format!("Invalid trusted proxy: {proxy}"))?;
+            config = config.with_trusted_proxy(ip);
+        }
+        if let Some(proxy_protocol) = file.boolean("proxy_protocol")? {
+            config = config.with_proxy_protocol(proxy_protocol);
+        }
+        if let Some(server) = file.string("server_header")? {
+            config = config.with_server_header(Some(server).filter(|server| !server.is_empty()));
+        }
+        if let Some(date) = file.boolean("date_header")? {
+            config = config.with_date_header(date);
+        }
+        if let Some(request_id) = file.boolean("request_id_header")? {
+            config = config.with_request_id_header(request_id);
+        }
+        if let Some(zero_copy) = file.boolean("zero_copy")? {
+            config = config.with_zero_copy(zero_copy);
+        }
+        if let Some(respond) = file.boolean("bad_request_responses")? {
+            config = config.with_bad_request_responses(respond);
+        }
+        if let Some(trace) = file.boolean("trace")? {
+            config = config.with_trace(trace);
+        }
+        if let Some(trailing_slash) = file.string("trailing_slash")? {
+            config = config.with_trailing_slash(match trailing_slash {
+                "strict" => TrailingSlash::Strict,
+                "ignore" => TrailingSlash::Ignore,
+                "redirect" => TrailingSlash::Redirect,
+                _ => return Err(format!("Invalid trailing_slash: {trailing_slash}")),
+            });
+        }
+        if let Some(case_insensitive) = file.boolean("case_insensitive_paths")? {
+            config = config.with_case_insensitive_paths(case_insensitive);
+        }
+        if let Some(addr) = file.string("https_redirect")? {
+            let https_port = match file.unsigned("https_port")? {
+                Some(port) => {
+                    u16::try_from(port).map_err(|_| "https_port is out of range".to_string())?
+                }
+                None => 443,
+            };
+            config = config.with_https_redirect(parse_addr(addr)?, https_port);
+        }
+        if let Some(addr) = file.string("tls.listen")? {
+            config = with_tls(config, file, parse_addr(addr)?)?;
+        }
+        if let Some(filter) = ip_filter(file)? {
+            config = config.with_ip_filter(filter);
+        }
+        if let Some(bytes) = file.unsigned("limits.max_request_line")? {
+            config = config.with_max_request_line(bytes as usize);
+        }
+        if let Some(bytes) = file.unsigned("limits.max_header_bytes")? {
+            config = config.with_max_header_bytes(bytes as usize);
+        }
+        if let Some(bytes) = file.unsigned("limits.max_body_bytes")? {
+            config = config.with_max_body_bytes(bytes as usize);
+        }
+        Ok(config)
+    }
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr, String> {
+    addr.parse().map_err(|_| format!("Invalid address: {addr}"))
+}
+
+/// The filter the `[ip_filter]` section describes, if any.
+fn ip_filter(file: &ConfigFile) -> Result<Option<IpFilter>, String> {
+    let ranges = |key: &str| -> Result<Vec<Cidr>, String> {
+        file.strings(key)?
+            .unwrap_or_default()
+            .iter()
+            .map(|range| range.parse())
+            .collect()
+    };
+    let (deny, allow) = (ranges("ip_filter.deny")?, ranges("ip_filter.allow")?);
+    let paths = file.strings("ip_filter.allow_paths")?.unwrap_or_default();
+    if deny.is_empty() && paths.is_empty() {
+        return Ok(None);
+    }
+    let mut filter = IpFilter::new();
+    for range in deny {
+        filter = filter.with_deny(range);
+    }
+    for path in paths {
+        filter = filter.with_allow(&path, &allow);
+    }
+    match file.string("ip_filter.action")? {
+        None | Some("forbidden") => {}
+        Some("drop") => filter = filter.with_action(FilterAction::Drop),
+        Some(action) => return Err(format!("Invalid ip_filter.action: {action}")),
+    }
+    Ok(Some(filter))
+}
+
+/// Serve HTTPS on `addr` with the certificates the `[tls]` section points to.
+#[cfg(feature = "tls")]
+fn with_tls(config: AppConfig, file: &ConfigFile, addr: SocketAddr) -> Result<AppConfig, String> {
+    let certificates = TlsCertificates::new();
+    if let Some(dir) = file.string("tls.certificates")? {
+        certificates.load_dir(dir)?;
+        if let Some(hostname) = file.string("tls.default")? {
+            let certificate = CertificateStore::new(Path::new(dir).join(hostname))
+                .load()?
+                .ok_or_else(|| format!("No certificate for the default {hostname}"))?;
+            certificates.set_default(&certificate)?;
+        }
+    }
+    let http2 = file.boolean("tls.http2")?.unwrap_or(false);
+    Ok(config
+        .with_tls(addr, Arc::new(certificates))
+        .with_http2(http2))
+}
+
+#[cfg(not(feature = "tls"))]
+fn with_tls(_: AppConfig, _: &ConfigFile, _: SocketAddr) -> Result<AppConfig, String> {
+    Err("tls.listen needs the tls feature".to_string())
+}
+
+/// Sections whose keys can be set from the environment, as `WWW_LIMITS_MAX_BODY_BYTES` can't
+/// tell a section from a key with an underscore otherwise.
+const SECTIONS: &[&str] = &[
+    "LIMITS_",
+    "RATE_LIMIT_",
+    "ERROR_PAGES_",
+    "TLS_",
+    "IP_FILTER_",
+];
+
+/// How many of the underscores in an environment variable name separate a section.
+fn section_count(name: &str) -> usize {
+    SECTIONS
+        .iter()
+        .filter(|section| name.starts_with(*section))
+        .count()
+}
+
+fn is_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-')
+}
+
+/// The line up to a `#` that isn't in a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    let (parsed, rest) = parse_prefix(value)?;
+    if !rest.trim().is_empty() {
+        return Err(format!("Unexpected {} after value", rest.trim()));
+    }
+    Ok(parsed)
+}
+
+/// Parse the value `source` starts with, returning it and what follows.
+fn parse_prefix(source: &str) -> Result<(Value, &str), String> {
+    let source = source.trim_start();
+    if let Some(rest) = source.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => value.push(match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some(c @ ('"' | '\\')) => c,
+                    _ => return Err("Invalid escape in string".to_string()),
+                }),
+                c => value.push(c),
+            }
+        }
+        return Err("Unclosed string".to_string());
+    }
+    if let Some(rest) = source.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("Unclosed string")?;
+        return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    if let Some(mut rest) = source.strip_prefix('[') {
+        let mut values = vec![];
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_prefix(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            match rest.strip_prefix(',') {
+                Some(after) => rest = after,
+                None if rest.starts_with(']') => {}
+                None => return Err("Expected , or ] in array".to_string()),
+            }
+        }
+    }
+    let end = source
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(source.len());
+    let (token, rest) = source.split_at(end);
+    let value = match token {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => Value::Integer(
+            token
+                .replace('_', "")
+                .parse()
+                .map_err(|_| format!("Invalid value: {token}"))?,
+        ),
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let file = ConfigFile::parse(
+            "# Server\n\
+             host = \"0.0.0.0\" # all interfaces\n\
+             port = 8_080\n\
+             proxy_protocol = true\n\
+             trusted_proxies = [\"10.0.0.1\", '10.0.0.2',]\n\
+             server_header = \"a \\\"b\\\" #c\"\n\
+             \n\
+             [limits]\n\
+             max_body_bytes = 1024\n",
+        )
+        .unwrap();
+        assert_eq!(file.string("host"), Ok(Some("0.0.0.0")));
+        assert_eq!(file.integer("port"), Ok(Some(8080)));
+        assert_eq!(file.boolean("proxy_protocol"), Ok(Some(true)));
+        assert_eq!(
+            file.strings("trusted_proxies"),
+            Ok(Some(vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()]))
+        );
+        assert_eq!(file.string("server_header"), Ok(Some("a \"b\" #c")));
+        assert_eq!(file.unsigned("limits.max_body_bytes"), Ok(Some(1024)));
+        assert_eq!(file.string("missing"), Ok(None));
+        assert!(file.integer("host").is_err());
+
+        let changed = ConfigFile::parse(
+            "host = \"0.0.0.0\"\nport = 9000\ntrusted_proxies = [\"10.0.0.1\"]\ndate_header = false",
+        )
+        .unwrap();
+        assert_eq!(
+            changed.changes(&file),
+            [
+                "date_header: false -> unset",
+                "limits.max_body_bytes: unset -> 1024",
+                "port: 9000 -> 8080",
+                "proxy_protocol: unset -> true",
+                "server_header: unset -> \"a \\\"b\\\" #c\"",
+                "trusted_proxies: [\"10.0.0.1\"] -> [\"10.0.0.1\", \"10.0.0.2\"]",
+            ]
+        );
+        assert!(file.changes(&file).is_empty());
+
+        assert!(ConfigFile::parse("port = 80\nport = 81").is_err());
+        assert!(ConfigFile::parse("port 80").is_err());
+        assert!(ConfigFile::parse("host = \"0.0.0.0").is_err());
+        assert!(ConfigFile::parse("[limits").is_err());
+        assert!(ConfigFile::parse("threads = four").is_err());
+        assert_eq!(
+            ConfigFile::parse("a = 1\nb = [1 2]").unwrap_err(),
+            "line 2: Expected , or ] in array"
+        );
+    }
+
+    #[test]
+    fn app_config() {
+        let mut file = ConfigFile::parse(
+            "host = \"::1\"\nport = 9000\nthreads = 2\naddrs = [\"127.0.0.1:9001\"]\n\
+             server_header = \"\"\n[limits]\nmax_body_bytes = 10",
+        )
+        .unwrap();
+        let config = AppConfig::from_config(&file).unwrap();
+        assert_eq!(
+            config.addrs,
+            vec![
+                "[::1]:9000".parse().unwrap(),
+                "127.0.0.1:9001".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.limits.max_body_bytes, 10);
+        assert!(!config.common_headers().contains("Server:"));
+
+        // Environment variables are strings, so arrays come comma separated.
+        file.set(
+            "trusted_proxies",
+            Value::String("10.0.0.1, 10.0.0.2".to_string()),
+        );
+        assert!(AppConfig::from_config(&file).is_ok());
+        file.set("trailing_slash", Value::String("redirect".to_string()));
+        assert!(AppConfig::from_config(&file).is_ok());
+        file.set("trailing_slash", Value::String("sometimes".to_string()));
+        assert!(AppConfig::from_config(&file).is_err());
+        file.set("trailing_slash", Value::String("strict".to_string()));
+        file.set("max_connections", Value::Integer(2));
+        file.set(
+            "max_connections_action",
+            Value::String("reject".to_string()),
+        );
+        assert!(AppConfig::from_config(&file).is_ok());
+        file.set("max_connections_action", Value::String("queue".to_string()));
+        assert!(AppConfig::from_config(&file).is_err());
+        file.set("max_connections_action", Value::String("wait".to_string()));
+        file.set(
+            "ip_filter.deny",
+            Value::String("203.0.113.0/24, ::1".to_string()),
+        );
+        file.set("ip_filter.action", Value::String("drop".to_string()));
+        assert!(AppConfig::from_config(&file).is_ok());
+        file.set("ip_filter.deny", Value::String("localhost".to_string()));
+        assert!(AppConfig::from_config(&file).is_err());
+        file.set("ip_filter.deny", Value::Array(vec![]));
+        file.set("tls.listen", Value::String("127.0.0.1:9443".to_string()));
+        assert_eq!(AppConfig::from_config(&file).is_ok(), cfg!(feature = "tls"));
+        file.set("tls.certificates", Value::String("missing".to_string()));
+        assert!(AppConfig::from_config(&file).is_err());
+        file.set("threads", Value::Integer(0));
+        assert!(AppConfig::from_config(&file).is_err());
+        file.set("threads", Value::Integer(-1));
+        assert!(AppConfig::from_config(&file).is_err());
+        assert!(AppConfig::from_file("missing.toml").is_err());
+    }
+
+    #[test]
+    fn env() {
+        env::set_var("CONFIG_TEST_PORT", "9000");
+        env::set_var("CONFIG_TEST_STATIC_DIR", "public");
+        env::set_var("CONFIG_TEST_LIMITS_MAX_BODY_BYTES", "10");
+        let file = ConfigFile::parse("port = 80")
+            .unwrap()
+            .with_env("CONFIG_TEST");
+        assert_eq!(file.integer("port"), Ok(Some(9000)));
+        assert_eq!(file.string("static_dir"), Ok(Some("public")));
+        assert_eq!(file.integer("limits.max_body_bytes"), Ok(Some(10)));
+    }
+}
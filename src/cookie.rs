@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie to send with `Response::with_cookie`.
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    max_age: Option<Duration>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that tells the client to delete the cookie with this name.
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "").with_max_age(Duration::ZERO)
+    }
+
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Keep the cookie for this long. Without a max age the cookie is dropped when the browser
+    /// closes.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Hide the cookie from scripts.
+    pub fn http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    /// Only send the cookie over HTTPS.
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// The value of the `Set-Cookie` header for this cookie.
+    pub fn header_value(&self) -> String {
+        let mut header = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            header.push_str(&format!("; Path={path}"));
+        }
+        if let Some(max_age) = self.max_age {
+            header.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if let Some(same_site) = self.same_site {
+            header.push_str(&format!("; SameSite={same_site:?}"));
+        }
+        header
+    }
+}
+
+/// Find a cookie in the value of a `Cookie` request header.
+pub fn find<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookie_header() {
+        assert_eq!(Cookie::new("a", "b").header_value(), "a=b");
+        assert_eq!(
+            Cookie::new("session", "123")
+                .with_path("/")
+                .with_max_age(Duration::from_secs(60))
+                .http_only()
+                .secure()
+                .with_same_site(SameSite::Lax)
+                .header_value(),
+            "session=123; Path=/; Max-Age=60; HttpOnly; Secure; SameSite=Lax"
+        );
+        assert_eq!(Cookie::removal("a").header_value(), "a=; Max-Age=0");
+    }
+
+    #[test]
+    fn find_cookie() {
+        assert_eq!(find("a=1; b=2", "b"), Some("2"));
+        assert_eq!(find("a=1;b=\"2\"", "b"), Some("2"));
+        assert_eq!(find("ab=1", "a"), None);
+        assert_eq!(find("", "a"), None);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::log;
+use crate::webserver::{Middleware, Request, RequestType, Response, StatusCode};
+use std::time::Duration;
+
+/// Middleware that lets pages on other origins call this server from the browser.
+///
+/// Preflight `OPTIONS` requests from allowed origins are answered directly, and
+/// `Access-Control-*` headers are added to every response to an allowed origin.
+pub struct Cors {
+    /// Allowed origins, such as `https://example.com`. Empty when any origin is allowed.
+    origins: Vec<String>,
+    any_origin: bool,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cors {
+    /// No origins are allowed until added. GET, POST, PUT and DELETE are allowed by default.
+    pub fn new() -> Self {
+        Self {
+            origins: vec![],
+            any_origin: false,
+            methods: ["GET", "POST", "PUT", "DELETE"].map(String::from).to_vec(),
+            headers: vec![],
+            expose_headers: vec![],
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        self.origins.push(origin.trim_end_matches('/').to_string());
+        self
+    }
+
+    pub fn with_any_origin(mut self) -> Self {
+        self.any_origin = true;
+        self
+    }
+
+    pub fn with_methods(mut self, methods: &[&str]) -> Self {
+        self.methods = methods.iter().map(|method| method.to_string()).collect();
+        self
+    }
+
+    /// Request headers the browser may send, besides the ones that are always allowed.
+    pub fn with_headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    /// Response headers scripts may read, besides the ones that are always exposed.
+    pub fn with_expose_headers(mut self, headers: &[&str]) -> Self {
+        self.expose_headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    /// Allow cookies and authorization headers to be sent along.
+    pub fn with_credentials(mut self) -> Self {
+        self.credentials = true;
+        self
+    }
+
+    /// How long browsers may cache the result of a preflight request.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.any_origin || self.origins.iter().any(|allowed| allowed == origin)
+    }
+
+    /// The `Access-Control-Allow-Origin` value. Credentials can't be used with `*`, so the
+    /// origin is echoed instead.
+    fn allow_origin<'a>(&self, origin: &'a str) -> &'a str {
+        match self.any_origin && !self.credentials {
+            true => "*",
+            false => origin,
+        }
+    }
+
+    /// Headers sent with both preflight and normal responses.
+    fn add_headers(&self, origin: &str, response: &mut Response) {
+        response.add_header("Access-Control-Allow-Origin", self.allow_origin(origin));
+        response.add_header("Vary", "Origin");
+        if self.credentials {
+            response.add_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+}
+
+impl Middleware for Cors {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if request.request_type() != RequestType::OPTIONS
+            || request.header("Access-Control-Request-Method").is_none()
+        {
+            return None;
+        }
+        let origin = request.header("Origin")?;
+        if !self.allows(origin) {
+            log!("Rejected CORS preflight from {origin}");
+            return Some(Response::empty(StatusCode::Forbidden));
+        }
+
+        let mut response = Response::empty(StatusCode::NoContent);
+        self.add_headers(origin, &mut response);
+        response.add_header("Access-Control-Allow-Methods", self.methods.join(", "));
+        if !self.headers.is_empty() {
+            response.add_header("Access-Control-Allow-Headers", self.headers.join(", "));
+        }
+        if let Some(max_age) = self.max_age {
+            response.add_header("Access-Control-Max-Age", max_age.as_secs().to_string());
+        }
+        Some(response)
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        let Some(origin) = request.header("Origin") else {
+            return;
+        };
+        // Preflight responses already have their headers.
+        if !self.allows(origin) || response.header("Access-Control-Allow-Origin").is_some() {
+            return;
+        }
+        self.add_headers(origin, response);
+        if !self.expose_headers.is_empty() {
+            response.add_header(
+                "Access-Control-Expose-Headers",
+                self.expose_headers.join(", "),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(raw: &str) -> Request {
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn preflight() {
+        let cors = Cors::new()
+            .with_origin("https://app.example.com/")
+            .with_headers(&["Content-Type"])
+            .with_max_age(Duration::from_secs(600));
+        let preflight = "OPTIONS /api HTTP/1.1\r\nOrigin: https://app.example.com\r\n\
+                         Access-Control-Request-Method: POST\r\n\r\n";
+        let response = cors.before(&mut request(preflight)).unwrap();
+        assert_eq!(
+            response.header("Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            response.header("Access-Control-Allow-Methods"),
+            Some("GET, POST, PUT, DELETE")
+        );
+        assert_eq!(
+            response.header("Access-Control-Allow-Headers"),
+            Some("Content-Type")
+        );
+        assert_eq!(response.header("Access-Control-Max-Age"), Some("600"));
+
+        let other = preflight.replace("app.example.com", "evil.example.com");
+        let response = cors.before(&mut request(&other)).unwrap();
+        assert!(response.header("Access-Control-Allow-Origin").is_none());
+
+        // Plain OPTIONS requests, such as from WebDAV clients, are left alone
+        assert!(cors
+            .before(&mut request(
+                "OPTIONS /api HTTP/1.1\r\nOrigin: https://app.example.com\r\n\r\n"
+            ))
+            .is_none());
+    }
+
+    #[test]
+    fn response_headers() {
+        let cors = Cors::new().with_any_origin().with_expose_headers(&["ETag"]);
+        let get = request("GET /api HTTP/1.1\r\nOrigin: https://app.example.com\r\n\r\n");
+        let mut response = Response::empty(StatusCode::OK);
+        cors.after(&get, &mut response);
+        assert_eq!(response.header("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(
+            response.header("Access-Control-Expose-Headers"),
+            Some("ETag")
+        );
+
+        let cors = Cors::new().with_any_origin().with_credentials();
+        let mut response = Response::empty(StatusCode::OK);
+        cors.after(&get, &mut response);
+        assert_eq!(
+            response.header("Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            response.header("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+
+        let mut response = Response::empty(StatusCode::OK);
+        cors.after(&request("GET /api HTTP/1.1\r\n\r\n"), &mut response);
+        assert!(response.header("Access-Control-Allow-Origin").is_none());
+    }
+}
@@ -0,0 +1,173 @@
+use crate::log;
+use crate::templates::{Context, Templates};
+use crate::webserver::{html_escape, Body, Request, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+enum ErrorPage {
+    Template(String),
+    File(PathBuf),
+}
+
+/// The pages error responses without a body of their own are sent with, set with
+/// `App::enable_error_pages`. Statuses without a page get a plain default one, with the status
+/// and the request ID to quote when reporting the problem.
+///
+/// Templates are rendered with `status` (the code), `reason`, `path` and `request_id`.
+#[derive(Default)]
+pub struct ErrorPages {
+    inner: RwLock<Pages>,
+}
+
+#[derive(Default)]
+struct Pages {
+    pages: HashMap<u16, ErrorPage>,
+    templates: Option<Arc<Templates>>,
+}
+
+impl ErrorPages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where the templates given to `with_template` are found.
+    pub fn with_templates(mut self, templates: Arc<Templates>) -> Self {
+        self.inner.get_mut().unwrap().templates = Some(templates);
+        self
+    }
+
+    /// Answer `status` with the template at `name`, such as `errors/404.html`.
+    pub fn with_template(mut self, status: StatusCode, name: &str) -> Self {
+        self.inner
+            .get_mut()
+            .unwrap()
+            .pages
+            .insert(status.code(), ErrorPage::Template(name.to_string()));
+        self
+    }
+
+    /// Answer `status` with the HTML file at `path`.
+    pub fn with_file(mut self, status: StatusCode, path: impl Into<PathBuf>) -> Self {
+        self.inner
+            .get_mut()
+            .unwrap()
+            .pages
+            .insert(status.code(), ErrorPage::File(path.into()));
+        self
+    }
+
+    /// Answer with the pages of `pages` from now on, such as after the configuration changed.
+    pub fn replace(&self, pages: ErrorPages) {
+        *self.inner.write().unwrap() = pages.inner.into_inner().unwrap();
+    }
+
+    /// The page for `status`. The default page is used if the one set can't be read or
+    /// rendered, so that an error page never fails itself.
+    pub fn page(&self, status: StatusCode, request: &Request) -> String {
+        let inner = self.inner.read().unwrap();
+        let page = match inner.pages.get(&status.code()) {
+            Some(ErrorPage::Template(name)) => {
+                let context = Context::new()
+                    .with("status", status.code().to_string())
+                    .with("reason", status.reason())
+                    .with("path", request.path())
+                    .with("request_id", request.id());
+                match &inner.templates {
+                    Some(templates) => templates.render(name, &context),
+                    None => Err(format!("No templates to render {name} with")),
+                }
+            }
+            Some(ErrorPage::File(path)) => fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {e}", path.display())),
+            None => return default_page(status, request.id()),
+        };
+        page.unwrap_or_else(|e| {
+            log!("Error page for {} failed: {e}", status.code());
+            default_page(status, request.id())
+        })
+    }
+
+    /// Give `response` the page for its status.
+    pub(crate) fn apply(&self, request: &Request, response: &mut Response) {
+        let page = self.page(response.status_code, request);
+        response.body = Body::Text(page);
+        if response.header("Content-Type").is_none() {
+            response.add_header("Content-Type", "text/html; charset=utf-8");
+        }
+    }
+}
+
+/// A small page showing the status and the request ID.
+pub fn default_page(status: StatusCode, request_id: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"utf-8\"><title>{reason}</title>\
+         <style>body{{font-family:sans-serif;max-width:40em;margin:4em auto;padding:0 1em;\
+         color:#333}}h1{{font-weight:normal}}small{{color:#888}}</style></head>\
+         <body><h1>{reason}</h1><p><small>Request ID: {id}</small></p></body></html>",
+        reason = status.reason(),
+        id = html_escape(request_id),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn request() -> Request {
+        let raw = "GET /missing HTTP/1.1\r\nX-Request-Id: abc\r\n\r\n";
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn pages() {
+        let dir = std::env::temp_dir().join(format!("error_pages_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::File::create(dir.join("404.html"))
+            .unwrap()
+            .write_all(b"{{status}} {{reason}} at {{path}} ({{request_id}})")
+            .unwrap();
+        let pages = ErrorPages::new()
+            .with_templates(Arc::new(Templates::new(&dir)))
+            .with_template(StatusCode::NotFound, "404.html")
+            .with_template(StatusCode::Forbidden, "missing.html")
+            .with_file(StatusCode::InternalServerError, dir.join("404.html"));
+
+        let request = request();
+        assert_eq!(
+            pages.page(StatusCode::NotFound, &request),
+            "404 404 NOT FOUND at /missing (abc)"
+        );
+        assert_eq!(
+            pages.page(StatusCode::InternalServerError, &request),
+            "{{status}} {{reason}} at {{path}} ({{request_id}})"
+        );
+        // A template that can't be rendered falls back on the default page.
+        assert_eq!(
+            pages.page(StatusCode::Forbidden, &request),
+            default_page(StatusCode::Forbidden, "abc")
+        );
+        assert!(pages
+            .page(StatusCode::BadRequest, &request)
+            .contains("<h1>400 BAD REQUEST</h1><p><small>Request ID: abc</small>"));
+
+        let mut response = Response::empty(StatusCode::NotFound).with_header("X-Test", "yes");
+        pages.apply(&request, &mut response);
+        assert!(matches!(&response.body, Body::Text(text) if text.starts_with("404 ")));
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        pages.replace(ErrorPages::new().with_file(StatusCode::NotFound, dir.join("missing.html")));
+        assert_eq!(
+            pages.page(StatusCode::NotFound, &request),
+            default_page(StatusCode::NotFound, "abc")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
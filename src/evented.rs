@@ -0,0 +1,278 @@
+use crate::concurrency::ThreadPool;
+use crate::log;
+use crate::webserver::{App, ServerError};
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Registry, Token, Waker,
+};
+use std::{
+    collections::HashMap,
+    io, net,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const WAKER: Token = Token(0);
+/// Listeners get the tokens from 1, and connections the ones after them.
+const FIRST_LISTENER: usize = 1;
+
+/// How often idle connections are checked for the idle timeout.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connection waiting for its next request.
+struct Idle {
+    stream: TcpStream,
+    since: Instant,
+}
+
+struct Connections {
+    idle: HashMap<Token, Idle>,
+    next_token: usize,
+}
+
+impl Connections {
+    fn add(&mut self, registry: &Registry, mut stream: TcpStream) {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        match registry.register(&mut stream, token, Interest::READABLE) {
+            Ok(()) => {
+                self.idle.insert(
+                    token,
+                    Idle {
+                        stream,
+                        since: Instant::now(),
+                    },
+                );
+            }
+            Err(e) => log!("Failed to register connection: {e:?}"),
+        }
+    }
+
+    /// Take the connection out of the event loop, as a blocking stream for a worker.
+    fn take(&mut self, registry: &Registry, token: Token) -> Option<net::TcpStream> {
+        let mut idle = self.idle.remove(&token)?;
+        let _ = registry.deregister(&mut idle.stream);
+        let stream = net::TcpStream::from(idle.stream);
+        stream.set_nonblocking(false).ok()?;
+        Some(stream)
+    }
+
+    /// Add the connections workers have finished a request on back to the event loop.
+    fn reclaim(&mut self, registry: &Registry, returned: &Receiver<net::TcpStream>) {
+        for stream in returned.try_iter() {
+            match stream.set_nonblocking(true) {
+                Ok(()) => self.add(registry, TcpStream::from_std(stream)),
+                Err(e) => log!("Failed to reuse connection: {e:?}"),
+            }
+        }
+    }
+
+    fn close_idle(&mut self, registry: &Registry, timeout: Duration) {
+        self.idle.retain(|_, idle| {
+            let open = idle.since.elapsed() < timeout;
+            if !open {
+                let _ = registry.deregister(&mut idle.stream);
+            }
+            open
+        });
+    }
+}
+
+/// Wait for connections to become readable and hand them to the pool one request at a time.
+/// Workers send connections that stay open back over a channel, waking up the poll.
+pub(crate) fn run(
+    app: Arc<App>,
+    listeners: Vec<net::TcpListener>,
+    pool: ThreadPool,
+    idle_timeout: Duration,
+    stop_flag: Option<Arc<AtomicBool>>,
+) -> Result<(), ServerError> {
+    let mut poll = Poll::new()?;
+    let mut listeners = listeners
+        .into_iter()
+        .map(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(TcpListener::from_std(listener))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    for (i, listener) in listeners.iter_mut().enumerate() {
+        poll.registry()
+            .register(listener, Token(FIRST_LISTENER + i), Interest::READABLE)?;
+    }
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let (sender, returned) = mpsc::channel::<net::TcpStream>();
+
+    let dispatch = |stream: net::TcpStream, keep_alive: bool| {
+        let app = Arc::clone(&app);
+        let sender = sender.clone();
+        let waker = Arc::clone(&waker);
+        pool.execute(move || {
+            let mut stream = stream;
+            // Only counted while a request is served, not while waiting for the next.
+            let _connection = app.track_connection();
+            if app.serve(&mut stream, keep_alive) && sender.send(stream).is_ok() {
+                if let Err(e) = waker.wake() {
+                    log!("Failed to wake event loop: {e:?}");
+                }
+            }
+        })
+    };
+
+    let next_connection = FIRST_LISTENER + listeners.len();
+    let mut connections = Connections {
+        idle: HashMap::new(),
+        next_token: next_connection,
+    };
+    let mut events = Events::with_capacity(1024);
+    loop {
+        if let Err(e) = poll.poll(&mut events, Some(SWEEP_INTERVAL)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+        if app.shutdown_requested() {
+            log!("Shutting down");
+            return Ok(());
+        }
+
+        for event in &events {
+            match event.token() {
+                Token(token) if (FIRST_LISTENER..next_connection).contains(&token) => loop {
+                    match listeners[token - FIRST_LISTENER].accept() {
+                        Ok((stream, _)) => {
+                            // As in `run`, the request that follows setting the flag is the
+                            // last one handled.
+                            if stop_flag
+                                .as_ref()
+                                .is_some_and(|stop_flag| stop_flag.load(Ordering::SeqCst))
+                            {
+                                let stream = net::TcpStream::from(stream);
+                                stream.set_nonblocking(false)?;
+                                dispatch(stream, false)?;
+                                return Ok(());
+                            }
+                            connections.add(poll.registry(), stream);
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            log!("Connection Failed: {e:?}");
+                            break;
+                        }
+                    }
+                },
+                WAKER => connections.reclaim(poll.registry(), &returned),
+                token => {
+                    if let Some(stream) = connections.take(poll.registry(), token) {
+                        dispatch(stream, true)?;
+                    }
+                }
+            }
+        }
+
+        connections.close_idle(poll.registry(), idle_timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::{AppConfig, RequestType, Resource, ResourceType, Response, StatusCode};
+    use std::{
+        io::{Read, Write},
+        thread,
+    };
+
+    /// The tokens of the connections that became readable within a second.
+    fn readable(poll: &mut Poll) -> Vec<Token> {
+        let mut events = Events::with_capacity(16);
+        poll.poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        events
+            .iter()
+            .filter(|event| event.is_readable())
+            .map(|event| event.token())
+            .collect()
+    }
+
+    #[test]
+    fn connections() {
+        let mut poll = Poll::new().unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut connections = Connections {
+            idle: HashMap::new(),
+            next_token: 5,
+        };
+        connections.add(poll.registry(), TcpStream::from_std(stream));
+
+        // A request makes the connection readable, and it's taken out as a blocking stream.
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        assert_eq!(readable(&mut poll), [Token(5)]);
+        let mut stream = connections.take(poll.registry(), Token(5)).unwrap();
+        assert!(connections.idle.is_empty());
+        assert!(connections.take(poll.registry(), Token(5)).is_none());
+        // The rest of the request arrives after the worker started reading.
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            client.write_all(b"\r\n").unwrap();
+            client
+        });
+        let mut request = [0; 18];
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"GET / HTTP/1.1\r\n\r\n");
+        let mut client = writer.join().unwrap();
+
+        // Handed back for the next request, under a new token.
+        let (sender, returned) = mpsc::channel();
+        sender.send(stream).unwrap();
+        connections.reclaim(poll.registry(), &returned);
+        assert!(connections.idle.contains_key(&Token(6)));
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(readable(&mut poll), [Token(6)]);
+
+        // Closed once idle for too long.
+        connections.close_idle(poll.registry(), Duration::from_secs(60));
+        assert_eq!(connections.idle.len(), 1);
+        connections.close_idle(poll.registry(), Duration::ZERO);
+        assert!(connections.idle.is_empty());
+        // Reset rather than closed, as the request was left unread.
+        assert!(matches!(client.read(&mut [0; 1]), Ok(0) | Err(_)));
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let addr: net::SocketAddr = "127.0.0.1:7732".parse().unwrap();
+        let mut app = App::new(AppConfig::new(addr, 1, 1));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "hello"))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let server = thread::spawn(move || app.run_evented(Some(stop_flag_clone)).unwrap());
+        thread::sleep(Duration::from_millis(100)); // Give the app time to start up
+
+        // Answered, and then closed after the read timeout without another request.
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let start = Instant::now();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let _ = net::TcpStream::connect(addr);
+        server.join().unwrap();
+    }
+}
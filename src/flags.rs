@@ -0,0 +1,282 @@
+use crate::log;
+use crate::webserver::{percent_decode, RequestType, Resource, ResourceType, Response, StatusCode};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+/// Environment variables starting with this prefix override flags, such as `FLAG_NEW_NAV=1`
+/// for `new_nav`.
+pub const ENV_PREFIX: &str = "FLAG_";
+
+/// Feature flags that can be switched at runtime, so unfinished features can be deployed
+/// switched off.
+///
+/// Flags are read from a file with one `name = true` or `name = false` per line, and `#`
+/// comments. Environment variables override the file. Share the flags with handlers through
+/// `App::with_state`, and call `watch` to pick up changes to the file without a restart.
+/// Unknown flags are disabled.
+pub struct FeatureFlags {
+    path: Option<PathBuf>,
+    flags: RwLock<BTreeMap<String, bool>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl FeatureFlags {
+    /// Flags that only live in memory, such as for tests.
+    pub fn new(flags: &[(&str, bool)]) -> Self {
+        Self {
+            path: None,
+            flags: RwLock::new(
+                flags
+                    .iter()
+                    .map(|(name, enabled)| (name.to_string(), *enabled))
+                    .collect(),
+            ),
+            modified: RwLock::new(None),
+        }
+    }
+
+    /// Load the flags from `path`. A missing file has no flags, and is created when a flag is set.
+    pub fn from_file(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let flags = Self {
+            path: Some(path.into()),
+            flags: RwLock::default(),
+            modified: RwLock::new(None),
+        };
+        flags.reload()?;
+        Ok(flags)
+    }
+
+    pub fn enabled(&self, name: &str) -> bool {
+        if let Some(enabled) = env::var(env_name(name))
+            .ok()
+            .and_then(|value| parse_bool(&value))
+        {
+            return enabled;
+        }
+        self.flags
+            .read()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// All flags with whether they're enabled, including environment overrides, sorted by name.
+    pub fn all(&self) -> Vec<(String, bool)> {
+        let names: Vec<String> = self.flags.read().unwrap().keys().cloned().collect();
+        names
+            .into_iter()
+            .map(|name| {
+                let enabled = self.enabled(&name);
+                (name, enabled)
+            })
+            .collect()
+    }
+
+    /// Switch a flag, saving the change to the file if there is one.
+    pub fn set(&self, name: &str, enabled: bool) -> Result<(), String> {
+        if !valid_name(name) {
+            return Err(format!("Invalid flag name: {name}"));
+        }
+        let mut flags = self.flags.write().unwrap();
+        flags.insert(name.to_string(), enabled);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents: String = flags
+            .iter()
+            .map(|(name, enabled)| format!("{name} = {enabled}\n"))
+            .collect();
+        fs::write(path, contents).map_err(|e| format!("Failed to save flags: {e}"))?;
+        *self.modified.write().unwrap() = fs::metadata(path).and_then(|m| m.modified()).ok();
+        log!("Feature flag {name} set to {enabled}");
+        Ok(())
+    }
+
+    /// Read the file again if it changed since it was last read. Returns whether it was read.
+    pub fn reload(&self) -> Result<bool, String> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == *self.modified.read().unwrap() {
+            return Ok(false);
+        }
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Failed to read flags: {e}")),
+        };
+        *self.flags.write().unwrap() = parse(&contents)?;
+        *self.modified.write().unwrap() = modified;
+        Ok(true)
+    }
+
+    /// Check the file for changes every `interval` in a background thread.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let flags = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match flags.reload() {
+                Ok(true) => log!("Reloaded feature flags"),
+                Ok(false) => {}
+                Err(e) => log!("{e}"),
+            }
+        })
+    }
+
+    /// An admin page at `path` listing the flags, with buttons that POST to the same path to
+    /// switch them. Protect the path with `Auth`.
+    pub fn admin_resources(self: &Arc<Self>, path: &str) -> [Resource; 2] {
+        let flags = Arc::clone(self);
+        let page_path = path.to_string();
+        let page = Resource::new(
+            RequestType::GET,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(move |_| {
+                Ok(Response::text(StatusCode::OK, flags.admin_page(&page_path))
+                    .with_header("Content-Type", "text/html; charset=utf-8"))
+            }),
+        );
+        let flags = Arc::clone(self);
+        let redirect_path = path.to_string();
+        let toggle = Resource::new(
+            RequestType::POST,
+            path.to_string(),
+            ResourceType::REDIRECT,
+            Box::new(move |request| {
+                let form = String::from_utf8_lossy(request.body());
+                let field = |name: &str| {
+                    form.split('&')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(key, _)| *key == name)
+                        .map(|(_, value)| percent_decode(value))
+                };
+                let (Some(name), Some(enabled)) = (field("name"), field("enabled")) else {
+                    return Ok(Response::empty(StatusCode::BadRequest));
+                };
+                let Some(enabled) = parse_bool(&enabled) else {
+                    return Ok(Response::empty(StatusCode::BadRequest));
+                };
+                match flags.set(&name, enabled) {
+                    Ok(()) => Ok(Response::redirect(StatusCode::SeeOther, &redirect_path)),
+                    Err(e) if valid_name(&name) => Err(e.into()),
+                    Err(_) => Ok(Response::empty(StatusCode::BadRequest)),
+                }
+            }),
+        );
+        [page, toggle]
+    }
+
+    fn admin_page(&self, path: &str) -> String {
+        let rows: String = self
+            .all()
+            .iter()
+            .map(|(name, enabled)| {
+                let overridden = env::var(env_name(name)).is_ok();
+                format!(
+                    "<tr><td>{name}</td><td>{}</td><td><form method=\"post\" action=\"{path}\">\
+                     <input type=\"hidden\" name=\"name\" value=\"{name}\">\
+                     <input type=\"hidden\" name=\"enabled\" value=\"{}\">\
+                     <button{}>{}</button></form></td></tr>",
+                    if *enabled { "on" } else { "off" },
+                    !enabled,
+                    if overridden { " disabled" } else { "" },
+                    if *enabled { "Disable" } else { "Enable" },
+                )
+            })
+            .collect();
+        format!(
+            "<!DOCTYPE html><html lang=\"en\"><head><title>Feature flags</title></head><body>\
+             <h1>Feature flags</h1><table>{rows}</table></body></html>"
+        )
+    }
+}
+
+fn parse(contents: &str) -> Result<BTreeMap<String, bool>, String> {
+    let mut flags = BTreeMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let flag = line
+            .split_once('=')
+            .map(|(name, value)| (name.trim(), parse_bool(value.trim())));
+        match flag {
+            Some((name, Some(enabled))) if valid_name(name) => {
+                flags.insert(name.to_string(), enabled);
+            }
+            _ => {
+                return Err(format!(
+                    "Invalid feature flag on line {}: {line}",
+                    number + 1
+                ))
+            }
+        }
+    }
+    Ok(flags)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "1" | "on" => Some(true),
+        "false" | "0" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Flag names are letters, digits and underscores, so they're safe in HTML and variable names.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn env_name(name: &str) -> String {
+    format!("{ENV_PREFIX}{}", name.to_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_flags() {
+        let flags = parse("# Navigation\nnew_nav = true\n\ndark_mode=off # later\n").unwrap();
+        assert_eq!(flags.get("new_nav"), Some(&true));
+        assert_eq!(flags.get("dark_mode"), Some(&false));
+        assert!(parse("new_nav = maybe").is_err());
+        assert!(parse("new nav = true").is_err());
+    }
+
+    #[test]
+    fn file_and_env() {
+        let path = env::temp_dir().join("wwwdaanlubbersnl_flags_test");
+        fs::write(&path, "new_nav = true\nenv_override = false\n").unwrap();
+        let flags = FeatureFlags::from_file(&path).unwrap();
+        assert!(flags.enabled("new_nav"));
+        assert!(!flags.enabled("unknown"));
+
+        env::set_var("FLAG_ENV_OVERRIDE", "1");
+        assert!(flags.enabled("env_override"));
+        env::remove_var("FLAG_ENV_OVERRIDE");
+
+        flags.set("new_nav", false).unwrap();
+        assert!(flags.set("new nav", false).is_err());
+        let reloaded = FeatureFlags::from_file(&path).unwrap();
+        assert_eq!(
+            reloaded.all(),
+            [
+                ("env_override".to_string(), false),
+                ("new_nav".to_string(), false)
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+use crate::concurrency::PoolCounters;
+use crate::http_client;
+use crate::log;
+use crate::scheduler::Scheduler;
+use crate::webserver::{
+    html_escape, http_date, Error, RequestType, Resource, ResourceType, Response, StatusCode,
+};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How many checks are kept per target by default.
+const DEFAULT_HISTORY: usize = 288;
+
+/// The result of checking a target once.
+#[derive(Clone)]
+pub struct Check {
+    pub time: SystemTime,
+    /// The response status, or the error when there was no response.
+    pub result: Result<u16, String>,
+    pub latency: Duration,
+}
+
+impl Check {
+    /// Whether the target answered without a client or server error. Redirects count as up,
+    /// since they aren't followed.
+    pub fn is_up(&self) -> bool {
+        self.result.as_ref().is_ok_and(|status| *status < 400)
+    }
+}
+
+struct Target {
+    name: String,
+    url: String,
+    history: VecDeque<Check>,
+}
+
+/// Checks linked services, such as profile pages or other apps, in the background and keeps a
+/// history of the results for a status page.
+///
+/// `https` targets need the `tls` feature.
+pub struct HealthChecker {
+    targets: Mutex<Vec<Target>>,
+    history: usize,
+}
+
+impl Default for HealthChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HealthChecker {
+    pub fn new() -> Self {
+        Self {
+            targets: Mutex::new(vec![]),
+            history: DEFAULT_HISTORY,
+        }
+    }
+
+    pub fn with_target(self, name: &str, url: &str) -> Self {
+        self.targets.lock().unwrap().push(Target {
+            name: name.to_string(),
+            url: url.to_string(),
+            history: VecDeque::new(),
+        });
+        self
+    }
+
+    /// Keep this many checks per target. Defaults to 288, a day of checks every 5 minutes.
+    pub fn with_history(mut self, checks: usize) -> Self {
+        self.history = checks.max(1);
+        self
+    }
+
+    /// Check every target once, one after another.
+    pub fn check_all(&self) {
+        let urls: Vec<String> = self
+            .targets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|target| target.url.clone())
+            .collect();
+        // Don't hold the lock while waiting on the network, so the status page stays available.
+        let checks: Vec<Check> = urls.iter().map(|url| check(url)).collect();
+
+        let mut targets = self.targets.lock().unwrap();
+        for (target, check) in targets.iter_mut().zip(checks) {
+            if !check.is_up() {
+                match &check.result {
+                    Ok(status) => log!("Health check of {} failed: {status}", target.url),
+                    Err(e) => log!("Health check of {} failed: {e}", target.url),
+                }
+            }
+            if target.history.len() == self.history {
+                target.history.pop_front();
+            }
+            target.history.push_back(check);
+        }
+    }
+
+    /// The checks of the target with this name, oldest first.
+    pub fn history(&self, name: &str) -> Vec<Check> {
+        self.targets
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|target| target.name == name)
+            .map(|target| target.history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Add a job checking every target every `interval` to the scheduler.
+    pub fn schedule(self: &Arc<Self>, scheduler: Scheduler, interval: Duration) -> Scheduler {
+        let checker = Arc::clone(self);
+        scheduler.every("health checks", interval, move || checker.check_all())
+    }
+
+    /// A GET resource at `path` with a status page listing every target's latest check and
+    /// its availability over the kept history.
+    pub fn status_page_resource(self: &Arc<Self>, path: &str) -> Resource {
+        let checker = Arc::clone(self);
+        Resource::new(
+            RequestType::GET,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(move |_| {
+                Ok(Response::text(StatusCode::OK, checker.status_page())
+                    .with_header("Content-Type", "text/html; charset=utf-8")
+                    .with_header("Cache-Control", "no-cache"))
+            }),
+        )
+    }
+
+    fn status_page(&self) -> String {
+        let targets = self.targets.lock().unwrap();
+        let rows: String = targets
+            .iter()
+            .map(|target| {
+                let (status, checked) = match target.history.back() {
+                    Some(check) => (
+                        match &check.result {
+                            Ok(status) => format!("{status} in {} ms", check.latency.as_millis()),
+                            Err(e) => html_escape(e),
+                        },
+                        http_date(check.time),
+                    ),
+                    None => ("Not checked yet".to_string(), String::new()),
+                };
+                let up = target.history.iter().filter(|check| check.is_up()).count();
+                let availability = match target.history.len() {
+                    0 => String::new(),
+                    checks => format!("{:.1}%", up as f64 * 100.0 / checks as f64),
+                };
+                format!(
+                    "<tr><td><a href=\"{url}\">{name}</a></td><td>{status}</td><td>{checked}</td>\
+                     <td>{availability}</td></tr>",
+                    url = html_escape(&target.url),
+                    name = html_escape(&target.name),
+                )
+            })
+            .collect();
+        format!(
+            "<!DOCTYPE html><html lang=\"en\"><head><title>Status</title></head><body>\
+             <h1>Status</h1><table><tr><th>Service</th><th>Status</th><th>Checked</th>\
+             <th>Availability</th></tr>{rows}</table></body></html>"
+        )
+    }
+}
+
+/// A GET resource at `path` answering 200, so a liveness probe knows the server still accepts
+/// and answers requests.
+pub fn liveness_resource(path: &str) -> Resource {
+    probe_resource(path, Box::new(|_| Ok(())))
+}
+
+/// A GET resource at `path` answering 200 if `ready` passes and no more than `max_queued`
+/// requests are waiting for a worker, and 503 with the reason otherwise, so a load balancer
+/// can send traffic elsewhere for a while.
+pub fn readiness_resource(
+    path: &str,
+    max_queued: usize,
+    ready: impl Fn() -> Result<(), Error> + Send + Sync + 'static,
+) -> Resource {
+    probe_resource(
+        path,
+        Box::new(move |counters| {
+            let queued = counters.map_or(0, |counters| counters.stats().queued);
+            if queued > max_queued {
+                return Err(format!("{queued} requests waiting for a worker").into());
+            }
+            ready()
+        }),
+    )
+}
+
+type Probe = Box<dyn Fn(Option<&PoolCounters>) -> Result<(), Error> + Send + Sync>;
+
+fn probe_resource(path: &str, probe: Probe) -> Resource {
+    Resource::new(
+        RequestType::GET,
+        path.to_string(),
+        ResourceType::TEXT,
+        Box::new(move |request| {
+            let response = match probe(request.state::<PoolCounters>().as_deref()) {
+                Ok(()) => Response::text(StatusCode::OK, "ok"),
+                Err(e) => Response::text(StatusCode::ServiceUnavailable, e.to_string()),
+            };
+            Ok(response
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_header("Cache-Control", "no-store"))
+        }),
+    )
+    .without_sitemap()
+}
+
+fn check(url: &str) -> Check {
+    let time = SystemTime::now();
+    let start = Instant::now();
+    let result = http_client::get(url).map(|response| response.status());
+    Check {
+        time,
+        result,
+        latency: start.elapsed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::{Body, Request};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn history_and_page() {
+        // Nothing listens on port 1, so the check fails right away.
+        let checker = HealthChecker::new()
+            .with_target("Closed <port>", "http://127.0.0.1:1/")
+            .with_history(2);
+        for _ in 0..3 {
+            checker.check_all();
+        }
+        let history = checker.history("Closed <port>");
+        assert_eq!(history.len(), 2);
+        assert!(!history[1].is_up());
+        assert!(checker.history("Unknown").is_empty());
+
+        let page = checker.status_page();
+        assert!(page.contains("<a href=\"http://127.0.0.1:1/\">Closed &lt;port&gt;</a>"));
+        assert!(page.contains("<td>0.0%</td>"));
+    }
+
+    #[test]
+    fn probes() {
+        let request = |path: &str| {
+            let request = format!("GET {path} HTTP/1.1\r\n\r\n");
+            Request::from_reader(&mut request.as_bytes()).unwrap()
+        };
+        let response = liveness_resource("/healthz")
+            .handle(&request("/healthz"))
+            .unwrap();
+        assert_eq!(response.status_code.code(), 200);
+        assert_eq!(response.header("Cache-Control"), Some("no-store"));
+
+        let ready = Arc::new(AtomicBool::new(false));
+        let ready_clone = Arc::clone(&ready);
+        let resource = readiness_resource("/readyz", 4, move || {
+            match ready_clone.load(Ordering::SeqCst) {
+                true => Ok(()),
+                false => Err("Warming up".into()),
+            }
+        });
+        let response = resource.handle(&request("/readyz")).unwrap();
+        assert_eq!(response.status_code.code(), 503);
+        assert!(matches!(&response.body, Body::Text(text) if text == "Warming up"));
+        ready.store(true, Ordering::SeqCst);
+        let response = resource.handle(&request("/readyz")).unwrap();
+        assert_eq!(response.status_code.code(), 200);
+    }
+}
@@ -0,0 +1,459 @@
+//! HPACK, the header compression of HTTP/2 (RFC 7541).
+
+use std::{collections::VecDeque, sync::OnceLock};
+
+/// The table clients start with, which is all they may use unless the server says otherwise.
+const MAX_TABLE_SIZE: usize = 4096;
+
+/// What every table entry costs on top of its name and value.
+const ENTRY_OVERHEAD: usize = 32;
+
+/// The entries every connection shares, indexed from 1.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Decodes the header blocks a client sends on a connection, keeping the table they build up.
+pub(crate) struct Decoder {
+    /// The newest entry first, as it has the lowest index.
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    /// The size of the headers in a block, counted like table entries, that is decoded at
+    /// most. A small block can refer to a large entry many times over.
+    max_list_size: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new(max_list_size: usize) -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: MAX_TABLE_SIZE,
+            max_list_size,
+        }
+    }
+
+    /// The headers in a block, in order, with the names as they were sent.
+    pub(crate) fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, String> {
+        let mut headers = vec![];
+        let mut list_size = 0;
+        let mut pos = 0;
+        while let Some(&byte) = block.get(pos) {
+            let header = if byte & 0x80 != 0 {
+                let index = decode_integer(block, &mut pos, 7)?;
+                self.get(index)?
+            } else if byte & 0x40 != 0 {
+                let header = self.literal(block, &mut pos, 6)?;
+                self.insert(header.clone());
+                header
+            } else if byte & 0x20 != 0 {
+                if !headers.is_empty() {
+                    return Err("Table size update after a header".to_string());
+                }
+                let size = decode_integer(block, &mut pos, 5)?;
+                if size > MAX_TABLE_SIZE {
+                    return Err(format!("Table size {size} is over the limit"));
+                }
+                self.max_size = size;
+                self.evict();
+                continue;
+            } else {
+                // Literals that aren't indexed, whether or not they may be by proxies.
+                self.literal(block, &mut pos, 4)?
+            };
+            list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+            if list_size > self.max_list_size {
+                return Err(format!("Header list over {} bytes", self.max_list_size));
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    fn get(&self, index: usize) -> Result<(String, String), String> {
+        let header = match index {
+            0 => None,
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Some((name.to_string(), value.to_string()))
+            }
+            _ => self.table.get(index - 62).cloned(),
+        };
+        header.ok_or_else(|| format!("Invalid table index {index}"))
+    }
+
+    /// A header with a literal value, and an indexed or literal name.
+    fn literal(
+        &self,
+        block: &[u8],
+        pos: &mut usize,
+        prefix: u8,
+    ) -> Result<(String, String), String> {
+        let name = match decode_integer(block, pos, prefix)? {
+            0 => decode_string(block, pos)?,
+            index => self.get(index)?.0,
+        };
+        Ok((name, decode_string(block, pos)?))
+    }
+
+    fn insert(&mut self, header: (String, String)) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        if size > self.max_size {
+            // An entry too large for the table empties it.
+            self.table.clear();
+            self.size = 0;
+            return;
+        }
+        self.table.push_front(header);
+        self.size += size;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let (name, value) = self.table.pop_back().unwrap();
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+/// Encode headers as literals that aren't added to the table, so the client's table stays
+/// empty, except for statuses in the static table. Names are sent in lowercase, as HTTP/2
+/// requires.
+pub(crate) fn encode(headers: &[(String, String)]) -> Vec<u8> {
+    let mut block = vec![];
+    for (name, value) in headers {
+        let name = name.to_ascii_lowercase();
+        let index = STATIC_TABLE
+            .iter()
+            .position(|entry| *entry == (name.as_str(), value.as_str()))
+            .filter(|_| name == ":status");
+        if let Some(index) = index {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|entry| entry.0 == name) {
+            Some(index) => encode_integer(&mut block, 0, 4, index + 1),
+            None => {
+                block.push(0);
+                encode_string(&mut block, &name);
+            }
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+/// An integer whose first byte starts with `prefix` bits of it.
+fn decode_integer(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, String> {
+    let truncated = || "Truncated integer".to_string();
+    let max = (1 << prefix) - 1;
+    let mut value = (block.get(*pos).ok_or_else(truncated)? & max as u8) as usize;
+    *pos += 1;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*pos).ok_or_else(truncated)?;
+        *pos += 1;
+        if shift > 28 {
+            return Err("Integer too large".to_string());
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// `value` with the first byte starting with `first`, the bits before the prefix.
+fn encode_integer(block: &mut Vec<u8>, first: u8, prefix: u8, mut value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        block.push(first | value as u8);
+        return;
+    }
+    block.push(first | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+/// A string literal, which may be Huffman coded. Bytes that aren't UTF-8 are replaced.
+fn decode_string(block: &[u8], pos: &mut usize) -> Result<String, String> {
+    let huffman = block.get(*pos).is_some_and(|byte| byte & 0x80 != 0);
+    let length = decode_integer(block, pos, 7)?;
+    let bytes = block.get(*pos..*pos + length).ok_or("Truncated string")?;
+    *pos += length;
+    let bytes = match huffman {
+        true => huffman_decode(bytes)?,
+        false => bytes.to_vec(),
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// A string literal without Huffman coding, which would only save a few bytes.
+fn encode_string(block: &mut Vec<u8>, text: &str) {
+    encode_integer(block, 0, 7, text.len());
+    block.extend_from_slice(text.as_bytes());
+}
+
+/// The lengths of the codes of bytes 0 to 255 and of the end of string, 256, from RFC 7541
+/// appendix B. The code is canonical, so the codes themselves follow from the lengths.
+const CODE_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, 30,
+];
+
+/// The symbols ordered by code, and for each code length the first code and how many there are.
+struct HuffmanTable {
+    symbols: Vec<u16>,
+    first_code: [u32; 31],
+    count: [u32; 31],
+    offset: [usize; 31],
+}
+
+fn huffman_table() -> &'static HuffmanTable {
+    static TABLE: OnceLock<HuffmanTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut symbols: Vec<u16> = (0..=256).collect();
+        symbols.sort_by_key(|symbol| (CODE_LENGTHS[*symbol as usize], *symbol));
+        let mut table = HuffmanTable {
+            symbols,
+            first_code: [0; 31],
+            count: [0; 31],
+            offset: [0; 31],
+        };
+        let mut code = 0u32;
+        let mut previous = CODE_LENGTHS[table.symbols[0] as usize];
+        for (i, symbol) in table.symbols.iter().enumerate() {
+            let length = CODE_LENGTHS[*symbol as usize];
+            if i > 0 {
+                code = (code + 1) << (length - previous);
+            }
+            let length = length as usize;
+            if table.count[length] == 0 {
+                table.first_code[length] = code;
+                table.offset[length] = i;
+            }
+            table.count[length] += 1;
+            previous = CODE_LENGTHS[*symbol as usize];
+        }
+        table
+    })
+}
+
+/// Decode a Huffman coded string, which is padded to a whole byte with the start of the end of
+/// string code, all ones.
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let table = huffman_table();
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut length = 0;
+    for byte in bytes {
+        for shift in (0..8).rev() {
+            code = code << 1 | (byte >> shift & 1) as u32;
+            length += 1;
+            if length > 30 {
+                return Err("Invalid Huffman code".to_string());
+            }
+            let index = code.wrapping_sub(table.first_code[length]);
+            if code >= table.first_code[length] && index < table.count[length] {
+                match table.symbols[table.offset[length] + index as usize] {
+                    256 => return Err("End of string in Huffman code".to_string()),
+                    symbol => decoded.push(symbol as u8),
+                }
+                code = 0;
+                length = 0;
+            }
+        }
+    }
+    if length > 7 || code != (1 << length) - 1 {
+        return Err("Invalid Huffman padding".to_string());
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn headers(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// The requests with Huffman coding from RFC 7541 appendix C.4, on one connection.
+    #[test]
+    fn decode() {
+        let mut decoder = Decoder::new(usize::MAX);
+        let first = decoder
+            .decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"))
+            .unwrap();
+        assert_eq!(
+            first,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        let second = decoder
+            .decode(&hex("8286 84be 5886 a8eb 1064 9cbf"))
+            .unwrap();
+        assert_eq!(second[3], first[3]);
+        assert_eq!(second[4], ("cache-control".into(), "no-cache".into()));
+        let third = decoder
+            .decode(&hex(
+                "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+            ))
+            .unwrap();
+        assert_eq!(
+            third,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.table.len(), 3);
+        assert_eq!(decoder.size, 164);
+
+        assert!(Decoder::new(usize::MAX).decode(&hex("be")).is_err());
+        assert!(Decoder::new(usize::MAX).decode(&hex("4188 f1e3")).is_err());
+        // "0" is 00000, then padding that has to be all ones.
+        assert_eq!(huffman_decode(&hex("07")), Ok(b"0".to_vec()));
+        assert!(huffman_decode(&hex("00")).is_err());
+    }
+
+    #[test]
+    fn limits() {
+        // A header with a 100 byte value added to the table, and then referred to 40 times.
+        let mut block = vec![0x40, 0x01, b'x', 100];
+        block.extend_from_slice(&[b'y'; 100]);
+        block.extend_from_slice(&[0xbe; 40]);
+        let mut decoder = Decoder::new(1000);
+        let e = decoder.decode(&block).unwrap_err();
+        assert_eq!(e, "Header list over 1000 bytes");
+        let decoded = Decoder::new(41 * 133).decode(&block).unwrap();
+        assert_eq!(decoded.len(), 41);
+
+        // Table size updates only come at the start of a block.
+        let mut decoder = Decoder::new(usize::MAX);
+        assert_eq!(decoder.decode(&hex("3f e1 1f 82")).unwrap().len(), 1);
+        assert_eq!(decoder.max_size, 4096);
+        assert!(decoder.decode(&hex("82 20")).is_err());
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let long = "x".repeat(300);
+        let sent = headers(&[
+            (":status", "200"),
+            ("Content-Type", "text/html"),
+            ("x-long", &long),
+        ]);
+        let block = encode(&sent);
+        assert_eq!(block[0], 0x88);
+        let decoded = Decoder::new(usize::MAX).decode(&block).unwrap();
+        assert_eq!(
+            decoded,
+            headers(&[
+                (":status", "200"),
+                ("content-type", "text/html"),
+                ("x-long", &long),
+            ])
+        );
+        let decoded = Decoder::new(usize::MAX)
+            .decode(&encode(&headers(&[(":status", "418")])))
+            .unwrap();
+        assert_eq!(decoded, headers(&[(":status", "418")]));
+    }
+}
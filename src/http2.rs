@@ -0,0 +1,1123 @@
+//! HTTP/2 (RFC 9113) for TLS connections whose client picks it, see `AppConfig::with_http2`.
+//!
+//! Each request is turned into the HTTP/1.1 request it stands for and answered by the app like
+//! any other, on one of up to `num_threads` threads of the connection, so a slow handler doesn't
+//! hold up the other streams. The response is sent back as HEADERS and DATA frames, within the
+//! windows the client allows.
+//! There are no priorities and no server push.
+
+use crate::hpack::{self, Decoder};
+use crate::log;
+use crate::tls::TlsStream;
+use crate::webserver::{App, Connection as _, Output, ReadError, Request, StatusCode, CHUNK_SIZE};
+use rustls::ServerConnection;
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Cursor, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, Scope},
+    time::Duration,
+};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Window sizes and the frame size until the client's settings say otherwise. The server keeps
+/// these for what it receives.
+const DEFAULT_WINDOW: i64 = 65_535;
+const DEFAULT_FRAME_SIZE: usize = 16_384;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// Streams open at once on a connection. A stream counts until its handler returns, even if
+/// the client resets it before then, so resetting streams doesn't make room for more.
+const MAX_STREAMS: usize = 100;
+
+/// Headers that only mean something for a single HTTP/1.1 connection, which HTTP/2 forbids.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Serve the streams of a connection whose handshake picked HTTP/2, until the client goes away,
+/// sends GOAWAY or is idle for the read timeout.
+pub(crate) fn serve(app: &App, stream: TlsStream) {
+    let remote_addr = stream.remote_addr();
+    let (tls, socket) = stream.into_parts();
+    let timeout = Duration::from_secs(app.config.read_timeout);
+    let _ = socket.set_read_timeout(Some(timeout));
+    let _ = socket.set_write_timeout(Some(timeout));
+    let peer = Peer {
+        tls: Mutex::new(tls),
+        socket,
+        flow: Mutex::new(Flow {
+            window: DEFAULT_WINDOW,
+            streams: HashMap::new(),
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_FRAME_SIZE,
+            closed: false,
+            open: 0,
+        }),
+        window_opened: Condvar::new(),
+        timeout,
+    };
+    let (jobs, queue) = mpsc::channel();
+    let workers = Workers {
+        queue: Mutex::new(queue),
+        idle: AtomicUsize::new(0),
+        max: app.config.num_threads.clamp(1, MAX_STREAMS),
+    };
+    let mut dispatcher = Dispatcher {
+        app,
+        peer: &peer,
+        remote_addr,
+        // The headers a client may send, as advertised in SETTINGS_MAX_HEADER_LIST_SIZE.
+        decoder: Decoder::new(app.config.limits.max_header_bytes),
+        incoming: HashMap::new(),
+        last_stream: 0,
+        workers: &workers,
+        jobs,
+        spawned: 0,
+    };
+    thread::scope(|scope| {
+        let result = dispatcher.run(scope);
+        // The workers stop once they've answered the streams still queued.
+        let Dispatcher {
+            jobs, last_stream, ..
+        } = dispatcher;
+        drop(jobs);
+        let code = match result {
+            Ok(()) => NO_ERROR,
+            Err((code, e)) => {
+                log!("HTTP/2 connection error: {e}");
+                code
+            }
+        };
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        let _ = peer.write_frame(GOAWAY, 0, 0, &payload);
+        // Streams still being answered fail to write from here on.
+        if code != NO_ERROR {
+            peer.close();
+        }
+    });
+    peer.close();
+    let mut tls = peer.tls.lock().unwrap();
+    tls.send_close_notify();
+    let _ = tls.write_tls(&mut &peer.socket);
+    let _ = peer.socket.shutdown(Shutdown::Both);
+}
+
+/// A frame, other than its length.
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+/// An error ending the connection, with its HTTP/2 error code.
+type ConnectionError = (u32, String);
+
+fn error(code: u32, message: impl Into<String>) -> ConnectionError {
+    (code, message.into())
+}
+
+/// A request whose headers arrived, but not yet all of its body.
+struct Incoming {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    too_large: bool,
+}
+
+/// A complete request, and the stream to answer it on.
+type Job = (u32, Incoming);
+
+/// The threads answering the streams of a connection, which are started as they're needed.
+struct Workers {
+    queue: Mutex<Receiver<Job>>,
+    /// Workers waiting for a job.
+    idle: AtomicUsize,
+    /// At most as many as the app has threads, so a connection can't run more handlers at once
+    /// than the app as a whole.
+    max: usize,
+}
+
+/// The reading side of a connection, which hands every complete request to the workers.
+struct Dispatcher<'a> {
+    app: &'a App,
+    peer: &'a Peer,
+    remote_addr: Option<SocketAddr>,
+    decoder: Decoder,
+    incoming: HashMap<u32, Incoming>,
+    last_stream: u32,
+    workers: &'a Workers,
+    jobs: Sender<Job>,
+    spawned: usize,
+}
+
+impl<'a> Dispatcher<'a> {
+    fn run<'scope>(&mut self, scope: &'scope Scope<'scope, '_>) -> Result<(), ConnectionError>
+    where
+        'a: 'scope,
+    {
+        let mut reader = BufReader::new(PeerReader(self.peer));
+        let mut preface = [0; PREFACE.len()];
+        reader
+            .read_exact(&mut preface)
+            .map_err(|e| error(PROTOCOL_ERROR, format!("Failed to read preface: {e}")))?;
+        if preface != PREFACE {
+            return Err(error(PROTOCOL_ERROR, "Invalid preface"));
+        }
+        let mut settings = vec![];
+        for (id, value) in [
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                self.app.config.limits.max_header_bytes,
+            ),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        self.send(SETTINGS, 0, 0, &settings)?;
+
+        loop {
+            let frame = match read_frame(&mut reader) {
+                Ok(frame) => frame?,
+                Err(e) => {
+                    if !matches!(
+                        e.kind(),
+                        io::ErrorKind::UnexpectedEof
+                            | io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                    ) {
+                        log!("Failed to read frame: {e}");
+                    }
+                    return Ok(());
+                }
+            };
+            match frame.kind {
+                SETTINGS => self.settings(frame)?,
+                PING if frame.payload.len() != 8 => {
+                    return Err(error(FRAME_SIZE_ERROR, "Invalid PING"))
+                }
+                PING if frame.flags & ACK == 0 => self.send(PING, ACK, 0, &frame.payload)?,
+                WINDOW_UPDATE => self.window_update(frame)?,
+                HEADERS => {
+                    let frame = self.continued(frame, &mut reader)?;
+                    self.headers(frame, scope)?;
+                }
+                DATA => self.data(frame, scope)?,
+                // A stream that's complete stays open until its handler returns, or until a worker
+                // finds it was reset.
+                RST_STREAM => match self.incoming.remove(&frame.stream) {
+                    Some(_) => self.peer.answered(frame.stream),
+                    None => self.peer.finish(frame.stream),
+                },
+                GOAWAY => return Ok(()),
+                PUSH_PROMISE | CONTINUATION => {
+                    return Err(error(PROTOCOL_ERROR, "Unexpected frame"))
+                }
+                // PRIORITY, and frame types this server doesn't know.
+                _ => {}
+            }
+        }
+    }
+
+    fn send(
+        &self,
+        kind: u8,
+        flags: u8,
+        stream: u32,
+        payload: &[u8],
+    ) -> Result<(), ConnectionError> {
+        self.peer
+            .write_frame(kind, flags, stream, payload)
+            .map_err(|e| error(INTERNAL_ERROR, format!("Failed to write frame: {e}")))
+    }
+
+    fn settings(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.flags & ACK != 0 {
+            return Ok(());
+        }
+        if frame.stream != 0 || !frame.payload.len().is_multiple_of(6) {
+            return Err(error(FRAME_SIZE_ERROR, "Invalid SETTINGS"));
+        }
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE if value as i64 > MAX_WINDOW => {
+                    return Err(error(FLOW_CONTROL_ERROR, "Initial window too large"))
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => self.peer.set_initial_window(value as i64),
+                SETTINGS_MAX_FRAME_SIZE if !(16_384..1 << 24).contains(&value) => {
+                    return Err(error(PROTOCOL_ERROR, "Invalid maximum frame size"))
+                }
+                SETTINGS_MAX_FRAME_SIZE => self.peer.flow().max_frame_size = value as usize,
+                _ => {}
+            }
+        }
+        self.send(SETTINGS, ACK, 0, &[])
+    }
+
+    fn window_update(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let Ok(increment) = <[u8; 4]>::try_from(frame.payload.as_slice()) else {
+            return Err(error(FRAME_SIZE_ERROR, "Invalid WINDOW_UPDATE"));
+        };
+        let increment = (u32::from_be_bytes(increment) & 0x7fff_ffff) as i64;
+        if increment == 0 {
+            return Err(error(PROTOCOL_ERROR, "Window update of 0"));
+        }
+        let mut flow = self.peer.flow();
+        let window = match frame.stream {
+            0 => Some(&mut flow.window),
+            stream => flow.streams.get_mut(&stream),
+        };
+        if let Some(window) = window {
+            *window += increment;
+            if *window > MAX_WINDOW {
+                return Err(error(FLOW_CONTROL_ERROR, "Window too large"));
+            }
+        }
+        self.peer.window_opened.notify_all();
+        Ok(())
+    }
+
+    /// The HEADERS frame with the payloads of the CONTINUATION frames that follow it, without
+    /// padding or priority.
+    fn continued(
+        &self,
+        mut frame: Frame,
+        reader: &mut impl Read,
+    ) -> Result<Frame, ConnectionError> {
+        let mut payload = &frame.payload[..];
+        if frame.flags & PADDED != 0 {
+            let (&padding, rest) = payload
+                .split_first()
+                .ok_or_else(|| error(PROTOCOL_ERROR, "Invalid padding"))?;
+            payload = rest
+                .get(..rest.len().wrapping_sub(padding as usize))
+                .ok_or_else(|| error(PROTOCOL_ERROR, "Invalid padding"))?;
+        }
+        if frame.flags & PRIORITY != 0 {
+            payload = payload
+                .get(5..)
+                .ok_or_else(|| error(FRAME_SIZE_ERROR, "Invalid priority"))?;
+        }
+        frame.payload = payload.to_vec();
+        while frame.flags & END_HEADERS == 0 {
+            let next = read_frame(reader)
+                .map_err(|e| error(PROTOCOL_ERROR, format!("Failed to read frame: {e}")))??;
+            if next.kind != CONTINUATION || next.stream != frame.stream {
+                return Err(error(PROTOCOL_ERROR, "Expected CONTINUATION"));
+            }
+            frame.payload.extend_from_slice(&next.payload);
+            if frame.payload.len() > self.app.config.limits.max_header_bytes {
+                return Err(error(PROTOCOL_ERROR, "Headers too large"));
+            }
+            frame.flags |= next.flags & END_HEADERS;
+        }
+        Ok(frame)
+    }
+
+    fn headers<'scope>(
+        &mut self,
+        frame: Frame,
+        scope: &'scope Scope<'scope, '_>,
+    ) -> Result<(), ConnectionError>
+    where
+        'a: 'scope,
+    {
+        let headers = self
+            .decoder
+            .decode(&frame.payload)
+            .map_err(|e| error(COMPRESSION_ERROR, e))?;
+        let id = frame.stream;
+        let end = frame.flags & END_STREAM != 0;
+        // Trailers, which end the body. Handlers don't get to see them.
+        if self.incoming.contains_key(&id) {
+            if !end {
+                return Err(error(PROTOCOL_ERROR, "Trailers without END_STREAM"));
+            }
+            let incoming = self.incoming.remove(&id).unwrap();
+            self.start(id, incoming, scope);
+            return Ok(());
+        }
+        if id.is_multiple_of(2) || id <= self.last_stream {
+            return Err(error(PROTOCOL_ERROR, format!("Invalid stream {id}")));
+        }
+        self.last_stream = id;
+        if self.peer.flow().open >= MAX_STREAMS {
+            return self.send(RST_STREAM, 0, id, &REFUSED_STREAM.to_be_bytes());
+        }
+        self.peer.open(id);
+        let incoming = Incoming {
+            headers,
+            body: vec![],
+            too_large: false,
+        };
+        match end {
+            true => self.start(id, incoming, scope),
+            false => {
+                self.incoming.insert(id, incoming);
+            }
+        }
+        Ok(())
+    }
+
+    fn data<'scope>(
+        &mut self,
+        frame: Frame,
+        scope: &'scope Scope<'scope, '_>,
+    ) -> Result<(), ConnectionError>
+    where
+        'a: 'scope,
+    {
+        if frame.stream == 0 {
+            return Err(error(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        // Receiving is only limited by the size of requests, so give the window back right away.
+        let length = frame.payload.len() as u32;
+        if length > 0 {
+            self.send(WINDOW_UPDATE, 0, 0, &length.to_be_bytes())?;
+        }
+        let mut payload = &frame.payload[..];
+        if frame.flags & PADDED != 0 {
+            let (&padding, rest) = payload
+                .split_first()
+                .ok_or_else(|| error(PROTOCOL_ERROR, "Invalid padding"))?;
+            payload = rest
+                .get(..rest.len().wrapping_sub(padding as usize))
+                .ok_or_else(|| error(PROTOCOL_ERROR, "Invalid padding"))?;
+        }
+        let Some(incoming) = self.incoming.get_mut(&frame.stream) else {
+            return self.send(RST_STREAM, 0, frame.stream, &STREAM_CLOSED.to_be_bytes());
+        };
+        if incoming.body.len() + payload.len() > self.app.config.limits.max_body_bytes {
+            incoming.too_large = true;
+            incoming.body = vec![];
+        }
+        if !incoming.too_large {
+            incoming.body.extend_from_slice(payload);
+        }
+        if frame.flags & END_STREAM != 0 {
+            let incoming = self.incoming.remove(&frame.stream).unwrap();
+            self.start(frame.stream, incoming, scope);
+        } else if length > 0 {
+            self.send(WINDOW_UPDATE, 0, frame.stream, &length.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Queue a complete request for the workers, starting another if none is waiting.
+    fn start<'scope>(&mut self, id: u32, incoming: Incoming, scope: &'scope Scope<'scope, '_>)
+    where
+        'a: 'scope,
+    {
+        let workers = self.workers;
+        if workers.idle.load(Ordering::SeqCst) == 0 && self.spawned < workers.max {
+            self.spawned += 1;
+            let (app, peer, remote_addr) = (self.app, self.peer, self.remote_addr);
+            scope.spawn(move || work(app, peer, remote_addr, workers));
+        }
+        // The workers only stop once this sender is dropped.
+        let _ = self.jobs.send((id, incoming));
+    }
+}
+
+/// Answer queued requests until the connection stops reading them.
+fn work(app: &App, peer: &Peer, remote_addr: Option<SocketAddr>, workers: &Workers) {
+    loop {
+        workers.idle.fetch_add(1, Ordering::SeqCst);
+        let job = workers.queue.lock().unwrap().recv();
+        workers.idle.fetch_sub(1, Ordering::SeqCst);
+        let Ok((id, incoming)) = job else {
+            return;
+        };
+        // Streams the client reset while they were queued aren't answered at all.
+        if peer.flow().streams.contains_key(&id) {
+            answer(app, peer, remote_addr, id, incoming);
+        }
+        peer.answered(id);
+    }
+}
+
+fn answer(app: &App, peer: &Peer, remote_addr: Option<SocketAddr>, id: u32, incoming: Incoming) {
+    let output = match request(app, incoming) {
+        Ok(mut request) => {
+            app.attach(&mut request, remote_addr);
+            Some(app.respond(&mut request))
+        }
+        Err(e) => app.rejection(e).map(|response| Output {
+            bytes: response.into_bytes(),
+            file: None,
+            stream: None,
+            chunked: false,
+            upgrade: None,
+        }),
+    };
+    let result = match output {
+        Some(output) => peer.send_output(id, output),
+        None => peer.write_frame(RST_STREAM, 0, id, &PROTOCOL_ERROR.to_be_bytes()),
+    };
+    if let Err(e) = result {
+        log!("Failed to write to stream: {e:?}");
+    }
+}
+
+/// The HTTP/1.1 request a stream stands for, checked like one read from a connection.
+fn request(app: &App, incoming: Incoming) -> Result<Request, ReadError> {
+    let malformed = |e: &str| ReadError::Malformed(StatusCode::BadRequest, e.to_string());
+    if incoming.too_large {
+        return Err(ReadError::TooLarge(
+            StatusCode::PayloadTooLarge,
+            "Request body too large".to_string(),
+        ));
+    }
+    let (mut method, mut path, mut authority) = (None, None, None);
+    let mut head = String::new();
+    let mut cookies = vec![];
+    for (name, value) in incoming.headers {
+        // Anything that would end the line, and a header name in uppercase, which HTTP/2 clients
+        // mustn't send.
+        if [&name, &value]
+            .iter()
+            .any(|text| text.contains(['\r', '\n', '\0']))
+            || name.chars().any(|c| c.is_ascii_uppercase())
+        {
+            return Err(malformed("Invalid header"));
+        }
+        match name.as_str() {
+            ":method" => method = Some(value),
+            ":path" => path = Some(value),
+            ":authority" => authority = Some(value),
+            ":scheme" => {}
+            "host" => authority = authority.or(Some(value)),
+            "cookie" => cookies.push(value),
+            // The body is the length of the DATA frames.
+            "content-length" => {}
+            name if name.starts_with(':') || CONNECTION_HEADERS.contains(&name) => {
+                return Err(malformed("Invalid header"))
+            }
+            _ => head.push_str(&format!("{name}: {value}\r\n")),
+        }
+    }
+    let (Some(method), Some(path)) = (method, path) else {
+        return Err(malformed("Missing :method or :path"));
+    };
+    if [&method, &path].iter().any(|text| text.contains(' ')) {
+        return Err(malformed("Invalid :method or :path"));
+    }
+    let mut raw = format!("{method} {path} HTTP/1.1\r\n");
+    if let Some(authority) = authority {
+        raw.push_str(&format!("Host: {authority}\r\n"));
+    }
+    raw.push_str(&head);
+    if !cookies.is_empty() {
+        raw.push_str(&format!("Cookie: {}\r\n", cookies.join("; ")));
+    }
+    raw.push_str(&format!("Content-Length: {}\r\n\r\n", incoming.body.len()));
+    let mut raw = Cursor::new(raw.into_bytes()).chain(&incoming.body[..]);
+    let mut reader = BufReader::new(&mut raw);
+    let limits = &app.config.limits;
+    let mut request = Request::read_head(&mut reader, limits)?;
+    request.read_body(&mut reader, limits)?;
+    Ok(request)
+}
+
+/// The send windows of the connection and its streams, which the client opens up with
+/// WINDOW_UPDATE frames.
+struct Flow {
+    window: i64,
+    /// The streams being answered.
+    streams: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame_size: usize,
+    /// Whether the connection is going away, so nothing more can be sent.
+    closed: bool,
+    /// Streams that are open, see `MAX_STREAMS`.
+    open: usize,
+}
+
+/// The shared side of a connection. The thread reading frames and those answering streams all
+/// write to it, one frame at a time.
+struct Peer {
+    tls: Mutex<ServerConnection>,
+    socket: TcpStream,
+    flow: Mutex<Flow>,
+    window_opened: Condvar,
+    /// How long to wait for the client to open a window before giving up on the stream.
+    timeout: Duration,
+}
+
+impl Peer {
+    fn flow(&self) -> MutexGuard<'_, Flow> {
+        self.flow.lock().unwrap()
+    }
+
+    fn open(&self, id: u32) {
+        let mut flow = self.flow();
+        let window = flow.initial_window;
+        flow.streams.insert(id, window);
+        flow.open += 1;
+    }
+
+    /// Stop sending on the stream, once the client reset it.
+    fn finish(&self, id: u32) {
+        self.flow().streams.remove(&id);
+        self.window_opened.notify_all();
+    }
+
+    /// Close the stream, once it's answered, or reset before it was complete.
+    fn answered(&self, id: u32) {
+        self.finish(id);
+        self.flow().open -= 1;
+    }
+
+    fn close(&self) {
+        self.flow().closed = true;
+        self.window_opened.notify_all();
+    }
+
+    /// Apply a new initial window to the streams, which may leave them with a negative window.
+    fn set_initial_window(&self, window: i64) {
+        let mut flow = self.flow();
+        let delta = window - flow.initial_window;
+        flow.initial_window = window;
+        for window in flow.streams.values_mut() {
+            *window += delta;
+        }
+        self.window_opened.notify_all();
+    }
+
+    fn write_frame(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(9 + payload.len());
+        encode_frame(&mut frame, kind, flags, stream, payload);
+        self.write(&frame)
+    }
+
+    /// Write frames at once, so no other frame can come between them.
+    fn write(&self, frames: &[u8]) -> io::Result<()> {
+        let mut tls = self.tls.lock().unwrap();
+        tls.writer().write_all(frames)?;
+        while tls.wants_write() {
+            tls.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+
+    /// Send a response the app made, as if to an HTTP/1.1 client, as HEADERS and DATA frames.
+    fn send_output(&self, id: u32, output: Output) -> io::Result<()> {
+        let Output {
+            bytes,
+            file,
+            stream,
+            ..
+        } = output;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid response");
+        let end = bytes
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(invalid)?;
+        let head = std::str::from_utf8(&bytes[..end]).map_err(|_| invalid())?;
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .ok_or_else(invalid)?;
+        let mut headers = vec![(":status".to_string(), status.to_string())];
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+            let name = name.trim().to_ascii_lowercase();
+            if !CONNECTION_HEADERS.contains(&name.as_str()) {
+                headers.push((name, value.trim().to_string()));
+            }
+        }
+        let inline = &bytes[end + 4..];
+        let mut body: Box<dyn Read + Send> = match (file, stream) {
+            (Some((file, length)), _) => Box::new(Cursor::new(inline).chain(file.take(length))),
+            (None, Some(stream)) => Box::new(Cursor::new(inline).chain(stream)),
+            (None, None) if inline.is_empty() => {
+                return self.send_headers(id, &hpack::encode(&headers), true)
+            }
+            (None, None) => Box::new(Cursor::new(inline)),
+        };
+        self.send_headers(id, &hpack::encode(&headers), false)?;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let read = match body.read(&mut chunk) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // Let the client know the body is incomplete.
+                    let _ = self.write_frame(RST_STREAM, 0, id, &INTERNAL_ERROR.to_be_bytes());
+                    return Err(e);
+                }
+            };
+            self.send_data(id, &chunk[..read], read == 0)?;
+            if read == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// A HEADERS frame, followed by CONTINUATION frames if the block doesn't fit.
+    fn send_headers(&self, id: u32, block: &[u8], end_stream: bool) -> io::Result<()> {
+        let max_frame_size = self.flow().max_frame_size;
+        let mut frames = vec![];
+        let mut chunks = block.chunks(max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        // An empty block still needs a HEADERS frame.
+        let mut chunk = chunks.next().unwrap_or(&[]);
+        loop {
+            let last = chunks.peek().is_none();
+            if last {
+                flags |= END_HEADERS;
+            }
+            encode_frame(&mut frames, kind, flags, id, chunk);
+            let Some(next) = chunks.next() else {
+                break;
+            };
+            (kind, flags, chunk) = (CONTINUATION, 0, next);
+        }
+        self.write(&frames)
+    }
+
+    /// DATA frames with `data`, as the windows allow. The last ends the stream if `end_stream`
+    /// is set, which may be an empty frame.
+    fn send_data(&self, id: u32, mut data: &[u8], end_stream: bool) -> io::Result<()> {
+        loop {
+            let length = self.reserve(id, data.len())?;
+            let last = length == data.len();
+            let flags = if end_stream && last { END_STREAM } else { 0 };
+            self.write_frame(DATA, flags, id, &data[..length])?;
+            data = &data[length..];
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wait until up to `wanted` bytes may be sent on the stream, and take them from the windows.
+    fn reserve(&self, id: u32, wanted: usize) -> io::Result<usize> {
+        let mut flow = self.flow();
+        loop {
+            if flow.closed {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            let Some(&stream_window) = flow.streams.get(&id) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "Stream reset",
+                ));
+            };
+            let available = flow.window.min(stream_window).max(0) as usize;
+            let length = wanted.min(available).min(flow.max_frame_size);
+            if length > 0 || wanted == 0 {
+                flow.window -= length as i64;
+                *flow.streams.get_mut(&id).unwrap() -= length as i64;
+                return Ok(length);
+            }
+            let (guard, result) = self.window_opened.wait_timeout(flow, self.timeout).unwrap();
+            if result.timed_out() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Window stayed shut",
+                ));
+            }
+            flow = guard;
+        }
+    }
+}
+
+/// Reads the plaintext of the connection, without keeping it from being written to while it
+/// waits for the socket.
+struct PeerReader<'a>(&'a Peer);
+
+impl Read for PeerReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let peer = self.0;
+        let mut received = [0; 16 * 1024];
+        loop {
+            match peer.tls.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            let read = match (&peer.socket).read(&mut received) {
+                Ok(0) => return Ok(0),
+                Ok(read) => read,
+                // Only an idle connection times out, not one with responses still being sent.
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) && !peer.flow().streams.is_empty() =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            let mut tls = peer.tls.lock().unwrap();
+            let mut received = &received[..read];
+            while !received.is_empty() {
+                tls.read_tls(&mut received)?;
+                tls.process_new_packets().map_err(io::Error::other)?;
+            }
+            while tls.wants_write() {
+                tls.write_tls(&mut &peer.socket)?;
+            }
+        }
+    }
+}
+
+/// Read a frame, or the connection error of one that's too large to be read.
+fn read_frame(reader: &mut impl Read) -> io::Result<Result<Frame, ConnectionError>> {
+    let mut header = [0; 9];
+    reader.read_exact(&mut header)?;
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if length > DEFAULT_FRAME_SIZE {
+        return Ok(Err(error(FRAME_SIZE_ERROR, "Frame too large")));
+    }
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    Ok(Ok(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload,
+    }))
+}
+
+fn encode_frame(frames: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    frames.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frames.push(kind);
+    frames.push(flags);
+    frames.extend_from_slice(&stream.to_be_bytes());
+    frames.extend_from_slice(payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::tests::{certificate, AcceptAny};
+    use crate::tls::TlsCertificates;
+    use crate::webserver::{AppConfig, RequestType, Resource, ResourceType, Response};
+    use rustls::{
+        crypto::ring::default_provider, pki_types::ServerName, ClientConfig, ClientConnection,
+        StreamOwned,
+    };
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    type Client = StreamOwned<ClientConnection, TcpStream>;
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn request_headers(method: &str, path: &str) -> Vec<u8> {
+        let headers = [
+            (":method", method),
+            (":scheme", "https"),
+            (":path", path),
+            (":authority", "daanlubbers.nl"),
+        ];
+        hpack::encode(
+            &headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Run `app` until the returned flag is set and its listener is connected to.
+    fn run(app: App) -> (Arc<AtomicBool>, thread::JoinHandle<()>) {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || app.run(Some(stop_flag_clone)).unwrap());
+        thread::sleep(Duration::from_millis(100)); // Give the app time to start up
+        (stop_flag, thread)
+    }
+
+    fn config(addr: &str, tls_addr: &str) -> AppConfig {
+        let certificates = Arc::new(TlsCertificates::new());
+        certificates.set_default(&certificate("default")).unwrap();
+        AppConfig::new(addr.parse().unwrap(), 2, 5)
+            .with_tls(tls_addr.parse().unwrap(), certificates)
+            .with_http2(true)
+    }
+
+    /// A connection that picked HTTP/2, with the preface and `frames` sent.
+    fn connect(tls_addr: &str, frames: &[u8]) -> Client {
+        let mut client = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAny))
+            .with_no_client_auth();
+        client.alpn_protocols = vec![b"h2".to_vec()];
+        let name = ServerName::try_from("daanlubbers.nl").unwrap();
+        let connection = ClientConnection::new(Arc::new(client), name).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(tls_addr).unwrap());
+        let mut sent = PREFACE.to_vec();
+        sent.extend(frame(SETTINGS, 0, 0, &[]));
+        sent.extend_from_slice(frames);
+        stream.write_all(&sent).unwrap();
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+        stream
+    }
+
+    fn receive(stream: &mut Client) -> Frame {
+        read_frame(stream).unwrap().unwrap()
+    }
+
+    #[test]
+    fn serve_http2() {
+        let mut app = App::new(config("127.0.0.1:7722", "127.0.0.1:7723"));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/slow".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| {
+                thread::sleep(Duration::from_millis(300));
+                Ok(Response::text(StatusCode::OK, "slow"))
+            }),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/fast".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "fast"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/echo".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| Ok(Response::bytes(StatusCode::OK, request.body().to_vec()))),
+        ));
+        let (stop_flag, thread) = run(app);
+
+        let mut sent = vec![];
+        sent.extend(frame(
+            HEADERS,
+            END_STREAM | END_HEADERS,
+            1,
+            &request_headers("GET", "/slow"),
+        ));
+        sent.extend(frame(
+            HEADERS,
+            END_STREAM | END_HEADERS,
+            3,
+            &request_headers("GET", "/fast"),
+        ));
+        sent.extend(frame(
+            HEADERS,
+            END_HEADERS,
+            5,
+            &request_headers("POST", "/echo"),
+        ));
+        sent.extend(frame(DATA, END_STREAM, 5, b"ping"));
+        let mut stream = connect("127.0.0.1:7723", &sent);
+
+        let mut decoder = Decoder::new(usize::MAX);
+        let mut responses: HashMap<u32, (String, Vec<u8>)> = HashMap::new();
+        let mut ended = vec![];
+        while ended.len() < 3 {
+            let frame = receive(&mut stream);
+            let response = responses.entry(frame.stream).or_default();
+            match frame.kind {
+                HEADERS => {
+                    let headers = decoder.decode(&frame.payload).unwrap();
+                    assert_eq!(headers[0].0, ":status");
+                    response.0.clone_from(&headers[0].1);
+                }
+                DATA => response.1.extend_from_slice(&frame.payload),
+                _ => continue,
+            }
+            if frame.flags & END_STREAM != 0 {
+                ended.push(frame.stream);
+            }
+        }
+        // The fast response doesn't wait for the slow one.
+        assert_eq!(ended.last(), Some(&1));
+        assert_eq!(responses[&1], ("200".to_string(), b"slow".to_vec()));
+        assert_eq!(responses[&3], ("200".to_string(), b"fast".to_vec()));
+        assert_eq!(responses[&5], ("200".to_string(), b"ping".to_vec()));
+        drop(stream);
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect("127.0.0.1:7722");
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn serve_http2_resets() {
+        let mut app = App::new(config("127.0.0.1:7725", "127.0.0.1:7726"));
+        // Handlers running, the most that ran at once, and how many ran at all.
+        let counts = Arc::new([
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+        ]);
+        let counts_clone = Arc::clone(&counts);
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/slow".to_string(),
+            ResourceType::TEXT,
+            Box::new(move |_| {
+                let running = counts_clone[0].fetch_add(1, Ordering::SeqCst) + 1;
+                counts_clone[1].fetch_max(running, Ordering::SeqCst);
+                counts_clone[2].fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(200));
+                counts_clone[0].fetch_sub(1, Ordering::SeqCst);
+                Ok(Response::text(StatusCode::OK, "slow"))
+            }),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/fast".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "fast"))),
+        ));
+        let (stop_flag, thread) = run(app);
+
+        // Requests reset right after they're sent, with CANCEL.
+        let mut sent = vec![];
+        for id in (1..100).step_by(2) {
+            sent.extend(frame(
+                HEADERS,
+                END_STREAM | END_HEADERS,
+                id,
+                &request_headers("GET", "/slow"),
+            ));
+            sent.extend(frame(RST_STREAM, 0, id, &8u32.to_be_bytes()));
+        }
+        sent.extend(frame(
+            HEADERS,
+            END_STREAM | END_HEADERS,
+            101,
+            &request_headers("GET", "/fast"),
+        ));
+        let mut stream = connect("127.0.0.1:7726", &sent);
+
+        let mut decoder = Decoder::new(usize::MAX);
+        let mut status = String::new();
+        let mut body = vec![];
+        loop {
+            let frame = receive(&mut stream);
+            match (frame.kind, frame.stream) {
+                (HEADERS, 101) => status.clone_from(&decoder.decode(&frame.payload).unwrap()[0].1),
+                (HEADERS, _) => drop(decoder.decode(&frame.payload).unwrap()),
+                (DATA, 101) => body.extend_from_slice(&frame.payload),
+                _ => continue,
+            }
+            if frame.stream == 101 && frame.flags & END_STREAM != 0 {
+                break;
+            }
+        }
+        assert_eq!((status.as_str(), &body[..]), ("200", &b"fast"[..]));
+        // No more handlers ran at once than the app has threads, and those of requests reset
+        // while they waited didn't run at all.
+        assert!(counts[1].load(Ordering::SeqCst) <= 2);
+        assert!(counts[2].load(Ordering::SeqCst) < 10);
+        drop(stream);
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect("127.0.0.1:7725");
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn header_list_limit() {
+        let config = config("127.0.0.1:7728", "127.0.0.1:7729").with_max_header_bytes(1024);
+        let mut app = App::new(config);
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "home"))),
+        ));
+        let (stop_flag, thread) = run(app);
+
+        // A small block, adding a header with a 100 byte value to the table and then referring
+        // to it 40 times, which decodes to more than the advertised header list size.
+        let mut block = request_headers("GET", "/");
+        block.extend_from_slice(&[0x40, 0x01, b'x', 100]);
+        block.extend_from_slice(&[b'y'; 100]);
+        block.extend_from_slice(&[0xbe; 40]);
+        let mut stream = connect(
+            "127.0.0.1:7729",
+            &frame(HEADERS, END_STREAM | END_HEADERS, 1, &block),
+        );
+        let goaway = loop {
+            let frame = receive(&mut stream);
+            assert_ne!(frame.kind, HEADERS);
+            if frame.kind == GOAWAY {
+                break frame;
+            }
+        };
+        assert_eq!(goaway.payload[4..8], COMPRESSION_ERROR.to_be_bytes());
+
+        // A table size update has to come before the headers.
+        let mut block = request_headers("GET", "/");
+        block.push(0x20);
+        let mut stream = connect(
+            "127.0.0.1:7729",
+            &frame(HEADERS, END_STREAM | END_HEADERS, 1, &block),
+        );
+        let goaway = loop {
+            let frame = receive(&mut stream);
+            if frame.kind == GOAWAY {
+                break frame;
+            }
+        };
+        assert_eq!(goaway.payload[4..8], COMPRESSION_ERROR.to_be_bytes());
+        drop(stream);
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect("127.0.0.1:7728");
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// Connecting, and every read or write, fails after this long.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A response received by the client, or read from anything else, such as the output of
+/// `TestClient`.
+pub struct ParsedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl ParsedResponse {
+    /// Read a response, such as from a connection or a string in tests. The body ends as the
+    /// headers say, or with the reader when they don't.
+    pub fn from_reader(reader: &mut impl BufRead) -> Result<Self, String> {
+        let status_line = read_line(reader)?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| format!("Malformed status line: {status_line}"))?;
+
+        let mut headers = vec![];
+        loop {
+            let line = read_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            match line.split_once(':') {
+                Some((name, value)) => {
+                    headers.push((name.trim().to_string(), value.trim().to_string()))
+                }
+                None => return Err(format!("Malformed header: {line}")),
+            }
+        }
+        let mut response = Self {
+            status,
+            headers,
+            body: vec![],
+        };
+
+        // These never have a body, whatever their headers say.
+        if matches!(response.status, 204 | 304) {
+            return Ok(response);
+        }
+        if response
+            .header("Transfer-Encoding")
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+        {
+            loop {
+                let size_line = read_line(reader)?;
+                let size = size_line.split(';').next().unwrap_or_default().trim();
+                let size = usize::from_str_radix(size, 16)
+                    .map_err(|_| format!("Invalid chunk size: {size_line}"))?;
+                if size == 0 {
+                    break;
+                }
+                let start = response.body.len();
+                response.body.resize(start + size, 0);
+                reader
+                    .read_exact(&mut response.body[start..])
+                    .map_err(|e| format!("Failed to read body: {e}"))?;
+                read_line(reader)?;
+            }
+        } else if let Some(length) = response.header("Content-Length") {
+            let length = length
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid Content-Length: {length}"))?;
+            response.body = vec![0; length];
+            reader
+                .read_exact(&mut response.body)
+                .map_err(|e| format!("Failed to read body: {e}"))?;
+        } else {
+            reader
+                .read_to_end(&mut response.body)
+                .map_err(|e| format!("Failed to read body: {e}"))?;
+        }
+        Ok(response)
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Whether the status is 2xx.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The value of the first header with this name, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All headers in the order they were received.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+pub fn get(url: &str) -> Result<ParsedResponse, String> {
+    send("GET", url, &[], &[])
+}
+
+pub fn post(url: &str, content_type: &str, body: &[u8]) -> Result<ParsedResponse, String> {
+    send("POST", url, &[("Content-Type", content_type)], body)
+}
+
+/// Send a request and wait for the whole response. Redirects are not followed.
+///
+/// `https` URLs need the `tls` feature.
+pub fn send(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<ParsedResponse, String> {
+    let url = Url::parse(url)?;
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {}: {e}", url.host))?
+        .next()
+        .ok_or_else(|| format!("Failed to resolve {}", url.host))?;
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .map_err(|e| format!("Failed to connect to {addr}: {e}"))?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+
+    let mut head = format!(
+        "{method} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        url.target,
+        url.host_header(),
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    let request = [head.as_bytes(), body].concat();
+
+    if url.tls {
+        tls::exchange(stream, &url.host, &request)
+    } else {
+        exchange(stream, &request)
+    }
+}
+
+/// Write the request and read the response from any connection.
+fn exchange(mut stream: impl Read + Write, request: &[u8]) -> Result<ParsedResponse, String> {
+    stream
+        .write_all(request)
+        .and_then(|_| stream.flush())
+        .map_err(|e| format!("Failed to send request: {e}"))?;
+    ParsedResponse::from_reader(&mut BufReader::new(stream))
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Err("Connection closed".to_string()),
+        Ok(_) => Ok(line.trim_end().to_string()),
+        Err(e) => Err(format!("Failed to read response: {e}")),
+    }
+}
+
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// The path and query.
+    target: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self, String> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!("Unsupported URL: {url}"));
+        };
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(index) => (&rest[..index], rest[index..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        let target = match target.starts_with('?') {
+            true => format!("/{target}"),
+            false => target,
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| format!("Invalid port in URL: {url}"))?,
+            ),
+            _ => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in URL: {url}"));
+        }
+        Ok(Self {
+            tls,
+            host: host.trim_matches(['[', ']']).to_string(),
+            port,
+            target,
+        })
+    }
+
+    fn host_header(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match (self.tls, self.port) {
+            (true, 443) | (false, 80) => host,
+            (_, port) => format!("{host}:{port}"),
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+mod tls {
+    use super::ParsedResponse;
+    use rustls::{
+        pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    };
+    use std::{
+        net::TcpStream,
+        sync::{Arc, OnceLock},
+    };
+
+    fn config() -> Arc<ClientConfig> {
+        static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+        CONFIG
+            .get_or_init(|| {
+                let roots =
+                    RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                Arc::new(
+                    ClientConfig::builder_with_provider(Arc::new(
+                        rustls::crypto::ring::default_provider(),
+                    ))
+                    .with_safe_default_protocol_versions()
+                    .expect("ring supports the default protocol versions")
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+                )
+            })
+            .clone()
+    }
+
+    pub fn exchange(
+        stream: TcpStream,
+        host: &str,
+        request: &[u8],
+    ) -> Result<ParsedResponse, String> {
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|e| format!("Invalid server name {host}: {e}"))?;
+        let connection =
+            ClientConnection::new(config(), name).map_err(|e| format!("TLS error: {e}"))?;
+        super::exchange(StreamOwned::new(connection, stream), request)
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+mod tls {
+    use super::ParsedResponse;
+    use std::net::TcpStream;
+
+    pub fn exchange(_: TcpStream, host: &str, _: &[u8]) -> Result<ParsedResponse, String> {
+        Err(format!(
+            "Can't connect to https://{host} without the tls feature"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn parse_url() {
+        let url = Url::parse("https://api.indexnow.org/indexnow?a=b").unwrap();
+        assert!(url.tls);
+        assert_eq!(url.host, "api.indexnow.org");
+        assert_eq!(url.port, 443);
+        assert_eq!(url.target, "/indexnow?a=b");
+        assert_eq!(url.host_header(), "api.indexnow.org");
+
+        let url = Url::parse("http://127.0.0.1:8080").unwrap();
+        assert!(!url.tls);
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.target, "/");
+        assert_eq!(url.host_header(), "127.0.0.1:8080");
+
+        let url = Url::parse("http://[::1]:8080?x").unwrap();
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.target, "/?x");
+        assert_eq!(url.host_header(), "[::1]:8080");
+
+        assert!(Url::parse("ftp://example.com").is_err());
+        assert!(Url::parse("http://").is_err());
+        assert!(Url::parse("http://example.com:http/").is_err());
+    }
+
+    #[test]
+    fn parse_response() {
+        let raw = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Test: yes\r\n\r\nhello";
+        let response = ParsedResponse::from_reader(&mut raw.as_bytes()).unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.is_success());
+        assert_eq!(response.header("x-test"), Some("yes"));
+        assert_eq!(response.headers().len(), 2);
+        assert_eq!(response.text(), "hello");
+
+        let raw = "HTTP/1.1 202 Accepted\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n";
+        let response = ParsedResponse::from_reader(&mut raw.as_bytes()).unwrap();
+        assert_eq!(response.status(), 202);
+        assert_eq!(response.text(), "hello world");
+
+        let raw = "HTTP/1.0 404 Not Found\r\n\r\nmissing";
+        let response = ParsedResponse::from_reader(&mut raw.as_bytes()).unwrap();
+        assert!(!response.is_success());
+        assert_eq!(response.text(), "missing");
+
+        assert!(ParsedResponse::from_reader(&mut "garbage\r\n\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn send_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook?id=1", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                reader.read_line(&mut head).unwrap();
+            }
+            let mut body = [0; 5];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok")
+                .unwrap();
+            (head, body)
+        });
+
+        let response = send("PUT", &url, &[("X-Signature", "abc")], b"hello").unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.text(), "ok");
+        let (head, body) = server.join().unwrap();
+        assert!(head.starts_with("PUT /hook?id=1 HTTP/1.1\r\n"));
+        assert!(head.contains("\r\nContent-Length: 5\r\n"));
+        assert!(head.contains("\r\nX-Signature: abc\r\n"));
+        assert_eq!(&body, b"hello");
+    }
+}
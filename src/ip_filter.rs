@@ -0,0 +1,223 @@
+use crate::security::percent_decode_path;
+use std::{
+    fmt::{self, Display},
+    net::IpAddr,
+    str::FromStr,
+};
+
+/// A range of addresses such as `10.0.0.0/8` or `2001:db8::/32`. A single address is a range
+/// with the full prefix length.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` bits of `a` and `b` are the same.
+fn prefix_matches(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let bytes = prefix as usize / 8;
+    let bits = prefix % 8;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid address range: {text}");
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text, None),
+        };
+        let network = address
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// What happens to connections from addresses that aren't allowed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilterAction {
+    /// Answer with a 403.
+    Forbidden,
+    /// Close the connection without a response.
+    Drop,
+}
+
+/// Which client addresses may connect, see `AppConfig::with_ip_filter`. Addresses on the deny
+/// list are turned away before anything is read from them. Paths with an allow list, such as
+/// `/admin`, are only served to addresses on it, which is checked once the request line and
+/// headers are read.
+///
+/// The address is that of the connection, or the one in the PROXY protocol header. A reverse
+/// proxy sending `X-Forwarded-For` would have to filter by itself.
+#[derive(Clone, Debug)]
+pub struct IpFilter {
+    deny: Vec<Cidr>,
+    /// Path prefixes and the addresses allowed under them.
+    allow: Vec<(String, Vec<Cidr>)>,
+    action: FilterAction,
+}
+
+impl Default for IpFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IpFilter {
+    /// Allow everyone, answering those that are denied later on with a 403.
+    pub fn new() -> Self {
+        Self {
+            deny: vec![],
+            allow: vec![],
+            action: FilterAction::Forbidden,
+        }
+    }
+
+    /// Turn away connections from `range` altogether.
+    pub fn with_deny(mut self, range: Cidr) -> Self {
+        self.deny.push(range);
+        self
+    }
+
+    /// Only serve `prefix` and the paths below it to `ranges`.
+    pub fn with_allow(mut self, prefix: &str, ranges: &[Cidr]) -> Self {
+        let prefix = prefix.trim_end_matches('/').to_ascii_lowercase();
+        match self
+            .allow
+            .iter_mut()
+            .find(|(existing, _)| *existing == prefix)
+        {
+            Some((_, existing)) => existing.extend_from_slice(ranges),
+            None => self.allow.push((prefix, ranges.to_vec())),
+        }
+        self
+    }
+
+    pub fn with_action(mut self, action: FilterAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    pub fn action(&self) -> FilterAction {
+        self.action
+    }
+
+    /// Whether `ip` may connect at all.
+    pub fn allows_connection(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+    }
+
+    /// Whether `ip` may request `path`, once it's allowed to connect. Prefixes match regardless
+    /// of case and percent-encoding, so `/%41dmin` is as restricted as `/admin`.
+    pub fn allows_path(&self, ip: IpAddr, path: &str) -> bool {
+        let path = percent_decode_path(path)
+            .unwrap_or_else(|_| path.to_string())
+            .to_ascii_lowercase();
+        self.allow
+            .iter()
+            .filter(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .all(|(_, ranges)| ranges.iter().any(|range| range.contains(ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(text: &str) -> Cidr {
+        text.parse().unwrap()
+    }
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("192.168.1.128/25").contains(ip("192.168.1.200")));
+        assert!(!cidr("192.168.1.128/25").contains(ip("192.168.1.127")));
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(cidr("127.0.0.1").contains(ip("127.0.0.1")));
+        assert!(!cidr("127.0.0.1").contains(ip("127.0.0.2")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(!cidr("::/0").contains(ip("10.0.0.1")));
+        // IPv4 clients of a dual-stack listener.
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert_eq!(cidr(" 10.1.2.3/16 ").to_string(), "10.1.2.3/16");
+        for invalid in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "localhost",
+        ] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let filter = IpFilter::new()
+            .with_deny(cidr("203.0.113.0/24"))
+            .with_allow("/admin/", &[cidr("10.0.0.0/8")])
+            .with_allow("/admin", &[cidr("127.0.0.1")]);
+        assert!(!filter.allows_connection(ip("203.0.113.7")));
+        assert!(filter.allows_connection(ip("198.51.100.1")));
+        assert!(filter.allows_path(ip("198.51.100.1"), "/"));
+        assert!(filter.allows_path(ip("198.51.100.1"), "/administration"));
+        assert!(!filter.allows_path(ip("198.51.100.1"), "/admin"));
+        assert!(!filter.allows_path(ip("198.51.100.1"), "/admin/reload"));
+        assert!(filter.allows_path(ip("10.1.1.1"), "/admin/reload"));
+        assert!(filter.allows_path(ip("127.0.0.1"), "/admin"));
+        assert!(!filter.allows_path(ip("198.51.100.1"), "/ADMIN/reload"));
+        assert!(!filter.allows_path(ip("198.51.100.1"), "/%61dmin"));
+        assert_eq!(filter.action(), FilterAction::Forbidden);
+    }
+}
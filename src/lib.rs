@@ -1,3 +1,71 @@
+pub mod acme;
+pub mod admin;
+pub mod assets;
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod auth;
+#[cfg(feature = "markdown")]
+pub mod blog;
+pub mod cache;
+pub mod calendar;
+pub mod compliance;
+pub mod compression;
+pub mod concurrency;
+pub mod config;
+pub mod cookie;
+pub mod cors;
+pub mod digest;
+pub mod error_pages;
+pub mod flags;
+pub mod health;
+pub mod http_client;
+pub mod ip_filter;
+pub mod meta;
+pub mod method_override;
+pub mod metrics;
+pub mod multipart;
+pub mod negotiation;
+#[cfg(feature = "og")]
+pub mod og;
+pub mod pagination;
+pub mod proxy;
+#[cfg(feature = "qr")]
+pub mod qr;
+pub mod ratelimit;
+pub mod redirects;
+pub mod reload;
+pub mod router;
+pub mod scheduler;
+pub mod search_notify;
+pub mod security;
+pub mod security_headers;
+pub mod session;
+pub mod signing;
+pub mod sitemap;
+pub mod sse;
+pub mod state;
+pub mod static_dir;
+pub mod templates;
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trace;
+pub mod upload;
+pub mod uptime;
+pub mod variant;
+pub mod vcard;
 pub mod webserver;
+pub mod websocket;
 
-mod concurrency;
+mod buffers;
+#[cfg(feature = "evented")]
+mod evented;
+#[cfg(feature = "tls")]
+mod hpack;
+#[cfg(feature = "tls")]
+mod http2;
+#[cfg(target_os = "linux")]
+mod sendfile;
+#[cfg(unix)]
+mod signals;
+mod webdav;
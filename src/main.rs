@@ -1,68 +1,368 @@
-use std::{env, fs};
+use std::{env, fs, path::Path, process, sync::Arc, thread, time::Duration};
+use wwwdaanlubbersnl::admin::Admin;
+use wwwdaanlubbersnl::auth::Auth;
+use wwwdaanlubbersnl::cache::CachePolicy;
+use wwwdaanlubbersnl::calendar::{self, Disposition};
+use wwwdaanlubbersnl::compliance;
+#[cfg(feature = "gzip")]
+use wwwdaanlubbersnl::compression::Compression;
+use wwwdaanlubbersnl::config::{ConfigFile, Value};
+use wwwdaanlubbersnl::error_pages::ErrorPages;
+use wwwdaanlubbersnl::flags::FeatureFlags;
+#[cfg(feature = "tls")]
+use wwwdaanlubbersnl::health::HealthChecker;
+use wwwdaanlubbersnl::metrics::Metrics;
+use wwwdaanlubbersnl::ratelimit::RateLimit;
+use wwwdaanlubbersnl::redirects::Redirects;
+use wwwdaanlubbersnl::reload::ConfigReloader;
+use wwwdaanlubbersnl::scheduler::Scheduler;
+use wwwdaanlubbersnl::security_headers::SecurityHeaders;
+use wwwdaanlubbersnl::sitemap::RobotsTxt;
+use wwwdaanlubbersnl::uptime::UptimeTracker;
+use wwwdaanlubbersnl::vcard::VCard;
 use wwwdaanlubbersnl::webserver::*;
 
 fn main() {
-    let ip: String;
-    let port = match env::var("PORT") {
-        Ok(port) => {
-            ip = "0.0.0.0".to_string();
-            port
-        }
-        Err(_) => {
-            println!("No PORT environment variable found, using default port 8080");
-            ip = "127.0.0.1".to_string();
-            "8080".to_string()
+    let settings = load_settings().unwrap_or_else(|e| {
+        println!("Invalid configuration: {e}");
+        process::exit(1);
+    });
+    let config = AppConfig::from_config(&settings)
+        .unwrap_or_else(|e| {
+            println!("Invalid configuration: {e}");
+            process::exit(1);
+        })
+        .with_shutdown_signals(true)
+        .with_strict_files(true);
+    let addr = config.addrs()[0];
+    let threads = config.num_threads();
+    let mut app = create_app(config);
+    let rate_limit = register_rate_limit(&mut app, &settings);
+    register_resources(&mut app, static_dir(&settings));
+    let csp = settings.string("content_security_policy").ok().flatten();
+    app.register_middleware(Box::new(
+        SecurityHeaders::new().with_content_security_policy(csp),
+    ));
+    let pages = app.enable_error_pages(error_pages(&settings));
+    let metrics = register_metrics(&mut app);
+    register_redirects(&mut app, metrics.as_deref());
+    register_feature_flags(&mut app);
+    register_admin(&mut app, metrics);
+    register_uptime_tracking(&mut app, threads);
+    // Nothing to wait for besides the workers, which `/readyz` checks by itself.
+    app.enable_health_endpoints(|| Ok(()));
+    #[cfg(feature = "tls")]
+    register_health_checks(&mut app);
+    register_config_reload(&mut app, settings, pages, rate_limit);
+    // Last, so it compresses what the other middleware added.
+    #[cfg(feature = "gzip")]
+    app.register_middleware(Box::new(Compression::new()));
+
+    // Print the routing table, to check what the setup above ends up serving.
+    if env::args().any(|arg| arg == "--routes") {
+        for route in app.routes() {
+            println!("{route}");
         }
+        return;
+    }
+
+    // Check the protocol handling of the server as configured, then exit with whether it passed.
+    if env::args().any(|arg| arg == "--self-test") {
+        thread::spawn(move || {
+            if let Err(e) = app.run(None) {
+                println!("{e}");
+                process::exit(1);
+            }
+        });
+        thread::sleep(Duration::from_millis(500));
+        let results = compliance::run(addr, "/status");
+        println!("{}", compliance::report(&results));
+        process::exit(if results.iter().all(|result| result.passed()) {
+            0
+        } else {
+            1
+        });
+    }
+    if let Err(e) = app.run(None) {
+        println!("{e}");
+        process::exit(1);
+    }
+}
+
+/// The settings in `server.toml`, or the file `WWW_CONFIG` names, overridden by `WWW_`
+/// environment variables such as `WWW_PORT` and `WWW_STATIC_DIR`. Without a file, everything
+/// comes from the environment.
+fn load_settings() -> Result<ConfigFile, String> {
+    let path = env::var("WWW_CONFIG").unwrap_or_else(|_| "server.toml".to_string());
+    let settings = if Path::new(&path).exists() {
+        ConfigFile::load(&path)?
+    } else {
+        ConfigFile::default()
     };
+    let mut settings = settings.with_env("WWW");
+    // Heroku sets the port to listen on, on every interface.
+    if let Ok(port) = env::var("PORT") {
+        let port = port.parse().map_err(|_| format!("Invalid PORT: {port}"))?;
+        settings.set("host", Value::String("0.0.0.0".to_string()));
+        settings.set("port", Value::Integer(port));
+    }
+    Ok(settings)
+}
 
-    let config = AppConfig::new(format!("{}:{}", ip, port).parse().unwrap(), 4, 5);
-    let mut app = create_app(config);
-    register_resources(&mut app);
-    app.run(None);
+fn static_dir(settings: &ConfigFile) -> &str {
+    settings
+        .string("static_dir")
+        .ok()
+        .flatten()
+        .unwrap_or("static")
+}
+
+/// The pages for 404 and 500 are in the static folder, unless `[error_pages]` points elsewhere.
+/// Other errors get the default page.
+fn error_pages(settings: &ConfigFile) -> ErrorPages {
+    let page = |status: u16| match settings.string(&format!("error_pages.{status}")) {
+        Ok(Some(path)) => path.to_string(),
+        _ => format!("{}/html/{status}.html", static_dir(settings)),
+    };
+    ErrorPages::new()
+        .with_file(StatusCode::NotFound, page(404))
+        .with_file(StatusCode::InternalServerError, page(500))
+}
+
+/// The requests per second and burst set in `[rate_limit]`, if any.
+fn rate_limit_settings(settings: &ConfigFile) -> Result<Option<(f64, u32)>, String> {
+    match (
+        settings.unsigned("rate_limit.rate")?,
+        settings.unsigned("rate_limit.burst")?,
+    ) {
+        (Some(0), Some(_)) => Err("rate_limit.rate must be at least 1".to_string()),
+        (Some(rate), Some(burst)) => {
+            let burst = u32::try_from(burst).map_err(|_| "rate_limit.burst is too large")?;
+            Ok(Some((rate as f64, burst)))
+        }
+        (None, None) => Ok(None),
+        _ => Err("rate_limit needs both rate and burst".to_string()),
+    }
+}
+
+fn register_rate_limit(app: &mut App, settings: &ConfigFile) -> Option<Arc<RateLimit>> {
+    let (rate, burst) = rate_limit_settings(settings).unwrap_or_else(|e| {
+        println!("Invalid configuration: {e}");
+        process::exit(1);
+    })?;
+    let limit = Arc::new(RateLimit::new(rate, burst));
+    app.register_middleware(Box::new(Arc::clone(&limit)));
+    Some(limit)
+}
+
+/// Apply changes to the settings on `kill -HUP`, or a POST to /admin/reload. Server settings,
+/// such as the port, still take a restart.
+fn register_config_reload(
+    app: &mut App,
+    settings: ConfigFile,
+    pages: Arc<ErrorPages>,
+    rate_limit: Option<Arc<RateLimit>>,
+) {
+    let reloader = ConfigReloader::new(settings, load_settings)
+        .on_reload(move |settings| {
+            pages.replace(error_pages(settings));
+            Ok(())
+        })
+        .on_reload(move |settings| {
+            match (&rate_limit, rate_limit_settings(settings)?) {
+                (Some(limit), Some((rate, burst))) => limit.set_limit(rate, burst),
+                (None, None) => {}
+                _ => return Err("Turning rate limiting on or off takes a restart".to_string()),
+            }
+            Ok(())
+        });
+    let reloader = Arc::new(reloader);
+    #[cfg(unix)]
+    if let Err(e) = reloader.watch_sighup() {
+        println!("Failed to handle SIGHUP: {e}");
+    }
+    // Behind the `Auth` of `register_feature_flags`.
+    if env::var("ADMIN_PASSWORD").is_ok() {
+        app.register_resource(reloader.admin_resource("/admin/reload"));
+    }
 }
 
-fn register_resources(app: &mut App) {
-    register_all_resources_in_folder_for_get(app, "/", "static/html");
-    register_all_resources_in_folder_for_get(app, "/", "static/images");
+fn register_resources(app: &mut App, static_dir: &str) {
+    register_all_resources_in_folder_for_get(app, "/", &format!("{static_dir}/html"));
+    register_all_resources_in_folder_for_get(app, "/", &format!("{static_dir}/images"));
 
     app.register_resource(Resource::new(
         RequestType::GET,
         "/maria".to_string(),
         ResourceType::REDIRECT,
-        Box::new(|| {
-            Ok(Response::new(
+        Box::new(|_| {
+            Ok(Response::redirect(
                 StatusCode::PermanentRedirect,
-                "https://www.mariagomez.art".to_string(),
+                "https://www.mariagomez.art",
             ))
         }),
     ));
+
+    app.register_resource(
+        VCard::new("Daan Lubbers")
+            .with_name("Daan", "Lubbers")
+            .with_email("contact@daanlubbers.nl")
+            .with_url("https://www.daanlubbers.nl")
+            .with_url("https://www.linkedin.com/in/daanlubbers")
+            .with_url("https://www.github.com/Daan4")
+            .resource("/contact.vcf"),
+    );
+
+    app.enable_sitemap("https://www.daanlubbers.nl");
+    app.enable_robots_txt(RobotsTxt::new());
+}
+
+/// Legacy URLs of the old website are redirected with the nginx map in `redirects.map`, which
+/// is reloaded when it changes. The legacy URLs still getting a 404 are logged daily.
+fn register_redirects(app: &mut App, metrics: Option<&Metrics>) {
+    let mut redirects = Redirects::from_file("redirects.map").unwrap();
+    if let Some(metrics) = metrics {
+        redirects = redirects.with_metrics(metrics);
+    }
+    let redirects = Arc::new(redirects);
+    app.register_middleware(Box::new(Arc::clone(&redirects)));
+    let scheduler = redirects.schedule(Scheduler::new(), Duration::from_secs(60));
+    redirects
+        .schedule_report(scheduler, Duration::from_secs(24 * 60 * 60), 20)
+        .start();
+}
+
+/// Feature flags are kept in `flags.txt`. The admin page for switching them is only served when
+/// the ADMIN_PASSWORD environment variable is set.
+fn register_feature_flags(app: &mut App) {
+    let flags = app.enable_feature_flags(FeatureFlags::from_file("flags.txt").unwrap());
+    flags.watch(Duration::from_secs(10));
+
+    if let Ok(password) = env::var("ADMIN_PASSWORD") {
+        app.register_middleware(Box::new(
+            Auth::new("/admin", "Admin").with_users(&[("admin", &password)]),
+        ));
+        for resource in flags.admin_resources("/admin/flags") {
+            app.register_resource(resource);
+        }
+    }
+}
+
+/// Stats, log levels, the route list and shutting down at /admin/stats and the like, see
+/// `Admin`, and the routing table with middleware at /admin/route-table. Behind the `Auth` of
+/// `register_feature_flags`.
+fn register_admin(app: &mut App, metrics: Option<Arc<Metrics>>) {
+    if env::var("ADMIN_PASSWORD").is_err() {
+        return;
+    }
+    let admin = match metrics {
+        Some(metrics) => Admin::new().with_metrics(metrics),
+        None => Admin::new(),
+    };
+    app.scope("/admin", |router| admin.register(router));
+    app.enable_route_table("/admin/route-table");
+}
+
+/// Uptime and request success rates are kept in `uptime.txt` and shown at /status, which is
+/// kept up to date through the events at /status/events, which take up at most half of the
+/// `threads` workers.
+fn register_uptime_tracking(app: &mut App, threads: usize) {
+    let tracker = Arc::new(UptimeTracker::load("uptime.txt").unwrap());
+    app.register_middleware(Box::new(Arc::clone(&tracker)));
+    app.register_resource(tracker.status_page_resource("/status"));
+    app.register_resource(tracker.events_resource(
+        "/status/events",
+        Duration::from_secs(10),
+        threads,
+    ));
+    tracker
+        .schedule(Scheduler::new(), Duration::from_secs(60))
+        .start();
+}
+
+/// Request counts, latencies and sizes are served for Prometheus at /admin/metrics, behind the
+/// same password as the feature flags. Requests taking over a second are logged.
+fn register_metrics(app: &mut App) -> Option<Arc<Metrics>> {
+    env::var("ADMIN_PASSWORD").ok()?;
+    let metrics = Arc::new(Metrics::new().with_slow_request_threshold(Duration::from_secs(1)));
+    app.register_middleware(Box::new(Arc::clone(&metrics)));
+    app.register_resource(metrics.resource("/admin/metrics"));
+    Some(metrics)
+}
+
+/// Check the linked profiles every 5 minutes, shown at /links/status. They're all https, so
+/// this needs the tls feature.
+#[cfg(feature = "tls")]
+fn register_health_checks(app: &mut App) {
+    let checker = Arc::new(
+        HealthChecker::new()
+            .with_target("GitHub", "https://www.github.com/Daan4")
+            .with_target("Lichess", "https://lichess.org/@/dnl")
+            .with_target("Medium", "https://medium.com/@daan_lubbers")
+            .with_target("LinkedIn", "https://www.linkedin.com/in/daanlubbers")
+            .with_target("Maria Gomez", "https://www.mariagomez.art"),
+    );
+    app.register_resource(checker.status_page_resource("/links/status"));
+    checker
+        .schedule(Scheduler::new(), Duration::from_secs(300))
+        .start();
 }
 
 fn register_all_resources_in_folder_for_get(app: &mut App, base_path: &str, folder: &str) {
-    let files: fs::ReadDir = fs::read_dir(folder).unwrap();
+    let files = fs::read_dir(folder).unwrap_or_else(|e| {
+        println!("Invalid configuration: Failed to read static files in {folder}: {e}");
+        process::exit(1);
+    });
     for file in files {
         let file = file.unwrap();
         let mut file_name = file.file_name().into_string().unwrap();
-        let file_ext = match file_name.split('.').last() {
-            Some(ext) => ext,
-            None => "",
-        };
+        let file_ext = file_name
+            .split('.')
+            .next_back()
+            .unwrap_or_default()
+            .to_string();
         let file_path = format!("{}/{}", folder, file_name);
-        let resource_type = match file_ext {
+        let file = file_path.clone();
+        let resource_type = match file_ext.as_str() {
             "html" => {
                 file_name = file_name.replace(".html", "");
                 ResourceType::TEXT
             }
             "css" => ResourceType::TEXT,
             "js" => ResourceType::TEXT,
+            "ics" => ResourceType::TEXT,
             _ => ResourceType::BINARY,
         };
-        let resource = Resource::new(
+        let handler: ResourceHandler = match file_ext.as_str() {
+            "ics" => Box::new(move |_| {
+                Ok(calendar::file_response(
+                    file_path.clone(),
+                    Disposition::Inline,
+                    Some(Duration::from_secs(3600)),
+                ))
+            }),
+            _ => Box::new(move |_| Ok(Response::file(StatusCode::OK, file_path.clone()))),
+        };
+        // Pages are checked for changes on every visit, other files are kept for a day.
+        let cache_policy = match file_ext.as_str() {
+            "html" => CachePolicy::NoCache,
+            _ => CachePolicy::MaxAge(86400),
+        };
+        let mut resource = Resource::new(
             RequestType::GET,
             format!("{}{}", base_path, file_name),
             resource_type,
-            Box::new(move || Ok(Response::new(StatusCode::OK, file_path.clone()))),
-        );
-        app.register_resource(resource);
+            handler,
+        )
+        .with_cache(cache_policy)
+        .with_file(file);
+        // Error pages can be opened directly, but shouldn't show up in search results.
+        if file_name == "404" || file_name == "500" {
+            resource = resource.with_robots("noindex");
+        }
+        if let Err(e) = app.try_register_resource(resource) {
+            println!("Invalid configuration: {e}");
+            process::exit(1);
+        }
     }
 }
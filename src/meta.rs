@@ -0,0 +1,118 @@
+use crate::webserver::{html_escape, Body, Response};
+use std::fs;
+
+/// Search engine hints for a resource, set with `Resource::with_canonical` and
+/// `Resource::with_robots`.
+///
+/// Every response gets them as `Link` and `X-Robots-Tag` headers. HTML pages also get them as
+/// `<link rel="canonical">` and `<meta name="robots">` tags at the end of their `<head>`.
+#[derive(Clone, Default)]
+pub struct PageMeta {
+    /// The URL search engines should index the page under, such as
+    /// `https://www.daanlubbers.nl/blog`.
+    pub canonical: Option<String>,
+    /// Robots directives, such as `noindex, nofollow`.
+    pub robots: Option<String>,
+}
+
+impl PageMeta {
+    pub fn is_empty(&self) -> bool {
+        self.canonical.is_none() && self.robots.is_none()
+    }
+
+    pub(crate) fn apply(&self, response: &mut Response) {
+        if self.is_empty() {
+            return;
+        }
+        if let Some(canonical) = &self.canonical {
+            response.add_header("Link", format!("<{canonical}>; rel=\"canonical\""));
+        }
+        if let Some(robots) = &self.robots {
+            response.add_header("X-Robots-Tag", robots.clone());
+        }
+
+        let is_html = match response.header("Content-Type") {
+            Some(content_type) => content_type.starts_with("text/html"),
+            None => match &response.body {
+                Body::File(path) => path
+                    .extension()
+                    .is_some_and(|ext| ext == "html" || ext == "htm"),
+                _ => false,
+            },
+        };
+        if !is_html {
+            return;
+        }
+        let html = match &response.body {
+            Body::Text(text) => text.clone(),
+            Body::File(path) => match fs::read_to_string(path) {
+                Ok(html) => html,
+                // Left for `serialize_response` to answer with a 404.
+                Err(_) => return,
+            },
+            Body::Bytes(_) | Body::Stream(_) | Body::Empty => return,
+        };
+        if let Some(html) = self.inject(&html) {
+            response.body = Body::Text(html);
+        }
+    }
+
+    /// Insert the tags before `</head>`, or `None` if the page has no head.
+    fn inject(&self, html: &str) -> Option<String> {
+        let end = html.to_ascii_lowercase().find("</head>")?;
+        let mut tags = String::new();
+        if let Some(canonical) = &self.canonical {
+            tags.push_str(&format!(
+                "<link rel=\"canonical\" href=\"{}\">",
+                html_escape(canonical)
+            ));
+        }
+        if let Some(robots) = &self.robots {
+            tags.push_str(&format!(
+                "<meta name=\"robots\" content=\"{}\">",
+                html_escape(robots)
+            ));
+        }
+        Some(format!("{}{tags}{}", &html[..end], &html[end..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::StatusCode;
+
+    #[test]
+    fn inject_tags() {
+        let meta = PageMeta {
+            canonical: Some("https://www.daanlubbers.nl/".to_string()),
+            robots: Some("noindex".to_string()),
+        };
+        let mut response = Response::text(
+            StatusCode::OK,
+            "<html><head><title>Home</title></HEAD><body></body></html>",
+        )
+        .with_header("Content-Type", "text/html; charset=utf-8");
+        meta.apply(&mut response);
+        assert_eq!(
+            response.header("Link"),
+            Some("<https://www.daanlubbers.nl/>; rel=\"canonical\"")
+        );
+        assert_eq!(response.header("X-Robots-Tag"), Some("noindex"));
+        match &response.body {
+            Body::Text(html) => assert_eq!(
+                html,
+                "<html><head><title>Home</title><link rel=\"canonical\" \
+                 href=\"https://www.daanlubbers.nl/\"><meta name=\"robots\" content=\"noindex\">\
+                 </HEAD><body></body></html>"
+            ),
+            _ => panic!("Expected a text body"),
+        }
+
+        // Other responses only get the headers
+        let mut response = Response::text(StatusCode::OK, "</head>");
+        meta.apply(&mut response);
+        assert_eq!(response.header("X-Robots-Tag"), Some("noindex"));
+        assert!(matches!(&response.body, Body::Text(text) if text == "</head>"));
+    }
+}
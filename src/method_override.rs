@@ -0,0 +1,118 @@
+use crate::log;
+use crate::webserver::{Middleware, Request, RequestType, Response};
+
+/// Middleware letting POST requests stand for PUT, PATCH or DELETE, as named by an
+/// `X-HTTP-Method-Override` header or a `_method` form field, so HTML forms, which can only send
+/// GET and POST, can use the same routes as other clients. Other requests are left as they are.
+///
+/// Register it before middleware that checks the method, as it runs in order.
+#[derive(Clone, Debug)]
+pub struct MethodOverride {
+    form_field: bool,
+}
+
+impl Default for MethodOverride {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MethodOverride {
+    /// Take the method from the header, or else from the form field.
+    pub fn new() -> Self {
+        Self { form_field: true }
+    }
+
+    /// Whether to look at the `_method` field of form bodies, rather than only the header.
+    pub fn with_form_field(mut self, form_field: bool) -> Self {
+        self.form_field = form_field;
+        self
+    }
+
+    fn method(&self, request: &Request) -> Option<String> {
+        if let Some(method) = request.header("X-HTTP-Method-Override") {
+            return Some(method.to_string());
+        }
+        if !self.form_field {
+            return None;
+        }
+        request.form().ok()?.remove("_method")
+    }
+}
+
+impl Middleware for MethodOverride {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if request.request_type() != RequestType::POST {
+            return None;
+        }
+        let method = self.method(request)?;
+        let request_type = match method.trim().to_ascii_uppercase().as_str() {
+            "PUT" => RequestType::PUT,
+            "PATCH" => RequestType::PATCH,
+            "DELETE" => RequestType::DELETE,
+            _ => {
+                log!("Ignored method override to {method}");
+                return None;
+            }
+        };
+        request.set_request_type(request_type);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestClient;
+    use crate::webserver::{App, AppConfig, Resource, ResourceType, StatusCode};
+
+    #[test]
+    fn overrides() {
+        let mut app = App::new(AppConfig::new("127.0.0.1:0".parse().unwrap(), 1, 5));
+        app.register_middleware(Box::new(MethodOverride::new()));
+        for request_type in [RequestType::POST, RequestType::DELETE, RequestType::PATCH] {
+            app.register_resource(Resource::new(
+                request_type,
+                "/posts/1".to_string(),
+                ResourceType::TEXT,
+                Box::new(move |_| Ok(Response::text(StatusCode::OK, format!("{request_type:?}")))),
+            ));
+        }
+        let client = TestClient::new(app);
+        let form = "application/x-www-form-urlencoded";
+        let send = |headers: &[(&str, &str)], body: &str| {
+            let mut headers = headers.to_vec();
+            headers.push(("Content-Type", form));
+            client
+                .request("POST", "/posts/1", &headers, body.as_bytes())
+                .text()
+        };
+        assert_eq!(send(&[], "_method=delete&title=x"), "DELETE");
+        assert_eq!(send(&[("X-HTTP-Method-Override", "PATCH")], ""), "PATCH");
+        assert_eq!(
+            send(&[("X-HTTP-Method-Override", "PATCH")], "_method=DELETE"),
+            "PATCH"
+        );
+        assert_eq!(send(&[], "title=x"), "POST");
+        // Only unsafe methods can be asked for.
+        assert_eq!(send(&[], "_method=GET"), "POST");
+        assert_eq!(
+            client
+                .post("/posts/1", "text/plain", b"_method=DELETE")
+                .text(),
+            "POST"
+        );
+        // Other methods aren't overridden.
+        assert_eq!(
+            client
+                .request(
+                    "PATCH",
+                    "/posts/1",
+                    &[("X-HTTP-Method-Override", "DELETE")],
+                    &[]
+                )
+                .text(),
+            "PATCH"
+        );
+    }
+}
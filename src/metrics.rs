@@ -0,0 +1,406 @@
+use crate::concurrency::{ConnectionCounter, PoolCounters};
+use crate::warn;
+use crate::webserver::{
+    Body, Middleware, Request, RequestType, Resource, ResourceType, Response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Bucket bounds for body sizes in bytes, from empty up to the default body limit of 16 MiB.
+pub const SIZE_BUCKETS: &[u64] = &[
+    0,
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    16 << 20,
+];
+
+pub const HEADER_COUNT_BUCKETS: &[u64] = &[5, 10, 20, 50, 100];
+
+/// Bucket bounds for the time to answer a request in milliseconds.
+pub const DURATION_BUCKETS: &[u64] = &[1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 10000];
+
+/// Counts values into buckets with these upper bounds, like a Prometheus histogram.
+pub struct Histogram {
+    bounds: Vec<u64>,
+    /// One per bound, and one for values above the last bound.
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[u64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    /// The number of values at or below each bound, and then the total count.
+    pub fn cumulative(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .scan(0, |total, bucket| {
+                *total += bucket.load(Ordering::Relaxed);
+                Some(*total)
+            })
+            .collect()
+    }
+
+    /// The bound of the bucket the `quantile` (from 0 to 1) of the values falls in, such as the
+    /// 0.99 quantile being at most 250 ms. `None` without values, or if it's above the last
+    /// bound.
+    pub fn quantile(&self, quantile: f64) -> Option<u64> {
+        let cumulative = self.cumulative();
+        let count = cumulative.last().copied().unwrap_or_default();
+        if count == 0 {
+            return None;
+        }
+        let rank = (quantile.clamp(0.0, 1.0) * count as f64).ceil().max(1.0) as u64;
+        let bucket = cumulative.partition_point(|total| *total < rank);
+        self.bounds.get(bucket).copied()
+    }
+
+    /// Write the histogram in the Prometheus text format.
+    fn render(&self, name: &str, help: &str, output: &mut String) {
+        output.push_str(&format!("# HELP {name} {help}\n# TYPE {name} histogram\n"));
+        let cumulative = self.cumulative();
+        for (bound, count) in self.bounds.iter().zip(&cumulative) {
+            output.push_str(&format!("{name}_bucket{{le=\"{bound}\"}} {count}\n"));
+        }
+        let count = cumulative.last().copied().unwrap_or_default();
+        output.push_str(&format!("{name}_bucket{{le=\"+Inf\"}} {count}\n"));
+        output.push_str(&format!(
+            "{name}_sum {}\n{name}_count {count}\n",
+            self.sum()
+        ));
+    }
+}
+
+/// Middleware counting requests by route and status, and recording how long they took, the
+/// sizes of request and response bodies and the number of request headers, to help tune the
+/// body limits and buffer sizes. The sum of the response sizes is the number of bytes served,
+/// apart from streams.
+///
+/// `resource` serves them, along with the open connections and the thread pool statistics, in
+/// the Prometheus text format. Requests slower than `with_slow_request_threshold` are logged.
+pub struct Metrics {
+    pub request_body_bytes: Histogram,
+    pub response_body_bytes: Histogram,
+    pub request_headers: Histogram,
+    /// From reading the request line to the response being ready to send.
+    pub request_duration_ms: Histogram,
+    /// Requests by route and status code.
+    requests: Mutex<HashMap<(String, u16), u64>>,
+    /// Counters kept by other parts of the app, with their help text.
+    counters: Mutex<Vec<(String, String, Arc<AtomicU64>)>>,
+    /// The duration above which a request is logged, and the count of those requests.
+    slow_requests: Option<(Duration, Arc<AtomicU64>)>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            request_body_bytes: Histogram::new(SIZE_BUCKETS),
+            response_body_bytes: Histogram::new(SIZE_BUCKETS),
+            request_headers: Histogram::new(HEADER_COUNT_BUCKETS),
+            request_duration_ms: Histogram::new(DURATION_BUCKETS),
+            requests: Mutex::default(),
+            counters: Mutex::default(),
+            slow_requests: None,
+        }
+    }
+
+    /// Log a warning with the route and duration of requests taking longer than `threshold`,
+    /// and count them as `http_slow_requests_total`.
+    pub fn with_slow_request_threshold(mut self, threshold: Duration) -> Self {
+        let counter = self.counter(
+            "http_slow_requests_total",
+            "Requests slower than the threshold.",
+        );
+        self.slow_requests = Some((threshold, counter));
+        self
+    }
+
+    /// A counter served along with the other metrics, such as `legacy_redirects_total`. Asking
+    /// for the same name again returns the same counter.
+    pub fn counter(&self, name: &str, help: &str) -> Arc<AtomicU64> {
+        let mut counters = self.counters.lock().unwrap();
+        if let Some((_, _, counter)) = counters.iter().find(|(existing, _, _)| existing == name) {
+            return Arc::clone(counter);
+        }
+        let counter = Arc::new(AtomicU64::new(0));
+        counters.push((name.to_string(), help.to_string(), Arc::clone(&counter)));
+        counter
+    }
+
+    /// How many requests were answered with `status` at `route`, see `route`.
+    pub fn requests(&self, route: &str, status: u16) -> u64 {
+        let requests = self.requests.lock().unwrap();
+        requests
+            .get(&(route.to_string(), status))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// A GET resource at `path` with the metrics in the Prometheus text format.
+    pub fn resource(self: &Arc<Self>, path: &str) -> Resource {
+        let metrics = Arc::clone(self);
+        Resource::new(
+            RequestType::GET,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(move |request| {
+                let pool = request.state::<PoolCounters>();
+                let connections = request.state::<ConnectionCounter>();
+                let output = metrics.render(pool.as_deref(), connections.as_deref());
+                Ok(Response::text(StatusCode::OK, output)
+                    .with_header("Content-Type", "text/plain; version=0.0.4")
+                    .with_header("Cache-Control", "no-store"))
+            }),
+        )
+        .without_sitemap()
+    }
+
+    fn render(
+        &self,
+        pool: Option<&PoolCounters>,
+        connections: Option<&ConnectionCounter>,
+    ) -> String {
+        let mut output = String::from(
+            "# HELP http_requests_total Requests by route and status.\n\
+             # TYPE http_requests_total counter\n",
+        );
+        let mut requests = self
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((route, status), count)| (route.clone(), *status, *count))
+            .collect::<Vec<_>>();
+        requests.sort();
+        for (route, status, count) in requests {
+            output.push_str(&format!(
+                "http_requests_total{{route=\"{}\",status=\"{status}\"}} {count}\n",
+                label_escape(&route)
+            ));
+        }
+        self.request_duration_ms.render(
+            "http_request_duration_milliseconds",
+            "Time to answer requests.",
+            &mut output,
+        );
+        self.request_body_bytes.render(
+            "http_request_body_bytes",
+            "Size of request bodies.",
+            &mut output,
+        );
+        self.response_body_bytes.render(
+            "http_response_body_bytes",
+            "Size of response bodies.",
+            &mut output,
+        );
+        self.request_headers.render(
+            "http_request_headers",
+            "Number of request headers.",
+            &mut output,
+        );
+        for (name, help, counter) in self.counters.lock().unwrap().iter() {
+            output.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                counter.load(Ordering::Relaxed)
+            ));
+        }
+        if let Some(connections) = connections {
+            output.push_str(&format!(
+                "# TYPE http_connections_open gauge\nhttp_connections_open {}\n",
+                connections.open()
+            ));
+        }
+        if let Some(pool) = pool {
+            let stats = pool.stats();
+            for (name, kind, value) in [
+                ("thread_pool_queued", "gauge", stats.queued as u64),
+                ("thread_pool_busy", "gauge", stats.busy as u64),
+                ("thread_pool_completed_total", "counter", stats.completed),
+                ("thread_pool_panicked_total", "counter", stats.panicked),
+            ] {
+                output.push_str(&format!("# TYPE {name} {kind}\n{name} {value}\n"));
+            }
+        }
+        output
+    }
+}
+
+impl Middleware for Metrics {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        self.request_body_bytes.observe(request.body().len() as u64);
+        self.request_headers.observe(request.headers().len() as u64);
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        let status = response.status_code.code();
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route(request, status), status))
+            .or_default() += 1;
+        let duration = request.received().elapsed();
+        self.request_duration_ms
+            .observe(duration.as_millis().try_into().unwrap_or(u64::MAX));
+        if let Some((threshold, counter)) = &self.slow_requests {
+            if duration > *threshold {
+                counter.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Slow request {:?} {} took {} ms ({status})",
+                    request.request_type(),
+                    route(request, status),
+                    duration.as_millis()
+                );
+            }
+        }
+
+        let length = match &response.body {
+            Body::File(path) => fs::metadata(path).map_or(0, |metadata| metadata.len()),
+            Body::Text(text) => text.len() as u64,
+            Body::Bytes(bytes) => bytes.len() as u64,
+            // The length isn't known until it is sent.
+            Body::Stream(_) => return,
+            Body::Empty => 0,
+        };
+        self.response_body_bytes.observe(length);
+    }
+}
+
+/// The route label of a request: its path, unless nothing was found there, so scanners trying
+/// many paths don't add a series each.
+fn route(request: &Request, status: u16) -> String {
+    match status {
+        404 => "unmatched".to_string(),
+        _ => request.path().to_string(),
+    }
+}
+
+/// Escape a label value for the Prometheus text format.
+fn label_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::new(&[10, 100]);
+        for value in [0, 10, 11, 100, 1000] {
+            histogram.observe(value);
+        }
+        assert_eq!(histogram.cumulative(), vec![2, 4, 5]);
+        assert_eq!((histogram.count(), histogram.sum()), (5, 1121));
+        assert_eq!(histogram.quantile(0.4), Some(10));
+        assert_eq!(histogram.quantile(0.5), Some(100));
+        assert_eq!(histogram.quantile(0.0), Some(10));
+        assert_eq!(histogram.quantile(1.0), None);
+        assert_eq!(Histogram::new(&[10]).quantile(0.5), None);
+
+        let mut output = String::new();
+        histogram.render("test", "Test values.", &mut output);
+        assert_eq!(
+            output,
+            "# HELP test Test values.\n# TYPE test histogram\ntest_bucket{le=\"10\"} 2\n\
+             test_bucket{le=\"100\"} 4\ntest_bucket{le=\"+Inf\"} 5\ntest_sum 1121\n\
+             test_count 5\n"
+        );
+    }
+
+    #[test]
+    fn middleware() {
+        let metrics = Metrics::new();
+        let mut request = Request::from_reader(&mut BufReader::new(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nbody".as_bytes(),
+        ))
+        .unwrap();
+        assert!(metrics.before(&mut request).is_none());
+        let mut response = Response::text(StatusCode::OK, "x".repeat(2000));
+        metrics.after(&request, &mut response);
+        assert_eq!(metrics.request_body_bytes.cumulative()[..2], [0, 1]);
+        assert_eq!(metrics.request_headers.cumulative()[0], 1);
+        assert_eq!(metrics.response_body_bytes.cumulative()[..3], [0, 0, 1]);
+        metrics
+            .counter("test_total", "Test counter.")
+            .fetch_add(2, Ordering::Relaxed);
+        metrics
+            .counter("test_total", "Test counter.")
+            .fetch_add(1, Ordering::Relaxed);
+        let mut not_found = Response::empty(StatusCode::NotFound);
+        metrics.after(&request, &mut not_found);
+        assert_eq!(metrics.requests("/", 200), 1);
+        assert_eq!(metrics.requests("unmatched", 404), 1);
+        assert_eq!(metrics.request_duration_ms.count(), 2);
+        let connections = Arc::new(ConnectionCounter::default());
+        let _connection = Arc::clone(&connections).track();
+        let output = metrics.render(Some(&PoolCounters::default()), Some(&connections));
+        assert!(output.contains("http_requests_total{route=\"/\",status=\"200\"} 1\n"));
+        assert!(output.contains("http_requests_total{route=\"unmatched\",status=\"404\"} 1\n"));
+        assert!(output.contains("http_connections_open 1\n"));
+        assert_eq!(label_escape("a\"b\\c\n"), "a\\\"b\\\\c\\n");
+        assert!(output.contains("# TYPE test_total counter\ntest_total 3\n"));
+        assert!(output.contains("thread_pool_busy 0\n"));
+    }
+
+    #[test]
+    fn slow_requests() {
+        let metrics = Metrics::new().with_slow_request_threshold(Duration::from_millis(50));
+        let request =
+            Request::from_reader(&mut BufReader::new("GET /slow HTTP/1.1\r\n\r\n".as_bytes()))
+                .unwrap();
+        metrics.after(&request, &mut Response::empty(StatusCode::OK));
+        std::thread::sleep(Duration::from_millis(60));
+        metrics.after(&request, &mut Response::empty(StatusCode::OK));
+        let output = metrics.render(None, None);
+        assert!(output.contains("http_slow_requests_total 1\n"));
+        assert!(!Metrics::new().render(None, None).contains("slow"));
+    }
+}
@@ -0,0 +1,626 @@
+use crate::webserver::{percent_decode, Error, Request, Response, StatusCode};
+use std::{
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Longest header line accepted in a part.
+const MAX_HEADER_LINE: usize = 8 * 1024;
+/// Most headers accepted in a part.
+const MAX_HEADERS: usize = 32;
+
+/// Why a multipart body could not be read.
+#[derive(Debug)]
+pub enum MultipartError {
+    /// The body is not valid multipart, answered with a 400.
+    Malformed(String),
+    /// A part or the whole body is over the `Multipart` limits, answered with a 413.
+    TooLarge(String),
+    /// Reading the body or spooling a part to disk failed, answered with a 500.
+    Io(io::Error),
+}
+
+impl MultipartError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            MultipartError::Malformed(_) => StatusCode::BadRequest,
+            MultipartError::TooLarge(_) => StatusCode::PayloadTooLarge,
+            MultipartError::Io(_) => StatusCode::InternalServerError,
+        }
+    }
+
+    /// A response telling the client what was wrong with its request.
+    pub fn response(&self) -> Response {
+        match self {
+            MultipartError::Io(_) => Response::empty(self.status_code()),
+            _ => Response::text(self.status_code(), self.to_string()),
+        }
+    }
+}
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultipartError::Malformed(e) | MultipartError::TooLarge(e) => write!(f, "{e}"),
+            MultipartError::Io(e) => write!(f, "Failed to read multipart body: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(e: io::Error) -> Self {
+        MultipartError::Io(e)
+    }
+}
+
+impl From<MultipartError> for Error {
+    fn from(e: MultipartError) -> Self {
+        match e {
+            MultipartError::Io(e) => Error::Internal(Box::new(e)),
+            e => Error::Status(e.status_code(), e.to_string()),
+        }
+    }
+}
+
+/// Reads the parts of a `multipart/form-data` body one at a time, as posted by HTML forms
+/// with file inputs.
+///
+/// Parts are kept in memory up to `memory_threshold` bytes, and spooled to a temporary file
+/// once they grow larger. A part larger than `max_part_size`, or a body larger than
+/// `max_total_size`, stops reading with `MultipartError::TooLarge`.
+pub struct Multipart<R> {
+    reader: R,
+    /// `\r\n--<boundary>`, which ends every part.
+    delimiter: Vec<u8>,
+    /// Read but not yet handled. Starts with `\r\n`, so the first delimiter can be found like
+    /// the others even at the start of the body.
+    buffer: Vec<u8>,
+    started: bool,
+    eof: bool,
+    done: bool,
+    total: u64,
+    max_part_size: u64,
+    max_total_size: u64,
+    memory_threshold: usize,
+    temp_dir: PathBuf,
+}
+
+impl<'a> Multipart<&'a [u8]> {
+    /// The parts of a request body, with the boundary from its `Content-Type`.
+    pub fn from_request(request: &'a Request) -> Result<Self, MultipartError> {
+        let content_type = request.header("Content-Type").unwrap_or_default();
+        let boundary = boundary(content_type).ok_or_else(|| {
+            MultipartError::Malformed(format!("Not a multipart body: {content_type}"))
+        })?;
+        Ok(Self::new(request.body(), &boundary))
+    }
+}
+
+impl<R: BufRead> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Self {
+            reader,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            buffer: b"\r\n".to_vec(),
+            started: false,
+            eof: false,
+            done: false,
+            total: 0,
+            max_part_size: 16 * 1024 * 1024,
+            max_total_size: 64 * 1024 * 1024,
+            memory_threshold: 64 * 1024,
+            temp_dir: std::env::temp_dir(),
+        }
+    }
+
+    /// Reject parts larger than this many bytes. Defaults to 16 MiB.
+    pub fn with_max_part_size(mut self, bytes: u64) -> Self {
+        self.max_part_size = bytes;
+        self
+    }
+
+    /// Reject bodies larger than this many bytes, headers included. Defaults to 64 MiB.
+    pub fn with_max_total_size(mut self, bytes: u64) -> Self {
+        self.max_total_size = bytes;
+        self
+    }
+
+    /// Spool parts larger than this many bytes to a temporary file. Defaults to 64 KiB.
+    pub fn with_memory_threshold(mut self, bytes: usize) -> Self {
+        self.memory_threshold = bytes;
+        self
+    }
+
+    /// Where spooled parts are stored, the system's temporary directory by default.
+    pub fn with_temp_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = dir.into();
+        self
+    }
+
+    /// The next part, or `None` after the last one.
+    pub fn next_part(&mut self) -> Result<Option<Part>, MultipartError> {
+        if self.done {
+            return Ok(None);
+        }
+        if !self.started {
+            self.started = true;
+            // Skip the preamble before the first delimiter.
+            self.read_until_delimiter(&mut io::sink(), u64::MAX)?;
+            if self.finish_delimiter()? {
+                return Ok(None);
+            }
+        }
+
+        let headers = self.read_headers()?;
+        let disposition = header(&headers, "Content-Disposition").unwrap_or_default();
+        let temp_dir = self.temp_dir.clone();
+        let mut writer = SpoolWriter {
+            memory: vec![],
+            file: None,
+            threshold: self.memory_threshold,
+            temp_dir: &temp_dir,
+        };
+        let size = self.read_until_delimiter(&mut writer, self.max_part_size)?;
+        let data = writer.into_data();
+        self.done = self.finish_delimiter()?;
+        Ok(Some(Part {
+            name: disposition_param(disposition, "name"),
+            file_name: disposition_param(disposition, "filename"),
+            headers,
+            data,
+            size,
+        }))
+    }
+
+    /// Read more of the body into the buffer, returning false at the end of it.
+    fn fill(&mut self) -> Result<bool, MultipartError> {
+        if self.eof {
+            return Ok(false);
+        }
+        let chunk = self.reader.fill_buf()?;
+        if chunk.is_empty() {
+            self.eof = true;
+            return Ok(false);
+        }
+        let length = chunk.len();
+        self.total += length as u64;
+        if self.total > self.max_total_size {
+            return Err(MultipartError::TooLarge(format!(
+                "Multipart body over {} bytes",
+                self.max_total_size
+            )));
+        }
+        self.buffer.extend_from_slice(chunk);
+        self.reader.consume(length);
+        Ok(true)
+    }
+
+    /// Copy the body up to the next delimiter to `writer`, and consume the delimiter. Returns
+    /// how many bytes were copied.
+    fn read_until_delimiter(
+        &mut self,
+        writer: &mut impl Write,
+        max_size: u64,
+    ) -> Result<u64, MultipartError> {
+        let mut size = 0;
+        loop {
+            let found = find(&self.buffer, &self.delimiter);
+            // Without a delimiter, its start may still be at the end of the buffer.
+            let end = found.unwrap_or(self.buffer.len().saturating_sub(self.delimiter.len() - 1));
+            size += end as u64;
+            if size > max_size {
+                return Err(MultipartError::TooLarge(format!(
+                    "Multipart part over {max_size} bytes"
+                )));
+            }
+            writer.write_all(&self.buffer[..end])?;
+            match found {
+                Some(start) => {
+                    self.buffer.drain(..start + self.delimiter.len());
+                    return Ok(size);
+                }
+                None => {
+                    self.buffer.drain(..end);
+                }
+            }
+            if !self.fill()? {
+                return Err(MultipartError::Malformed(
+                    "Multipart body ended inside a part".to_string(),
+                ));
+            }
+        }
+    }
+
+    /// Read what follows a delimiter: `--` for the last one, or the line break before the next
+    /// part's headers. Returns whether it was the last one.
+    fn finish_delimiter(&mut self) -> Result<bool, MultipartError> {
+        while self.buffer.len() < 2 && self.fill()? {}
+        if self.buffer.starts_with(b"--") {
+            // The epilogue after the last delimiter is ignored.
+            self.buffer.clear();
+            return Ok(true);
+        }
+        let line = self.read_line()?;
+        if !line.trim().is_empty() {
+            return Err(MultipartError::Malformed(
+                "Invalid multipart delimiter".to_string(),
+            ));
+        }
+        Ok(false)
+    }
+
+    /// Read a line ending in `\r\n`, without it.
+    fn read_line(&mut self) -> Result<String, MultipartError> {
+        loop {
+            if let Some(end) = find(&self.buffer, b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            if self.buffer.len() > MAX_HEADER_LINE {
+                return Err(MultipartError::Malformed(
+                    "Multipart header line too long".to_string(),
+                ));
+            }
+            if !self.fill()? {
+                return Err(MultipartError::Malformed(
+                    "Multipart body ended inside the headers".to_string(),
+                ));
+            }
+        }
+    }
+
+    fn read_headers(&mut self) -> Result<Vec<(String, String)>, MultipartError> {
+        let mut headers = vec![];
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                return Ok(headers);
+            }
+            if headers.len() == MAX_HEADERS {
+                return Err(MultipartError::Malformed(
+                    "Too many multipart headers".to_string(),
+                ));
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(MultipartError::Malformed(format!(
+                    "Invalid multipart header: {line}"
+                )));
+            };
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+}
+
+/// One part of a multipart body, such as a form field or an uploaded file.
+pub struct Part {
+    headers: Vec<(String, String)>,
+    name: Option<String>,
+    file_name: Option<String>,
+    data: PartData,
+    size: u64,
+}
+
+impl Part {
+    /// The value of the first header with this name, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The name of the form field.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The name of the uploaded file as sent by the client, for file inputs. Pass it through
+    /// `upload::sanitize_file_name` before using it as a path.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn data(&self) -> &PartData {
+        &self.data
+    }
+
+    pub fn into_data(self) -> PartData {
+        self.data
+    }
+
+    /// The contents of the part, read back from disk if it was spooled.
+    pub fn bytes(&self) -> io::Result<Vec<u8>> {
+        match &self.data {
+            PartData::Memory(bytes) => Ok(bytes.clone()),
+            PartData::File(file) => fs::read(file.path()),
+        }
+    }
+
+    /// The contents of the part as text, such as the value of a form field.
+    pub fn text(&self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes()?).into_owned())
+    }
+}
+
+/// Where the contents of a part are kept.
+pub enum PartData {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+impl PartData {
+    /// Store the contents at `path`, moving the temporary file there if possible.
+    pub fn persist(self, path: &Path) -> io::Result<()> {
+        match self {
+            PartData::Memory(bytes) => fs::write(path, bytes),
+            PartData::File(file) => file.persist(path),
+        }
+    }
+}
+
+/// A file in the temporary directory that is removed when dropped, unless it is persisted.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create(dir: &Path) -> io::Result<(Self, File)> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "wwwdaanlubbersnl-part-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        let file = File::options().write(true).create_new(true).open(&path)?;
+        Ok((Self { path }, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the file to `path`, or copy it there if that is on another file system.
+    pub fn persist(self, path: &Path) -> io::Result<()> {
+        if fs::rename(&self.path, path).is_err() {
+            fs::copy(&self.path, path)?;
+        }
+        Ok(())
+    }
+
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // Already gone if it was persisted by renaming.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Writes a part to memory until it grows past the threshold, then to a temporary file.
+struct SpoolWriter<'a> {
+    memory: Vec<u8>,
+    file: Option<(TempFile, File)>,
+    threshold: usize,
+    temp_dir: &'a Path,
+}
+
+impl SpoolWriter<'_> {
+    fn into_data(self) -> PartData {
+        match self.file {
+            Some((temp_file, _)) => PartData::File(temp_file),
+            None => PartData::Memory(self.memory),
+        }
+    }
+}
+
+impl Write for SpoolWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() && self.memory.len() + buf.len() > self.threshold {
+            let (temp_file, mut file) = TempFile::create(self.temp_dir)?;
+            file.write_all(&self.memory)?;
+            self.memory = vec![];
+            self.file = Some((temp_file, file));
+        }
+        match &mut self.file {
+            Some((_, file)) => file.write_all(buf)?,
+            None => self.memory.extend_from_slice(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The boundary of a `multipart/form-data` content type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let (media_type, params) = content_type.split_once(';')?;
+    if !media_type
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    let boundary = param(params, "boundary")?;
+    (1..=70).contains(&boundary.len()).then_some(boundary)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// A parameter of a `Content-Disposition` header, preferring the RFC 5987 `name*` form that
+/// browsers send for non-ASCII file names.
+fn disposition_param(disposition: &str, name: &str) -> Option<String> {
+    let params = disposition.split_once(';')?.1;
+    if let Some(extended) = param(params, &format!("{name}*")) {
+        if let Some((_, encoded)) = extended.split_once("''") {
+            return Some(percent_decode(&encoded.replace('+', "%2B")));
+        }
+    }
+    param(params, name)
+}
+
+/// The value of a `name=value` parameter, which may be a quoted string with escapes.
+fn param(params: &str, name: &str) -> Option<String> {
+    let mut rest = params;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let key = key.rsplit(';').next().unwrap_or_default().trim();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (_, '\\') => value.push(chars.next()?.1),
+                        (i, '"') => break i + 1,
+                        (_, c) => value.push(c),
+                    }
+                };
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        if key.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = after
+            .trim_start_matches(|c| c != ';')
+            .trim_start_matches(';');
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    const BODY: &str = "preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"message\"\r\n\
+        \r\n\
+        Hi\r\n--Xy\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        0123456789\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"photo\"; filename=\"cafe.jpg\"; filename*=UTF-8''caf%C3%A9+1.jpg\r\n\
+        \r\n\
+        \r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    /// A parser reading a few bytes at a time, so delimiters are split across reads.
+    fn parser(body: &str) -> Multipart<BufReader<&[u8]>> {
+        Multipart::new(BufReader::with_capacity(3, body.as_bytes()), "XyZ")
+    }
+
+    #[test]
+    fn parse_parts() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_multipart");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut multipart = parser(BODY).with_memory_threshold(8).with_temp_dir(&dir);
+
+        let message = multipart.next_part().unwrap().unwrap();
+        assert_eq!(message.name(), Some("message"));
+        assert_eq!(message.file_name(), None);
+        assert_eq!(message.text().unwrap(), "Hi\r\n--Xy");
+        assert!(matches!(message.data(), PartData::Memory(_)));
+
+        let file = multipart.next_part().unwrap().unwrap();
+        assert_eq!(file.file_name(), Some("a \"b\".txt"));
+        assert_eq!(file.content_type(), Some("text/plain"));
+        assert_eq!(file.size(), 10);
+        let PartData::File(temp_file) = file.data() else {
+            panic!("Expected the part to be spooled");
+        };
+        let temp_path = temp_file.path().to_path_buf();
+        assert!(temp_path.starts_with(&dir));
+        assert_eq!(file.bytes().unwrap(), b"0123456789");
+        file.into_data().persist(&dir.join("stored.txt")).unwrap();
+        assert!(!temp_path.exists());
+        assert_eq!(fs::read(dir.join("stored.txt")).unwrap(), b"0123456789");
+
+        let photo = multipart.next_part().unwrap().unwrap();
+        assert_eq!(photo.file_name(), Some("café+1.jpg"));
+        assert_eq!(photo.size(), 0);
+        assert!(multipart.next_part().unwrap().is_none());
+        assert!(multipart.next_part().unwrap().is_none());
+
+        // Spooled parts that are not persisted are removed.
+        let mut multipart = parser(BODY).with_memory_threshold(8).with_temp_dir(&dir);
+        multipart.next_part().unwrap();
+        drop(multipart.next_part().unwrap());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn limits_and_errors() {
+        let error = |mut multipart: Multipart<BufReader<&[u8]>>| loop {
+            match multipart.next_part() {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("Expected an error"),
+                Err(e) => break e.status_code().code(),
+            }
+        };
+        assert_eq!(error(parser(BODY).with_max_part_size(9)), 413);
+        assert_eq!(error(parser(BODY).with_max_total_size(100)), 413);
+        assert!(parser(BODY)
+            .with_max_part_size(20)
+            .with_max_total_size(BODY.len() as u64)
+            .next_part()
+            .is_ok());
+        assert_eq!(error(parser(&BODY[..120])), 400);
+        assert_eq!(error(parser("--XyZ\r\nNo colon\r\n\r\n--XyZ--")), 400);
+        assert_eq!(error(parser("--XyZ garbage\r\n\r\n--XyZ--")), 400);
+        assert!(parser("--XyZ--").next_part().unwrap().is_none());
+
+        assert_eq!(
+            boundary("multipart/form-data; charset=utf-8; boundary=\"a;b\"").unwrap(),
+            "a;b"
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data;boundary=----x").unwrap(),
+            "----x"
+        );
+        assert!(boundary("multipart/form-data").is_none());
+        assert!(boundary("text/plain; boundary=x").is_none());
+        assert!(boundary(&format!("multipart/form-data; boundary={}", "x".repeat(71))).is_none());
+    }
+}
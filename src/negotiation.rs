@@ -0,0 +1,254 @@
+use crate::webserver::{Error, Request, ResourceHandler, Response, StatusCode};
+
+/// The values of an `Accept` style header with their quality, such as `text/html` with 1.0 and
+/// `*/*` with 0.8 for `text/html, */*;q=0.8`. Parameters other than the quality are left off,
+/// and values with an invalid quality are left out.
+pub fn preferences(header: &str) -> Vec<(&str, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let value = parts.next().filter(|value| !value.is_empty())?;
+            let mut quality = 1.0;
+            for parameter in parts {
+                if let Some(q) = parameter
+                    .strip_prefix("q=")
+                    .or_else(|| parameter.strip_prefix("Q="))
+                {
+                    quality = q.parse().ok().filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+            Some((value, quality))
+        })
+        .collect()
+}
+
+/// How specifically a media range such as `text/*` matches a media type, if at all.
+fn media_range_matches(range: &str, media_type: &str) -> Option<u8> {
+    let main_type = media_type.split('/').next().unwrap_or_default();
+    if range.eq_ignore_ascii_case(media_type) {
+        Some(2)
+    } else if range
+        .strip_suffix("/*")
+        .is_some_and(|range| range.eq_ignore_ascii_case(main_type))
+    {
+        Some(1)
+    } else {
+        (range == "*/*").then_some(0)
+    }
+}
+
+/// How specifically a language range matches a language tag, if at all. Besides `en` matching
+/// `en-GB`, `en-GB` matches `en`, so clients asking for a regional variant get the language.
+fn language_range_matches(range: &str, language: &str) -> Option<u8> {
+    let extends = |longer: &str, shorter: &str| {
+        longer.len() > shorter.len()
+            && longer.as_bytes()[shorter.len()] == b'-'
+            && longer[..shorter.len()].eq_ignore_ascii_case(shorter)
+    };
+    if range.eq_ignore_ascii_case(language) {
+        Some(2)
+    } else if extends(language, range) || extends(range, language) {
+        Some(1)
+    } else {
+        (range == "*").then_some(0)
+    }
+}
+
+/// Whether a coding such as `gzip`, or `*`, matches a content coding.
+fn coding_matches(coding: &str, encoding: &str) -> Option<u8> {
+    match coding {
+        "*" => Some(0),
+        _ => coding.eq_ignore_ascii_case(encoding).then_some(1),
+    }
+}
+
+/// Of the `available` values, the one the header prefers, or the first one of those it prefers
+/// equally. Each gets the quality of the most specific range matching it.
+fn best<'a>(
+    header: &str,
+    available: &[&'a str],
+    matches: fn(&str, &str) -> Option<u8>,
+) -> Option<&'a str> {
+    let preferences = preferences(header);
+    let mut best = None;
+    for value in available {
+        let quality = preferences
+            .iter()
+            .filter_map(|(range, quality)| Some((matches(range, value)?, *quality)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, quality)| quality);
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((*value, quality));
+        }
+    }
+    best.map(|(value, _)| value)
+}
+
+/// Of the `available` media types, the one the `Accept` header prefers, or the first one of
+/// those it prefers equally. Without a header any will do, so that is the first one. `None` if
+/// none are acceptable.
+pub fn best_media_type<'a>(accept: Option<&str>, available: &[&'a str]) -> Option<&'a str> {
+    match accept {
+        Some(accept) => best(accept, available, media_range_matches),
+        None => available.first().copied(),
+    }
+}
+
+/// Of the `available` content codings, such as `br` and `gzip`, the one the `Accept-Encoding`
+/// header prefers, or the first one of those it prefers equally. `None` if it accepts none, in
+/// which case the response is sent as it is.
+pub fn best_encoding<'a>(accept_encoding: &str, available: &[&'a str]) -> Option<&'a str> {
+    best(accept_encoding, available, coding_matches)
+}
+
+/// Of the `available` language tags, such as `en` and `nl`, the one the `Accept-Language`
+/// header prefers, or the first one of those it prefers equally. `None` if it accepts none.
+pub fn best_language<'a>(accept_language: &str, available: &[&'a str]) -> Option<&'a str> {
+    best(accept_language, available, language_range_matches)
+}
+
+/// Handlers for the representations of one resource, such as HTML for browsers and JSON for
+/// scripts, of which the one the `Accept` header prefers answers the request. Requests that
+/// accept none of them get a 406.
+///
+/// Responses get the chosen media type as their `Content-Type`, unless the handler set one,
+/// and `Vary: Accept` for caches.
+pub struct Representations {
+    handlers: Vec<(String, ResourceHandler)>,
+}
+
+impl Default for Representations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Representations {
+    pub fn new() -> Self {
+        Self { handlers: vec![] }
+    }
+
+    /// Add a representation, which is preferred over those added after it when the client
+    /// doesn't mind which one it gets.
+    pub fn with(mut self, media_type: &str, handler: ResourceHandler) -> Self {
+        self.handlers.push((media_type.to_string(), handler));
+        self
+    }
+
+    /// The handler to register the resource with.
+    pub fn into_handler(self) -> ResourceHandler {
+        Box::new(move |request| self.handle(request))
+    }
+
+    fn handle(&self, request: &Request) -> Result<Response, Error> {
+        let available: Vec<&str> = self
+            .handlers
+            .iter()
+            .map(|(media_type, _)| media_type.as_str())
+            .collect();
+        let Some(media_type) = best_media_type(request.header("Accept"), &available) else {
+            return Err(Error::Status(
+                StatusCode::NotAcceptable,
+                format!("None of {} acceptable", available.join(", ")),
+            ));
+        };
+        let (_, handler) = self
+            .handlers
+            .iter()
+            .find(|(available, _)| available == media_type)
+            .unwrap();
+        let mut response = handler(request)?;
+        if response.header("Content-Type").is_none() {
+            response.add_header("Content-Type", media_type);
+        }
+        response.add_header("Vary", "Accept");
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_types() {
+        assert_eq!(
+            preferences("text/html, application/xml;q=0.9, */*;q=0.8, bad;q=2, ;q=1"),
+            [("text/html", 1.0), ("application/xml", 0.9), ("*/*", 0.8)]
+        );
+        let available = ["text/html", "application/json", "text/plain"];
+        let best = |accept| best_media_type(accept, &available);
+        assert_eq!(best(None), Some("text/html"));
+        assert_eq!(best(Some("application/json")), Some("application/json"));
+        assert_eq!(best(Some("text/*")), Some("text/html"));
+        assert_eq!(
+            best(Some("text/*;q=0.5, text/plain, */*;q=0.1")),
+            Some("text/plain")
+        );
+        assert_eq!(
+            best(Some("application/json;q=0.9, text/html;q=0.9")),
+            Some("text/html")
+        );
+        // The most specific range decides, even when a wider one has a higher quality.
+        assert_eq!(best(Some("*/*, text/html;q=0")), Some("application/json"));
+        assert_eq!(best(Some("image/png")), None);
+    }
+
+    #[test]
+    fn languages() {
+        let available = ["en", "nl"];
+        let best = |accept| best_language(accept, &available);
+        assert_eq!(best("nl"), Some("nl"));
+        assert_eq!(best("nl-BE, en;q=0.5"), Some("nl"));
+        assert_eq!(best("EN"), Some("en"));
+        assert_eq!(best("fr, *;q=0.1"), Some("en"));
+        assert_eq!(best("*, en;q=0"), Some("nl"));
+        assert_eq!(best("fr, nlx"), None);
+        assert_eq!(best_language("en", &["en-GB", "en-US"]), Some("en-GB"));
+    }
+
+    #[test]
+    fn encodings() {
+        let available = ["br", "gzip"];
+        let best = |accept| best_encoding(accept, &available);
+        assert_eq!(best("gzip, deflate, br"), Some("br"));
+        assert_eq!(best("gzip, br;q=0.5"), Some("gzip"));
+        assert_eq!(best("GZIP"), Some("gzip"));
+        assert_eq!(best("*"), Some("br"));
+        assert_eq!(best("*, br;q=0"), Some("gzip"));
+        assert_eq!(best("identity"), None);
+    }
+
+    #[test]
+    fn representations() {
+        let handler = Representations::new()
+            .with(
+                "text/html",
+                Box::new(|_| Ok(Response::text(StatusCode::OK, "<p>hi</p>"))),
+            )
+            .with(
+                "application/json",
+                Box::new(|_| {
+                    Ok(Response::text(StatusCode::OK, "{}")
+                        .with_header("Content-Type", "application/json; charset=utf-8"))
+                }),
+            )
+            .into_handler();
+        let request = |accept: &str| {
+            let raw = format!("GET / HTTP/1.1\r\nAccept: {accept}\r\n\r\n");
+            Request::from_reader(&mut raw.as_bytes()).unwrap()
+        };
+
+        let response = handler(&request("text/html")).unwrap();
+        assert_eq!(response.header("Content-Type"), Some("text/html"));
+        assert_eq!(response.header("Vary"), Some("Accept"));
+        let response = handler(&request("application/json")).unwrap();
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("application/json; charset=utf-8")
+        );
+        let error = handler(&request("image/*")).err().unwrap();
+        assert_eq!(error.status_code().code(), 406);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::digest::{hex, sha256};
+use crate::webserver::{RequestType, Resource, ResourceType, Response, StatusCode};
+use ab_glyph::{point, Font, FontVec, PxScale, ScaleFont};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// The size recommended for Open Graph images, used when there is no background image.
+pub const WIDTH: u32 = 1200;
+pub const HEIGHT: u32 = 630;
+
+/// Cached images are dropped once this many different cards have been rendered.
+const MAX_CACHED: usize = 256;
+
+/// Titles are wrapped over at most this many lines, shrinking the text until they fit.
+const MAX_LINES: usize = 3;
+const MAX_FONT_SIZE: f32 = 72.0;
+const MIN_FONT_SIZE: f32 = 40.0;
+
+/// Looks up the title of a blog post by the `post` query parameter.
+pub type TitleLookup = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// Renders Open Graph preview images ("social cards"): a title over a background, as a PNG.
+pub struct CardTemplate {
+    font: FontVec,
+    width: u32,
+    height: u32,
+    /// RGB pixels, row by row.
+    background: Vec<u8>,
+    text_color: [u8; 3],
+    margin: f32,
+}
+
+impl CardTemplate {
+    /// A template with a plain white background, using this TrueType or OpenType font.
+    pub fn new(font: Vec<u8>) -> Result<Self, String> {
+        let font = FontVec::try_from_vec(font).map_err(|e| format!("Invalid font: {e}"))?;
+        Ok(Self {
+            font,
+            width: WIDTH,
+            height: HEIGHT,
+            background: vec![0xff; (WIDTH * HEIGHT * 3) as usize],
+            text_color: [0x21, 0x25, 0x29],
+            margin: 80.0,
+        })
+    }
+
+    /// A template with the font and background image (a PNG, which sets the card size) from
+    /// these files.
+    pub fn from_files(
+        font: impl AsRef<Path>,
+        background: impl AsRef<Path>,
+    ) -> Result<Self, String> {
+        let font = fs::read(font).map_err(|e| format!("Failed to read font: {e}"))?;
+        let background =
+            fs::read(background).map_err(|e| format!("Failed to read background: {e}"))?;
+        Self::new(font)?.with_background(&background)
+    }
+
+    /// Draw the title over this PNG image, which sets the card size.
+    pub fn with_background(mut self, png: &[u8]) -> Result<Self, String> {
+        let mut decoder = png::Decoder::new(png);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder
+            .read_info()
+            .map_err(|e| format!("Failed to read background: {e}"))?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut pixels)
+            .map_err(|e| format!("Failed to read background: {e}"))?;
+        pixels.truncate(info.buffer_size());
+
+        self.background = match info.color_type {
+            png::ColorType::Rgb => pixels,
+            png::ColorType::Rgba => pixels
+                .chunks_exact(4)
+                .flat_map(|p| [p[0], p[1], p[2]])
+                .collect(),
+            png::ColorType::Grayscale => pixels.iter().flat_map(|&p| [p, p, p]).collect(),
+            png::ColorType::GrayscaleAlpha => pixels
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[0], p[0]])
+                .collect(),
+            png::ColorType::Indexed => return Err("Unexpected indexed background".to_string()),
+        };
+        self.width = info.width;
+        self.height = info.height;
+        Ok(self)
+    }
+
+    pub fn with_text_color(mut self, rgb: [u8; 3]) -> Self {
+        self.text_color = rgb;
+        self
+    }
+
+    /// Pixels kept free around the title.
+    pub fn with_margin(mut self, margin: f32) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    /// Render the card for this title as a PNG.
+    pub fn render(&self, title: &str) -> Result<Vec<u8>, String> {
+        let max_width = self.width as f32 - 2.0 * self.margin;
+        let mut size = MAX_FONT_SIZE;
+        let mut lines = self.wrap(title, size, max_width);
+        while lines.len() > MAX_LINES && size > MIN_FONT_SIZE {
+            size -= 8.0;
+            lines = self.wrap(title, size, max_width);
+        }
+        if lines.len() > MAX_LINES {
+            lines.truncate(MAX_LINES);
+            lines[MAX_LINES - 1].push('…');
+        }
+
+        let font = self.font.as_scaled(PxScale::from(size));
+        let line_height = font.height() + font.line_gap();
+        let top = (self.height as f32 - line_height * lines.len() as f32) / 2.0;
+        let mut pixels = self.background.clone();
+        for (i, line) in lines.iter().enumerate() {
+            let baseline = top + line_height * i as f32 + font.ascent();
+            self.draw_line(&mut pixels, line, size, self.margin, baseline);
+        }
+
+        let mut output = vec![];
+        let mut encoder = png::Encoder::new(&mut output, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder
+            .write_header()
+            .map_err(|e| format!("Failed to write PNG: {e}"))?;
+        writer
+            .write_image_data(&pixels)
+            .map_err(|e| format!("Failed to write PNG: {e}"))?;
+        writer
+            .finish()
+            .map_err(|e| format!("Failed to write PNG: {e}"))?;
+        Ok(output)
+    }
+
+    /// Split the text into lines no wider than `max_width`, breaking between words.
+    fn wrap(&self, text: &str, size: f32, max_width: f32) -> Vec<String> {
+        let mut lines: Vec<String> = vec![];
+        for word in text.split_whitespace() {
+            match lines.last_mut() {
+                Some(line) if self.width_of(&format!("{line} {word}"), size) <= max_width => {
+                    line.push(' ');
+                    line.push_str(word);
+                }
+                _ => lines.push(word.to_string()),
+            }
+        }
+        lines
+    }
+
+    fn width_of(&self, text: &str, size: f32) -> f32 {
+        let font = self.font.as_scaled(PxScale::from(size));
+        let mut width = 0.0;
+        let mut previous = None;
+        for c in text.chars() {
+            let id = font.glyph_id(c);
+            if let Some(previous) = previous {
+                width += font.kern(previous, id);
+            }
+            width += font.h_advance(id);
+            previous = Some(id);
+        }
+        width
+    }
+
+    fn draw_line(&self, pixels: &mut [u8], text: &str, size: f32, x: f32, baseline: f32) {
+        let font = self.font.as_scaled(PxScale::from(size));
+        let mut caret = x;
+        let mut previous = None;
+        for c in text.chars() {
+            let id = font.glyph_id(c);
+            if let Some(previous) = previous {
+                caret += font.kern(previous, id);
+            }
+            let glyph = id.with_scale_and_position(size, point(caret, baseline));
+            caret += font.h_advance(id);
+            previous = Some(id);
+
+            let Some(outline) = self.font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|gx, gy, coverage| {
+                let (px, py) = (
+                    bounds.min.x as i64 + gx as i64,
+                    bounds.min.y as i64 + gy as i64,
+                );
+                if px < 0 || py < 0 || px >= self.width as i64 || py >= self.height as i64 {
+                    return;
+                }
+                let offset = (py as usize * self.width as usize + px as usize) * 3;
+                for (channel, text) in pixels[offset..offset + 3].iter_mut().zip(self.text_color) {
+                    *channel = (f32::from(*channel) * (1.0 - coverage) + f32::from(text) * coverage)
+                        .round() as u8;
+                }
+            });
+        }
+    }
+
+    /// A GET resource at `path` rendering the card for `?post=<slug>`, with the title looked up
+    /// by `titles`. Unknown posts are answered with 404. Cards are cached by a hash of their
+    /// title, which is also sent as the ETag.
+    pub fn resource(self, path: &str, titles: TitleLookup) -> Resource {
+        let cache: Mutex<HashMap<String, Arc<Vec<u8>>>> = Mutex::new(HashMap::new());
+        Resource::new(
+            RequestType::GET,
+            path.to_string(),
+            ResourceType::BINARY,
+            Box::new(move |request| {
+                let Some(title) = request
+                    .query_param_decoded("post")
+                    .and_then(|post| titles(&post))
+                else {
+                    return Ok(Response::empty(StatusCode::NotFound));
+                };
+                let key = hex(&sha256(title.as_bytes()));
+                let cached = cache.lock().map_err(|e| e.to_string())?.get(&key).cloned();
+                let image = match cached {
+                    Some(image) => image,
+                    None => {
+                        let image = Arc::new(self.render(&title)?);
+                        let mut cache = cache.lock().map_err(|e| e.to_string())?;
+                        if cache.len() >= MAX_CACHED {
+                            cache.clear();
+                        }
+                        cache.insert(key.clone(), Arc::clone(&image));
+                        image
+                    }
+                };
+                Ok(Response::bytes(StatusCode::OK, image.to_vec())
+                    .with_header("Content-Type", "image/png")
+                    .with_header("ETag", format!("\"{key}\""))
+                    .with_header("Cache-Control", "public, max-age=3600"))
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_and_render() {
+        // Rendering needs a real font, which is only there on systems with DejaVu installed.
+        let Ok(font) = fs::read("/usr/share/fonts/truetype/dejavu/DejaVuSans-Bold.ttf") else {
+            return;
+        };
+        let template = CardTemplate::new(font.clone()).unwrap();
+        let title = "Writing a web server from scratch in Rust, without any dependencies at all";
+        let lines = template.wrap(title, MAX_FONT_SIZE, WIDTH as f32 - 160.0);
+        assert!(lines.len() > 1);
+        assert_eq!(lines.join(" "), title);
+
+        let png = template.render(title).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        let background = template.render("").unwrap();
+        assert_ne!(png, background);
+
+        let card = CardTemplate::new(font)
+            .unwrap()
+            .with_background(&png)
+            .unwrap();
+        assert_eq!((card.width, card.height), (WIDTH, HEIGHT));
+    }
+
+    #[test]
+    fn invalid_font() {
+        assert!(CardTemplate::new(b"not a font".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::webserver::{Request, Response};
+
+/// The page of a list asked for with the `page` and `per_page` query parameters, such as
+/// `/blog?page=2&per_page=10`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PageRequest {
+    /// Starts at 1.
+    pub page: usize,
+    pub per_page: usize,
+}
+
+impl PageRequest {
+    /// Read the page from the request's query. Missing or invalid values fall back to the first
+    /// page of `default_per_page` items, and `per_page` is kept between 1 and `max_per_page`.
+    pub fn from_request(request: &Request, default_per_page: usize, max_per_page: usize) -> Self {
+        let param = |name| {
+            request
+                .query_param(name)
+                .and_then(|value| value.parse::<usize>().ok())
+                .filter(|value| *value > 0)
+        };
+        Self {
+            page: param("page").unwrap_or(1),
+            per_page: param("per_page")
+                .unwrap_or(default_per_page)
+                .clamp(1, max_per_page.max(1)),
+        }
+    }
+
+    /// The index of the first item on the page.
+    pub fn offset(&self) -> usize {
+        (self.page - 1).saturating_mul(self.per_page)
+    }
+
+    /// The items on the page, which is empty past the last page.
+    pub fn slice<'a, T>(&self, items: &'a [T]) -> &'a [T] {
+        let start = self.offset().min(items.len());
+        let end = start.saturating_add(self.per_page).min(items.len());
+        &items[start..end]
+    }
+
+    /// Where the page is among `total` items.
+    pub fn info(&self, total: usize) -> PageInfo {
+        let total_pages = total.div_ceil(self.per_page).max(1);
+        PageInfo {
+            page: self.page,
+            per_page: self.per_page,
+            total,
+            total_pages,
+            prev: (self.page > 1).then(|| (self.page - 1).min(total_pages)),
+            next: (self.page < total_pages).then_some(self.page + 1),
+        }
+    }
+}
+
+/// Where a page is among all items, for rendering page links.
+///
+/// With the `json` feature this serializes to an object with the same fields.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PageInfo {
+    pub page: usize,
+    pub per_page: usize,
+    pub total: usize,
+    /// At least 1, even without items.
+    pub total_pages: usize,
+    pub prev: Option<usize>,
+    pub next: Option<usize>,
+}
+
+impl PageInfo {
+    /// The URL of `page`, which is the request's path and query with its `page` parameter
+    /// replaced.
+    pub fn url(&self, request: &Request, page: usize) -> String {
+        let mut query: Vec<String> = request
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty() && pair.split('=').next() != Some("page"))
+            .map(str::to_string)
+            .collect();
+        query.push(format!("page={page}"));
+        format!("{}?{}", request.path(), query.join("&"))
+    }
+
+    /// A `Link` header value pointing to the first, previous, next and last pages.
+    pub fn link_header(&self, request: &Request) -> String {
+        let mut links = vec![format!("<{}>; rel=\"first\"", self.url(request, 1))];
+        if let Some(prev) = self.prev {
+            links.push(format!("<{}>; rel=\"prev\"", self.url(request, prev)));
+        }
+        if let Some(next) = self.next {
+            links.push(format!("<{}>; rel=\"next\"", self.url(request, next)));
+        }
+        links.push(format!(
+            "<{}>; rel=\"last\"",
+            self.url(request, self.total_pages)
+        ));
+        links.join(", ")
+    }
+
+    /// Add the `Link` header, along with an `X-Total-Count` header with the number of items.
+    pub fn apply(&self, request: &Request, response: &mut Response) {
+        response.add_header("Link", self.link_header(request));
+        response.add_header("X-Total-Count", self.total.to_string());
+    }
+}
+
+#[cfg(feature = "json")]
+impl serde::Serialize for PageInfo {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("PageInfo", 6)?;
+        state.serialize_field("page", &self.page)?;
+        state.serialize_field("per_page", &self.per_page)?;
+        state.serialize_field("total", &self.total)?;
+        state.serialize_field("total_pages", &self.total_pages)?;
+        state.serialize_field("prev", &self.prev)?;
+        state.serialize_field("next", &self.next)?;
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    fn page(page: usize, per_page: usize) -> PageRequest {
+        PageRequest { page, per_page }
+    }
+
+    fn request(target: &str) -> Request {
+        let text = format!("GET {target} HTTP/1.1\r\n\r\n");
+        Request::from_reader(&mut BufReader::new(text.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn from_request() {
+        let parse = |target| PageRequest::from_request(&request(target), 10, 50);
+        assert_eq!(parse("/blog"), page(1, 10));
+        assert_eq!(parse("/blog?page=3&per_page=1000"), page(3, 50));
+        assert_eq!(parse("/blog?page=0&per_page=x"), page(1, 10));
+
+        let items: Vec<usize> = (0..25).collect();
+        assert_eq!(page(3, 10).offset(), 20);
+        assert_eq!(page(3, 10).slice(&items), &[20, 21, 22, 23, 24]);
+        assert!(page(4, 10).slice(&items).is_empty());
+    }
+
+    #[test]
+    fn info_and_links() {
+        let request = request("/search?q=rust&page=2");
+        let info = PageRequest::from_request(&request, 10, 50).info(25);
+        assert_eq!(
+            info,
+            PageInfo {
+                page: 2,
+                per_page: 10,
+                total: 25,
+                total_pages: 3,
+                prev: Some(1),
+                next: Some(3),
+            }
+        );
+        assert_eq!(
+            info.link_header(&request),
+            "</search?q=rust&page=1>; rel=\"first\", </search?q=rust&page=1>; rel=\"prev\", \
+             </search?q=rust&page=3>; rel=\"next\", </search?q=rust&page=3>; rel=\"last\""
+        );
+
+        let info = page(9, 10).info(0);
+        assert_eq!((info.total_pages, info.prev, info.next), (1, Some(1), None));
+    }
+}
@@ -0,0 +1,269 @@
+use crate::webserver::{Connection, Request};
+#[cfg(target_os = "linux")]
+use std::fs::File;
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr},
+    time::Duration,
+};
+
+/// How a PROXY protocol v1 header starts.
+pub(crate) const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest v1 header, including the CRLF.
+pub(crate) const V1_MAX_LENGTH: usize = 107;
+/// How a PROXY protocol v2 header starts.
+pub(crate) const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read the PROXY protocol header a load balancer such as HAProxy sends before the first
+/// request, without reading any further. Returns the address of the client, or `None` for
+/// connections the proxy makes itself, such as health checks.
+pub(crate) fn read_header(reader: &mut impl Read) -> Result<Option<SocketAddr>, String> {
+    let read_error = |e: io::Error| format!("Failed to read PROXY header: {e}");
+    let mut head = [0; 16];
+    reader.read_exact(&mut head[..8]).map_err(read_error)?;
+    if head.starts_with(V1_PREFIX) {
+        // The request follows right after, so the line is read a byte at a time.
+        let mut line = head[..8].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LENGTH {
+                return Err("PROXY header too long".to_string());
+            }
+            let mut byte = [0];
+            reader.read_exact(&mut byte).map_err(read_error)?;
+            line.push(byte[0]);
+        }
+        return parse_v1(&line);
+    }
+    reader.read_exact(&mut head[8..]).map_err(read_error)?;
+    let mut addresses = vec![0; v2_length(&head)?];
+    reader.read_exact(&mut addresses).map_err(read_error)?;
+    parse_v2(&head, &addresses)
+}
+
+/// Parse a v1 header such as `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`.
+pub(crate) fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, String> {
+    let invalid = || "Invalid PROXY header".to_string();
+    let line = std::str::from_utf8(line).map_err(|_| invalid())?;
+    let line = line.strip_suffix("\r\n").ok_or_else(invalid)?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| invalid())?;
+            let port = port.parse::<u16>().map_err(|_| invalid())?;
+            match (*protocol, ip) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {
+                    Ok(Some(SocketAddr::new(ip, port)))
+                }
+                _ => Err(invalid()),
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// The length of the addresses following the first 16 bytes of a v2 header.
+pub(crate) fn v2_length(head: &[u8; 16]) -> Result<usize, String> {
+    if !head.starts_with(V2_SIGNATURE) || head[12] >> 4 != 2 {
+        return Err("Invalid PROXY header".to_string());
+    }
+    Ok(u16::from_be_bytes([head[14], head[15]]) as usize)
+}
+
+/// Parse a v2 header from its first 16 bytes and the addresses after them. Only the source
+/// address of TCP and UDP over IPv4 and IPv6 is used.
+pub(crate) fn parse_v2(head: &[u8; 16], addresses: &[u8]) -> Result<Option<SocketAddr>, String> {
+    match head[12] & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err("Invalid PROXY command".to_string()),
+    }
+    let too_short = || "PROXY addresses too short".to_string();
+    match head[13] >> 4 {
+        1 => {
+            let a = addresses.get(..12).ok_or_else(too_short)?;
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            let port = u16::from_be_bytes([a[8], a[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        2 => {
+            let a = addresses.get(..36).ok_or_else(too_short)?;
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&a[..16]).unwrap());
+            let port = u16::from_be_bytes([a[32], a[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port)))
+        }
+        // Unspecified or Unix domain sockets, which have no address to use.
+        _ => Ok(None),
+    }
+}
+
+/// The client a request from `peer` was forwarded for, if `peer` is one of the `trusted`
+/// proxies.
+///
+/// That is the last address in `X-Forwarded-For` that isn't a trusted proxy itself, as the
+/// ones before it could have been made up by the client, or else the one in `X-Real-IP`.
+pub(crate) fn forwarded_client(
+    request: &Request,
+    peer: IpAddr,
+    trusted: &[IpAddr],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: IpAddr| trusted.contains(&ip.to_canonical());
+    if !is_trusted(peer) {
+        return None;
+    }
+    let forwarded = request
+        .headers()
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("X-Forwarded-For"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(parse_ip)
+        .collect::<Vec<_>>();
+    let mut client = None;
+    for ip in forwarded.into_iter().rev() {
+        // Anything before an address that doesn't parse can't be relied on.
+        client = Some(ip?);
+        if !client.is_some_and(is_trusted) {
+            break;
+        }
+    }
+    client.or_else(|| request.header("X-Real-IP").and_then(parse_ip))
+}
+
+/// An address from a forwarding header, which may come with a port.
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+/// A connection whose client address came from a PROXY header.
+pub(crate) struct Proxied<C> {
+    pub(crate) stream: C,
+    pub(crate) remote_addr: Option<SocketAddr>,
+}
+
+impl<C: Connection> Read for Proxied<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<C: Connection> Write for Proxied<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<C: Connection> Connection for Proxied<C> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_file(&self, file: &File, length: u64) -> io::Result<bool> {
+        self.stream.send_file(file, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_headers() {
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let mut reader = v1.as_slice();
+        assert_eq!(
+            read_header(&mut reader),
+            Ok(Some("192.0.2.1:56324".parse().unwrap()))
+        );
+        assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+        assert_eq!(
+            read_header(&mut b"PROXY TCP6 2001:db8::1 2001:db8::2 80 443\r\n".as_slice()),
+            Ok(Some("[2001:db8::1]:80".parse().unwrap()))
+        );
+        assert_eq!(read_header(&mut b"PROXY UNKNOWN\r\n".as_slice()), Ok(None));
+        assert!(read_header(&mut b"PROXY TCP4 2001:db8::1 ::1 1 2\r\n".as_slice()).is_err());
+        assert!(read_header(
+            &mut [b"PROXY "[..].to_vec(), vec![b'A'; 200]]
+                .concat()
+                .as_slice()
+        )
+        .is_err());
+        assert!(read_header(&mut b"GET / HTTP/1.1\r\n\r\n".as_slice()).is_err());
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04]);
+        v2.extend_from_slice(&[1, 187, b'G']);
+        let mut reader = v2.as_slice();
+        assert_eq!(
+            read_header(&mut reader),
+            Ok(Some("192.0.2.1:56324".parse().unwrap()))
+        );
+        assert_eq!(reader, b"G");
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut local.as_slice()), Ok(None));
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend_from_slice(&[0x21, 0x21, 0, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(read_header(&mut short.as_slice()).is_err());
+    }
+
+    #[test]
+    fn forwarded() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let request = |headers: &str| {
+            let request = format!("GET / HTTP/1.1\r\n{headers}\r\n");
+            Request::from_reader(&mut request.as_bytes()).unwrap()
+        };
+        let trusted = [proxy, "10.0.0.2".parse().unwrap()];
+        let client = |headers, peer: &str| {
+            let peer = peer.parse::<SocketAddr>().unwrap().ip();
+            forwarded_client(&request(headers), peer, &trusted)
+        };
+
+        let spoofed = "X-Forwarded-For: 1.1.1.1, 203.0.113.7\r\n";
+        assert_eq!(client(spoofed, "10.0.0.1:80"), "203.0.113.7".parse().ok());
+        assert_eq!(
+            client(spoofed, "[::ffff:10.0.0.1]:80"),
+            "203.0.113.7".parse().ok()
+        );
+        assert_eq!(client(spoofed, "192.0.2.9:80"), None);
+        let chained = "X-Forwarded-For: 203.0.113.7:5000\r\nX-Forwarded-For: 10.0.0.2\r\n";
+        assert_eq!(client(chained, "10.0.0.1:80"), "203.0.113.7".parse().ok());
+        assert_eq!(
+            client("X-Forwarded-For: 10.0.0.2\r\n", "10.0.0.1:80"),
+            "10.0.0.2".parse().ok()
+        );
+        assert_eq!(
+            client("X-Forwarded-For: nonsense, 10.0.0.2\r\n", "10.0.0.1:80"),
+            None
+        );
+        assert_eq!(
+            client("X-Real-IP: 203.0.113.8\r\n", "10.0.0.1:80"),
+            "203.0.113.8".parse().ok()
+        );
+        assert_eq!(client("", "10.0.0.1:80"), None);
+    }
+}
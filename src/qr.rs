@@ -0,0 +1,166 @@
+use crate::digest::{hex, sha256};
+use crate::webserver::{Error, Request, RequestType, Resource, ResourceType, Response, StatusCode};
+use qrcode::{render::svg, Color, EcLevel, QrCode};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Longest string that will be encoded, QR codes for more data get hard to scan.
+pub const MAX_DATA_LENGTH: usize = 512;
+
+/// Cached images are dropped once this many different codes have been rendered.
+const MAX_CACHED: usize = 256;
+
+/// Pixels per module in PNG output.
+const PNG_MODULE_SIZE: usize = 8;
+
+/// Modules of white space around the code.
+const QUIET_ZONE: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QrFormat {
+    Svg,
+    Png,
+}
+
+impl QrFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            QrFormat::Svg => "image/svg+xml",
+            QrFormat::Png => "image/png",
+        }
+    }
+}
+
+/// Render `data` (such as a URL, vCard or payment address) as a QR code image.
+pub fn render(data: &str, format: QrFormat) -> Result<Vec<u8>, String> {
+    let code = QrCode::with_error_correction_level(data, EcLevel::M)
+        .map_err(|e| format!("Failed to encode QR code: {e}"))?;
+    match format {
+        QrFormat::Svg => Ok(code
+            .render::<svg::Color>()
+            .quiet_zone(true)
+            .min_dimensions(256, 256)
+            .build()
+            .into_bytes()),
+        QrFormat::Png => png(&code),
+    }
+}
+
+/// Encode the code as an 8-bit grayscale PNG.
+fn png(code: &QrCode) -> Result<Vec<u8>, String> {
+    let modules = code.width();
+    let size = (modules + 2 * QUIET_ZONE) * PNG_MODULE_SIZE;
+    let colors = code.to_colors();
+    let mut pixels = vec![0xff; size * size];
+    for y in 0..size {
+        for x in 0..size {
+            let (mx, my) = (x / PNG_MODULE_SIZE, y / PNG_MODULE_SIZE);
+            if (QUIET_ZONE..QUIET_ZONE + modules).contains(&mx)
+                && (QUIET_ZONE..QUIET_ZONE + modules).contains(&my)
+                && colors[(my - QUIET_ZONE) * modules + mx - QUIET_ZONE] == Color::Dark
+            {
+                pixels[y * size + x] = 0;
+            }
+        }
+    }
+
+    let mut output = vec![];
+    let mut encoder = png::Encoder::new(&mut output, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| format!("Failed to write PNG: {e}"))?;
+    writer
+        .write_image_data(&pixels)
+        .map_err(|e| format!("Failed to write PNG: {e}"))?;
+    writer
+        .finish()
+        .map_err(|e| format!("Failed to write PNG: {e}"))?;
+    Ok(output)
+}
+
+/// A GET resource at `path` that renders `?data=...&format=svg|png` (svg by default) as a QR
+/// code. Images are cached by a hash of their content, which is also sent as the ETag.
+pub fn resource(path: &str) -> Resource {
+    let cache: Mutex<HashMap<String, Arc<Vec<u8>>>> = Mutex::new(HashMap::new());
+    Resource::new(
+        RequestType::GET,
+        path.to_string(),
+        ResourceType::BINARY,
+        Box::new(move |request| handle(request, &cache)),
+    )
+}
+
+fn handle(
+    request: &Request,
+    cache: &Mutex<HashMap<String, Arc<Vec<u8>>>>,
+) -> Result<Response, Error> {
+    let Some(data) = request.query_param_decoded("data") else {
+        return Ok(Response::text(StatusCode::BadRequest, "Missing data"));
+    };
+    if data.is_empty() || data.len() > MAX_DATA_LENGTH {
+        return Ok(Response::text(
+            StatusCode::BadRequest,
+            format!("Data must be 1 to {MAX_DATA_LENGTH} bytes"),
+        ));
+    }
+    let format = match request.query_param("format") {
+        None | Some("svg") => QrFormat::Svg,
+        Some("png") => QrFormat::Png,
+        Some(_) => return Ok(Response::text(StatusCode::BadRequest, "Unknown format")),
+    };
+
+    let key = hex(&sha256(format!("{format:?}\n{data}").as_bytes()));
+    let cached = cache.lock().map_err(|e| e.to_string())?.get(&key).cloned();
+    let image = match cached {
+        Some(image) => image,
+        None => {
+            let image = Arc::new(render(&data, format)?);
+            let mut cache = cache.lock().map_err(|e| e.to_string())?;
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+            cache.insert(key.clone(), Arc::clone(&image));
+            image
+        }
+    };
+
+    Ok(Response::bytes(StatusCode::OK, image.to_vec())
+        .with_header("Content-Type", format.content_type())
+        .with_header("ETag", format!("\"{key}\""))
+        .with_header("Cache-Control", "public, max-age=31536000, immutable"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_formats() {
+        let svg =
+            String::from_utf8(render("https://daanlubbers.nl", QrFormat::Svg).unwrap()).unwrap();
+        assert!(svg.contains("<svg"));
+
+        let png = render("https://daanlubbers.nl", QrFormat::Png).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[test]
+    fn handle_request() {
+        let cache = Mutex::new(HashMap::new());
+        let request = |target: &str| {
+            let raw = format!("GET {target} HTTP/1.1\r\n\r\n");
+            Request::from_reader(&mut raw.as_bytes()).unwrap()
+        };
+
+        assert!(handle(&request("/qr?data=hello%20world&format=png"), &cache).is_ok());
+        assert_eq!(cache.lock().unwrap().len(), 1);
+        assert!(handle(&request("/qr?data=hello+world&format=png"), &cache).is_ok());
+        assert_eq!(cache.lock().unwrap().len(), 1);
+        assert!(handle(&request("/qr?data=hello+world"), &cache).is_ok());
+        assert_eq!(cache.lock().unwrap().len(), 2);
+    }
+}
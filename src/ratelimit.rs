@@ -0,0 +1,171 @@
+use crate::log;
+use crate::webserver::{Middleware, Request, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+/// Buckets are cleaned up once this many clients are tracked.
+const MAX_TRACKED: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Middleware that limits the number of requests per client IP with a token bucket, answering
+/// 429 with `Retry-After` when a client goes over the limit.
+///
+/// Every client can make `burst` requests at once, after which the bucket refills at `rate`
+/// requests per second. With a ban set, clients that go over the limit are refused for the
+/// whole ban duration.
+pub struct RateLimit {
+    /// The rate and the burst, which can be changed while the server runs.
+    limit: RwLock<(f64, f64)>,
+    ban: Option<Duration>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimit {
+    /// With a `rate` of 0, the bucket never refills, so clients are told to retry after the
+    /// longest time there is.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            limit: RwLock::new((rate, f64::from(burst))),
+            ban: None,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_ban(mut self, ban: Duration) -> Self {
+        self.ban = Some(ban);
+        self
+    }
+
+    /// Change the rate and burst, such as after the configuration changed. Clients keep the
+    /// tokens they have, up to the new burst.
+    pub fn set_limit(&self, rate: f64, burst: u32) {
+        *self.limit.write().unwrap() = (rate, f64::from(burst));
+    }
+
+    /// Take a token for a request from `ip` at `now`. Returns how long to wait if there is none.
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let (rate, burst) = *self.limit.read().unwrap();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED {
+            buckets.retain(|_, bucket| !refill(bucket, now, rate, burst));
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            banned_until: None,
+        });
+        if let Some(banned_until) = bucket.banned_until {
+            if banned_until > now {
+                return Err(banned_until - now);
+            }
+            bucket.banned_until = None;
+        }
+
+        refill(bucket, now, rate, burst);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        match self.ban {
+            Some(ban) => {
+                bucket.banned_until = Some(now + ban);
+                Err(ban)
+            }
+            None => {
+                Err(Duration::try_from_secs_f64((1.0 - bucket.tokens) / rate)
+                    .unwrap_or(Duration::MAX))
+            }
+        }
+    }
+}
+
+/// Add the tokens earned since the last update. Returns whether the bucket is full again and
+/// not banned, so it no longer needs to be tracked.
+fn refill(bucket: &mut Bucket, now: Instant, rate: f64, burst: f64) -> bool {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+    bucket.updated = now;
+    bucket.tokens >= burst && bucket.banned_until.is_none_or(|until| until <= now)
+}
+
+impl Middleware for RateLimit {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let ip = request.client_ip()?;
+        let wait = self.check(ip, Instant::now()).err()?;
+        log!("Rate limited {ip}");
+        Some(
+            Response::empty(StatusCode::TooManyRequests)
+                .with_header("Retry-After", retry_after(wait).to_string()),
+        )
+    }
+}
+
+/// Seconds to wait, rounded up, so clients that wait as long as they're told aren't refused
+/// again.
+fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs()
+        .saturating_add(u64::from(wait.subsec_nanos() > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn token_bucket() {
+        let limit = RateLimit::new(2.0, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limit.check(IP, start).is_ok());
+        }
+        assert_eq!(limit.check(IP, start), Err(Duration::from_millis(500)));
+        assert!(limit.check(OTHER_IP, start).is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limit.check(IP, later).is_ok());
+        assert!(limit.check(IP, later).is_err());
+
+        limit.set_limit(4.0, 1);
+        let later = later + Duration::from_millis(250);
+        assert!(limit.check(IP, later).is_ok());
+        assert_eq!(limit.check(IP, later), Err(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn no_refill() {
+        let limit = RateLimit::new(0.0, 1);
+        let start = Instant::now();
+        assert!(limit.check(IP, start).is_ok());
+        assert_eq!(limit.check(IP, start), Err(Duration::MAX));
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(limit.check(IP, later), Err(Duration::MAX));
+        assert_eq!(retry_after(Duration::MAX), u64::MAX);
+        assert_eq!(retry_after(Duration::from_millis(1500)), 2);
+    }
+
+    #[test]
+    fn ban() {
+        let limit = RateLimit::new(10.0, 1).with_ban(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(limit.check(IP, start).is_ok());
+        assert_eq!(limit.check(IP, start), Err(Duration::from_secs(60)));
+
+        let later = start + Duration::from_secs(30);
+        assert_eq!(limit.check(IP, later), Err(Duration::from_secs(30)));
+        assert!(limit.check(IP, start + Duration::from_secs(60)).is_ok());
+    }
+}
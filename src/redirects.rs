@@ -0,0 +1,513 @@
+use crate::log;
+use crate::metrics::Metrics;
+use crate::scheduler::Scheduler;
+use crate::webserver::{
+    Middleware, Request, RequestType, Resource, ResourceType, Response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Extensions of the paths of the previous website, which the current one doesn't use.
+pub const LEGACY_EXTENSIONS: &[&str] = &["php", "asp", "aspx", "jsp", "cgi", "htm", "html"];
+
+/// How many distinct unmatched paths are counted, so scanners can't grow the audit unbounded.
+const MAX_UNMATCHED: usize = 10_000;
+
+/// Where a redirected path goes.
+#[derive(Clone, Copy)]
+struct Target {
+    index: usize,
+    status_code: StatusCode,
+}
+
+#[derive(Default)]
+struct Table {
+    paths: HashMap<String, Target>,
+    locations: Vec<String>,
+}
+
+/// Middleware redirecting GET requests for old paths, such as the legacy URLs of the previous
+/// website, with a table loaded from a file.
+///
+/// Files ending in `.csv` have a `from,to` line per redirect, optionally followed by the
+/// status: 301 (the default), 302, 303 or 307. Other files are read as an nginx map, with a
+/// `/from /to;` line per redirect, which may be inside a `map $uri $new_uri { ... }` block.
+/// Lines starting with `#` are comments. The query string of a request is kept unless the
+/// target has one of its own. Call `schedule` to pick up changes to the file without a restart.
+///
+/// To audit the migration, every redirect is logged, as is every 404 for a path that looks like
+/// a legacy URL, going by its extension. Both are counted, in the metrics when set with
+/// `with_metrics`, and `schedule_report` regularly logs the most requested unmatched paths.
+pub struct Redirects {
+    path: PathBuf,
+    table: RwLock<Table>,
+    modified: RwLock<Option<SystemTime>>,
+    legacy_extensions: Vec<String>,
+    redirected: Arc<AtomicU64>,
+    not_found: Arc<AtomicU64>,
+    unmatched: Mutex<HashMap<String, u64>>,
+}
+
+impl Redirects {
+    /// Load the redirects from `path`. A missing file has no redirects.
+    pub fn from_file(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let redirects = Self {
+            path: path.into(),
+            table: RwLock::default(),
+            modified: RwLock::new(None),
+            legacy_extensions: LEGACY_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            redirected: Arc::default(),
+            not_found: Arc::default(),
+            unmatched: Mutex::default(),
+        };
+        redirects.reload()?;
+        Ok(redirects)
+    }
+
+    /// Count redirects and legacy 404s as `legacy_redirects_total` and
+    /// `legacy_not_found_total` in `metrics`.
+    pub fn with_metrics(mut self, metrics: &Metrics) -> Self {
+        self.redirected = metrics.counter(
+            "legacy_redirects_total",
+            "Requests redirected from a legacy URL.",
+        );
+        self.not_found = metrics.counter(
+            "legacy_not_found_total",
+            "Requests for a legacy-looking URL without a redirect.",
+        );
+        self
+    }
+
+    /// Replace `LEGACY_EXTENSIONS` as the extensions of paths that look like legacy URLs.
+    pub fn with_legacy_extensions(mut self, extensions: &[&str]) -> Self {
+        self.legacy_extensions = extensions.iter().map(|e| e.to_lowercase()).collect();
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.read().unwrap().paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Where `path` redirects to, with the status to redirect with.
+    pub fn get(&self, path: &str) -> Option<(String, StatusCode)> {
+        let table = self.table.read().unwrap();
+        let target = table.paths.get(path)?;
+        Some((table.locations[target.index].clone(), target.status_code))
+    }
+
+    /// Read the file again if it changed since it was last read. Returns whether it was read.
+    /// The old redirects are kept if the file is invalid.
+    pub fn reload(&self) -> Result<bool, String> {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == *self.modified.read().unwrap() {
+            return Ok(false);
+        }
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Failed to read redirects: {e}")),
+        };
+        let table = match self.path.extension() {
+            Some(extension) if extension == "csv" => parse_csv(&contents)?,
+            _ => parse_map(&contents)?,
+        };
+        log!("Loaded {} redirects", table.paths.len());
+        *self.table.write().unwrap() = table;
+        *self.modified.write().unwrap() = modified;
+        Ok(true)
+    }
+
+    /// The number of requests redirected, and of legacy-looking requests that got a 404.
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.redirected.load(Ordering::Relaxed),
+            self.not_found.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The `count` legacy-looking paths that got a 404 most often, with how often, most
+    /// requested first.
+    pub fn top_unmatched(&self, count: usize) -> Vec<(String, u64)> {
+        let mut paths: Vec<(String, u64)> = self
+            .unmatched
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, hits)| (path.clone(), *hits))
+            .collect();
+        paths.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        paths.truncate(count);
+        paths
+    }
+
+    /// A report of the counts and the `count` most requested unmatched paths, which could use a
+    /// redirect.
+    pub fn report(&self, count: usize) -> String {
+        let (redirected, not_found) = self.counts();
+        let mut report = format!("Legacy URLs: {redirected} redirected, {not_found} not found");
+        for (path, hits) in self.top_unmatched(count) {
+            report.push_str(&format!("\n{hits:>8} {path}"));
+        }
+        report
+    }
+
+    fn looks_legacy(&self, path: &str) -> bool {
+        Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                self.legacy_extensions
+                    .iter()
+                    .any(|legacy| legacy.eq_ignore_ascii_case(extension))
+            })
+    }
+
+    /// Add a job logging the report every `interval` to the scheduler.
+    pub fn schedule_report(
+        self: &Arc<Self>,
+        scheduler: Scheduler,
+        interval: Duration,
+        count: usize,
+    ) -> Scheduler {
+        let redirects = Arc::clone(self);
+        scheduler.every("legacy URL report", interval, move || {
+            log!("{}", redirects.report(count));
+        })
+    }
+
+    /// Add a job checking the file for changes every `interval` to the scheduler.
+    pub fn schedule(self: &Arc<Self>, scheduler: Scheduler, interval: Duration) -> Scheduler {
+        let redirects = Arc::clone(self);
+        scheduler.every("redirects reload", interval, move || {
+            if let Err(e) = redirects.reload() {
+                log!("{e}");
+            }
+        })
+    }
+}
+
+impl Middleware for Redirects {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if request.request_type() != RequestType::GET {
+            return None;
+        }
+        let (location, status_code) = self.get(request.path())?;
+        let location = with_query(location, request);
+        log!("Legacy redirect: {} -> {location}", request.path());
+        self.redirected.fetch_add(1, Ordering::Relaxed);
+        Some(Response::redirect(status_code, location))
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        if response.status_code.code() != 404 || !self.looks_legacy(request.path()) {
+            return;
+        }
+        log!("Legacy URL not found: {}", request.path());
+        self.not_found.fetch_add(1, Ordering::Relaxed);
+        let mut unmatched = self.unmatched.lock().unwrap();
+        if let Some(hits) = unmatched.get_mut(request.path()) {
+            *hits += 1;
+        } else if unmatched.len() < MAX_UNMATCHED {
+            unmatched.insert(request.path().to_string(), 1);
+        }
+    }
+}
+
+/// Resources redirecting GET requests, read from a file with a `/from -> /to` line per
+/// redirect, optionally followed by the status as in `.csv` files for `Redirects`. Lines
+/// starting with `#` are comments.
+///
+/// Unlike `Redirects`, these are routed like any other resource, but aren't audited, and
+/// changes to the file take a restart. See `App::register_redirects_from`.
+pub fn resources_from_file(path: impl AsRef<Path>) -> Result<Vec<Resource>, String> {
+    let contents = fs::read_to_string(&path).map_err(|e| {
+        format!(
+            "Failed to read redirects from {}: {e}",
+            path.as_ref().display()
+        )
+    })?;
+    let table = parse_arrows(&contents)?;
+    let mut paths: Vec<(String, Target)> = table.paths.into_iter().collect();
+    paths.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(paths
+        .into_iter()
+        .map(|(from, target)| {
+            let location = table.locations[target.index].clone();
+            Resource::new(
+                RequestType::GET,
+                from,
+                ResourceType::REDIRECT,
+                Box::new(move |request| {
+                    Ok(Response::redirect(
+                        target.status_code,
+                        with_query(location.clone(), request),
+                    ))
+                }),
+            )
+        })
+        .collect())
+}
+
+/// Keep the query string of the request, unless `location` has one of its own.
+fn with_query(location: String, request: &Request) -> String {
+    match request.query() {
+        Some(query) if !location.contains('?') => format!("{location}?{query}"),
+        _ => location,
+    }
+}
+
+impl Table {
+    fn insert(&mut self, line: usize, from: &str, to: &str, status_code: StatusCode) {
+        if self.paths.contains_key(from) {
+            log!("Redirect on line {line} replaces an earlier one for {from}");
+        }
+        // Many legacy paths tend to share a target, so each target is kept once.
+        let index = match self.locations.iter().position(|location| location == to) {
+            Some(index) => index,
+            None => {
+                self.locations.push(to.to_string());
+                self.locations.len() - 1
+            }
+        };
+        self.paths
+            .insert(from.to_string(), Target { index, status_code });
+    }
+}
+
+fn parse_csv(contents: &str) -> Result<Table, String> {
+    let mut table = Table::default();
+    for (number, line) in contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+    {
+        // Also skip a header line, as spreadsheets tend to add.
+        if line.is_empty() || line.starts_with('#') || (number == 1 && line.starts_with("from,")) {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let status_code = parse_status(number, fields.get(2).copied())?;
+        match fields.as_slice() {
+            [from, to, ..] if fields.len() <= 3 && valid_path(from) && !to.is_empty() => {
+                table.insert(number, from, to, status_code)
+            }
+            _ => return Err(format!("Invalid redirect on line {number}: {line}")),
+        }
+    }
+    Ok(table)
+}
+
+fn parse_map(contents: &str) -> Result<Table, String> {
+    let mut table = Table::default();
+    for (number, line) in contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+    {
+        if line.is_empty() || line.starts_with('#') || line == "}" || line.starts_with("map ") {
+            continue;
+        }
+        let fields: Vec<&str> = line
+            .trim_end_matches(';')
+            .split_whitespace()
+            .map(|field| field.trim_matches(|c| c == '"' || c == '\''))
+            .collect();
+        match fields.as_slice() {
+            ["default", _] => {}
+            [from, to] if valid_path(from) => {
+                table.insert(number, from, to, StatusCode::PermanentRedirect)
+            }
+            // Regular expressions can't be looked up by path.
+            [from, _] if from.starts_with('~') => {
+                log!("Skipped redirect on line {number}, which isn't a plain path")
+            }
+            _ => return Err(format!("Invalid redirect on line {number}: {line}")),
+        }
+    }
+    Ok(table)
+}
+
+fn parse_arrows(contents: &str) -> Result<Table, String> {
+    let mut table = Table::default();
+    for (number, line) in contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+    {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [from, "->", to, status @ ..] if status.len() <= 1 && valid_path(from) => {
+                let status_code = parse_status(number, status.first().copied())?;
+                table.insert(number, from, to, status_code)
+            }
+            _ => return Err(format!("Invalid redirect on line {number}: {line}")),
+        }
+    }
+    Ok(table)
+}
+
+/// The status of a redirect, 301 if it isn't given.
+fn parse_status(number: usize, status: Option<&str>) -> Result<StatusCode, String> {
+    match status {
+        None | Some("") | Some("301") => Ok(StatusCode::PermanentRedirect),
+        Some("302") => Ok(StatusCode::Found),
+        Some("303") => Ok(StatusCode::SeeOther),
+        Some("307") => Ok(StatusCode::TemporaryRedirect),
+        Some(status) => Err(format!(
+            "Unsupported redirect status on line {number}: {status}"
+        )),
+    }
+}
+
+fn valid_path(path: &str) -> bool {
+    path.starts_with('/') && !path.contains(char::is_whitespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::{App, AppConfig};
+    use std::io::BufReader;
+
+    fn request(target: &str) -> Request {
+        let text = format!("GET {target} HTTP/1.1\r\n\r\n");
+        Request::from_reader(&mut BufReader::new(text.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let table = parse_map(
+            "# Legacy pages\nmap $uri $new_uri {\n    default \"\";\n    /old.php /blog;\n    \
+             \"/about.php\" \"https://www.daanlubbers.nl/\";\n    ~^/tag/(.*)$ /blog;\n}\n",
+        )
+        .unwrap();
+        assert_eq!(table.paths.len(), 2);
+        assert!(parse_map("/old.php").is_err());
+
+        let table = parse_csv("from,to,status\n/a,/b\n/c,/b,307\n").unwrap();
+        assert_eq!(table.paths.len(), 2);
+        assert_eq!(table.locations, vec!["/b"]);
+        assert!(parse_csv("/a,/b,200").is_err());
+        assert!(parse_csv("a,/b").is_err());
+
+        let table = parse_arrows("# Old site\n/a -> /b\n/c   ->  /d 302\n\n").unwrap();
+        assert_eq!(table.paths.len(), 2);
+        assert_eq!(table.paths["/c"].status_code.code(), 302);
+        assert!(parse_arrows("/a /b").is_err());
+        assert!(parse_arrows("/a -> /b 200").is_err());
+        assert!(parse_arrows("/a -> /b 301 extra").is_err());
+    }
+
+    #[test]
+    fn redirect_and_reload() {
+        let path = std::env::temp_dir().join("wwwdaanlubbersnl_redirects_test.csv");
+        fs::write(&path, "/old,/new\n/search.php,/search?legacy=1,302\n").unwrap();
+        let redirects = Redirects::from_file(&path).unwrap();
+        assert_eq!(redirects.len(), 2);
+
+        let response = redirects.before(&mut request("/old?page=2")).unwrap();
+        assert_eq!(response.status_code.code(), 301);
+        assert_eq!(response.header("Location"), Some("/new?page=2"));
+        let response = redirects.before(&mut request("/search.php?q=x")).unwrap();
+        assert_eq!(response.status_code.code(), 302);
+        assert_eq!(response.header("Location"), Some("/search?legacy=1"));
+        assert!(redirects.before(&mut request("/new")).is_none());
+
+        // Invalid changes keep the old redirects.
+        fs::write(&path, "/old").unwrap();
+        *redirects.modified.write().unwrap() = None;
+        assert!(redirects.reload().is_err());
+        assert_eq!(redirects.len(), 2);
+
+        fs::remove_file(&path).unwrap();
+        assert!(redirects.reload().unwrap());
+        assert!(redirects.is_empty());
+    }
+
+    #[test]
+    fn redirect_resources() {
+        let path = std::env::temp_dir().join("wwwdaanlubbersnl_redirects_test.txt");
+        fs::write(
+            &path,
+            "/old.php -> /blog\n/tmp.php -> /search?legacy=1 302\n",
+        )
+        .unwrap();
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut app = App::new(AppConfig::new(addr, 1, 5));
+        assert_eq!(app.register_redirects_from(&path), Ok(2));
+        fs::remove_file(&path).unwrap();
+        assert!(app.register_redirects_from(&path).is_err());
+
+        let respond = |target: &str| {
+            let mut request = request(target);
+            String::from_utf8(app.respond(&mut request).bytes).unwrap()
+        };
+        let response = respond("/old.php?page=2");
+        assert!(response.starts_with("HTTP/1.1 301 "));
+        assert!(response.contains("Location: /blog?page=2\r\n"));
+        let response = respond("/tmp.php?q=x");
+        assert!(response.starts_with("HTTP/1.1 302 "));
+        assert!(response.contains("Location: /search?legacy=1\r\n"));
+        assert!(respond("/blog").starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn audit() {
+        let metrics = Metrics::new();
+        let path = std::env::temp_dir().join("wwwdaanlubbersnl_redirects_audit_test.csv");
+        fs::write(&path, "/old.php,/new\n").unwrap();
+        let redirects = Redirects::from_file(&path).unwrap().with_metrics(&metrics);
+        fs::remove_file(&path).unwrap();
+
+        assert!(redirects.before(&mut request("/old.php")).is_some());
+        for target in [
+            "/gone.php",
+            "/gone.php?x=1",
+            "/Page.HTM",
+            "/gone.php",
+            "/missing",
+        ] {
+            let mut request = request(target);
+            assert!(redirects.before(&mut request).is_none());
+            let mut response = Response::text(StatusCode::NotFound, String::new());
+            redirects.after(&request, &mut response);
+        }
+        let mut response = Response::text(StatusCode::OK, String::new());
+        redirects.after(&request("/page.html"), &mut response);
+
+        assert_eq!(redirects.counts(), (1, 4));
+        assert_eq!(
+            redirects.top_unmatched(1),
+            vec![("/gone.php".to_string(), 3)]
+        );
+        assert_eq!(
+            redirects.report(5),
+            "Legacy URLs: 1 redirected, 4 not found\n       3 /gone.php\n       1 /Page.HTM"
+        );
+        assert_eq!(
+            metrics
+                .counter("legacy_not_found_total", "")
+                .load(Ordering::Relaxed),
+            4
+        );
+
+        let redirects = redirects.with_legacy_extensions(&["asp"]);
+        assert!(!redirects.looks_legacy("/gone.php"));
+        assert!(redirects.looks_legacy("/default.asp"));
+    }
+}
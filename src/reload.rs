@@ -0,0 +1,186 @@
+use crate::config::{ConfigFile, SERVER_KEYS};
+use crate::log;
+#[cfg(unix)]
+use crate::signals;
+use crate::webserver::{RequestType, Resource, ResourceType, Response, StatusCode};
+use std::sync::{Arc, RwLock};
+#[cfg(unix)]
+use std::{
+    io,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+type Load = Box<dyn Fn() -> Result<ConfigFile, String> + Send + Sync>;
+type Hook = Box<dyn Fn(&ConfigFile) -> Result<(), String> + Send + Sync>;
+
+/// Reads the configuration again while the server runs, on `SIGHUP` or through an admin
+/// resource, and hands the new settings to hooks that apply them, such as to a `RateLimit`,
+/// a `ResponseCache` or `ErrorPages`. What changed is logged.
+///
+/// Settings of the server itself, see `AppConfig::from_config`, need a restart, which is
+/// logged along with the change.
+pub struct ConfigReloader {
+    load: Load,
+    settings: RwLock<ConfigFile>,
+    hooks: Vec<Hook>,
+}
+
+impl ConfigReloader {
+    /// Reload with `load`, such as `ConfigFile::load` on the file `settings` were read from.
+    pub fn new(
+        settings: ConfigFile,
+        load: impl Fn() -> Result<ConfigFile, String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            load: Box::new(load),
+            settings: RwLock::new(settings),
+            hooks: vec![],
+        }
+    }
+
+    /// Run `hook` with the new settings on every reload that changed something.
+    pub fn on_reload(
+        mut self,
+        hook: impl Fn(&ConfigFile) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// The settings last read.
+    pub fn settings(&self) -> ConfigFile {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Read the configuration again and apply it. Returns what changed. Settings that can't be
+    /// read leave the current ones in place, and hooks that fail don't stop the others.
+    pub fn reload(&self) -> Result<Vec<String>, String> {
+        let settings = (self.load)()?;
+        let changes = self.settings.read().unwrap().changes(&settings);
+        if changes.is_empty() {
+            return Ok(changes);
+        }
+        for change in &changes {
+            let key = change.split(':').next().unwrap_or_default();
+            match SERVER_KEYS.contains(&key) {
+                true => log!("Configuration changed: {change} (takes a restart)"),
+                false => log!("Configuration changed: {change}"),
+            }
+        }
+        let failed: Vec<String> = self
+            .hooks
+            .iter()
+            .filter_map(|hook| hook(&settings).err())
+            .collect();
+        *self.settings.write().unwrap() = settings;
+        match failed.is_empty() {
+            true => Ok(changes),
+            false => Err(format!(
+                "Failed to apply configuration: {}",
+                failed.join(", ")
+            )),
+        }
+    }
+
+    /// Reload whenever the process gets `SIGHUP`, as with `kill -HUP`, checking for it every
+    /// second in a background thread.
+    #[cfg(unix)]
+    pub fn watch_sighup(self: &Arc<Self>) -> io::Result<JoinHandle<()>> {
+        signals::install(signals::SIGHUP)?;
+        let reloader = Arc::clone(self);
+        Ok(thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if !signals::take_hangup() {
+                continue;
+            }
+            match reloader.reload() {
+                Ok(changes) if changes.is_empty() => log!("Reloaded configuration, no changes"),
+                Ok(_) => log!("Reloaded configuration"),
+                Err(e) => log!("{e}"),
+            }
+        }))
+    }
+
+    /// A POST resource at `path` that reloads and answers with what changed. Protect the path
+    /// with `Auth`.
+    pub fn admin_resource(self: &Arc<Self>, path: &str) -> Resource {
+        let reloader = Arc::clone(self);
+        Resource::new(
+            RequestType::POST,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(move |_| {
+                let response = match reloader.reload() {
+                    Ok(changes) if changes.is_empty() => {
+                        Response::text(StatusCode::OK, "No changes\n")
+                    }
+                    Ok(changes) => Response::text(StatusCode::OK, changes.join("\n") + "\n"),
+                    Err(e) => {
+                        log!("{e}");
+                        Response::text(StatusCode::InternalServerError, e + "\n")
+                    }
+                };
+                Ok(response.with_header("Content-Type", "text/plain; charset=utf-8"))
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Value;
+    use crate::webserver::Request;
+    use std::sync::Mutex;
+
+    #[test]
+    fn reload() {
+        let file = Arc::new(Mutex::new(String::from("port = 80\nrate = 1")));
+        let source = Arc::clone(&file);
+        let applied = Arc::new(Mutex::new(vec![]));
+        let hook_applied = Arc::clone(&applied);
+        let reloader = ConfigReloader::new(
+            ConfigFile::parse(&file.lock().unwrap()).unwrap(),
+            move || ConfigFile::parse(&source.lock().unwrap()),
+        )
+        .on_reload(move |settings| {
+            let rate = settings.integer("rate")?.ok_or("rate is missing")?;
+            hook_applied.lock().unwrap().push(rate);
+            Ok(())
+        });
+        let reloader = Arc::new(reloader);
+
+        assert_eq!(reloader.reload(), Ok(vec![]));
+        *file.lock().unwrap() = "port = 81\nrate = 2".to_string();
+        assert_eq!(
+            reloader.reload(),
+            Ok(vec![
+                "port: 80 -> 81".to_string(),
+                "rate: 1 -> 2".to_string()
+            ])
+        );
+        assert_eq!(*applied.lock().unwrap(), [2]);
+        assert_eq!(reloader.settings().get("rate"), Some(&Value::Integer(2)));
+
+        // Settings that can't be read are left alone, hooks that fail are reported.
+        *file.lock().unwrap() = "port = ".to_string();
+        assert!(reloader.reload().is_err());
+        assert_eq!(reloader.settings().get("port"), Some(&Value::Integer(81)));
+        *file.lock().unwrap() = "port = 81".to_string();
+        assert_eq!(
+            reloader.reload(),
+            Err("Failed to apply configuration: rate is missing".to_string())
+        );
+
+        let resource = reloader.admin_resource("/admin/reload");
+        let request =
+            Request::from_reader(&mut "POST /admin/reload HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+        *file.lock().unwrap() = "port = 81\nrate = 3".to_string();
+        let response = resource.handle(&request).unwrap();
+        assert!(
+            matches!(&response.body, crate::webserver::Body::Text(text) if text == "rate: unset -> 3\n")
+        );
+        assert_eq!(*applied.lock().unwrap(), [2, 3]);
+    }
+}
@@ -0,0 +1,400 @@
+use crate::webserver::{Error, Middleware, Request, RequestType, Resource, ResourceType, Response};
+use std::{borrow::Cow, collections::HashMap, sync::Mutex};
+
+/// The method and path of every resource registered with the `App`, in its state, so they can
+/// be listed such as by the admin endpoints.
+#[derive(Default)]
+pub struct RouteList {
+    routes: Mutex<Vec<(RequestType, String)>>,
+}
+
+impl RouteList {
+    /// The routes in the order they were registered.
+    pub fn all(&self) -> Vec<(RequestType, String)> {
+        self.routes.lock().unwrap().clone()
+    }
+
+    pub(crate) fn push(&self, request_type: RequestType, path: &str) {
+        self.routes
+            .lock()
+            .unwrap()
+            .push((request_type, path.to_string()));
+    }
+}
+
+/// Resources and middleware registered under a common path prefix, see `App::scope`.
+///
+/// Paths are relative to the prefix, with `/` standing for the prefix itself. Middleware only
+/// runs for requests under the prefix, such as `/admin` and `/admin/flags`, but not
+/// `/administrator`, and runs after the middleware registered with the app before the scope.
+pub struct Router {
+    prefix: String,
+    pub(crate) resources: Vec<Resource>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    pub(crate) fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            resources: vec![],
+            middleware: vec![],
+        }
+    }
+
+    /// The full path of `path` under the prefix.
+    pub fn path(&self, path: &str) -> String {
+        match path {
+            "" | "/" if !self.prefix.is_empty() => self.prefix.clone(),
+            _ => format!("{}{path}", self.prefix),
+        }
+    }
+
+    /// Register `resource`, with its path taken as relative to the prefix.
+    pub fn register_resource(&mut self, mut resource: Resource) {
+        resource.path = self.path(&resource.path);
+        self.resources.push(resource);
+    }
+
+    /// Register middleware that only runs for requests under the prefix.
+    pub fn register_middleware(&mut self, middleware: Box<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    /// A text resource answering GET requests at `path`.
+    pub fn get(
+        &mut self,
+        path: &str,
+        handler: impl Fn(&Request) -> Result<Response, Error> + Send + Sync + 'static,
+    ) {
+        self.route(RequestType::GET, path, handler);
+    }
+
+    /// A text resource answering POST requests at `path`.
+    pub fn post(
+        &mut self,
+        path: &str,
+        handler: impl Fn(&Request) -> Result<Response, Error> + Send + Sync + 'static,
+    ) {
+        self.route(RequestType::POST, path, handler);
+    }
+
+    fn route(
+        &mut self,
+        request_type: RequestType,
+        path: &str,
+        handler: impl Fn(&Request) -> Result<Response, Error> + Send + Sync + 'static,
+    ) {
+        self.register_resource(Resource::new(
+            request_type,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(handler),
+        ));
+    }
+
+    /// A nested group under `prefix`, relative to this one. Its middleware runs after this
+    /// group's middleware.
+    pub fn scope(&mut self, prefix: &str, routes: impl FnOnce(&mut Router)) {
+        let mut router = Router::new(&self.path(prefix));
+        routes(&mut router);
+        let (resources, group) = router.finish();
+        self.resources.extend(resources);
+        if let Some(group) = group {
+            self.middleware.push(Box::new(group));
+        }
+    }
+
+    /// The resources with their full paths, and the middleware to run for the group, if any.
+    pub(crate) fn finish(self) -> (Vec<Resource>, Option<Group>) {
+        let group = (!self.middleware.is_empty()).then(|| Group {
+            prefix: self.prefix,
+            middleware: self.middleware,
+        });
+        (self.resources, group)
+    }
+}
+
+/// The middleware of a `Router`, which passes requests under its prefix on to it.
+pub(crate) struct Group {
+    prefix: String,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Group {
+    fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+impl Middleware for Group {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if !self.matches(request.path()) {
+            return None;
+        }
+        self.middleware
+            .iter()
+            .find_map(|middleware| middleware.before(request))
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        if !self.matches(request.path()) {
+            return;
+        }
+        for middleware in &self.middleware {
+            middleware.after(request, response);
+        }
+    }
+
+    fn names(&self, path: &str) -> Vec<String> {
+        if !self.matches(path) {
+            return vec![];
+        }
+        self.middleware
+            .iter()
+            .flat_map(|middleware| middleware.names(path))
+            .collect()
+    }
+}
+
+/// How a request for a path that only differs from that of a resource by a trailing slash, such
+/// as `/about/` for `/about`, is answered, see `AppConfig::with_trailing_slash`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TrailingSlash {
+    /// Answer with a 404, unless a resource was registered for that path as well.
+    Strict,
+    /// Answer with the resource, as if its own path was requested.
+    Ignore,
+    /// Answer with a 301 to the path of the resource, so there's one URL for every page.
+    Redirect,
+}
+
+/// Values, such as resources, found by method and path in a trie of path segments, so a lookup
+/// takes time in the length of the path rather than the number of routes.
+///
+/// Paths match exactly, including trailing slashes, and ignoring ASCII case if the trie is
+/// made with `with_case_insensitive`. The first value inserted for a method and path is kept.
+pub struct RouteTrie<T> {
+    root: Node<T>,
+    case_insensitive: bool,
+}
+
+struct Node<T> {
+    children: HashMap<String, Node<T>>,
+    values: Vec<(RequestType, T)>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Self {
+            children: HashMap::new(),
+            values: vec![],
+        }
+    }
+}
+
+impl<T> Default for RouteTrie<T> {
+    fn default() -> Self {
+        Self {
+            root: Node::new(),
+            case_insensitive: false,
+        }
+    }
+}
+
+impl<T> RouteTrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match paths ignoring ASCII case, so `/About` finds `/about`. Set before inserting.
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Add `value` for `request_type` requests to `path`. Returns false, leaving the trie as it
+    /// was, if there already is one.
+    pub fn insert(&mut self, request_type: RequestType, path: &str, value: T) -> bool {
+        let case_insensitive = self.case_insensitive;
+        let node = path.split('/').fold(&mut self.root, |node, segment| {
+            node.children
+                .entry(key(case_insensitive, segment).into_owned())
+                .or_insert_with(Node::new)
+        });
+        if node
+            .values
+            .iter()
+            .any(|(method, _)| *method == request_type)
+        {
+            return false;
+        }
+        node.values.push((request_type, value));
+        true
+    }
+
+    pub fn get(&self, request_type: RequestType, path: &str) -> Option<&T> {
+        let node = path.split('/').try_fold(&self.root, |node, segment| {
+            node.children
+                .get(key(self.case_insensitive, segment).as_ref())
+        })?;
+        node.values
+            .iter()
+            .find(|(method, _)| *method == request_type)
+            .map(|(_, value)| value)
+    }
+}
+
+/// The child of a node that `segment` leads to.
+fn key(case_insensitive: bool, segment: &str) -> Cow<'_, str> {
+    match case_insensitive {
+        true => Cow::Owned(segment.to_ascii_lowercase()),
+        false => Cow::Borrowed(segment),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Auth;
+    use crate::webserver::{App, AppConfig, StatusCode};
+    use std::net::SocketAddr;
+
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn after(&self, _request: &Request, response: &mut Response) {
+            response
+                .headers
+                .push(("X-Tag".to_string(), self.0.to_string()));
+        }
+    }
+
+    fn respond(app: &App, raw: &str) -> String {
+        let mut request = Request::from_reader(&mut raw.as_bytes()).unwrap();
+        String::from_utf8(app.respond(&mut request).bytes).unwrap()
+    }
+
+    #[test]
+    fn route_trie() {
+        let mut routes = RouteTrie::new();
+        assert!(routes.insert(RequestType::GET, "/", 0));
+        assert!(routes.insert(RequestType::GET, "/blog", 1));
+        assert!(routes.insert(RequestType::POST, "/blog", 2));
+        assert!(routes.insert(RequestType::GET, "/blog/", 3));
+        assert!(routes.insert(RequestType::GET, "/blog/first-post", 4));
+        assert!(!routes.insert(RequestType::GET, "/blog", 5));
+
+        assert_eq!(routes.get(RequestType::GET, "/"), Some(&0));
+        assert_eq!(routes.get(RequestType::GET, "/blog"), Some(&1));
+        assert_eq!(routes.get(RequestType::POST, "/blog"), Some(&2));
+        assert_eq!(routes.get(RequestType::GET, "/blog/"), Some(&3));
+        assert_eq!(routes.get(RequestType::GET, "/blog/first-post"), Some(&4));
+        assert_eq!(routes.get(RequestType::DELETE, "/blog"), None);
+        assert_eq!(routes.get(RequestType::GET, "/blog/second-post"), None);
+        assert_eq!(routes.get(RequestType::GET, "/blo"), None);
+        assert_eq!(routes.get(RequestType::GET, ""), None);
+    }
+
+    #[test]
+    fn route_trie_case_insensitive() {
+        let mut routes = RouteTrie::new().with_case_insensitive(true);
+        assert!(routes.insert(RequestType::GET, "/About/Me", 0));
+        assert!(!routes.insert(RequestType::GET, "/about/me", 1));
+        assert_eq!(routes.get(RequestType::GET, "/about/me"), Some(&0));
+        assert_eq!(routes.get(RequestType::GET, "/ABOUT/ME"), Some(&0));
+        assert_eq!(routes.get(RequestType::GET, "/about/me/"), None);
+    }
+
+    #[test]
+    fn scope() {
+        let mut app = App::new(AppConfig::new(SocketAddr::from(([127, 0, 0, 1], 0)), 1, 5));
+        app.register_middleware(Box::new(Tag("a")));
+        app.scope("/api/", |api| {
+            api.register_middleware(Box::new(Tag("b")));
+            api.get("/", |_| Ok(Response::text(StatusCode::OK, "index")));
+            api.get("/posts", |_| Ok(Response::text(StatusCode::OK, "posts")));
+            api.scope("/admin", |admin| {
+                admin.register_middleware(Box::new(
+                    Auth::new("/", "Admin").with_bearer(Box::new(|token| token == "secret")),
+                ));
+                admin.register_middleware(Box::new(Tag("c")));
+                admin.post("/publish", |_| {
+                    Ok(Response::text(StatusCode::OK, "published"))
+                });
+            });
+        });
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/apis".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "apis"))),
+        ));
+
+        let response = respond(&app, "GET /api HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("X-Tag: a\r\nX-Tag: b\r\n"));
+        assert!(response.ends_with("index"));
+        assert!(respond(&app, "GET /api/posts HTTP/1.1\r\n\r\n").ends_with("posts"));
+        // Middleware of a group doesn't run for paths that only start the same way.
+        let response = respond(&app, "GET /apis HTTP/1.1\r\n\r\n");
+        assert!(response.contains("X-Tag: a\r\n") && !response.contains("X-Tag: b"));
+        assert!(response.ends_with("apis"));
+
+        let response = respond(&app, "POST /api/admin/publish HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 "));
+        let response = respond(
+            &app,
+            "POST /api/admin/publish HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n",
+        );
+        assert!(response.contains("X-Tag: a\r\nX-Tag: b\r\nX-Tag: c\r\n"));
+        assert!(response.ends_with("published"));
+    }
+
+    fn pages(config: AppConfig) -> App {
+        let mut app = App::new(config);
+        app.scope("/", |pages| {
+            pages.get("/about", |request| {
+                Ok(Response::text(StatusCode::OK, request.path().to_string()))
+            });
+            pages.get("/blog/", |_| Ok(Response::text(StatusCode::OK, "blog")));
+        });
+        app
+    }
+
+    #[test]
+    fn trailing_slash() {
+        let config = || AppConfig::new(SocketAddr::from(([127, 0, 0, 1], 0)), 1, 5);
+        let app = pages(config());
+        assert!(respond(&app, "GET /about/ HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+
+        // Handlers see the path they were registered with.
+        let app = pages(config().with_trailing_slash(TrailingSlash::Ignore));
+        assert!(respond(&app, "GET /about/ HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n/about"));
+        assert!(respond(&app, "GET /blog HTTP/1.1\r\n\r\n").ends_with("blog"));
+        assert!(respond(&app, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+
+        let app = pages(config().with_trailing_slash(TrailingSlash::Redirect));
+        let response = respond(&app, "GET /about/?lang=nl HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 301 "));
+        assert!(response.contains("Location: /about?lang=nl\r\n"));
+        let response = respond(&app, "GET /blog HTTP/1.1\r\n\r\n");
+        assert!(response.contains("Location: /blog/\r\n"));
+    }
+
+    #[test]
+    fn case_insensitive_paths() {
+        let config = AppConfig::new(SocketAddr::from(([127, 0, 0, 1], 0)), 1, 5);
+        let mut app = pages(config.with_case_insensitive_paths(true));
+        app.scope("/admin", |admin| {
+            admin.register_middleware(Box::new(Auth::new("/admin", "Admin")));
+            admin.get("/flags", |_| Ok(Response::text(StatusCode::OK, "flags")));
+        });
+        assert!(respond(&app, "GET /ABOUT HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n/about"));
+        // The path is matched before middleware, so its prefixes can't be avoided.
+        assert!(respond(&app, "GET /Admin/Flags HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 401 "));
+    }
+}
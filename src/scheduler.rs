@@ -0,0 +1,110 @@
+use crate::log;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+struct Job {
+    interval: Duration,
+    next: Instant,
+    task: Box<dyn FnMut() + Send>,
+}
+
+/// Runs jobs at fixed intervals on one background thread, such as health checks or cleanup.
+///
+/// Jobs run one after another, so a slow job delays the others but never overlaps itself.
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<Job>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `task` every `interval`, starting right away.
+    pub fn every(
+        mut self,
+        name: &str,
+        interval: Duration,
+        task: impl FnMut() + Send + 'static,
+    ) -> Self {
+        log!("Scheduled job {name} every {interval:?}");
+        self.jobs.push(Job {
+            interval,
+            next: Instant::now(),
+            task: Box::new(task),
+        });
+        self
+    }
+
+    pub fn start(mut self) -> SchedulerHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            while !stop_clone.load(Ordering::SeqCst) {
+                let now = Instant::now();
+                for job in self.jobs.iter_mut().filter(|job| job.next <= now) {
+                    (job.task)();
+                    // Skip runs that were missed, rather than running them back to back.
+                    while job.next <= Instant::now() {
+                        job.next += job.interval.max(Duration::from_millis(1));
+                    }
+                }
+                match self.jobs.iter().map(|job| job.next).min() {
+                    Some(next) => {
+                        thread::park_timeout(next.saturating_duration_since(Instant::now()))
+                    }
+                    None => thread::park(),
+                }
+            }
+        });
+        SchedulerHandle { stop, thread }
+    }
+}
+
+/// Stops the scheduler when asked. Dropping the handle leaves the scheduler running.
+pub struct SchedulerHandle {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl SchedulerHandle {
+    /// Stop after the running job, if any, finishes.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.thread.thread().unpark();
+        self.thread.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn runs_jobs() {
+        let fast = Arc::new(AtomicUsize::new(0));
+        let slow = Arc::new(AtomicUsize::new(0));
+        let (fast_clone, slow_clone) = (fast.clone(), slow.clone());
+        let handle = Scheduler::new()
+            .every("fast", Duration::from_millis(20), move || {
+                fast_clone.fetch_add(1, Ordering::SeqCst);
+            })
+            .every("slow", Duration::from_secs(60), move || {
+                slow_clone.fetch_add(1, Ordering::SeqCst);
+            })
+            .start();
+        thread::sleep(Duration::from_millis(110));
+        handle.stop();
+
+        assert!(fast.load(Ordering::SeqCst) >= 3);
+        assert_eq!(slow.load(Ordering::SeqCst), 1);
+    }
+}
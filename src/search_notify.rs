@@ -0,0 +1,173 @@
+use crate::http_client;
+use crate::log;
+use crate::webserver::{percent_encode, RequestType, Resource, ResourceType, Response, StatusCode};
+use std::thread::{self, JoinHandle};
+
+pub const INDEXNOW_ENDPOINT: &str = "https://api.indexnow.org/indexnow";
+
+/// Tells search engines about changed pages, through IndexNow and sitemap pings.
+///
+/// Notifications are sent from a background thread and their results are logged, so publishing
+/// never waits for or fails on a search engine.
+#[derive(Clone)]
+pub struct SearchNotifier {
+    /// The site's host name, such as `www.daanlubbers.nl`.
+    host: String,
+    indexnow_key: Option<String>,
+    indexnow_endpoint: String,
+    sitemap_url: Option<String>,
+    /// URLs that the sitemap URL is appended to, percent-encoded.
+    ping_urls: Vec<String>,
+}
+
+impl SearchNotifier {
+    pub fn new(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            indexnow_key: None,
+            indexnow_endpoint: INDEXNOW_ENDPOINT.to_string(),
+            sitemap_url: None,
+            ping_urls: vec![],
+        }
+    }
+
+    /// Submit changed URLs to IndexNow with this key. The key must be 8 to 128 letters, digits
+    /// or dashes, and has to be served by `key_file_resource`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is not valid.
+    pub fn with_indexnow(mut self, key: &str) -> Self {
+        assert!(
+            (8..=128).contains(&key.len())
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+            "Invalid IndexNow key"
+        );
+        self.indexnow_key = Some(key.to_string());
+        self
+    }
+
+    /// Use another IndexNow endpoint, such as a single search engine's.
+    pub fn with_indexnow_endpoint(mut self, endpoint: &str) -> Self {
+        self.indexnow_endpoint = endpoint.to_string();
+        self
+    }
+
+    /// Ping `ping_url` followed by the percent-encoded `sitemap_url` after every change,
+    /// such as `https://example.com/ping?sitemap=`.
+    pub fn with_sitemap_ping(mut self, sitemap_url: &str, ping_url: &str) -> Self {
+        self.sitemap_url = Some(sitemap_url.to_string());
+        self.ping_urls.push(ping_url.to_string());
+        self
+    }
+
+    /// The path IndexNow fetches the key from to check the submission comes from this site.
+    pub fn key_path(&self) -> Option<String> {
+        self.indexnow_key.as_ref().map(|key| format!("/{key}.txt"))
+    }
+
+    /// A GET resource serving the IndexNow key file.
+    pub fn key_file_resource(&self) -> Option<Resource> {
+        let key = self.indexnow_key.clone()?;
+        Some(Resource::new(
+            RequestType::GET,
+            self.key_path()?,
+            ResourceType::TEXT,
+            Box::new(move |_| {
+                Ok(Response::text(StatusCode::OK, key.clone())
+                    .with_header("Content-Type", "text/plain; charset=utf-8"))
+            }),
+        ))
+    }
+
+    /// Notify search engines that these URLs changed, in the background.
+    pub fn notify(&self, urls: Vec<String>) -> JoinHandle<()> {
+        let notifier = self.clone();
+        thread::spawn(move || notifier.notify_now(&urls))
+    }
+
+    fn notify_now(&self, urls: &[String]) {
+        if let Some(body) = self.indexnow_body(urls) {
+            match http_client::post(
+                &self.indexnow_endpoint,
+                "application/json; charset=utf-8",
+                body.as_bytes(),
+            ) {
+                Ok(response) => log!(
+                    "IndexNow submission of {} URLs: {}",
+                    urls.len(),
+                    response.status()
+                ),
+                Err(e) => log!("IndexNow submission failed: {e}"),
+            }
+        }
+        if let Some(sitemap_url) = &self.sitemap_url {
+            for ping_url in &self.ping_urls {
+                let url = format!("{ping_url}{}", percent_encode(sitemap_url));
+                match http_client::get(&url) {
+                    Ok(response) => log!("Sitemap ping {url}: {}", response.status()),
+                    Err(e) => log!("Sitemap ping {url} failed: {e}"),
+                }
+            }
+        }
+    }
+
+    fn indexnow_body(&self, urls: &[String]) -> Option<String> {
+        let key = self.indexnow_key.as_ref()?;
+        if urls.is_empty() {
+            return None;
+        }
+        let url_list: Vec<String> = urls.iter().map(|url| json_string(url)).collect();
+        Some(format!(
+            "{{\"host\":{},\"key\":{},\"keyLocation\":{},\"urlList\":[{}]}}",
+            json_string(&self.host),
+            json_string(key),
+            json_string(&format!("https://{}{}", self.host, self.key_path()?)),
+            url_list.join(",")
+        ))
+    }
+}
+
+/// Quote and escape a string for JSON.
+pub(crate) fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexnow_body() {
+        let notifier = SearchNotifier::new("www.daanlubbers.nl").with_indexnow("0123456789abcdef");
+        assert_eq!(notifier.key_path().unwrap(), "/0123456789abcdef.txt");
+        assert_eq!(
+            notifier
+                .indexnow_body(&["https://www.daanlubbers.nl/blog/\"post\"".to_string()])
+                .unwrap(),
+            "{\"host\":\"www.daanlubbers.nl\",\"key\":\"0123456789abcdef\",\
+             \"keyLocation\":\"https://www.daanlubbers.nl/0123456789abcdef.txt\",\
+             \"urlList\":[\"https://www.daanlubbers.nl/blog/\\\"post\\\"\"]}"
+        );
+        assert!(notifier.indexnow_body(&[]).is_none());
+        assert!(SearchNotifier::new("www.daanlubbers.nl")
+            .indexnow_body(&["https://www.daanlubbers.nl/".to_string()])
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid IndexNow key")]
+    fn invalid_key() {
+        SearchNotifier::new("www.daanlubbers.nl").with_indexnow("short");
+    }
+}
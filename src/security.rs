@@ -0,0 +1,161 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+/// The file inside `root` for `relative`, the part of a request path below where `root` is
+/// served. Every handler serving or storing files at paths taken from a request goes through
+/// this, so none of them can be made to reach outside their directory.
+///
+/// The path is percent-decoded once, then rejected if it has `.` or `..` segments, backslashes,
+/// NUL bytes or invalid UTF-8, so encoded traversal such as `%2e%2e%2f` is caught as well. The
+/// existing part of the result must still be inside `root` once symlinks are resolved. The
+/// returned path is `root` joined with the decoded segments, and may not exist yet.
+pub fn safe_path(root: &Path, relative: &str) -> Result<PathBuf, String> {
+    let decoded = percent_decode_path(relative)?;
+    let mut path = root.to_path_buf();
+    for segment in decoded.split('/').filter(|segment| !segment.is_empty()) {
+        if segment.contains(['\\', '\0']) {
+            return Err(format!("Invalid path segment: {segment:?}"));
+        }
+        // Also rejects `.`, `..` and, on Windows, prefixes such as `C:`.
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => path.push(segment),
+            _ => return Err(format!("Invalid path segment: {segment:?}")),
+        }
+    }
+    check_inside(root, &path)?;
+    Ok(path)
+}
+
+/// Decode `%XX` escapes in a URL path. Unlike query strings, `+` is kept as it is. Invalid
+/// escapes and invalid UTF-8, such as overlong encodings of `.` and `/`, are rejected.
+pub fn percent_decode_path(path: &str) -> Result<String, String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("Invalid escape in path: {path}"))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("Invalid UTF-8 in path: {path}"))
+}
+
+/// Check that the deepest existing ancestor of `path`, with symlinks resolved, is inside
+/// `root`. A missing `root` has nothing to escape to.
+fn check_inside(root: &Path, path: &Path) -> Result<(), String> {
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Failed to resolve {}: {e}", root.display())),
+    };
+    for existing in path.ancestors() {
+        match existing.canonicalize() {
+            Ok(resolved) if resolved.starts_with(&root) => return Ok(()),
+            Ok(_) => return Err(format!("Path escapes its directory: {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to resolve {}: {e}", existing.display())),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn decode() {
+        assert_eq!(percent_decode_path("a%20b+c").unwrap(), "a b+c");
+        assert_eq!(percent_decode_path("%2E%2e%2F").unwrap(), "../");
+        assert!(percent_decode_path("%zz").is_err());
+        assert!(percent_decode_path("%2").is_err());
+        assert!(percent_decode_path("%c0%ae").is_err());
+    }
+
+    #[test]
+    fn attack_strings() {
+        let root = Path::new("static_test");
+        for attack in [
+            "..",
+            "../",
+            "../etc/passwd",
+            "a/../../etc/passwd",
+            "a/b/../../..",
+            "./test.html",
+            "%2e%2e/etc/passwd",
+            "%2E%2E%2Fetc%2Fpasswd",
+            "..%2fetc%2fpasswd",
+            "..%2F..%2F..%2Fetc%2Fpasswd",
+            ".%2e/.%2e/etc/passwd",
+            "%2e./etc/passwd",
+            "..\\..\\windows\\win.ini",
+            "..%5c..%5cwindows%5cwin.ini",
+            "%5c..%5c",
+            "test.html%00.jpg",
+            "%00",
+            "%c0%ae%c0%ae/etc/passwd",
+            "%c0%af",
+            "%e0%80%ae%e0%80%ae/",
+            "%",
+            "%2",
+            "%g0",
+        ] {
+            assert!(safe_path(root, attack).is_err(), "{attack} was accepted");
+        }
+    }
+
+    #[test]
+    fn allowed_paths() {
+        let root = Path::new("static_test");
+        for (relative, expected) in [
+            ("", root.to_path_buf()),
+            ("test.html", root.join("test.html")),
+            ("/test.html", root.join("test.html")),
+            ("a//b/", root.join("a").join("b")),
+            ("my%20file.txt", root.join("my file.txt")),
+            ("a+b.txt", root.join("a+b.txt")),
+            ("....", root.join("....")),
+            ("..a/b..", root.join("..a").join("b..")),
+            // Decoded only once, so this is a literal name.
+            ("%252e%252e/x", root.join("%2e%2e").join("x")),
+            ("caf%C3%A9", root.join("café")),
+        ] {
+            assert_eq!(safe_path(root, relative).unwrap(), expected, "{relative}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_escape() {
+        let base = std::env::temp_dir().join("wwwdaanlubbersnl_test_security");
+        let _ = fs::remove_dir_all(&base);
+        let root = base.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        fs::write(base.join("outside").join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(base.join("outside"), root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(root.join("sub"), root.join("inside")).unwrap();
+
+        assert!(safe_path(&root, "escape").is_err());
+        assert!(safe_path(&root, "escape/secret.txt").is_err());
+        assert!(safe_path(&root, "escape/new.txt").is_err());
+        assert!(safe_path(&root, "inside/new.txt").is_ok());
+        assert!(safe_path(&root, "sub/missing/new.txt").is_ok());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
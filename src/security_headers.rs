@@ -0,0 +1,145 @@
+use crate::webserver::{Middleware, Request, Response};
+use std::time::Duration;
+
+/// Middleware adding headers that have browsers defend pages against common attacks:
+/// `Strict-Transport-Security`, `X-Content-Type-Options`, `X-Frame-Options`, `Referrer-Policy`
+/// and, once one is set, `Content-Security-Policy`.
+///
+/// Register it on a `Router` to only cover that route group. Headers the response already has
+/// are kept, so handlers can override them, and of several instances covering a request the
+/// one registered first wins.
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders {
+    headers: Vec<(&'static str, Option<String>)>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecurityHeaders {
+    /// HTTPS for a year including subdomains, `nosniff`, no framing by other sites, and only
+    /// the origin in the referrer of requests to other sites. No content security policy.
+    pub fn new() -> Self {
+        Self {
+            headers: vec![
+                (
+                    "Strict-Transport-Security",
+                    Some("max-age=31536000; includeSubDomains".to_string()),
+                ),
+                ("X-Content-Type-Options", Some("nosniff".to_string())),
+                ("X-Frame-Options", Some("SAMEORIGIN".to_string())),
+                (
+                    "Referrer-Policy",
+                    Some("strict-origin-when-cross-origin".to_string()),
+                ),
+                ("Content-Security-Policy", None),
+            ],
+        }
+    }
+
+    /// Have browsers only use HTTPS for this long, `None` to leave the header out.
+    pub fn with_hsts(self, max_age: Option<Duration>, include_subdomains: bool) -> Self {
+        let value = max_age.map(|max_age| match include_subdomains {
+            true => format!("max-age={}; includeSubDomains", max_age.as_secs()),
+            false => format!("max-age={}", max_age.as_secs()),
+        });
+        self.with("Strict-Transport-Security", value)
+    }
+
+    /// `DENY` or `SAMEORIGIN`, `None` to leave the header out.
+    pub fn with_frame_options(self, value: Option<&str>) -> Self {
+        self.with("X-Frame-Options", value.map(str::to_string))
+    }
+
+    /// Such as `no-referrer`, `None` to leave the header out.
+    pub fn with_referrer_policy(self, value: Option<&str>) -> Self {
+        self.with("Referrer-Policy", value.map(str::to_string))
+    }
+
+    /// Such as `default-src 'self'`, `None` to leave the header out.
+    pub fn with_content_security_policy(self, value: Option<&str>) -> Self {
+        self.with("Content-Security-Policy", value.map(str::to_string))
+    }
+
+    /// Whether to send `X-Content-Type-Options: nosniff`.
+    pub fn with_nosniff(self, nosniff: bool) -> Self {
+        self.with(
+            "X-Content-Type-Options",
+            nosniff.then(|| "nosniff".to_string()),
+        )
+    }
+
+    fn with(mut self, name: &'static str, value: Option<String>) -> Self {
+        if let Some(header) = self.headers.iter_mut().find(|header| header.0 == name) {
+            header.1 = value;
+        }
+        self
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn after(&self, _request: &Request, response: &mut Response) {
+        for (name, value) in &self.headers {
+            if response.header(name).is_none() {
+                if let Some(value) = value {
+                    response.add_header(*name, value.as_str());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::StatusCode;
+
+    fn headers(middleware: &SecurityHeaders, mut response: Response) -> Vec<(String, String)> {
+        let request = Request::from_reader(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+        middleware.after(&request, &mut response);
+        response.headers
+    }
+
+    #[test]
+    fn defaults() {
+        let headers = headers(
+            &SecurityHeaders::new(),
+            Response::text(StatusCode::OK, "hi"),
+        );
+        let names: Vec<&str> = headers.iter().map(|(name, _)| name.as_str()).collect();
+        assert!(names.contains(&"Strict-Transport-Security"));
+        assert!(names.contains(&"X-Content-Type-Options"));
+        assert!(names.contains(&"X-Frame-Options"));
+        assert!(names.contains(&"Referrer-Policy"));
+        assert!(!names.contains(&"Content-Security-Policy"));
+    }
+
+    #[test]
+    fn configured() {
+        let middleware = SecurityHeaders::new()
+            .with_hsts(Some(Duration::from_secs(60)), false)
+            .with_frame_options(None)
+            .with_referrer_policy(Some("no-referrer"))
+            .with_content_security_policy(Some("default-src 'self'"))
+            .with_nosniff(false);
+        let response =
+            Response::text(StatusCode::OK, "hi").with_header("Referrer-Policy", "same-origin");
+        let headers = headers(&middleware, response);
+        let header = |name: &str| {
+            headers
+                .iter()
+                .filter(|header| header.0 == name)
+                .map(|header| header.1.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(header("Strict-Transport-Security"), ["max-age=60"]);
+        assert_eq!(header("Content-Security-Policy"), ["default-src 'self'"]);
+        // Set by the handler, so kept.
+        assert_eq!(header("Referrer-Policy"), ["same-origin"]);
+        assert!(header("X-Frame-Options").is_empty());
+        assert!(header("X-Content-Type-Options").is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use std::{fs::File, io, os::fd::AsRawFd};
+
+/// The most `sendfile` sends in one call.
+const MAX_COUNT: u64 = 1 << 30;
+
+const EINVAL: i32 = 22;
+const ENOSYS: i32 = 38;
+
+extern "C" {
+    fn sendfile64(out_fd: i32, in_fd: i32, offset: *mut i64, count: usize) -> isize;
+}
+
+/// Send the first `length` bytes of the file to the stream with `sendfile(2)`, which copies
+/// them within the kernel. Returns `Ok(false)`, without sending anything, if the file can't be
+/// sent this way, such as on some network file systems.
+pub(crate) fn send(stream: &impl AsRawFd, file: &File, length: u64) -> io::Result<bool> {
+    let mut offset: i64 = 0;
+    while (offset as u64) < length {
+        let count = (length - offset as u64).min(MAX_COUNT) as usize;
+        // Both descriptors stay open during the call, and the kernel only writes to `offset`.
+        let sent = unsafe { sendfile64(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+        match sent {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "File shrunk while streaming",
+                ))
+            }
+            -1 => {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    _ if e.kind() == io::ErrorKind::Interrupted => {}
+                    Some(EINVAL | ENOSYS) if offset == 0 => return Ok(false),
+                    _ => return Err(e),
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs,
+        io::Read,
+        net::{TcpListener, TcpStream},
+        thread::{self, JoinHandle},
+    };
+
+    /// Accept a connection, with a client reading everything sent over it.
+    fn connect(listener: &TcpListener) -> (TcpStream, JoinHandle<Vec<u8>>) {
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut received = vec![];
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let _ = stream.read_to_end(&mut received);
+            received
+        });
+        (listener.accept().unwrap().0, client)
+    }
+
+    #[test]
+    fn send_file() {
+        let content: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+        let path = std::env::temp_dir().join("wwwdaanlubbersnl_sendfile_test");
+        fs::write(&path, &content).unwrap();
+        let file = File::open(&path).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let (stream, client) = connect(&listener);
+        assert!(send(&stream, &file, 200_000).unwrap());
+        drop(stream);
+        assert!(client.join().unwrap() == content[..200_000]);
+
+        // Asking for more than the file has fails once it runs out.
+        let (stream, client) = connect(&listener);
+        assert!(send(&stream, &file, 400_000).is_err());
+        drop(stream);
+        assert_eq!(client.join().unwrap().len(), 300_000);
+        fs::remove_file(&path).unwrap();
+    }
+}
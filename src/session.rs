@@ -0,0 +1,297 @@
+use crate::cookie::{Cookie, SameSite};
+use crate::digest::{hex, sha256};
+use crate::webserver::{Middleware, Request, Response};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::Read,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub type SessionData = HashMap<String, String>;
+
+/// Where sessions are kept between requests.
+pub trait SessionStore: Send + Sync {
+    /// The data of the session with this id, or `None` if it doesn't exist or has expired.
+    fn load(&self, id: &str) -> Option<SessionData>;
+
+    fn save(&self, id: &str, data: SessionData, expires: SystemTime);
+
+    fn remove(&self, id: &str);
+}
+
+/// Keeps sessions in memory, so they are lost when the server restarts.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, (SessionData, SystemTime)>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let mut sessions = self.sessions.lock().ok()?;
+        let now = SystemTime::now();
+        // Expired sessions are cleaned up whenever a session is loaded.
+        sessions.retain(|_, (_, expires)| *expires > now);
+        sessions.get(id).map(|(data, _)| data.clone())
+    }
+
+    fn save(&self, id: &str, data: SessionData, expires: SystemTime) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.insert(id.to_string(), (data, expires));
+        }
+    }
+
+    fn remove(&self, id: &str) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.remove(id);
+        }
+    }
+}
+
+/// The session of the current request, see `Request::session`.
+///
+/// A session is only stored, and its cookie only sent, once something has been inserted.
+pub struct Session {
+    /// The id the client sent, if it belonged to a stored session.
+    existing_id: Option<String>,
+    state: Mutex<SessionState>,
+}
+
+struct SessionState {
+    id: String,
+    data: SessionData,
+}
+
+impl Session {
+    fn new(existing_id: Option<String>, data: SessionData) -> Self {
+        let id = existing_id.clone().unwrap_or_else(new_id);
+        Self {
+            existing_id,
+            state: Mutex::new(SessionState { id, data }),
+        }
+    }
+
+    pub fn id(&self) -> String {
+        self.state.lock().unwrap().id.clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.lock().unwrap().data.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: &str, value: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .data
+            .insert(key.to_string(), value.to_string());
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        self.state.lock().unwrap().data.remove(key)
+    }
+
+    /// Give the session a new id, keeping its data. Call this after logging in, so an id that
+    /// was handed out before can't be used to take over the session.
+    pub fn renew(&self) {
+        self.state.lock().unwrap().id = new_id();
+    }
+
+    /// Remove the session and its data, such as when logging out.
+    pub fn destroy(&self) {
+        self.state.lock().unwrap().data.clear();
+    }
+}
+
+/// Middleware that loads the session named by the session cookie into every request, and
+/// stores it again when the response is sent.
+pub struct Sessions {
+    store: Box<dyn SessionStore>,
+    ttl: Duration,
+    cookie_name: String,
+    secure: bool,
+}
+
+impl Sessions {
+    /// Sessions expire after `ttl` without requests.
+    pub fn new(store: Box<dyn SessionStore>, ttl: Duration) -> Self {
+        Self {
+            store,
+            ttl,
+            cookie_name: "session".to_string(),
+            secure: false,
+        }
+    }
+
+    pub fn with_cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.to_string();
+        self
+    }
+
+    /// Only send the session cookie over HTTPS.
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    fn cookie(&self, id: &str) -> Cookie {
+        let cookie = Cookie::new(&self.cookie_name, id)
+            .with_path("/")
+            .with_max_age(self.ttl)
+            .http_only()
+            .with_same_site(SameSite::Lax);
+        match self.secure {
+            true => cookie.secure(),
+            false => cookie,
+        }
+    }
+}
+
+impl Middleware for Sessions {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let loaded = request
+            .cookie(&self.cookie_name)
+            .and_then(|id| Some((id.to_string(), self.store.load(id)?)));
+        let session = match loaded {
+            Some((id, data)) => Session::new(Some(id), data),
+            None => Session::new(None, SessionData::new()),
+        };
+        request.set_session(session);
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        let Some(session) = request.session() else {
+            return;
+        };
+        let state = session.state.lock().unwrap();
+
+        if let Some(existing_id) = &session.existing_id {
+            if state.data.is_empty() || *existing_id != state.id {
+                self.store.remove(existing_id);
+            }
+        }
+        if !state.data.is_empty() {
+            self.store
+                .save(&state.id, state.data.clone(), SystemTime::now() + self.ttl);
+            response.add_cookie(self.cookie(&state.id));
+        } else if session.existing_id.is_some() {
+            response.add_cookie(Cookie::removal(&self.cookie_name).with_path("/"));
+        }
+    }
+}
+
+/// A new random session id. Random bytes come from the OS where possible.
+fn new_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut seed = [0u8; 32];
+    if File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut seed))
+        .is_err()
+    {
+        // Fall back on the random keys std uses for HashMap, mixed with the time and a counter.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        seed[..8].copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    hex(&sha256(&seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(cookie: Option<&str>) -> Request {
+        let raw = match cookie {
+            Some(cookie) => format!("GET / HTTP/1.1\r\nCookie: session={cookie}\r\n\r\n"),
+            None => "GET / HTTP/1.1\r\n\r\n".to_string(),
+        };
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn memory_store_expiry() {
+        let store = MemorySessionStore::new();
+        let data = SessionData::from([("user".to_string(), "daan".to_string())]);
+        store.save(
+            "a",
+            data.clone(),
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        store.save(
+            "b",
+            data.clone(),
+            SystemTime::now() - Duration::from_secs(1),
+        );
+        assert_eq!(store.load("a"), Some(data));
+        assert_eq!(store.load("b"), None);
+        store.remove("a");
+        assert_eq!(store.load("a"), None);
+    }
+
+    #[test]
+    fn session_lifecycle() {
+        let sessions = Sessions::new(Box::new(MemorySessionStore::new()), Duration::from_secs(60));
+
+        // No cookie until something is stored
+        let mut first = request(None);
+        sessions.before(&mut first);
+        let mut response = Response::empty(crate::webserver::StatusCode::OK);
+        sessions.after(&first, &mut response);
+        assert!(response.header("Set-Cookie").is_none());
+
+        first.session().unwrap().insert("user", "daan");
+        let mut response = Response::empty(crate::webserver::StatusCode::OK);
+        sessions.after(&first, &mut response);
+        let id = first.session().unwrap().id();
+        assert_eq!(
+            response.header("Set-Cookie").unwrap(),
+            format!("session={id}; Path=/; Max-Age=60; HttpOnly; SameSite=Lax")
+        );
+
+        // The cookie brings the session back, renewing it replaces the id
+        let mut second = request(Some(&id));
+        sessions.before(&mut second);
+        let session = second.session().unwrap();
+        assert_eq!(session.get("user").unwrap(), "daan");
+        session.renew();
+        let mut response = Response::empty(crate::webserver::StatusCode::OK);
+        sessions.after(&second, &mut response);
+        let renewed = session.id();
+        assert_ne!(renewed, id);
+        assert!(sessions.store.load(&id).is_none());
+
+        // Unknown ids get a fresh session, destroying removes it
+        let mut third = request(Some("unknown"));
+        sessions.before(&mut third);
+        assert_ne!(third.session().unwrap().id(), "unknown");
+        let mut fourth = request(Some(&renewed));
+        sessions.before(&mut fourth);
+        fourth.session().unwrap().destroy();
+        let mut response = Response::empty(crate::webserver::StatusCode::OK);
+        sessions.after(&fourth, &mut response);
+        assert_eq!(
+            response.header("Set-Cookie").unwrap(),
+            "session=; Path=/; Max-Age=0"
+        );
+        assert!(sessions.store.load(&renewed).is_none());
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+pub(crate) const SIGHUP: i32 = 1;
+pub(crate) const SIGINT: i32 = 2;
+pub(crate) const SIGTERM: i32 = 15;
+const SIG_ERR: usize = usize::MAX;
+
+static HANGUP: AtomicBool = AtomicBool::new(false);
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    fn _exit(status: i32) -> !;
+}
+
+/// Only sets a flag, as little else is safe in a signal handler. A second `SIGINT` or
+/// `SIGTERM` exits right away, for when stopping gracefully takes too long.
+extern "C" fn handle(signum: i32) {
+    match signum {
+        SIGHUP => HANGUP.store(true, Ordering::SeqCst),
+        _ if SHUTDOWN.swap(true, Ordering::SeqCst) => unsafe { _exit(128 + signum) },
+        _ => {}
+    }
+}
+
+/// Handle `signum` by setting a flag, see `take_hangup` and `shutdown_requested`.
+pub(crate) fn install(signum: i32) -> io::Result<()> {
+    // `handle` lives as long as the process, and is safe to run at any point.
+    match unsafe { signal(signum, handle) } {
+        SIG_ERR => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `SIGHUP` came in since this was last called.
+pub(crate) fn take_hangup() -> bool {
+    HANGUP.swap(false, Ordering::SeqCst)
+}
+
+/// Whether `SIGINT` or `SIGTERM` came in. Stays set, so every server in the process stops.
+pub(crate) fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
@@ -0,0 +1,143 @@
+use crate::digest::{constant_time_eq, hex, hmac_sha256};
+use crate::log;
+use crate::webserver::{Middleware, Request, Response, StatusCode};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Creates and checks expiring URLs signed with HMAC-SHA256, such as
+/// `/download/file?expires=1700000000&sig=...`.
+///
+/// The signer is cheap to clone, so the same key can be shared between the `SignedUrls`
+/// middleware and the handlers that hand out links.
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Arc<[u8]>,
+}
+
+impl UrlSigner {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// Sign `path` so that it is valid until `expires` (seconds since the unix epoch).
+    pub fn sign(&self, path: &str, expires: u64) -> String {
+        format!(
+            "{path}?expires={expires}&sig={}",
+            self.signature(path, expires)
+        )
+    }
+
+    /// Sign `path` so that it is valid for the given duration from now.
+    pub fn sign_for(&self, path: &str, valid_for: Duration) -> String {
+        self.sign(path, now() + valid_for.as_secs())
+    }
+
+    /// Check that the request carries a valid, unexpired signature for its path.
+    pub fn verify(&self, request: &Request) -> bool {
+        let Some(expires) = request
+            .query_param("expires")
+            .and_then(|expires| expires.parse::<u64>().ok())
+        else {
+            return false;
+        };
+        let Some(signature) = request.query_param("sig") else {
+            return false;
+        };
+        if expires < now() {
+            return false;
+        }
+
+        constant_time_eq(
+            signature.as_bytes(),
+            self.signature(request.path(), expires).as_bytes(),
+        )
+    }
+
+    fn signature(&self, path: &str, expires: u64) -> String {
+        hex(&hmac_sha256(
+            &self.key,
+            format!("{path}\n{expires}").as_bytes(),
+        ))
+    }
+}
+
+/// Middleware that answers 403 for requests under `prefix` without a valid signed URL.
+pub struct SignedUrls {
+    prefix: String,
+    signer: UrlSigner,
+}
+
+impl SignedUrls {
+    pub fn new(prefix: &str, signer: UrlSigner) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            signer,
+        }
+    }
+}
+
+impl Middleware for SignedUrls {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if !request.path().starts_with(&self.prefix) || self.signer.verify(request) {
+            return None;
+        }
+        log!("Rejected unsigned or expired URL: {}", request.path());
+        Some(Response::empty(StatusCode::Forbidden))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(target: &str) -> Request {
+        let raw = format!("GET {target} HTTP/1.1\r\n\r\n");
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn signed_url_roundtrip() {
+        let signer = UrlSigner::new(b"secret");
+        let url = signer.sign_for("/download/file", Duration::from_secs(60));
+        assert!(url.starts_with("/download/file?expires="));
+        assert!(signer.verify(&request(&url)));
+
+        // Different key, path or expiry
+        assert!(!UrlSigner::new(b"other").verify(&request(&url)));
+        assert!(!signer.verify(&request(&url.replace("/file", "/other"))));
+        let expires = now() + 60;
+        let url = signer.sign("/download/file", expires);
+        let tampered = url.replace(&expires.to_string(), &(expires + 1).to_string());
+        assert!(!signer.verify(&request(&tampered)));
+
+        // Missing parameters
+        assert!(!signer.verify(&request("/download/file")));
+        assert!(!signer.verify(&request("/download/file?expires=99999999999")));
+    }
+
+    #[test]
+    fn signed_url_expired() {
+        let signer = UrlSigner::new(b"secret");
+        let url = signer.sign("/download/file", now() - 1);
+        assert!(!signer.verify(&request(&url)));
+    }
+
+    #[test]
+    fn signed_urls_middleware() {
+        let signer = UrlSigner::new(b"secret");
+        let middleware = SignedUrls::new("/download/", signer.clone());
+        assert!(middleware.before(&mut request("/public")).is_none());
+        assert!(middleware.before(&mut request("/download/file")).is_some());
+        let url = signer.sign_for("/download/file", Duration::from_secs(60));
+        assert!(middleware.before(&mut request(&url)).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::webserver::{html_escape, RequestType, Resource, ResourceType, Response, StatusCode};
+
+pub const SITEMAP_PATH: &str = "/sitemap.xml";
+pub const CONTENT_TYPE: &str = "application/xml; charset=utf-8";
+/// The priority of pages that did not set one, which is also what search engines assume.
+pub const DEFAULT_PRIORITY: f32 = 0.5;
+
+/// Whether a resource is listed in the sitemap, set with `Resource::with_sitemap_priority` and
+/// `Resource::without_sitemap`.
+#[derive(Clone, Copy, Default)]
+pub(crate) enum SitemapEntry {
+    /// Listed with the default priority if it is a page, see `Resource::sitemap_priority`.
+    #[default]
+    Auto,
+    Priority(f32),
+    Excluded,
+}
+
+/// The `/sitemap.xml` served by `App::enable_sitemap`, listing the pages of the site under
+/// `base_url`, such as `https://www.daanlubbers.nl`.
+pub struct Sitemap {
+    base_url: String,
+}
+
+impl Sitemap {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// The URL the sitemap is served at, to refer to from robots.txt and sitemap pings.
+    pub fn url(&self) -> String {
+        format!("{}{SITEMAP_PATH}", self.base_url)
+    }
+
+    /// The sitemap listing `pages`, which are paths or absolute canonical URLs with their
+    /// priority. The URLs are sorted, and listed once.
+    pub fn to_xml(&self, pages: &[(String, f32)]) -> String {
+        let mut urls = pages
+            .iter()
+            .map(|(page, priority)| {
+                let loc = match page.starts_with("http://") || page.starts_with("https://") {
+                    true => page.clone(),
+                    false => format!("{}{page}", self.base_url),
+                };
+                (loc, priority.clamp(0.0, 1.0))
+            })
+            .collect::<Vec<_>>();
+        urls.sort_by(|a, b| a.0.cmp(&b.0));
+        urls.dedup_by(|a, b| a.0 == b.0);
+
+        let mut xml = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+        );
+        for (loc, priority) in urls {
+            xml.push_str(&format!(
+                "<url><loc>{}</loc><priority>{priority:.1}</priority></url>\n",
+                html_escape(&loc)
+            ));
+        }
+        xml.push_str("</urlset>\n");
+        xml
+    }
+
+    pub fn response(&self, pages: &[(String, f32)]) -> Response {
+        Response::text(StatusCode::OK, self.to_xml(pages)).with_header("Content-Type", CONTENT_TYPE)
+    }
+}
+
+/// Builds a robots.txt, served with `App::enable_robots_txt`.
+///
+/// Rules apply to the user agent last given with `user_agent`, which is `*` until then. A group
+/// without rules allows everything.
+#[derive(Clone)]
+pub struct RobotsTxt {
+    groups: Vec<(String, Vec<String>)>,
+    sitemaps: Vec<String>,
+}
+
+impl Default for RobotsTxt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RobotsTxt {
+    pub fn new() -> Self {
+        Self {
+            groups: vec![("*".to_string(), vec![])],
+            sitemaps: vec![],
+        }
+    }
+
+    /// Start the rules for `agent`, such as `GPTBot`.
+    pub fn user_agent(mut self, agent: &str) -> Self {
+        match self.groups.as_mut_slice() {
+            // No rules for `*` were given, so it was never meant.
+            [(only, rules)] if only == "*" && rules.is_empty() => *only = agent.to_string(),
+            _ => self.groups.push((agent.to_string(), vec![])),
+        }
+        self
+    }
+
+    pub fn allow(mut self, path: &str) -> Self {
+        self.push_rule(format!("Allow: {path}"));
+        self
+    }
+
+    pub fn disallow(mut self, path: &str) -> Self {
+        self.push_rule(format!("Disallow: {path}"));
+        self
+    }
+
+    pub fn with_sitemap(mut self, url: &str) -> Self {
+        self.sitemaps.push(url.to_string());
+        self
+    }
+
+    pub(crate) fn has_sitemap(&self) -> bool {
+        !self.sitemaps.is_empty()
+    }
+
+    fn push_rule(&mut self, rule: String) {
+        self.groups.last_mut().unwrap().1.push(rule);
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut groups = vec![];
+        for (agent, rules) in &self.groups {
+            let mut group = format!("User-agent: {agent}\n");
+            if rules.is_empty() {
+                group.push_str("Disallow:\n");
+            }
+            for rule in rules {
+                group.push_str(rule);
+                group.push('\n');
+            }
+            groups.push(group);
+        }
+        if !self.sitemaps.is_empty() {
+            groups.push(
+                self.sitemaps
+                    .iter()
+                    .map(|url| format!("Sitemap: {url}\n"))
+                    .collect(),
+            );
+        }
+        groups.join("\n")
+    }
+
+    /// A GET resource at `path` serving the file, which is left out of the sitemap.
+    pub fn resource(self, path: &str) -> Resource {
+        let text = self.to_text();
+        Resource::new(
+            RequestType::GET,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(move |_| {
+                Ok(Response::text(StatusCode::OK, text.clone())
+                    .with_header("Content-Type", "text/plain; charset=utf-8"))
+            }),
+        )
+        .without_sitemap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sitemap_xml() {
+        let sitemap = Sitemap::new("https://www.daanlubbers.nl/");
+        assert_eq!(sitemap.url(), "https://www.daanlubbers.nl/sitemap.xml");
+        let xml = sitemap.to_xml(&[
+            ("/b?x=1&y=2".to_string(), 0.5),
+            ("/".to_string(), 2.0),
+            ("https://www.daanlubbers.nl/".to_string(), 0.1),
+            ("https://example.com/a".to_string(), 0.3),
+        ]);
+        assert_eq!(
+            xml,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n\
+             <url><loc>https://example.com/a</loc><priority>0.3</priority></url>\n\
+             <url><loc>https://www.daanlubbers.nl/</loc><priority>1.0</priority></url>\n\
+             <url><loc>https://www.daanlubbers.nl/b?x=1&amp;y=2</loc><priority>0.5</priority></url>\n\
+             </urlset>\n"
+        );
+    }
+
+    #[test]
+    fn robots_txt() {
+        assert_eq!(RobotsTxt::new().to_text(), "User-agent: *\nDisallow:\n");
+        let robots = RobotsTxt::new()
+            .disallow("/admin")
+            .allow("/admin/public")
+            .user_agent("GPTBot")
+            .disallow("/")
+            .with_sitemap("https://www.daanlubbers.nl/sitemap.xml");
+        assert_eq!(
+            robots.to_text(),
+            "User-agent: *\nDisallow: /admin\nAllow: /admin/public\n\n\
+             User-agent: GPTBot\nDisallow: /\n\n\
+             Sitemap: https://www.daanlubbers.nl/sitemap.xml\n"
+        );
+        let robots = RobotsTxt::new().user_agent("GPTBot").disallow("/");
+        assert_eq!(robots.to_text(), "User-agent: GPTBot\nDisallow: /\n");
+    }
+}
@@ -0,0 +1,185 @@
+use crate::webserver::{Response, StatusCode};
+use std::{
+    fmt::{self, Display},
+    io::{self, Read},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+pub const CONTENT_TYPE: &str = "text/event-stream";
+/// How long a stream may be idle before a comment is sent, so proxies don't time it out and
+/// closed connections are noticed.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// A server-sent event, as received by an `EventSource` in the browser.
+#[derive(Clone, Debug)]
+pub struct Event {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            event: None,
+            data: data.into(),
+            id: None,
+            retry: None,
+        }
+    }
+
+    /// The event type, which `EventSource` listeners are registered for. Without one the event
+    /// goes to `onmessage`.
+    pub fn with_event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    /// The ID the browser sends back in a `Last-Event-ID` header when it reconnects.
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// How long the browser waits before reconnecting after the stream is closed.
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+/// Line breaks can't be escaped, so they are left out of the event type and ID, and split the
+/// data over several `data` fields.
+impl Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", single_line(event))?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", single_line(id))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            writeln!(f, "data: {line}")?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Sends events to a stream created with `Response::event_stream`. It can be cloned to send
+/// from several threads, and the stream ends once every sender is dropped.
+#[derive(Clone)]
+pub struct EventSender {
+    sender: Sender<String>,
+}
+
+impl EventSender {
+    /// Send an event, or fail once the client has disconnected, to stop producing events.
+    pub fn send(&self, event: &Event) -> Result<(), String> {
+        self.sender
+            .send(event.to_string())
+            .map_err(|_| "Event stream closed".to_string())
+    }
+
+    /// Send a comment, which clients ignore.
+    pub fn comment(&self, comment: &str) -> Result<(), String> {
+        let comment = comment.replace(['\r', '\n'], " ");
+        self.sender
+            .send(format!(": {comment}\n\n"))
+            .map_err(|_| "Event stream closed".to_string())
+    }
+}
+
+/// The body of an event stream, reading events as they are sent and a keep-alive comment
+/// after every `keep_alive` without one.
+struct EventStream {
+    receiver: Receiver<String>,
+    keep_alive: Duration,
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            let next = match self.receiver.recv_timeout(self.keep_alive) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => ":\n\n".to_string(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.pending = next.into_bytes();
+            self.position = 0;
+        }
+        let length = buf.len().min(self.pending.len() - self.position);
+        buf[..length].copy_from_slice(&self.pending[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+/// A response streaming events from the returned sender, with a comment after every
+/// `keep_alive` without events. It keeps a worker thread busy for as long as it is open.
+pub fn event_stream(keep_alive: Duration) -> (Response, EventSender) {
+    let (sender, receiver) = mpsc::channel();
+    let body = EventStream {
+        receiver,
+        keep_alive,
+        pending: vec![],
+        position: 0,
+    };
+    let response = Response::stream(StatusCode::OK, body)
+        .with_header("Content-Type", CONTENT_TYPE)
+        .with_header("Cache-Control", "no-cache")
+        // Keeps nginx from buffering the events.
+        .with_header("X-Accel-Buffering", "no");
+    (response, EventSender { sender })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::Body;
+
+    #[test]
+    fn event_format() {
+        let event = Event::new("line 1\nline 2\r\n")
+            .with_event("status\nupdate")
+            .with_id("42")
+            .with_retry(Duration::from_secs(3));
+        assert_eq!(
+            event.to_string(),
+            "event: statusupdate\nid: 42\nretry: 3000\ndata: line 1\ndata: line 2\ndata: \n\n"
+        );
+        assert_eq!(Event::new("").to_string(), "data: \n\n");
+    }
+
+    #[test]
+    fn stream_events() {
+        let (response, events) = event_stream(Duration::from_millis(10));
+        assert_eq!(response.header("Content-Type"), Some(CONTENT_TYPE));
+        let Body::Stream(mut body) = response.body else {
+            panic!("Expected a stream");
+        };
+        events.send(&Event::new("up")).unwrap();
+        events.comment("hello\nworld").unwrap();
+        let mut buf = [0; 64];
+        let read = body.read(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"data: up\n\n");
+        let read = body.read(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b": hello world\n\n");
+        // Nothing was sent in time, so a keep-alive comment is.
+        let read = body.read(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b":\n\n");
+
+        drop(events);
+        assert_eq!(body.read(&mut buf).unwrap(), 0);
+        let (response, events) = event_stream(DEFAULT_KEEP_ALIVE);
+        drop(response);
+        assert!(events.send(&Event::new("gone")).is_err());
+    }
+}
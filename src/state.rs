@@ -0,0 +1,93 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::Arc,
+};
+
+/// Application state shared with every handler, holding at most one value per type.
+///
+/// Values are added with `App::with_state` and read from handlers through `Request::state`.
+#[derive(Clone, Default)]
+pub struct State {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl State {
+    /// Store `value`, replacing any earlier value of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .cloned()
+            .and_then(|value| value.downcast::<T>().ok())
+    }
+}
+
+/// Values middleware attach to a request for the handlers after it, such as the signed in user
+/// from `Auth`, holding at most one value per type. See `Request::extensions_mut`.
+///
+/// Defining a type for each value, rather than storing a plain `String`, keeps middleware from
+/// reading or replacing each other's values by accident.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Store `value`, replacing any earlier value of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>())?.downcast_ref::<T>()
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<Arc<T>> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast::<T>().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_by_type() {
+        let mut state = State::default();
+        assert!(state.get::<u32>().is_none());
+
+        state.insert(1u32);
+        state.insert("name".to_string());
+        assert_eq!(*state.get::<u32>().unwrap(), 1);
+        assert_eq!(*state.get::<String>().unwrap(), "name");
+        assert!(state.get::<u64>().is_none());
+
+        state.insert(2u32);
+        assert_eq!(*state.get::<u32>().unwrap(), 2);
+    }
+
+    #[test]
+    fn extensions_by_type() {
+        struct CurrentUser(&'static str);
+
+        let mut extensions = Extensions::default();
+        assert!(!extensions.contains::<CurrentUser>());
+        extensions.insert(CurrentUser("daan"));
+        extensions.insert(7u8);
+        assert_eq!(extensions.get::<CurrentUser>().unwrap().0, "daan");
+        let copy = extensions.clone();
+        assert_eq!(extensions.remove::<u8>().as_deref(), Some(&7));
+        assert!(extensions.get::<u8>().is_none());
+        assert_eq!(copy.get::<u8>(), Some(&7));
+    }
+}
@@ -0,0 +1,522 @@
+use crate::compression::{add_vary, Encoding};
+use crate::log;
+use crate::negotiation::best_language;
+use crate::security::safe_path;
+use crate::webserver::{
+    file_content_type_header, html_escape, http_date, percent_encode, Error, Request, RequestType,
+    Response, StatusCode,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A directory of files served for GET requests to `<prefix>/<path>`, registered with
+/// `App::serve_dir`.
+///
+/// A request for a subdirectory is answered with its `index.html`, after redirecting to the
+/// path with a trailing slash so relative links in it work. Directories without one are a 404,
+/// unless `autoindex` is enabled. Hidden files, starting with a dot, are never served.
+pub struct StaticDir {
+    prefix: String,
+    dir: PathBuf,
+    autoindex: bool,
+    localized: bool,
+    precompressed: bool,
+}
+
+impl StaticDir {
+    pub fn new(prefix: &str, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            dir: dir.into(),
+            autoindex: false,
+            localized: false,
+            precompressed: false,
+        }
+    }
+
+    /// List the files of directories without an `index.html`, with their sizes and when they
+    /// were last modified.
+    pub fn autoindex(&mut self) -> &mut Self {
+        self.autoindex = true;
+        self
+    }
+
+    /// Serve translations of files, such as `about.nl.html` and `about.en.html` next to
+    /// `about.html`, by the `Accept-Language` header of requests for the file. Clients that
+    /// accept none of the languages get the file itself, or the first translation by language
+    /// if there is none. Responses name their language in `Content-Language`.
+    ///
+    /// The language is the part before the extension that looks like a language tag, two or
+    /// three letters with an optional region, so `app.min.js` counts as a translation as well.
+    pub fn localized(&mut self) -> &mut Self {
+        self.localized = true;
+        self
+    }
+
+    /// Serve files compressed ahead of time, such as `app.css.br` and `app.css.gz` next to
+    /// `app.css`, to clients that accept brotli or gzip, instead of the file itself. This needs
+    /// no compression feature, as only the compressed files are read.
+    pub fn precompressed(&mut self) -> &mut Self {
+        self.precompressed = true;
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether this directory handles the request.
+    pub fn matches(&self, request: &Request) -> bool {
+        request.request_type() == RequestType::GET && self.relative_path(request.path()).is_some()
+    }
+
+    /// Answer the request with a file, an index or a redirect, or `None` if there is nothing at
+    /// its path.
+    pub fn handle(&self, request: &Request) -> Result<Option<Response>, Error> {
+        let Some(relative) = self.relative_path(request.path()) else {
+            return Ok(None);
+        };
+        let Some(path) = resolve(&self.dir, relative) else {
+            return Ok(None);
+        };
+        let Ok(metadata) = fs::metadata(&path) else {
+            return Ok(self.file(path, request));
+        };
+        if metadata.is_file() {
+            return Ok(self.file(path, request));
+        }
+        if !request.path().ends_with('/') {
+            let location = match request.query() {
+                Some(query) => format!("{}/?{query}", request.path()),
+                None => format!("{}/", request.path()),
+            };
+            return Ok(Some(Response::redirect(
+                StatusCode::PermanentRedirect,
+                location,
+            )));
+        }
+        if let Some(response) = self.file(path.join("index.html"), request) {
+            return Ok(Some(response));
+        }
+        if !self.autoindex {
+            return Ok(None);
+        }
+        let is_root = relative.trim_matches('/').is_empty();
+        let listing = listing(request.path(), &path, !is_root).map_err(|e| e.to_string())?;
+        Ok(Some(
+            Response::text(StatusCode::OK, listing)
+                .with_header("Content-Type", "text/html; charset=utf-8"),
+        ))
+    }
+
+    /// The file at `path`, or the translation of it the client prefers. `None` if there is
+    /// neither.
+    fn file(&self, path: PathBuf, request: &Request) -> Option<Response> {
+        let translations = match self.localized {
+            true => translations(&path),
+            false => vec![],
+        };
+        if translations.is_empty() {
+            if !path.is_file() {
+                return None;
+            }
+            return Some(self.file_response(path, request));
+        }
+        let languages: Vec<&str> = translations
+            .iter()
+            .map(|(language, _)| language.as_str())
+            .collect();
+        let language = request
+            .header("Accept-Language")
+            .and_then(|accept| best_language(accept, &languages));
+        let (language, path) = match language {
+            Some(language) => translations
+                .iter()
+                .find(|(available, _)| available == language)
+                .map(|(language, path)| (Some(language), path.clone()))?,
+            None if path.is_file() => (None, path),
+            None => (Some(&translations[0].0), translations[0].1.clone()),
+        };
+        let mut response = self
+            .file_response(path, request)
+            .with_header("Vary", "Accept-Language");
+        if let Some(language) = language {
+            response.add_header("Content-Language", language);
+        }
+        Some(response)
+    }
+
+    /// The response with the file at `path`, or with a precompressed version of it.
+    fn file_response(&self, path: PathBuf, request: &Request) -> Response {
+        let mut encodings = vec![];
+        if self.precompressed {
+            for encoding in Encoding::ALL {
+                let mut compressed = path.clone().into_os_string();
+                compressed.push(format!(".{}", encoding.extension()));
+                let compressed = PathBuf::from(compressed);
+                if compressed.is_file() {
+                    encodings.push((encoding, compressed));
+                }
+            }
+        }
+        let available: Vec<Encoding> = encodings.iter().map(|(encoding, _)| *encoding).collect();
+        let encoding = Encoding::negotiate(request.header("Accept-Encoding"), &available);
+        let mut response = match encoding {
+            Some(encoding) => {
+                let (_, compressed) = encodings
+                    .into_iter()
+                    .find(|(available, _)| *available == encoding)
+                    .unwrap();
+                // Last-Modified is that of the file, so it doesn't change with the encoding.
+                let modified = fs::metadata(&path).and_then(|metadata| metadata.modified());
+                let mut response = Response::file(StatusCode::OK, compressed)
+                    .with_header("Content-Encoding", encoding.name());
+                // The type is that of the file as well, rather than of `.gz` or `.br`.
+                if let Some(content_type) = file_content_type_header(&path) {
+                    response.add_header("Content-Type", content_type);
+                }
+                if let Ok(modified) = modified {
+                    response.add_header("Last-Modified", http_date(modified));
+                }
+                response
+            }
+            None => Response::file(StatusCode::OK, path),
+        };
+        if !available.is_empty() {
+            add_vary(&mut response);
+        }
+        response.apply_last_modified(request);
+        response
+    }
+
+    /// The part of the path after the prefix, if the path is inside this directory.
+    fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        match path.strip_prefix(&self.prefix)? {
+            "" => Some(""),
+            rest => rest.strip_prefix('/'),
+        }
+    }
+}
+
+/// The path inside `dir` for a path relative to it, see `safe_path`. Hidden files are rejected
+/// as well.
+fn resolve(dir: &Path, relative: &str) -> Option<PathBuf> {
+    let path = match safe_path(dir, relative) {
+        Ok(path) => path,
+        Err(e) => {
+            log!("Rejected path: {e}");
+            return None;
+        }
+    };
+    let hidden = path
+        .strip_prefix(dir)
+        .ok()?
+        .iter()
+        .any(|segment| segment.as_encoded_bytes().starts_with(b"."));
+    (!hidden).then_some(path)
+}
+
+/// The translations of the file at `path`, such as `about.nl.html` for `about.html`, with
+/// their language and sorted by it.
+fn translations(path: &Path) -> Vec<(String, PathBuf)> {
+    let (Some(dir), Some(name)) = (
+        path.parent(),
+        path.file_name().and_then(|name| name.to_str()),
+    ) else {
+        return vec![];
+    };
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut translations: Vec<(String, PathBuf)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let file_name = entry.file_name();
+            let language = file_name
+                .to_str()?
+                .strip_prefix(stem)?
+                .strip_prefix('.')?
+                .strip_suffix(extension.as_str())?;
+            (is_language_tag(language) && entry.path().is_file())
+                .then(|| (language.to_string(), entry.path()))
+        })
+        .collect();
+    translations.sort();
+    translations
+}
+
+/// Whether `text` looks like a language tag such as `nl` or `en-GB`.
+fn is_language_tag(text: &str) -> bool {
+    let mut subtags = text.split('-');
+    let language = subtags.next().unwrap_or_default();
+    (2..=3).contains(&language.len())
+        && language.bytes().all(|byte| byte.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len())
+                && subtag.bytes().all(|byte| byte.is_ascii_alphanumeric())
+        })
+}
+
+/// An HTML page listing the entries of `dir`, which is at `url_path`. Directories come first,
+/// and both are sorted by name, after a link to the parent directory if there is one.
+fn listing(url_path: &str, dir: &Path, parent: bool) -> std::io::Result<String> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        entries.push((!metadata.is_dir(), name, metadata));
+    }
+    entries.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+
+    let title = format!("Index of {}", html_escape(url_path));
+    let mut rows = String::new();
+    if parent {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td>-</td><td>-</td></tr>");
+    }
+    for (is_file, name, metadata) in entries {
+        let (href, name, size) = match is_file {
+            true => (
+                percent_encode(&name),
+                html_escape(&name),
+                format_size(metadata.len()),
+            ),
+            false => (
+                format!("{}/", percent_encode(&name)),
+                format!("{}/", html_escape(&name)),
+                "-".to_string(),
+            ),
+        };
+        let modified = metadata
+            .modified()
+            .map_or_else(|_| "-".to_string(), http_date);
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{href}\">{name}</a></td><td>{size}</td><td>{modified}</td></tr>"
+        ));
+    }
+    Ok(format!(
+        "<!DOCTYPE html><html lang=\"en\"><head><title>{title}</title></head><body>\
+         <h1>{title}</h1><table><tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\
+         {rows}</table></body></html>"
+    ))
+}
+
+/// A size in bytes, or in KiB, MiB or GiB with one decimal from 1 KiB on.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::Body;
+    use std::io::BufReader;
+
+    fn request(target: &str) -> Request {
+        let text = format!("GET {target} HTTP/1.1\r\n\r\n");
+        Request::from_reader(&mut BufReader::new(text.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn resolve_paths() {
+        let dir = Path::new("static");
+        assert_eq!(resolve(dir, "").unwrap(), dir);
+        assert_eq!(
+            resolve(dir, "a/b.txt").unwrap(),
+            dir.join("a").join("b.txt")
+        );
+        assert!(resolve(dir, "../b.txt").is_none());
+        assert!(resolve(dir, "a/.git/config").is_none());
+        assert!(resolve(dir, "a\\..\\b").is_none());
+        assert!(resolve(dir, "%2e%2e/b.txt").is_none());
+        assert!(resolve(dir, "a/%2egit/config").is_none());
+        assert_eq!(resolve(dir, "a%20b.txt").unwrap(), dir.join("a b.txt"));
+        assert_eq!(format_size(1000), "1000 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 << 30), "5.0 GiB");
+    }
+
+    #[test]
+    fn index_and_listing() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_static_dir");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::create_dir_all(dir.join("site")).unwrap();
+        fs::write(dir.join("docs").join("b.txt"), "b").unwrap();
+        fs::write(dir.join("docs").join("a <1>.txt"), "a".repeat(2048)).unwrap();
+        fs::write(dir.join("docs").join(".secret"), "").unwrap();
+        fs::create_dir(dir.join("docs").join("sub")).unwrap();
+        fs::write(dir.join("site").join("index.html"), "index").unwrap();
+
+        let mut static_dir = StaticDir::new("/files/", &dir);
+        assert!(static_dir.matches(&request("/files")));
+        assert!(!static_dir.matches(&request("/filesystem")));
+        let handle = |static_dir: &StaticDir, target| static_dir.handle(&request(target)).unwrap();
+
+        let response = handle(&static_dir, "/files/docs/b.txt").unwrap();
+        assert!(matches!(response.body, Body::File(_)));
+        assert!(response.header("Last-Modified").is_some());
+        assert!(handle(&static_dir, "/files/docs/missing.txt").is_none());
+        assert!(handle(&static_dir, "/files/docs/.secret").is_none());
+
+        let response = handle(&static_dir, "/files/site?x=1").unwrap();
+        assert_eq!(response.status_code.code(), 301);
+        assert_eq!(response.header("Location"), Some("/files/site/?x=1"));
+        let response = handle(&static_dir, "/files/site/").unwrap();
+        assert!(matches!(&response.body, Body::File(path) if path.ends_with("index.html")));
+
+        assert!(handle(&static_dir, "/files/docs/").is_none());
+        static_dir.autoindex();
+        let response = handle(&static_dir, "/files/docs/").unwrap();
+        let Body::Text(listing) = response.body else {
+            panic!("Expected a listing");
+        };
+        assert!(listing.contains("<h1>Index of /files/docs/</h1>"));
+        let sub = listing.find("href=\"sub/\"").unwrap();
+        let a = listing
+            .find("<a href=\"a%20%3C1%3E.txt\">a &lt;1&gt;.txt</a>")
+            .unwrap();
+        let b = listing.find("href=\"b.txt\"").unwrap();
+        assert!(listing.contains("<a href=\"../\">"));
+        assert!(sub < a && a < b);
+        assert!(listing.contains("<td>2.0 KiB</td>"));
+        assert!(!listing.contains(".secret"));
+        let Body::Text(listing) = handle(&static_dir, "/files/").unwrap().body else {
+            panic!("Expected a listing");
+        };
+        assert!(listing.contains("href=\"docs/\"") && !listing.contains("href=\"../\""));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn translations() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_static_dir_localized");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("blog")).unwrap();
+        for name in [
+            "about.html",
+            "about.nl.html",
+            "about.en-GB.html",
+            "about.old.html",
+        ] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        fs::write(dir.join("contact.nl.html"), "").unwrap();
+        fs::write(dir.join("contact.en.html"), "").unwrap();
+        fs::write(dir.join("blog").join("index.nl.html"), "").unwrap();
+
+        let mut static_dir = StaticDir::new("/", &dir);
+        let handle = |static_dir: &StaticDir, target: &str, accept: &str| {
+            let text = format!("GET {target} HTTP/1.1\r\nAccept-Language: {accept}\r\n\r\n");
+            let request = Request::from_reader(&mut BufReader::new(text.as_bytes())).unwrap();
+            static_dir.handle(&request).unwrap()
+        };
+        let file = |response: &Response| match &response.body {
+            Body::File(path) => path.file_name().unwrap().to_string_lossy().into_owned(),
+            _ => panic!("Expected a file"),
+        };
+        let response = handle(&static_dir, "/about.html", "nl").unwrap();
+        assert_eq!(file(&response), "about.html");
+        assert!(response.header("Vary").is_none());
+        assert!(handle(&static_dir, "/contact.html", "nl").is_none());
+
+        static_dir.localized();
+        let response = handle(&static_dir, "/about.html", "nl-NL, en;q=0.5").unwrap();
+        assert_eq!(file(&response), "about.nl.html");
+        assert_eq!(response.header("Content-Language"), Some("nl"));
+        assert_eq!(response.header("Vary"), Some("Accept-Language"));
+        let response = handle(&static_dir, "/about.html", "en").unwrap();
+        assert_eq!(file(&response), "about.en-GB.html");
+        let response = handle(&static_dir, "/about.html", "fr").unwrap();
+        assert_eq!(file(&response), "about.html");
+        assert!(response.header("Content-Language").is_none());
+        assert_eq!(response.header("Vary"), Some("Accept-Language"));
+        // Without the file itself, the first translation is the default.
+        let response = handle(&static_dir, "/contact.html", "fr").unwrap();
+        assert_eq!(file(&response), "contact.en.html");
+        let response = handle(&static_dir, "/blog/", "en").unwrap();
+        assert_eq!(file(&response), "index.nl.html");
+        assert!(handle(&static_dir, "/missing.html", "nl").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn precompressed() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_static_dir_precompressed");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.css"), "body {}").unwrap();
+        fs::write(dir.join("app.css.br"), "br").unwrap();
+        fs::write(dir.join("app.css.gz"), "gz").unwrap();
+        fs::write(dir.join("app.js"), "").unwrap();
+
+        let mut static_dir = StaticDir::new("/", &dir);
+        let handle = |static_dir: &StaticDir, target: &str, accept: &str| {
+            let text = format!("GET {target} HTTP/1.1\r\nAccept-Encoding: {accept}\r\n\r\n");
+            let request = Request::from_reader(&mut BufReader::new(text.as_bytes())).unwrap();
+            static_dir.handle(&request).unwrap().unwrap()
+        };
+        let file = |response: &Response| match &response.body {
+            Body::File(path) => path.file_name().unwrap().to_string_lossy().into_owned(),
+            _ => panic!("Expected a file"),
+        };
+        assert_eq!(file(&handle(&static_dir, "/app.css", "br")), "app.css");
+
+        static_dir.precompressed();
+        let response = handle(&static_dir, "/app.css", "gzip, deflate, br");
+        assert_eq!(file(&response), "app.css.br");
+        assert_eq!(response.header("Content-Encoding"), Some("br"));
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        let modified = fs::metadata(dir.join("app.css"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(
+            response.header("Last-Modified"),
+            Some(http_date(modified).as_str())
+        );
+        let response = handle(&static_dir, "/app.css", "gzip");
+        assert_eq!(file(&response), "app.css.gz");
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/css; charset=utf-8")
+        );
+        let response = handle(&static_dir, "/app.css", "identity");
+        assert_eq!(file(&response), "app.css");
+        assert!(response.header("Content-Encoding").is_none());
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        let response = handle(&static_dir, "/app.js", "br");
+        assert!(response.header("Vary").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,602 @@
+use crate::assets::Assets;
+use crate::security::safe_path;
+use crate::webserver::{html_escape, Error, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// A value to substitute into a template.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Text(String),
+    /// Trusted HTML, such as a rendered blog post, which is inserted without escaping.
+    Html(String),
+    Bool(bool),
+    /// Repeated by `{{#each}}`, once per item.
+    List(Vec<Context>),
+}
+
+impl Value {
+    /// Whether `{{#if}}` shows its content: true, non-empty text or a non-empty list.
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(text) | Value::Html(text) => !text.is_empty(),
+            Value::Bool(value) => *value,
+            Value::List(items) => !items.is_empty(),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<Vec<Context>> for Value {
+    fn from(items: Vec<Context>) -> Self {
+        Value::List(items)
+    }
+}
+
+/// The values a template is rendered with, by name.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    values: HashMap<String, Value>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.insert(name, value);
+        self
+    }
+
+    pub fn insert(&mut self, name: &str, value: impl Into<Value>) {
+        self.values.insert(name.to_string(), value.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.get(name)
+    }
+}
+
+enum Token {
+    Text(String),
+    /// The trimmed contents of a `{{...}}` tag.
+    Tag(String),
+}
+
+enum Node {
+    Text(String),
+    Variable(String),
+    If {
+        name: String,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Each {
+        name: String,
+        body: Vec<Node>,
+    },
+    Partial(String),
+    /// `{{function argument}}`, calling a function of `Templates`.
+    Call {
+        function: String,
+        argument: String,
+    },
+    Block {
+        name: String,
+        body: Vec<Node>,
+    },
+}
+
+/// How deeply partials and layouts may be nested, which also stops them from including
+/// themselves forever.
+const MAX_DEPTH: usize = 16;
+
+/// A parsed HTML template.
+///
+/// `{{name}}` is replaced by the value of `name`, HTML-escaped unless it is `Value::Html`, or by
+/// nothing if there is no such value. `{{#if name}}...{{else}}...{{/if}}` shows one part or the other depending on
+/// whether the value is true, non-empty text or a non-empty list, and
+/// `{{#each name}}...{{/each}}` repeats its content for every item of a list. Inside the loop,
+/// names are looked up in the item first, and then outside it.
+///
+/// Rendered through `Templates`, `{{> nav}}` includes the template `nav.html`, with the same
+/// values. A template starting with `{{extends base}}` is rendered as the layout `base.html`
+/// instead, with each `{{block name}}...{{/block}}` of the layout replaced by the block of the
+/// same name in the template, if it has one. `{{asset app.css}}` calls the function `asset` with
+/// `app.css` and inserts the result, HTML-escaped, see `Templates::with_function`.
+pub struct Template {
+    nodes: Vec<Node>,
+    /// The name of the layout this template extends.
+    extends: Option<String>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut tokens = tokenize(source)?;
+        let first = tokens
+            .iter()
+            .position(|token| !matches!(token, Token::Text(text) if text.trim().is_empty()));
+        let extends = match first.map(|first| (first, &tokens[first])) {
+            Some((first, Token::Tag(tag))) if tag.starts_with("extends ") => {
+                let layout = template_name(&tag["extends ".len()..]);
+                tokens.drain(..=first);
+                Some(layout)
+            }
+            _ => None,
+        };
+        match parse_nodes(&mut tokens.into_iter())? {
+            (nodes, None) => Ok(Self { nodes, extends }),
+            (_, Some(tag)) => Err(format!("Unexpected {tag} in template")),
+        }
+    }
+
+    /// Render the template on its own, which fails if it uses partials or a layout.
+    pub fn render(&self, context: &Context) -> Result<String, String> {
+        if self.extends.is_some() {
+            return Err("Layouts are only available through Templates".to_string());
+        }
+        let renderer = Renderer {
+            templates: None,
+            blocks: HashMap::new(),
+        };
+        let mut output = String::new();
+        renderer.render(&self.nodes, &[context], 0, &mut output)?;
+        Ok(output)
+    }
+}
+
+/// The file name of a partial or layout: the name as it is with an extension, or with `.html`.
+fn template_name(name: &str) -> String {
+    let name = name.trim();
+    match name
+        .rsplit('/')
+        .next()
+        .is_some_and(|file| file.contains('.'))
+    {
+        true => name.to_string(),
+        false => format!("{name}.html"),
+    }
+}
+
+/// Add the blocks in `nodes` to `blocks`, unless a block of the same name is already there.
+fn collect_blocks<'a>(nodes: &'a [Node], blocks: &mut HashMap<&'a str, &'a [Node]>) {
+    for node in nodes {
+        match node {
+            Node::Block { name, body } => {
+                blocks.entry(name).or_insert(body);
+                collect_blocks(body, blocks);
+            }
+            Node::If {
+                then, otherwise, ..
+            } => {
+                collect_blocks(then, blocks);
+                collect_blocks(otherwise, blocks);
+            }
+            Node::Each { body, .. } => collect_blocks(body, blocks),
+            Node::Text(_) | Node::Variable(_) | Node::Partial(_) | Node::Call { .. } => {}
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            tokens.push(Token::Text(rest[..start].to_string()));
+        }
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| "Unclosed {{ in template".to_string())?;
+        let tag = after[..end].trim();
+        if tag.is_empty() {
+            return Err("Empty {{}} in template".to_string());
+        }
+        tokens.push(Token::Tag(tag.to_string()));
+        rest = &after[end + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push(Token::Text(rest.to_string()));
+    }
+    Ok(tokens)
+}
+
+/// The nodes up to the next `{{else}}` or closing tag, which is returned along with them, or up
+/// to the end of the template.
+fn parse_nodes(
+    tokens: &mut impl Iterator<Item = Token>,
+) -> Result<(Vec<Node>, Option<String>), String> {
+    let mut nodes = vec![];
+    while let Some(token) = tokens.next() {
+        let tag = match token {
+            Token::Text(text) => {
+                nodes.push(Node::Text(text));
+                continue;
+            }
+            Token::Tag(tag) => tag,
+        };
+        if let Some(name) = tag.strip_prefix("#if ") {
+            let name = name.trim().to_string();
+            let (then, end) = parse_nodes(tokens)?;
+            let otherwise = match end.as_deref() {
+                Some("/if") => vec![],
+                Some("else") => match parse_nodes(tokens)? {
+                    (otherwise, Some(end)) if end == "/if" => otherwise,
+                    _ => return Err(format!("Unclosed #if {name} in template")),
+                },
+                _ => return Err(format!("Unclosed #if {name} in template")),
+            };
+            nodes.push(Node::If {
+                name,
+                then,
+                otherwise,
+            });
+        } else if let Some(name) = tag.strip_prefix("#each ") {
+            let name = name.trim().to_string();
+            match parse_nodes(tokens)? {
+                (body, Some(end)) if end == "/each" => nodes.push(Node::Each { name, body }),
+                _ => return Err(format!("Unclosed #each {name} in template")),
+            }
+        } else if let Some(name) = tag.strip_prefix("block ") {
+            let name = name.trim().to_string();
+            match parse_nodes(tokens)? {
+                (body, Some(end)) if end == "/block" => nodes.push(Node::Block { name, body }),
+                _ => return Err(format!("Unclosed block {name} in template")),
+            }
+        } else if let Some(name) = tag.strip_prefix('>') {
+            nodes.push(Node::Partial(template_name(name)));
+        } else if tag == "else" || tag.starts_with('/') {
+            return Ok((nodes, Some(tag)));
+        } else if tag.starts_with("extends ") {
+            return Err("{{extends}} has to come first in template".to_string());
+        } else if tag.starts_with('#') {
+            return Err(format!("Unknown block {tag} in template"));
+        } else if let Some((function, argument)) = tag.split_once(char::is_whitespace) {
+            nodes.push(Node::Call {
+                function: function.to_string(),
+                argument: argument.trim().trim_matches('"').to_string(),
+            });
+        } else {
+            nodes.push(Node::Variable(tag));
+        }
+    }
+    Ok((nodes, None))
+}
+
+/// The value of `name` in the innermost context that has it.
+fn lookup<'a>(scopes: &[&'a Context], name: &str) -> Option<&'a Value> {
+    scopes.iter().rev().find_map(|context| context.get(name))
+}
+
+/// Renders templates, including partials from `templates`.
+struct Renderer<'a> {
+    templates: Option<&'a Templates>,
+    /// The blocks of the templates extending the layout being rendered, by name.
+    blocks: HashMap<&'a str, &'a [Node]>,
+}
+
+impl Renderer<'_> {
+    /// Render the nodes, which are `depth` partials deep.
+    fn render(
+        &self,
+        nodes: &[Node],
+        scopes: &[&Context],
+        depth: usize,
+        output: &mut String,
+    ) -> Result<(), String> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => output.push_str(text),
+                Node::Variable(name) => match lookup(scopes, name) {
+                    Some(Value::Text(text)) => output.push_str(&html_escape(text)),
+                    Some(Value::Html(html)) => output.push_str(html),
+                    Some(Value::Bool(value)) => output.push_str(&value.to_string()),
+                    Some(Value::List(_)) | None => {}
+                },
+                Node::If {
+                    name,
+                    then,
+                    otherwise,
+                } => match lookup(scopes, name).is_some_and(Value::is_truthy) {
+                    true => self.render(then, scopes, depth, output)?,
+                    false => self.render(otherwise, scopes, depth, output)?,
+                },
+                Node::Each { name, body } => {
+                    if let Some(Value::List(items)) = lookup(scopes, name) {
+                        for item in items {
+                            let mut inner = scopes.to_vec();
+                            inner.push(item);
+                            self.render(body, &inner, depth, output)?;
+                        }
+                    }
+                }
+                Node::Partial(name) => {
+                    let templates = self
+                        .templates
+                        .ok_or("Partials are only available through Templates")?;
+                    if depth >= MAX_DEPTH {
+                        return Err(format!("Partials nested too deeply at {name}"));
+                    }
+                    let partial = templates.get(name)?;
+                    self.render(&partial.nodes, scopes, depth + 1, output)?;
+                }
+                Node::Call { function, argument } => {
+                    let call = self
+                        .templates
+                        .ok_or("Functions are only available through Templates")?
+                        .functions
+                        .get(function)
+                        .ok_or_else(|| format!("Unknown function {function} in template"))?;
+                    output.push_str(&html_escape(&call(argument)?));
+                }
+                Node::Block { name, body } => {
+                    let body = self.blocks.get(name.as_str()).copied().unwrap_or(body);
+                    self.render(body, scopes, depth, output)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The templates in a directory, parsed when first used and again once their file changes.
+///
+/// Share them with handlers through `App::with_state`, so they can render pages with
+/// `request.state::<Templates>()`.
+pub struct Templates {
+    dir: PathBuf,
+    /// Parsed templates by path, with when their file was last modified.
+    cache: Mutex<HashMap<PathBuf, (SystemTime, Arc<Template>)>>,
+    functions: HashMap<String, TemplateFunction>,
+}
+
+type TemplateFunction = Box<dyn Fn(&str) -> Result<String, String> + Send + Sync>;
+
+impl Templates {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            cache: Mutex::default(),
+            functions: HashMap::new(),
+        }
+    }
+
+    /// Let templates call `function` as `{{name argument}}`. Rendering fails with its error.
+    pub fn with_function(
+        mut self,
+        name: &str,
+        function: impl Fn(&str) -> Result<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.functions.insert(name.to_string(), Box::new(function));
+        self
+    }
+
+    /// Resolve `{{asset app.css}}` to the fingerprinted URL of the file, see `Assets`.
+    pub fn with_assets(self, assets: Arc<Assets>) -> Self {
+        self.with_function("asset", move |name| {
+            assets
+                .url(name)
+                .map(str::to_string)
+                .ok_or_else(|| format!("Unknown asset {name}"))
+        })
+    }
+
+    /// The template at `name` in the directory, such as `blog/index.html`.
+    pub fn get(&self, name: &str) -> Result<Arc<Template>, String> {
+        let path = safe_path(&self.dir, name)?;
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| format!("Failed to read template {name}: {e}"))?;
+        if let Some((cached, template)) = self.cache.lock().unwrap().get(&path) {
+            if *cached == modified {
+                return Ok(Arc::clone(template));
+            }
+        }
+        let source = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read template {name}: {e}"))?;
+        let template = Arc::new(Template::parse(&source).map_err(|e| format!("{e} {name}"))?);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(path, (modified, Arc::clone(&template)));
+        Ok(template)
+    }
+
+    /// Render the template at `name`, with its partials and layout.
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, String> {
+        // The template and the layouts it extends, up to the one that extends nothing.
+        let mut chain = vec![self.get(name)?];
+        while let Some(layout) = &chain[chain.len() - 1].extends {
+            if chain.len() > MAX_DEPTH {
+                return Err(format!("Layouts nested too deeply at {layout}"));
+            }
+            chain.push(self.get(layout)?);
+        }
+        // Blocks of the templates nearer to `name` replace those of their layouts.
+        let mut blocks = HashMap::new();
+        for template in &chain {
+            collect_blocks(&template.nodes, &mut blocks);
+        }
+        let renderer = Renderer {
+            templates: Some(self),
+            blocks,
+        };
+        let mut output = String::new();
+        renderer.render(&chain[chain.len() - 1].nodes, &[context], 0, &mut output)?;
+        Ok(output)
+    }
+
+    /// A 200 response with the rendered page.
+    pub fn response(&self, name: &str, context: &Context) -> Result<Response, Error> {
+        Ok(Response::text(StatusCode::OK, self.render(name, context)?)
+            .with_header("Content-Type", "text/html; charset=utf-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, time::Duration};
+
+    fn render(source: &str, context: &Context) -> String {
+        Template::parse(source).unwrap().render(context).unwrap()
+    }
+
+    #[test]
+    fn substitution() {
+        let context = Context::new()
+            .with("title", "Fish & <Chips>")
+            .with("draft", false)
+            .with("body", Value::Html("<p>Hi</p>".to_string()));
+        assert_eq!(
+            render("<h1>{{ title }}</h1>{{body}}{{draft}}{{missing}}", &context),
+            "<h1>Fish &amp; &lt;Chips&gt;</h1><p>Hi</p>false"
+        );
+        assert_eq!(render("no tags", &context), "no tags");
+        // Functions belong to `Templates`.
+        let call = Template::parse("{{asset app.css}}").unwrap();
+        assert!(call.render(&context).is_err());
+    }
+
+    #[test]
+    fn blocks() {
+        let posts = vec![
+            Context::new().with("title", "First").with("new", true),
+            Context::new().with("title", "<Second>"),
+        ];
+        let context = Context::new()
+            .with("author", "Daan")
+            .with("posts", posts)
+            .with("empty", Vec::new());
+        let source = "{{#each posts}}<li>{{title}} by {{author}}{{#if new}} (new){{/if}}</li>\
+                      {{/each}}{{#if empty}}posts{{else}}no posts{{/if}}";
+        assert_eq!(
+            render(source, &context),
+            "<li>First by Daan (new)</li><li>&lt;Second&gt; by Daan</li>no posts"
+        );
+
+        for invalid in [
+            "{{title",
+            "{{}}",
+            "{{#if a}}",
+            "{{#if a}}{{else}}",
+            "{{#if a}}{{/each}}",
+            "{{#each a}}{{/if}}",
+            "{{/if}}",
+            "{{else}}",
+            "{{#unless a}}{{/unless}}",
+            "{{block a}}",
+            "<p></p>{{extends base}}",
+        ] {
+            assert!(Template::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn cache() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_templates");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("page.html");
+        fs::write(&path, "<p>{{text}}</p>").unwrap();
+
+        let templates = Templates::new(&dir);
+        let context = Context::new().with("text", "hi");
+        let first = templates.get("page.html").unwrap();
+        assert!(Arc::ptr_eq(&first, &templates.get("page.html").unwrap()));
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<p>hi</p>"
+        );
+
+        // A changed file is parsed again.
+        fs::write(&path, "<div>{{text}}</div>").unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(10))
+            .unwrap();
+        let response = templates.response("page.html", &context).unwrap();
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<div>hi</div>"
+        );
+
+        assert!(templates.get("missing.html").is_err());
+        assert!(templates.get("../page.html").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn layouts_and_partials() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_template_layouts");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("partials")).unwrap();
+        fs::write(
+            dir.join("base.html"),
+            "<title>{{block title}}Site{{/block}}</title>{{> partials/nav}}\
+             <main>{{block content}}{{/block}}</main>{{> footer.txt}}",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("partials").join("nav.html"),
+            "<nav>{{#each links}}{{> partials/link}}{{/each}}</nav>",
+        )
+        .unwrap();
+        fs::write(dir.join("partials").join("link.html"), "<a>{{name}}</a>").unwrap();
+        fs::write(dir.join("footer.txt"), "<footer>{{author}}</footer>").unwrap();
+        fs::write(
+            dir.join("page.html"),
+            "\n{{extends base}}ignored{{block content}}<p>{{text}}</p>{{/block}}",
+        )
+        .unwrap();
+        fs::write(dir.join("loop.html"), "{{> loop}}").unwrap();
+
+        let templates = Templates::new(&dir);
+        let context = Context::new()
+            .with("links", vec![Context::new().with("name", "Home")])
+            .with("author", "Daan")
+            .with("text", "Hello");
+        assert_eq!(
+            templates.render("page.html", &context).unwrap(),
+            "<title>Site</title><nav><a>Home</a></nav><main><p>Hello</p></main>\
+             <footer>Daan</footer>"
+        );
+        assert!(templates.render("loop.html", &context).is_err());
+        let page = templates.get("page.html").unwrap();
+        assert!(page.render(&context).is_err());
+        let base = templates.get("base.html").unwrap();
+        assert!(base.render(&context).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use crate::http_client::ParsedResponse;
+use crate::webserver::{App, Connection, Request};
+#[cfg(target_os = "linux")]
+use std::fs::File;
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr},
+    time::Duration,
+};
+
+/// Sends requests straight to an `App`, without a listener, so tests need no ports or waiting
+/// for the server to start. Requests go through the middleware, routes and static directories
+/// as they would when served, without a client address.
+///
+/// Meant for tests, so malformed requests panic rather than return an error.
+pub struct TestClient {
+    app: App,
+}
+
+impl TestClient {
+    pub fn new(app: App) -> Self {
+        Self { app }
+    }
+
+    pub fn app(&self) -> &App {
+        &self.app
+    }
+
+    pub fn get(&self, target: &str) -> ParsedResponse {
+        self.request("GET", target, &[], &[])
+    }
+
+    pub fn delete(&self, target: &str) -> ParsedResponse {
+        self.request("DELETE", target, &[], &[])
+    }
+
+    pub fn post(&self, target: &str, content_type: &str, body: &[u8]) -> ParsedResponse {
+        self.request("POST", target, &[("Content-Type", content_type)], body)
+    }
+
+    pub fn put(&self, target: &str, content_type: &str, body: &[u8]) -> ParsedResponse {
+        self.request("PUT", target, &[("Content-Type", content_type)], body)
+    }
+
+    pub fn patch(&self, target: &str, content_type: &str, body: &[u8]) -> ParsedResponse {
+        self.request("PATCH", target, &[("Content-Type", content_type)], body)
+    }
+
+    /// Send any request. `Host` and `Content-Length` are added unless `headers` has them.
+    pub fn request(
+        &self,
+        method: &str,
+        target: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> ParsedResponse {
+        let has = |name: &str| {
+            headers
+                .iter()
+                .any(|(header, _)| header.eq_ignore_ascii_case(name))
+        };
+        let mut head = format!("{method} {target} HTTP/1.1\r\n");
+        if !has("Host") {
+            head.push_str("Host: localhost\r\n");
+        }
+        if !has("Content-Length") && !body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        let raw = [head.as_bytes(), body].concat();
+        let mut request = Request::from_reader(&mut raw.as_slice())
+            .unwrap_or_else(|e| panic!("Invalid test request: {e}"));
+        self.app.attach(&mut request, None);
+
+        let mut written = Written(vec![]);
+        self.app
+            .respond(&mut request)
+            .write_to(&mut written, false)
+            .unwrap();
+        ParsedResponse::from_reader(&mut written.0.as_slice())
+            .unwrap_or_else(|e| panic!("Invalid response: {e}"))
+    }
+}
+
+/// The connection a response is written to, which has nothing to read.
+struct Written(Vec<u8>);
+
+impl Read for Written {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Written {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for Written {
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_file(&self, _file: &File, _length: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::{AppConfig, Response, StatusCode};
+
+    #[test]
+    fn requests() {
+        let mut app = App::new(AppConfig::new(SocketAddr::from(([127, 0, 0, 1], 0)), 1, 5));
+        app.scope("/", |routes| {
+            routes.get("/", |_| Ok(Response::text(StatusCode::OK, "index")));
+            routes.post("/echo", |request| {
+                let kind = request.header("Content-Type").unwrap_or_default();
+                let body = String::from_utf8_lossy(request.body());
+                Ok(Response::text(StatusCode::OK, format!("{kind}: {body}")))
+            });
+        });
+        let client = TestClient::new(app);
+
+        let response = client.get("/");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text(), "index");
+        assert_eq!(response.header("Content-Length"), Some("5"));
+        let response = client.post("/echo", "text/plain", b"hello");
+        assert_eq!(response.text(), "text/plain: hello");
+        assert_eq!(client.get("/missing").status(), 404);
+        let response = client.request("POST", "/echo", &[("Content-Type", "text/csv")], b"");
+        assert_eq!(response.text(), "text/csv: ");
+    }
+}
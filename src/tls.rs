@@ -0,0 +1,424 @@
+use crate::acme::{pem_blocks, Certificate, CertificateStore};
+use crate::log;
+use crate::webserver::Connection;
+use rustls::{
+    crypto::ring::{default_provider, sign::any_supported_type},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig, ServerConnection, StreamOwned,
+};
+#[cfg(target_os = "linux")]
+use std::fs::File;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// The certificates a TLS listener presents, picked by the hostname clients send with SNI, so
+/// one listener can serve several sites. See `AppConfig::with_tls`.
+///
+/// Certificates can be replaced while the server runs, such as from
+/// `CertificateManager::on_renewed`, and are used from the next handshake on.
+#[derive(Debug, Default)]
+pub struct TlsCertificates {
+    hosts: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    default: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl TlsCertificates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Present `certificate` to clients asking for `hostname`. A hostname such as
+    /// `*.daanlubbers.nl` covers every subdomain without a certificate of its own.
+    pub fn insert(&self, hostname: &str, certificate: &Certificate) -> Result<(), String> {
+        let key = certified_key(certificate)?;
+        self.hosts
+            .write()
+            .unwrap()
+            .insert(hostname.to_ascii_lowercase(), key);
+        Ok(())
+    }
+
+    /// Whether there's no certificate to present at all, not even a default.
+    pub fn is_empty(&self) -> bool {
+        self.hosts.read().unwrap().is_empty() && self.default.read().unwrap().is_none()
+    }
+
+    pub fn remove(&self, hostname: &str) {
+        self.hosts
+            .write()
+            .unwrap()
+            .remove(&hostname.to_ascii_lowercase());
+    }
+
+    /// Present `certificate` to clients without SNI, or asking for a hostname without one.
+    /// Without a default, their handshakes fail.
+    pub fn set_default(&self, certificate: &Certificate) -> Result<(), String> {
+        *self.default.write().unwrap() = Some(certified_key(certificate)?);
+        Ok(())
+    }
+
+    /// Insert the certificate in every directory in `dir`, named after its hostname, such as
+    /// `dir/daanlubbers.nl/cert.pem` with the key in `key.pem` next to it. That's the layout
+    /// `CertificateStore` keeps. Returns how many were found.
+    pub fn load_dir(&self, dir: impl AsRef<Path>) -> Result<usize, String> {
+        let dir = dir.as_ref();
+        let entries =
+            fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {e}", dir.display()))?;
+        let mut count = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(hostname) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if !path.is_dir() {
+                continue;
+            }
+            if let Some(certificate) = CertificateStore::new(&path).load()? {
+                self.insert(hostname, &certificate)
+                    .map_err(|e| format!("{}: {e}", path.display()))?;
+                count += 1;
+            }
+        }
+        log!("Loaded {count} certificates from {}", dir.display());
+        Ok(count)
+    }
+
+    /// The certificate for `server_name`, a wildcard covering it, or the default.
+    fn find(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = server_name.map(str::to_ascii_lowercase) {
+            let hosts = self.hosts.read().unwrap();
+            let wildcard = name
+                .split_once('.')
+                .and_then(|(_, parent)| hosts.get(&format!("*.{parent}")));
+            if let Some(key) = hosts.get(&name).or(wildcard) {
+                return Some(Arc::clone(key));
+            }
+        }
+        self.default.read().unwrap().clone()
+    }
+}
+
+impl ResolvesServerCert for TlsCertificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = self.find(client_hello.server_name());
+        if key.is_none() {
+            log!("No certificate for {:?}", client_hello.server_name());
+        }
+        key
+    }
+}
+
+/// Parse a PEM chain and a PKCS #8, SEC1 or PKCS #1 private key.
+fn certified_key(certificate: &Certificate) -> Result<Arc<CertifiedKey>, String> {
+    let chain: Vec<CertificateDer<'static>> = pem_blocks(&certificate.chain, "CERTIFICATE")
+        .ok_or("Invalid certificate chain")?
+        .into_iter()
+        .map(CertificateDer::from)
+        .collect();
+    if chain.is_empty() {
+        return Err("No certificates in the chain".to_string());
+    }
+    let key = ["PRIVATE KEY", "EC PRIVATE KEY", "RSA PRIVATE KEY"]
+        .into_iter()
+        .find_map(|label| {
+            let der = pem_blocks(&certificate.key, label)?.into_iter().next()?;
+            Some(match label {
+                "PRIVATE KEY" => PrivateKeyDer::Pkcs8(der.into()),
+                "EC PRIVATE KEY" => PrivateKeyDer::Sec1(der.into()),
+                _ => PrivateKeyDer::Pkcs1(der.into()),
+            })
+        })
+        .ok_or("No private key found")?;
+    let key = any_supported_type(&key).map_err(|e| format!("Unsupported private key: {e}"))?;
+    Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+/// The configuration of a TLS listener presenting `certificates`, offering HTTP/2 with ALPN if
+/// `http2` is set.
+pub(crate) fn server_config(certificates: Arc<TlsCertificates>, http2: bool) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_no_client_auth()
+        .with_cert_resolver(certificates);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    if http2 {
+        config.alpn_protocols.insert(0, b"h2".to_vec());
+    }
+    Arc::new(config)
+}
+
+/// A connection to a TLS listener. The handshake happens on the first read, in the worker
+/// handling it rather than the accept loop.
+pub(crate) struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl TlsStream {
+    pub(crate) fn new(stream: TcpStream, config: &Arc<ServerConfig>) -> io::Result<Self> {
+        let connection = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+        Ok(Self(StreamOwned::new(connection, stream)))
+    }
+
+    /// Finish the handshake, returning whether the client picked HTTP/2.
+    pub(crate) fn handshake(&mut self) -> io::Result<bool> {
+        while self.0.conn.is_handshaking() {
+            self.0.conn.complete_io(&mut self.0.sock)?;
+        }
+        Ok(self.0.conn.alpn_protocol() == Some(b"h2"))
+    }
+
+    /// The connection and the socket, so they can be read from and written to by different
+    /// threads.
+    pub(crate) fn into_parts(self) -> (ServerConnection, TcpStream) {
+        (self.0.conn, self.0.sock)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Connection for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.0.sock.shutdown(how)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.0.sock.peer_addr().ok()
+    }
+
+    /// Files have to be encrypted on the way, so they can't skip user space.
+    #[cfg(target_os = "linux")]
+    fn send_file(&self, _: &File, _: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::acme::pem_encode;
+    use crate::webserver::{
+        App, AppConfig, RequestType, Resource, ResourceType, Response, StatusCode,
+    };
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        client::Resumption,
+        pki_types::{ServerName, UnixTime},
+        ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme,
+    };
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    /// A certificate with a real key, but made up contents, which the verifier below doesn't
+    /// look at. Tests tell them apart by those contents.
+    pub(crate) fn certificate(contents: &str) -> Certificate {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())
+                .unwrap();
+        Certificate {
+            chain: pem_encode("CERTIFICATE", contents.as_bytes()),
+            key: pem_encode("PRIVATE KEY", pkcs8.as_ref()),
+        }
+    }
+
+    fn contents(key: Option<Arc<CertifiedKey>>) -> Option<String> {
+        key.map(|key| String::from_utf8(key.cert[0].to_vec()).unwrap())
+    }
+
+    /// Accepts any certificate, so tests can see which one they got.
+    #[derive(Debug)]
+    pub(crate) struct AcceptAny;
+
+    impl ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _: &CertificateDer<'_>,
+            _: &[CertificateDer<'_>],
+            _: &ServerName<'_>,
+            _: &[u8],
+            _: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    #[test]
+    fn find() {
+        let certificates = TlsCertificates::new();
+        assert!(certificates.find(Some("daanlubbers.nl")).is_none());
+        certificates
+            .insert("daanlubbers.nl", &certificate("apex"))
+            .unwrap();
+        certificates
+            .insert("*.daanlubbers.nl", &certificate("wildcard"))
+            .unwrap();
+        certificates
+            .insert("blog.daanlubbers.nl", &certificate("blog"))
+            .unwrap();
+        certificates.set_default(&certificate("default")).unwrap();
+        let find = |name| contents(certificates.find(name)).unwrap();
+        assert_eq!(find(Some("Daanlubbers.NL")), "apex");
+        assert_eq!(find(Some("blog.daanlubbers.nl")), "blog");
+        assert_eq!(find(Some("www.daanlubbers.nl")), "wildcard");
+        assert_eq!(find(Some("a.b.daanlubbers.nl")), "default");
+        assert_eq!(find(None), "default");
+        certificates.remove("blog.daanlubbers.nl");
+        assert_eq!(find(Some("blog.daanlubbers.nl")), "wildcard");
+
+        let invalid = Certificate {
+            chain: String::new(),
+            key: certificate("").key,
+        };
+        assert!(certificates.insert("x", &invalid).is_err());
+        assert!(certificates
+            .insert(
+                "x",
+                &Certificate {
+                    key: String::new(),
+                    ..certificate("x")
+                }
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn load_dir() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_tls_test");
+        let _ = fs::remove_dir_all(&dir);
+        for host in ["daanlubbers.nl", "*.daanlubbers.nl"] {
+            CertificateStore::new(dir.join(host))
+                .save(&certificate(host))
+                .unwrap();
+        }
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        let certificates = TlsCertificates::new();
+        assert_eq!(certificates.load_dir(&dir), Ok(2));
+        assert_eq!(
+            contents(certificates.find(Some("www.daanlubbers.nl"))).unwrap(),
+            "*.daanlubbers.nl"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn serve() {
+        let addr: SocketAddr = "127.0.0.1:7716".parse().unwrap();
+        let tls_addr: SocketAddr = "127.0.0.1:7717".parse().unwrap();
+        let certificates = Arc::new(TlsCertificates::new());
+        certificates
+            .insert("daanlubbers.nl", &certificate("apex"))
+            .unwrap();
+        certificates.set_default(&certificate("default")).unwrap();
+        let config = AppConfig::new(addr, 2, 5).with_tls(tls_addr, Arc::clone(&certificates));
+        let mut app = App::new(config);
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "secure"))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || app.run(Some(stop_flag_clone)).unwrap());
+        thread::sleep(Duration::from_millis(100)); // Give the app time to start up
+
+        let mut client = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAny))
+            .with_no_client_auth();
+        // Resumed sessions skip picking a certificate.
+        client.resumption = Resumption::disabled();
+        let client = Arc::new(client);
+        let get = |name: &str| {
+            let name = ServerName::try_from(name.to_string()).unwrap();
+            let connection = ClientConnection::new(Arc::clone(&client), name).unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(tls_addr).unwrap());
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: daanlubbers.nl\r\n\r\n")
+                .unwrap();
+            let mut response = vec![];
+            // The server closes without close_notify, which rustls reports as an error.
+            let _ = stream.read_to_end(&mut response);
+            let certificate = stream.conn.peer_certificates().unwrap()[0].to_vec();
+            (
+                String::from_utf8(certificate).unwrap(),
+                String::from_utf8(response).unwrap(),
+            )
+        };
+        let (presented, response) = get("daanlubbers.nl");
+        assert_eq!(presented, "apex");
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert!(response.ends_with("secure"));
+        assert_eq!(get("other.nl").0, "default");
+        // Replaced certificates are used from the next handshake.
+        certificates
+            .insert("other.nl", &certificate("other"))
+            .unwrap();
+        assert_eq!(get("other.nl").0, "other");
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(addr);
+        thread.join().unwrap();
+    }
+}
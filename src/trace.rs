@@ -0,0 +1,195 @@
+use std::{
+    cell::RefCell,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The header a request ID is taken from and sent back in.
+pub const HEADER: &str = "X-Request-Id";
+/// Longer IDs from clients are replaced.
+const MAX_LENGTH: usize = 128;
+
+/// The `LogLevel` of the whole process, as its `u8`.
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// How much is logged, see `set_level`. Every level logs what the levels before it do.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Off,
+    /// Only lines logged with `warn!`, such as slow requests.
+    Warn,
+    /// Everything, including the request and response lines, which is the default.
+    Info,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(LogLevel::Off),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            _ => Err(format!("Unknown log level: {text}")),
+        }
+    }
+}
+
+/// Log only up to `level` from now on, in every thread.
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Off,
+        1 => LogLevel::Warn,
+        _ => LogLevel::Info,
+    }
+}
+
+/// Whether lines at `level` are logged.
+pub fn enabled(level: LogLevel) -> bool {
+    level != LogLevel::Off && level <= self::level()
+}
+
+thread_local! {
+    /// The ID of the request this thread is working on, which `log!` prefixes lines with.
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Log a line like `println!`, prefixed with the ID of the request the thread is working on.
+///
+/// Lines logged by async resources aren't prefixed, as they don't run on a thread of their own.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::trace::log(format_args!($($arg)*))
+    };
+}
+
+/// Log a line like `log!`, marked as a warning, which is still logged at `LogLevel::Warn`.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::trace::warn(format_args!($($arg)*))
+    };
+}
+
+pub fn log(args: fmt::Arguments) {
+    if enabled(LogLevel::Info) {
+        print(args);
+    }
+}
+
+pub fn warn(args: fmt::Arguments) {
+    if enabled(LogLevel::Warn) {
+        print(format_args!("Warning: {args}"));
+    }
+}
+
+fn print(args: fmt::Arguments) {
+    match current() {
+        Some(id) => println!("[{id}] {args}"),
+        None => println!("{args}"),
+    }
+}
+
+/// The ID of the request this thread is working on.
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Sets the ID of the request this thread is working on until dropped, after which the
+/// previous one is back.
+pub(crate) struct Scope {
+    previous: Option<String>,
+}
+
+impl Scope {
+    pub(crate) fn enter(id: &str) -> Self {
+        let previous = CURRENT.with(|current| current.replace(Some(id.to_string())));
+        Self { previous }
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+/// A new ID of 16 hex digits. They only have to tell requests apart in the logs, so they are
+/// not as random as session IDs.
+pub fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    format!("{:016x}", hasher.finish())
+}
+
+/// Whether an ID sent by a client, such as a proxy's, can be used. It ends up in log lines and
+/// response headers, so only short IDs of visible ASCII are.
+pub(crate) fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes() {
+        let first = generate();
+        assert_eq!(first.len(), 16);
+        assert_ne!(first, generate());
+        assert!(is_valid(&first));
+        assert!(is_valid("f47ac10b-58cc-4372-a567-0e02b2c3d479"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("a b"));
+        assert!(!is_valid(&"a".repeat(129)));
+
+        assert_eq!(current(), None);
+        {
+            let _outer = Scope::enter("outer");
+            {
+                let _inner = Scope::enter("inner");
+                assert_eq!(current().as_deref(), Some("inner"));
+            }
+            assert_eq!(current().as_deref(), Some("outer"));
+        }
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(level(), LogLevel::Info);
+        assert!(enabled(LogLevel::Warn) && enabled(LogLevel::Info));
+        assert!(!enabled(LogLevel::Off));
+        assert_eq!(" WARN".parse(), Ok(LogLevel::Warn));
+        assert!("debug".parse::<LogLevel>().is_err());
+        for level in [LogLevel::Off, LogLevel::Warn, LogLevel::Info] {
+            assert_eq!(level.as_str().parse(), Ok(level));
+        }
+    }
+}
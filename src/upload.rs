@@ -0,0 +1,210 @@
+use crate::log;
+use crate::security::safe_path;
+use crate::webdav;
+use crate::webserver::{Error, Request, RequestType, Response, StatusCode};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Decides whether a request may write to an upload mount.
+pub type UploadAuth = Box<dyn Fn(&Request) -> bool + Send + Sync>;
+
+/// A directory that accepts PUT and POST uploads to `<prefix>/<file name>`, registered with
+/// `App::serve_upload`.
+///
+/// Files are stored directly in the directory under a sanitized name, replacing any file of
+/// the same name. With `webdav` enabled the directory can also be browsed and managed from file
+/// managers.
+pub struct UploadMount {
+    prefix: String,
+    dir: PathBuf,
+    max_file_size: Option<u64>,
+    quota: Option<u64>,
+    auth: Option<UploadAuth>,
+    webdav: bool,
+    /// Held while checking the quota and writing, so concurrent uploads can't both fit the quota.
+    write_lock: Mutex<()>,
+}
+
+impl UploadMount {
+    pub fn new(prefix: &str, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            dir: dir.into(),
+            max_file_size: None,
+            quota: None,
+            auth: None,
+            webdav: false,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Reject uploads larger than this many bytes.
+    pub fn max_file_size(&mut self, bytes: u64) -> &mut Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Reject uploads that would make the files in the directory take up more than this many bytes.
+    pub fn quota(&mut self, bytes: u64) -> &mut Self {
+        self.quota = Some(bytes);
+        self
+    }
+
+    /// Only accept uploads for which `auth` returns true.
+    pub fn auth(&mut self, auth: UploadAuth) -> &mut Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Also handle OPTIONS, PROPFIND, GET, DELETE and MKCOL, so the directory can be mounted as a
+    /// WebDAV share. Subdirectories are allowed, and names that need sanitizing are rejected
+    /// instead of renamed. The same limits and authorization apply.
+    pub fn webdav(&mut self) -> &mut Self {
+        self.webdav = true;
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether this mount handles the request.
+    pub fn matches(&self, request: &Request) -> bool {
+        if self.webdav {
+            return self.relative_path(request.path()).is_some();
+        }
+        matches!(request.request_type(), RequestType::PUT | RequestType::POST)
+            && self.file_name(request.path()).is_some()
+    }
+
+    pub fn handle(&self, request: &Request) -> Result<Response, Error> {
+        if let Some(auth) = &self.auth {
+            if !auth(request) {
+                return Ok(Response::empty(StatusCode::Forbidden));
+            }
+        }
+        if self.webdav {
+            return webdav::handle(self, request);
+        }
+
+        let Some(name) = self.file_name(request.path()) else {
+            return Ok(Response::empty(StatusCode::NotFound));
+        };
+        let Some(name) = sanitize_file_name(name) else {
+            return Ok(Response::text(StatusCode::BadRequest, "Invalid file name"));
+        };
+
+        let Ok(path) = safe_path(&self.dir, &name) else {
+            return Ok(Response::text(StatusCode::BadRequest, "Invalid file name"));
+        };
+        self.store(&path, request.body(), format!("{}/{name}", self.prefix))
+    }
+
+    /// Write an upload to `path` if it fits the limits, answering 201 with `location`.
+    pub(crate) fn store(
+        &self,
+        path: &Path,
+        body: &[u8],
+        location: String,
+    ) -> Result<Response, Error> {
+        let size = body.len() as u64;
+        if self.max_file_size.is_some_and(|max| size > max) {
+            return Ok(Response::empty(StatusCode::PayloadTooLarge));
+        }
+
+        let _guard = self.write_lock.lock().map_err(|e| e.to_string())?;
+        if let Some(quota) = self.quota {
+            let replaced = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            let used = dir_size(&self.dir).map_err(|e| e.to_string())?;
+            if used.saturating_sub(replaced) + size > quota {
+                return Ok(Response::empty(StatusCode::InsufficientStorage));
+            }
+        }
+
+        fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+        fs::write(path, body).map_err(|e| e.to_string())?;
+        log!("Stored upload {}", path.display());
+        Ok(Response::empty(StatusCode::Created).with_header("Location", location))
+    }
+
+    /// The part of the path after the prefix, empty for the mount itself.
+    pub(crate) fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        match path.strip_prefix(&self.prefix)? {
+            "" => Some(""),
+            rest => rest.strip_prefix('/'),
+        }
+    }
+
+    /// The part of the path after the prefix, if the path is inside this mount.
+    fn file_name<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(&self.prefix)?.strip_prefix('/')
+    }
+}
+
+/// Make a name safe to use as a file name inside the upload directory. Path separators and
+/// other unusual characters are replaced, and leading dots are removed so uploads can't be
+/// hidden or escape the directory. Returns `None` if nothing usable is left.
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() || name.chars().all(|c| c == '_') {
+        return None;
+    }
+    Some(name.chars().take(255).collect())
+}
+
+/// Total size of the files inside `dir` and its subdirectories. A missing directory is empty.
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize() {
+        assert_eq!(sanitize_file_name("photo.jpg").unwrap(), "photo.jpg");
+        assert_eq!(
+            sanitize_file_name("my file (1).txt").unwrap(),
+            "my_file__1_.txt"
+        );
+        assert_eq!(
+            sanitize_file_name("../../etc/passwd").unwrap(),
+            "_.._etc_passwd"
+        );
+        assert_eq!(sanitize_file_name(".hidden").unwrap(), "hidden");
+        assert_eq!(sanitize_file_name(&"a".repeat(300)).unwrap().len(), 255);
+        assert!(sanitize_file_name("").is_none());
+        assert!(sanitize_file_name("..").is_none());
+        assert!(sanitize_file_name("/").is_none());
+    }
+}
@@ -0,0 +1,402 @@
+use crate::log;
+use crate::scheduler::Scheduler;
+use crate::sse::{Event, EventSender};
+use crate::webserver::{
+    Middleware, Request, RequestType, Resource, ResourceType, Response, StatusCode, UtcDateTime,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, Once},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How many days of request counts are kept and shown.
+pub const DAYS: u64 = 90;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+struct DayStats {
+    requests: u64,
+    /// Responses with a 5xx status.
+    errors: u64,
+}
+
+struct Stats {
+    restarts: u64,
+    /// Keyed by days since 1 January 1970.
+    days: BTreeMap<u64, DayStats>,
+}
+
+/// Middleware that counts responses per day, to track the share that didn't fail with a server
+/// error, along with the uptime and the number of restarts.
+///
+/// The counts are kept in a file, so they survive restarts. `schedule` saves them periodically.
+pub struct UptimeTracker {
+    path: PathBuf,
+    started: Instant,
+    stats: Mutex<Stats>,
+}
+
+impl UptimeTracker {
+    /// Load the counts from `path`. If the file exists, this start is counted as a restart.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let stats = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let mut stats = parse(&contents)?;
+                stats.restarts += 1;
+                stats
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Stats {
+                restarts: 0,
+                days: BTreeMap::new(),
+            },
+            Err(e) => return Err(format!("Failed to read uptime stats: {e}")),
+        };
+        Ok(Self {
+            path,
+            started: Instant::now(),
+            stats: Mutex::new(stats),
+        })
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn restarts(&self) -> u64 {
+        self.stats.lock().unwrap().restarts
+    }
+
+    /// The share of requests over the last 90 days that didn't fail with a server error, or
+    /// `None` without requests.
+    pub fn success_rate(&self) -> Option<f64> {
+        let stats = self.stats.lock().unwrap();
+        let (requests, errors) = stats
+            .days
+            .values()
+            .fold((0, 0), |(r, e), day| (r + day.requests, e + day.errors));
+        rate(requests, errors)
+    }
+
+    /// Count a response with this status on the day of `time`.
+    pub fn record(&self, time: SystemTime, status_code: StatusCode) {
+        let today = UtcDateTime::new(time).days;
+        let mut stats = self.stats.lock().unwrap();
+        let day = stats.days.entry(today).or_default();
+        day.requests += 1;
+        if status_code.code() >= 500 {
+            day.errors += 1;
+        }
+        stats.days.retain(|day, _| day + DAYS > today);
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let contents = {
+            let stats = self.stats.lock().unwrap();
+            let mut contents = format!("restarts {}\n", stats.restarts);
+            for (day, counts) in &stats.days {
+                contents.push_str(&format!(
+                    "day {day} {} {}\n",
+                    counts.requests, counts.errors
+                ));
+            }
+            contents
+        };
+        fs::write(&self.path, contents).map_err(|e| format!("Failed to save uptime stats: {e}"))
+    }
+
+    /// Add a job saving the counts every `interval` to the scheduler.
+    pub fn schedule(self: &Arc<Self>, scheduler: Scheduler, interval: Duration) -> Scheduler {
+        let tracker = Arc::clone(self);
+        scheduler.every("uptime stats", interval, move || {
+            if let Err(e) = tracker.save() {
+                log!("{e}");
+            }
+        })
+    }
+
+    /// A GET resource at `path` with a status page showing the uptime, restarts and the share
+    /// of successful requests for each of the last 90 days.
+    pub fn status_page_resource(self: &Arc<Self>, path: &str) -> Resource {
+        let tracker = Arc::clone(self);
+        Resource::new(
+            RequestType::GET,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(move |_| {
+                Ok(
+                    Response::text(StatusCode::OK, tracker.status_page(SystemTime::now()))
+                        .with_header("Content-Type", "text/html; charset=utf-8")
+                        .with_header("Cache-Control", "no-cache"),
+                )
+            }),
+        )
+    }
+
+    /// A GET resource at `path` streaming the summary at the top of the status page every
+    /// `interval` as `summary` events. The status page listens for them at `<its path>/events`.
+    ///
+    /// Every open stream keeps a worker thread busy, so streams take up at most half of the
+    /// app's `threads`, or the one thread of an app with a single worker. Clients beyond that
+    /// get a 503, which an `EventSource` doesn't retry, so their page just isn't kept up to
+    /// date. The events for all streams are sent from a single thread, started with the first
+    /// stream.
+    pub fn events_resource(
+        self: &Arc<Self>,
+        path: &str,
+        interval: Duration,
+        threads: usize,
+    ) -> Resource {
+        let max_streams = (threads / 2).max(1);
+        let tracker = Arc::clone(self);
+        let streams: Arc<Mutex<Vec<EventSender>>> = Arc::default();
+        let broadcaster = Once::new();
+        Resource::new(
+            RequestType::GET,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(move |_| {
+                let mut open = streams.lock().unwrap();
+                // Sending fails for streams whose client is gone.
+                open.retain(|events| events.comment("").is_ok());
+                if open.len() >= max_streams {
+                    return Ok(Response::empty(StatusCode::ServiceUnavailable));
+                }
+                let (response, events) = Response::event_stream();
+                let _ = events.send(&tracker.summary_event());
+                open.push(events);
+                broadcaster.call_once(|| {
+                    let (tracker, streams) = (Arc::clone(&tracker), Arc::clone(&streams));
+                    thread::spawn(move || loop {
+                        thread::sleep(interval);
+                        let event = tracker.summary_event();
+                        streams
+                            .lock()
+                            .unwrap()
+                            .retain(|events| events.send(&event).is_ok());
+                    });
+                });
+                Ok(response)
+            }),
+        )
+    }
+
+    fn summary_event(&self) -> Event {
+        Event::new(self.summary()).with_event("summary")
+    }
+
+    fn summary(&self) -> String {
+        let uptime = self.uptime().as_secs();
+        format!(
+            "<p>Up for {} days, {} hours and {} minutes, restarted {} times.</p>\
+             <p>{} of requests succeeded over the last {DAYS} days.</p>",
+            uptime / 86400,
+            uptime % 86400 / 3600,
+            uptime % 3600 / 60,
+            self.restarts(),
+            percentage(self.success_rate()),
+        )
+    }
+
+    fn status_page(&self, now: SystemTime) -> String {
+        let summary = self.summary();
+
+        let today = UtcDateTime::new(now).days;
+        let stats = self.stats.lock().unwrap();
+        let rows: String = (0..DAYS)
+            .filter_map(|ago| today.checked_sub(ago))
+            .map(|day| {
+                let counts = stats.days.get(&day).copied().unwrap_or_default();
+                let date = UtcDateTime::new(UNIX_EPOCH + Duration::from_secs(day * 86400));
+                format!(
+                    "<tr><td>{:04}-{:02}-{:02}</td><td>{}</td><td>{}</td></tr>",
+                    date.year,
+                    date.month,
+                    date.day,
+                    counts.requests,
+                    percentage(rate(counts.requests, counts.errors)),
+                )
+            })
+            .collect();
+        format!(
+            "<!DOCTYPE html><html lang=\"en\"><head><title>Status</title></head><body>\
+             <h1>Status</h1><div id=\"summary\">{summary}</div><table><tr><th>Date</th>\
+             <th>Requests</th><th>Succeeded</th></tr>{rows}</table><script>\
+             new EventSource(location.pathname + \"/events\").addEventListener(\"summary\", \
+             (e) => (document.getElementById(\"summary\").innerHTML = e.data));\
+             </script></body></html>"
+        )
+    }
+}
+
+impl Middleware for UptimeTracker {
+    fn after(&self, _request: &Request, response: &mut Response) {
+        self.record(SystemTime::now(), response.status_code);
+    }
+}
+
+fn rate(requests: u64, errors: u64) -> Option<f64> {
+    match requests {
+        0 => None,
+        _ => Some((requests - errors) as f64 / requests as f64),
+    }
+}
+
+fn percentage(rate: Option<f64>) -> String {
+    match rate {
+        Some(rate) => format!("{:.2}%", rate * 100.0),
+        None => "-".to_string(),
+    }
+}
+
+fn parse(contents: &str) -> Result<Stats, String> {
+    let mut stats = Stats {
+        restarts: 0,
+        days: BTreeMap::new(),
+    };
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let numbers: Vec<u64> = fields
+            .iter()
+            .skip(1)
+            .map(|field| field.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Invalid uptime stats line: {line}"))?;
+        match (fields.first().copied(), numbers.as_slice()) {
+            (Some("restarts"), &[restarts]) => stats.restarts = restarts,
+            (Some("day"), &[day, requests, errors]) => {
+                stats.days.insert(day, DayStats { requests, errors });
+            }
+            (None, _) => {}
+            _ => return Err(format!("Invalid uptime stats line: {line}")),
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::{create_app, AppConfig};
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpStream},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    const DAY: Duration = Duration::from_secs(86400);
+
+    #[test]
+    fn record_and_persist() {
+        let path = std::env::temp_dir().join("wwwdaanlubbersnl_uptime_test");
+        let _ = fs::remove_file(&path);
+        let tracker = UptimeTracker::load(&path).unwrap();
+        assert_eq!(tracker.restarts(), 0);
+        assert_eq!(tracker.success_rate(), None);
+
+        let now = UNIX_EPOCH + DAY * 20000;
+        tracker.record(now - DAY * DAYS as u32, StatusCode::InternalServerError);
+        tracker.record(now - DAY, StatusCode::OK);
+        tracker.record(now, StatusCode::NotFound);
+        tracker.record(now, StatusCode::OK);
+        tracker.record(now, StatusCode::InternalServerError);
+        assert_eq!(tracker.success_rate(), Some(0.75));
+        tracker.save().unwrap();
+
+        let tracker = UptimeTracker::load(&path).unwrap();
+        assert_eq!(tracker.restarts(), 1);
+        assert_eq!(tracker.success_rate(), Some(0.75));
+        let page = tracker.status_page(now);
+        assert!(page.contains("restarted 1 times"));
+        assert!(page.contains("<tr><td>2024-10-04</td><td>3</td><td>66.67%</td></tr>"));
+        assert!(page.contains("<tr><td>2024-10-03</td><td>1</td><td>100.00%</td></tr>"));
+        assert!(page.contains("<tr><td>2024-10-02</td><td>0</td><td>-</td></tr>"));
+        fs::remove_file(&path).unwrap();
+
+        assert!(parse("day 1 2").is_err());
+        assert!(parse("uptime 1").is_err());
+    }
+
+    /// Run an app with `threads` workers serving the status page and its events at `addr`,
+    /// until the returned flag is set and the app is connected to.
+    fn serve(addr: SocketAddr, threads: usize) -> (Arc<AtomicBool>, thread::JoinHandle<()>) {
+        let path = std::env::temp_dir().join("wwwdaanlubbersnl_uptime_events_test");
+        let tracker = Arc::new(UptimeTracker::load(&path).unwrap());
+        let mut app = create_app(AppConfig::new(addr, threads, 5));
+        app.register_resource(tracker.status_page_resource("/status"));
+        app.register_resource(tracker.events_resource(
+            "/status/events",
+            Duration::from_millis(50),
+            threads,
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let server = thread::spawn(move || app.run(Some(stop_flag_clone)).unwrap());
+        thread::sleep(Duration::from_millis(100)); // Give the app time to start up
+        (stop_flag, server)
+    }
+
+    fn request(addr: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        stream
+    }
+
+    /// Open an event stream, along with the start of the response.
+    fn open(addr: SocketAddr) -> (TcpStream, String) {
+        let mut stream = request(addr, "/status/events");
+        let mut received = vec![0; 4096];
+        let read = stream.read(&mut received).unwrap();
+        let received = String::from_utf8_lossy(&received[..read]).to_string();
+        (stream, received)
+    }
+
+    #[test]
+    fn events() {
+        let addr: SocketAddr = "127.0.0.1:7727".parse().unwrap();
+        // Room for one stream.
+        let (stop_flag, server) = serve(addr, 3);
+        let (events, received) = open(addr);
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+        // Over the limit.
+        let (_, received) = open(addr);
+        assert!(received.starts_with("HTTP/1.1 503 "));
+        // The other workers still answer requests.
+        let mut page = String::new();
+        request(addr, "/status").read_to_string(&mut page).unwrap();
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // The stream is closed once sending to the client fails.
+        drop(events);
+        let reopened = (0..40).find_map(|_| {
+            thread::sleep(Duration::from_millis(50));
+            let (stream, received) = open(addr);
+            received.starts_with("HTTP/1.1 200 ").then_some(stream)
+        });
+        assert!(reopened.is_some());
+        drop(reopened);
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(addr);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn events_single_thread() {
+        let addr: SocketAddr = "127.0.0.1:7731".parse().unwrap();
+        let (stop_flag, server) = serve(addr, 1);
+        let (events, received) = open(addr);
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+        drop(events);
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(addr);
+        server.join().unwrap();
+    }
+}
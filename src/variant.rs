@@ -0,0 +1,158 @@
+use crate::cookie::{Cookie, SameSite};
+use crate::digest::sha256;
+use crate::log;
+use crate::webserver::{Error, Request, ResourceHandler, Response};
+use std::time::Duration;
+
+/// The cookie that keeps a visitor in the same bucket, so they keep seeing the same variant.
+pub const COOKIE_NAME: &str = "bucket";
+
+/// How long a visitor keeps their bucket.
+const COOKIE_MAX_AGE: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// An alternate handler for a resource, served to a percentage of visitors, such as a redesigned
+/// page that is tried out before it replaces the old one. Added with `Resource::with_variant`.
+///
+/// Every visitor is put in one of 100 buckets, by hashing their IP on the first visit. The bucket
+/// is then kept in a cookie, so visitors stick to their variant when their IP changes. Visitors
+/// in the buckets below `percent` get the variant, so raising the percentage keeps everyone who
+/// already saw the variant on it.
+pub struct Variant {
+    name: String,
+    percent: u8,
+    handler: ResourceHandler,
+}
+
+impl Variant {
+    /// # Panics
+    ///
+    /// Panics if `percent` is over 100.
+    pub fn new(name: &str, percent: u8, handler: ResourceHandler) -> Self {
+        assert!(percent <= 100, "Variant percentage over 100");
+        Self {
+            name: name.to_string(),
+            percent,
+            handler,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether a visitor in this bucket gets the variant.
+    pub fn serves(&self, bucket: u8) -> bool {
+        bucket < self.percent
+    }
+
+    /// Handle the request with either the variant or the default handler.
+    pub(crate) fn handle(
+        &self,
+        default: &ResourceHandler,
+        request: &Request,
+    ) -> Result<Response, Error> {
+        let stored = request
+            .cookie(COOKIE_NAME)
+            .and_then(|bucket| bucket.parse::<u8>().ok())
+            .filter(|bucket| *bucket < 100);
+        let bucket = stored.unwrap_or_else(|| bucket(request));
+
+        let mut response = match self.serves(bucket) {
+            true => {
+                log!("Serving variant {} of {}", self.name, request.path());
+                (self.handler)(request)?
+            }
+            false => default(request)?,
+        };
+        // Caches must not give one visitor's variant to another.
+        response.add_header("Vary", "Cookie");
+        if stored.is_none() {
+            response.add_cookie(
+                Cookie::new(COOKIE_NAME, &bucket.to_string())
+                    .with_path("/")
+                    .with_max_age(COOKIE_MAX_AGE)
+                    .http_only()
+                    .with_same_site(SameSite::Lax),
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// The bucket for a visitor without a cookie, from a hash of their IP.
+fn bucket(request: &Request) -> u8 {
+    let ip = request
+        .client_ip()
+        .map(|ip| ip.to_string())
+        .unwrap_or_default();
+    let hash = sha256(ip.as_bytes());
+    (u16::from_be_bytes([hash[0], hash[1]]) % 100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::{RequestType, Resource, ResourceType, StatusCode};
+
+    fn resource(percent: u8) -> Resource {
+        Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::empty(StatusCode::OK).with_header("X-Version", "old"))),
+        )
+        .with_variant(Variant::new(
+            "redesign",
+            percent,
+            Box::new(|_| Ok(Response::empty(StatusCode::OK).with_header("X-Version", "new"))),
+        ))
+    }
+
+    fn request(raw: &str) -> Request {
+        Request::from_reader(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn version(response: &Response) -> &str {
+        response.header("X-Version").unwrap()
+    }
+
+    #[test]
+    fn sticky_bucket() {
+        let resource = resource(30);
+        let response = resource
+            .handle(&request("GET / HTTP/1.1\r\nCookie: bucket=29\r\n\r\n"))
+            .unwrap();
+        assert_eq!(version(&response), "new");
+        assert_eq!(response.header("Vary"), Some("Cookie"));
+        assert!(response.header("Set-Cookie").is_none());
+
+        let response = resource
+            .handle(&request("GET / HTTP/1.1\r\nCookie: bucket=30\r\n\r\n"))
+            .unwrap();
+        assert_eq!(version(&response), "old");
+    }
+
+    #[test]
+    fn new_visitor() {
+        let new_visitor = request("GET / HTTP/1.1\r\n\r\n");
+        let expected = bucket(&new_visitor);
+        let response = resource(100).handle(&new_visitor).unwrap();
+        assert_eq!(version(&response), "new");
+        assert_eq!(
+            response.header("Set-Cookie"),
+            Some(
+                format!("bucket={expected}; Path=/; Max-Age=7776000; HttpOnly; SameSite=Lax")
+                    .as_str()
+            )
+        );
+
+        let response = resource(0).handle(&new_visitor).unwrap();
+        assert_eq!(version(&response), "old");
+    }
+
+    #[test]
+    #[should_panic(expected = "Variant percentage over 100")]
+    fn invalid_percent() {
+        resource(101);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::calendar::{escape, fold};
+use crate::webserver::{RequestType, Resource, ResourceType, Response, StatusCode};
+
+pub const CONTENT_TYPE: &str = "text/vcard; charset=utf-8";
+
+/// Builds a contact card (vCard 3.0, RFC 2426), such as the one served at `/contact.vcf`.
+#[derive(Clone)]
+pub struct VCard {
+    full_name: String,
+    family_name: Option<String>,
+    given_name: Option<String>,
+    organization: Option<String>,
+    title: Option<String>,
+    emails: Vec<String>,
+    phones: Vec<String>,
+    urls: Vec<String>,
+    note: Option<String>,
+}
+
+impl VCard {
+    pub fn new(full_name: &str) -> Self {
+        Self {
+            full_name: full_name.to_string(),
+            family_name: None,
+            given_name: None,
+            organization: None,
+            title: None,
+            emails: vec![],
+            phones: vec![],
+            urls: vec![],
+            note: None,
+        }
+    }
+
+    /// The structured name, used by address books for sorting.
+    pub fn with_name(mut self, given_name: &str, family_name: &str) -> Self {
+        self.given_name = Some(given_name.to_string());
+        self.family_name = Some(family_name.to_string());
+        self
+    }
+
+    pub fn with_organization(mut self, organization: &str) -> Self {
+        self.organization = Some(organization.to_string());
+        self
+    }
+
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.title = Some(title.to_string());
+        self
+    }
+
+    pub fn with_email(mut self, email: &str) -> Self {
+        self.emails.push(email.to_string());
+        self
+    }
+
+    pub fn with_phone(mut self, phone: &str) -> Self {
+        self.phones.push(phone.to_string());
+        self
+    }
+
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.urls.push(url.to_string());
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.note = Some(note.to_string());
+        self
+    }
+
+    pub fn to_vcf(&self) -> String {
+        let mut lines = vec![
+            "BEGIN:VCARD".to_string(),
+            "VERSION:3.0".to_string(),
+            format!("FN:{}", escape(&self.full_name)),
+            format!(
+                "N:{};{};;;",
+                escape(self.family_name.as_deref().unwrap_or_default()),
+                escape(self.given_name.as_deref().unwrap_or_default())
+            ),
+        ];
+        if let Some(organization) = &self.organization {
+            lines.push(format!("ORG:{}", escape(organization)));
+        }
+        if let Some(title) = &self.title {
+            lines.push(format!("TITLE:{}", escape(title)));
+        }
+        for email in &self.emails {
+            lines.push(format!("EMAIL;TYPE=INTERNET:{}", escape(email)));
+        }
+        for phone in &self.phones {
+            lines.push(format!("TEL;TYPE=CELL:{}", escape(phone)));
+        }
+        for url in &self.urls {
+            lines.push(format!("URL:{url}"));
+        }
+        if let Some(note) = &self.note {
+            lines.push(format!("NOTE:{}", escape(note)));
+        }
+        lines.push("END:VCARD".to_string());
+
+        lines.iter().map(|line| fold(line)).collect()
+    }
+
+    /// The card as a download named `file_name`.
+    pub fn response(&self, file_name: &str) -> Response {
+        Response::text(StatusCode::OK, self.to_vcf())
+            .with_header("Content-Type", CONTENT_TYPE)
+            .with_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", file_name.replace('"', "")),
+            )
+    }
+
+    /// A GET resource at `path` serving the card. The download is named after the last part
+    /// of the path.
+    pub fn resource(self, path: &str) -> Resource {
+        let file_name = path.rsplit('/').next().unwrap_or_default().to_string();
+        Resource::new(
+            RequestType::GET,
+            path.to_string(),
+            ResourceType::TEXT,
+            Box::new(move |_| Ok(self.response(&file_name))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vcard_vcf() {
+        let card = VCard::new("Daan Lubbers")
+            .with_name("Daan", "Lubbers")
+            .with_email("contact@daanlubbers.nl")
+            .with_url("https://www.daanlubbers.nl")
+            .with_note("Rust, chess; and more");
+        assert_eq!(
+            card.to_vcf(),
+            "BEGIN:VCARD\r\n\
+             VERSION:3.0\r\n\
+             FN:Daan Lubbers\r\n\
+             N:Lubbers;Daan;;;\r\n\
+             EMAIL;TYPE=INTERNET:contact@daanlubbers.nl\r\n\
+             URL:https://www.daanlubbers.nl\r\n\
+             NOTE:Rust\\, chess\\; and more\r\n\
+             END:VCARD\r\n"
+        );
+    }
+}
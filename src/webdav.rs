@@ -0,0 +1,190 @@
+use crate::log;
+use crate::security::safe_path;
+use crate::upload::{sanitize_file_name, UploadMount};
+use crate::webserver::{http_date, Error, Request, RequestType, Response, StatusCode};
+use std::{
+    fs::{self, Metadata},
+    path::{Path, PathBuf},
+};
+
+const ALLOW: &str = "OPTIONS, PROPFIND, GET, PUT, DELETE, MKCOL";
+
+/// Handle a request to an upload mount with WebDAV enabled. Authorization has already been
+/// checked by the mount.
+pub(crate) fn handle(mount: &UploadMount, request: &Request) -> Result<Response, Error> {
+    let Some(relative) = mount.relative_path(request.path()) else {
+        return Ok(Response::empty(StatusCode::NotFound));
+    };
+    let Some(path) = resolve(mount.dir(), relative) else {
+        return Ok(Response::text(StatusCode::BadRequest, "Invalid path"));
+    };
+    fs::create_dir_all(mount.dir()).map_err(|e| e.to_string())?;
+
+    match request.request_type() {
+        RequestType::OPTIONS => Ok(Response::empty(StatusCode::OK)
+            .with_header("DAV", "1")
+            .with_header("Allow", ALLOW)),
+        RequestType::PROPFIND => propfind(mount, request, relative, &path),
+        RequestType::GET if path.is_file() => Ok(Response::file(StatusCode::OK, path)),
+        RequestType::GET if path.is_dir() => Ok(method_not_allowed()),
+        RequestType::GET => Ok(Response::empty(StatusCode::NotFound)),
+        RequestType::PUT => {
+            if path.is_dir() {
+                return Ok(method_not_allowed());
+            }
+            if !parent_exists(&path) {
+                return Ok(Response::empty(StatusCode::Conflict));
+            }
+            mount.store(&path, request.body(), href(mount, relative, false))
+        }
+        RequestType::DELETE => {
+            if relative.trim_matches('/').is_empty() {
+                return Ok(Response::empty(StatusCode::Forbidden));
+            }
+            let result = if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else if path.is_file() {
+                fs::remove_file(&path)
+            } else {
+                return Ok(Response::empty(StatusCode::NotFound));
+            };
+            result.map_err(|e| e.to_string())?;
+            log!("Deleted {}", path.display());
+            Ok(Response::empty(StatusCode::NoContent))
+        }
+        RequestType::MKCOL => {
+            if path.exists() {
+                return Ok(method_not_allowed());
+            }
+            if !parent_exists(&path) {
+                return Ok(Response::empty(StatusCode::Conflict));
+            }
+            fs::create_dir(&path).map_err(|e| e.to_string())?;
+            Ok(Response::empty(StatusCode::Created)
+                .with_header("Location", href(mount, relative, true)))
+        }
+        RequestType::POST | RequestType::PATCH | RequestType::TRACE => Ok(method_not_allowed()),
+    }
+}
+
+/// List the properties of the resource, and of its children unless `Depth: 0` is sent.
+fn propfind(
+    mount: &UploadMount,
+    request: &Request,
+    relative: &str,
+    path: &Path,
+) -> Result<Response, Error> {
+    let Ok(metadata) = fs::metadata(path) else {
+        return Ok(Response::empty(StatusCode::NotFound));
+    };
+    let href = href(mount, relative, metadata.is_dir());
+    let name = relative
+        .trim_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    xml.push_str(&prop_response(&href, name, &metadata));
+    if metadata.is_dir() && request.header("Depth") != Some("0") {
+        let mut children = vec![];
+        for entry in fs::read_dir(path).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            // Files that weren't uploaded through the mount may have names that can't be
+            // requested, so they aren't listed.
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if sanitize_file_name(&name).as_deref() != Some(name.as_str()) {
+                continue;
+            }
+            let metadata = entry.metadata().map_err(|e| e.to_string())?;
+            let suffix = if metadata.is_dir() { "/" } else { "" };
+            children.push(prop_response(
+                &format!("{href}{name}{suffix}"),
+                &name,
+                &metadata,
+            ));
+        }
+        children.sort();
+        xml.extend(children);
+    }
+    xml.push_str("</D:multistatus>\n");
+
+    Ok(Response::text(StatusCode::MultiStatus, xml)
+        .with_header("Content-Type", "application/xml; charset=utf-8"))
+}
+
+fn prop_response(href: &str, name: &str, metadata: &Metadata) -> String {
+    let mut props = format!("<D:displayname>{name}</D:displayname>");
+    if metadata.is_dir() {
+        props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        props.push_str(&format!(
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>",
+            metadata.len()
+        ));
+    }
+    if let Ok(modified) = metadata.modified() {
+        props.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            http_date(modified)
+        ));
+    }
+    format!(
+        "<D:response><D:href>{href}</D:href><D:propstat><D:prop>{props}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n"
+    )
+}
+
+/// The path inside `dir` for a path relative to the mount, see `safe_path`. Every segment must
+/// also already be a sanitized file name.
+fn resolve(dir: &Path, relative: &str) -> Option<PathBuf> {
+    let path = safe_path(dir, relative).ok()?;
+    let sanitized = path.strip_prefix(dir).ok()?.iter().all(|segment| {
+        segment
+            .to_str()
+            .is_some_and(|segment| sanitize_file_name(segment).as_deref() == Some(segment))
+    });
+    sanitized.then_some(path)
+}
+
+/// The URL of a resource in the mount. Collections end with a slash.
+fn href(mount: &UploadMount, relative: &str, collection: bool) -> String {
+    let relative = relative.trim_matches('/');
+    match (relative.is_empty(), collection) {
+        (true, _) => format!("{}/", mount.prefix()),
+        (false, true) => format!("{}/{relative}/", mount.prefix()),
+        (false, false) => format!("{}/{relative}", mount.prefix()),
+    }
+}
+
+fn parent_exists(path: &Path) -> bool {
+    path.parent().is_some_and(Path::is_dir)
+}
+
+fn method_not_allowed() -> Response {
+    Response::empty(StatusCode::MethodNotAllowed).with_header("Allow", ALLOW)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_paths() {
+        let dir = Path::new("uploads");
+        assert_eq!(resolve(dir, "").unwrap(), dir);
+        assert_eq!(
+            resolve(dir, "a/b.txt").unwrap(),
+            dir.join("a").join("b.txt")
+        );
+        assert_eq!(resolve(dir, "a/").unwrap(), dir.join("a"));
+        assert!(resolve(dir, "../b.txt").is_none());
+        assert!(resolve(dir, "a/.hidden").is_none());
+        assert!(resolve(dir, "my%20file").is_none());
+        assert!(resolve(dir, "a/%2e%2e/%2e%2e/b.txt").is_none());
+    }
+}
@@ -1,57 +1,631 @@
-use crate::concurrency::ThreadPool;
+use crate::acme::AcmeChallenges;
+#[cfg(feature = "async")]
+use crate::async_server::{self, AsyncResource};
+use crate::buffers;
+use crate::cache::CachePolicy;
+#[cfg(feature = "gzip")]
+use crate::compression;
+use crate::concurrency::{
+    ConnectionCounter, GracefulShutdown, OpenConnection, OverLimit, PoolCounters, PoolStats,
+    ThreadPool,
+};
+use crate::cookie::{self, Cookie};
+use crate::digest::{self, DigestAlgorithm};
+use crate::error_pages::ErrorPages;
+#[cfg(feature = "evented")]
+use crate::evented;
+use crate::flags::FeatureFlags;
+use crate::health;
+#[cfg(feature = "tls")]
+use crate::http2;
+use crate::ip_filter::{FilterAction, IpFilter};
+use crate::log;
+use crate::meta::PageMeta;
+use crate::proxy::{self, Proxied};
+use crate::redirects;
+use crate::router::{RouteList, RouteTrie, Router, TrailingSlash};
+#[cfg(target_os = "linux")]
+use crate::sendfile;
+use crate::session::Session;
+#[cfg(unix)]
+use crate::signals;
+use crate::signing::{SignedUrls, UrlSigner};
+use crate::sitemap::{RobotsTxt, Sitemap, SitemapEntry, DEFAULT_PRIORITY, SITEMAP_PATH};
+use crate::sse::{self, EventSender};
+use crate::state::{Extensions, State};
+use crate::static_dir::StaticDir;
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsCertificates, TlsStream};
+use crate::trace::{self, LogLevel, Scope};
+use crate::upload::UploadMount;
+use crate::variant::Variant;
+#[cfg(feature = "tls")]
+use crate::warn;
+use crate::websocket::{self, WebSocket, WebSocketHandler};
 use core::fmt::{self, Display};
+#[cfg(unix)]
+use std::os::unix::{
+    fs::FileTypeExt,
+    net::{UnixListener, UnixStream},
+};
 use std::{
-    fs,
-    io::{BufRead, BufReader, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufRead, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
         Arc,
     },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum RequestType {
     GET,
     POST,
     PUT,
     DELETE,
+    OPTIONS,
+    PROPFIND,
+    MKCOL,
+    PATCH,
+    /// Echoed back when `AppConfig::with_trace` allows it, unless a resource answers it.
+    TRACE,
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum StatusCode {
+    SwitchingProtocols,
     OK,
+    Created,
+    Accepted,
+    NoContent,
+    MultiStatus,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
     NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    Conflict,
+    PayloadTooLarge,
+    UriTooLong,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
     InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
+    InsufficientStorage,
     PermanentRedirect,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+}
+
+impl StatusCode {
+    /// The numeric status code, such as 404.
+    pub fn code(&self) -> u16 {
+        match *self {
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::OK => 200,
+            StatusCode::Created => 201,
+            StatusCode::Accepted => 202,
+            StatusCode::NoContent => 204,
+            StatusCode::MultiStatus => 207,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::NotAcceptable => 406,
+            StatusCode::Conflict => 409,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::UriTooLong => 414,
+            StatusCode::UpgradeRequired => 426,
+            StatusCode::TooManyRequests => 429,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::HttpVersionNotSupported => 505,
+            StatusCode::InsufficientStorage => 507,
+            StatusCode::PermanentRedirect => 301,
+            StatusCode::Found => 302,
+            StatusCode::SeeOther => 303,
+            StatusCode::NotModified => 304,
+            StatusCode::TemporaryRedirect => 307,
+        }
+    }
+}
+
+impl StatusCode {
+    /// The status line of a response to a request with this HTTP version.
+    pub fn status_line(&self, version: HttpVersion) -> String {
+        format!("{version} {}", self.reason())
+    }
+
+    /// The code and reason phrase, such as `404 NOT FOUND`.
+    pub(crate) fn reason(&self) -> &'static str {
+        match *self {
+            StatusCode::SwitchingProtocols => "101 SWITCHING PROTOCOLS",
+            StatusCode::OK => "200 OK",
+            StatusCode::Created => "201 CREATED",
+            StatusCode::Accepted => "202 ACCEPTED",
+            StatusCode::NoContent => "204 NO CONTENT",
+            StatusCode::MultiStatus => "207 MULTI-STATUS",
+            StatusCode::BadRequest => "400 BAD REQUEST",
+            StatusCode::Unauthorized => "401 UNAUTHORIZED",
+            StatusCode::Forbidden => "403 FORBIDDEN",
+            StatusCode::NotFound => "404 NOT FOUND",
+            StatusCode::MethodNotAllowed => "405 METHOD NOT ALLOWED",
+            StatusCode::NotAcceptable => "406 NOT ACCEPTABLE",
+            StatusCode::Conflict => "409 CONFLICT",
+            StatusCode::PayloadTooLarge => "413 PAYLOAD TOO LARGE",
+            StatusCode::UriTooLong => "414 URI TOO LONG",
+            StatusCode::UpgradeRequired => "426 UPGRADE REQUIRED",
+            StatusCode::TooManyRequests => "429 TOO MANY REQUESTS",
+            StatusCode::RequestHeaderFieldsTooLarge => "431 REQUEST HEADER FIELDS TOO LARGE",
+            StatusCode::InternalServerError => "500 INTERNAL SERVER ERROR",
+            StatusCode::NotImplemented => "501 NOT IMPLEMENTED",
+            StatusCode::ServiceUnavailable => "503 SERVICE UNAVAILABLE",
+            StatusCode::HttpVersionNotSupported => "505 HTTP VERSION NOT SUPPORTED",
+            StatusCode::InsufficientStorage => "507 INSUFFICIENT STORAGE",
+            StatusCode::PermanentRedirect => "301 PERMANENT REDIRECT",
+            StatusCode::Found => "302 FOUND",
+            StatusCode::SeeOther => "303 SEE OTHER",
+            StatusCode::NotModified => "304 NOT MODIFIED",
+            StatusCode::TemporaryRedirect => "307 TEMPORARY REDIRECT",
+        }
+    }
 }
 
+/// The status line for HTTP/1.1, for responses sent without a request to match.
 impl Display for StatusCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let output;
-        match *self {
-            StatusCode::OK => output = "HTTP/1.1 200 OK",
-            StatusCode::NotFound => output = "HTTP/1.1 404 NOT FOUND",
-            StatusCode::InternalServerError => output = "HTTP/1.1 500 INTERNAL SERVER ERROR",
-            StatusCode::PermanentRedirect => output = "HTTP/1.1 301 PERMANENT REDIRECT",
+        write!(f, "{}", self.status_line(HttpVersion::Http11))
+    }
+}
+
+/// The HTTP version of a request, which its response is sent with as well.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+}
+
+impl Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpVersion::Http10 => write!(f, "HTTP/1.0"),
+            HttpVersion::Http11 => write!(f, "HTTP/1.1"),
+        }
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format a time as an HTTP date, such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+    let t = UtcDateTime::new(time);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(t.days % 7) as usize],
+        t.day,
+        MONTHS[(t.month - 1) as usize],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    )
+}
+
+/// Parse an HTTP date in the format of `http_date`. The obsolete RFC 850 and asctime formats
+/// aren't supported, so dates in those are ignored like other invalid dates.
+pub fn parse_http_date(text: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let day = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| name == month)? as u64 + 1;
+    let year = year.parse().ok()?;
+    let time: Vec<u64> = time
+        .split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    let [hour, minute, second] = time.as_slice() else {
+        return None;
+    };
+    utc_time(year, month, day, *hour, *minute, *second)
+}
+
+/// The time at a UTC calendar date and time of day, if they're valid.
+pub(crate) fn utc_time(
+    year: u64,
+    month: u64,
+    day: u64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+) -> Option<SystemTime> {
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    // Convert the civil date to days since the epoch, see
+    // https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let yoe = y % 400;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + (153 * m + 2) / 5 + day - 1;
+    let days = (y / 400 * 146097 + doe).checked_sub(719468)?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + minute * 60 + second))
+}
+
+/// The URL of the request on HTTPS at `https_port`, going by its `Host` header. `None` if the
+/// host isn't a plain name or address, so it can't be used to add headers to the response.
+fn https_url(host: &str, https_port: u16, request: &Request) -> Option<String> {
+    let name = match host.strip_prefix('[') {
+        Some(address) => &host[..address.find(']')? + 2],
+        None => host.split(':').next()?,
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || ".-:[]".contains(c);
+    if name.is_empty() || !name.chars().all(valid) {
+        return None;
+    }
+    let port = match https_port {
+        443 => String::new(),
+        port => format!(":{port}"),
+    };
+    let query = request.query().map(|query| format!("?{query}"));
+    Some(format!(
+        "https://{name}{port}{}{}",
+        request.path(),
+        query.unwrap_or_default()
+    ))
+}
+
+/// Decode `%XX` escapes and `+` (as a space) in a query string value.
+/// Invalid escapes are kept as they are, and invalid UTF-8 is replaced.
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escape everything but unreserved characters (RFC 3986), such as for a URL in a query string.
+pub fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Escape text for use in HTML content and quoted attribute values.
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A time split into UTC calendar fields.
+pub(crate) struct UtcDateTime {
+    /// Days since 1 January 1970.
+    pub days: u64,
+    pub year: u64,
+    pub month: u64,
+    pub day: u64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+}
+
+impl UtcDateTime {
+    pub fn new(time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let days = secs / 86400;
+
+        // Convert days since the epoch to a civil date, see
+        // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z % 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+        Self {
+            days,
+            year: yoe + era * 400 + u64::from(month <= 2),
+            month,
+            day,
+            hour: secs % 86400 / 3600,
+            minute: secs % 3600 / 60,
+            second: secs % 60,
         }
-        write!(f, "{}", output)
     }
 }
 
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum ResourceType {
     TEXT,
     BINARY,
     REDIRECT,
+    /// Upgrades to a WebSocket connection, see `Resource::websocket`.
+    WEBSOCKET,
 }
 
 pub struct Resource {
     request_type: RequestType,
-    path: String,
+    pub(crate) path: String,
     resource_type: ResourceType,
     handler: ResourceHandler,
+    variant: Option<Variant>,
+    meta: PageMeta,
+    cache: Option<CachePolicy>,
+    sitemap: SitemapEntry,
+    websocket: Option<WebSocketHandler>,
+    headers: Vec<(String, String)>,
+    /// The file the handler serves, checked when registered with `AppConfig::with_strict_files`.
+    file: Option<PathBuf>,
+}
+
+const ONE_DAY: u64 = 24 * 60 * 60;
+const ONE_WEEK: u64 = 7 * ONE_DAY;
+
+/// The content type of an icon, by its extension.
+fn icon_content_type(path: &Path) -> &'static str {
+    match file_content_type(path) {
+        Some(content_type) if content_type.starts_with("image/") => content_type,
+        _ => "image/x-icon",
+    }
+}
+
+/// The content type of a file, by its extension, without a charset.
+fn file_content_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    Some(match extension.map(str::to_ascii_lowercase).as_deref()? {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "txt" => "text/plain",
+        "xml" => "application/xml",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "svg" => "image/svg+xml",
+        "gif" => "image/gif",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        _ => return None,
+    })
+}
+
+/// Bytes of a text file looked at for its charset, as many as browsers prescan for a `<meta
+/// charset>`, and then some.
+const CHARSET_SNIFF_BYTES: u64 = 8192;
+
+/// The `Content-Type` of a file response, for files with a known extension. Files are sent as
+/// they are, so text is only said to be UTF-8 if its start is. Otherwise the charset is left
+/// to the file, such as the `<meta charset>` of a Latin-1 page.
+pub(crate) fn file_content_type_header(path: &Path) -> Option<String> {
+    let content_type = file_content_type(path)?;
+    if !content_type.starts_with("text/") {
+        return Some(content_type.to_string());
+    }
+    let mut start = vec![];
+    File::open(path)
+        .and_then(|file| file.take(CHARSET_SNIFF_BYTES).read_to_end(&mut start))
+        .ok()?;
+    // A character cut off at the end of what was read is still UTF-8.
+    let utf8 = match std::str::from_utf8(&start) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && start.len() as u64 == CHARSET_SNIFF_BYTES,
+    };
+    Some(match utf8 {
+        true => format!("{content_type}; charset=utf-8"),
+        false => content_type.to_string(),
+    })
+}
+
+/// The version of a request line. Anything newer than HTTP/1.0 in 1.x is answered as HTTP/1.1,
+/// the highest version supported, while other major versions get a 505.
+fn parse_version(token: &str) -> Result<HttpVersion, ReadError> {
+    let (major, minor) = token
+        .strip_prefix("HTTP/")
+        .and_then(|version| version.split_once('.'))
+        .filter(|(major, minor)| {
+            [major, minor]
+                .iter()
+                .all(|digit| digit.len() == 1 && digit.as_bytes()[0].is_ascii_digit())
+        })
+        .ok_or_else(|| {
+            ReadError::Malformed(
+                StatusCode::BadRequest,
+                format!("Malformed version: {token}"),
+            )
+        })?;
+    match (major, minor) {
+        ("1", "0") => Ok(HttpVersion::Http10),
+        ("1", _) => Ok(HttpVersion::Http11),
+        _ => Err(ReadError::Malformed(
+            StatusCode::HttpVersionNotSupported,
+            format!("Unsupported version: {token}"),
+        )),
+    }
+}
+
+/// Add the headers the response doesn't have yet.
+fn add_missing_headers(response: &mut Response, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        if response.header(name).is_none() {
+            response.add_header(name.as_str(), value.as_str());
+        }
+    }
+}
+
+/// Error returned by a resource handler. Which response it is answered with follows from
+/// the variant, so that a handler can fail with a 404 or a 400 and get the matching error page.
+///
+/// Plain messages and I/O errors convert into `Internal`, so `?` works on them in handlers.
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    BadRequest(String),
+    Status(StatusCode, String),
+    Internal(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// Fail with any error, such as one from another crate, answered with a 500.
+    pub fn internal(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Error::Internal(e.into())
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::NotFound => StatusCode::NotFound,
+            Error::BadRequest(_) => StatusCode::BadRequest,
+            Error::Status(status_code, _) => *status_code,
+            Error::Internal(_) => StatusCode::InternalServerError,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "Not found"),
+            Error::BadRequest(e) | Error::Status(_, e) => write!(f, "{e}"),
+            Error::Internal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Internal(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(e: String) -> Self {
+        Error::Internal(e.into())
+    }
+}
+
+impl From<&str> for Error {
+    fn from(e: &str) -> Self {
+        Error::Internal(e.into())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Internal(Box::new(e))
+    }
+}
+
+/// Why the server stopped before its stop flag was set.
+#[derive(Debug)]
+pub enum ServerError {
+    /// An address or socket could not be bound to, such as one another server is using.
+    Bind(String, io::Error),
+    /// The workers are gone, so connections can't be handed to them.
+    PoolStopped,
+    /// Any other I/O error, such as the event loop failing.
+    Io(io::Error),
+    /// The app is set up in a way that can't work, such as with a missing static directory.
+    Setup(String),
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Bind(addr, e) => write!(f, "Failed to bind to {addr}: {e}"),
+            ServerError::PoolStopped => write!(f, "The thread pool has stopped"),
+            ServerError::Io(e) => write!(f, "{e}"),
+            ServerError::Setup(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Bind(_, e) | ServerError::Io(e) => Some(e),
+            ServerError::PoolStopped | ServerError::Setup(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
 }
 
-type ResourceHandler = Box<dyn Fn() -> Result<Response, String> + Send + Sync>;
+/// Handlers are closures, so they can capture state such as counters or database handles.
+pub type ResourceHandler = Box<dyn Fn(&Request) -> Result<Response, Error> + Send + Sync>;
 
 impl Resource {
     pub fn new(
@@ -65,705 +639,5632 @@ impl Resource {
             path,
             resource_type,
             handler,
+            variant: None,
+            meta: PageMeta::default(),
+            cache: None,
+            sitemap: SitemapEntry::Auto,
+            websocket: None,
+            headers: vec![],
+            file: None,
         }
     }
 
-    pub fn handle(&self) -> Result<Response, String> {
-        (self.handler)()
+    /// A GET resource at `path` answering WebSocket handshakes, after which the connection is
+    /// handed to `handler`. It keeps a worker thread busy for as long as it is open.
+    pub fn websocket(
+        path: &str,
+        handler: impl Fn(&Request, &mut WebSocket) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> Self {
+        let mut resource = Self::new(
+            RequestType::GET,
+            path.to_string(),
+            ResourceType::WEBSOCKET,
+            Box::new(websocket::handshake),
+        )
+        .without_sitemap();
+        resource.websocket = Some(Arc::new(handler));
+        resource
     }
-}
 
-pub struct Response {
-    status_code: StatusCode,
-    path: String,
-}
+    /// Tell search engines to index the page under `url`, see `PageMeta`.
+    pub fn with_canonical(mut self, url: &str) -> Self {
+        self.meta.canonical = Some(url.to_string());
+        self
+    }
 
-impl Response {
-    pub fn new(status_code: StatusCode, path: String) -> Self {
-        Self { status_code, path }
+    /// Send robots directives with the page, such as `noindex`, see `PageMeta`.
+    pub fn with_robots(mut self, directives: &str) -> Self {
+        self.meta.robots = Some(directives.to_string());
+        self
     }
-}
 
-pub struct AppConfig {
-    addr: SocketAddr,
-    num_threads: usize,
-    read_timeout: u64,
-}
+    /// Let clients and proxies keep the responses as long as `policy` says, see `CachePolicy`.
+    pub fn with_cache(mut self, policy: CachePolicy) -> Self {
+        self.cache = Some(policy);
+        self
+    }
 
-impl AppConfig {
-    pub fn new(addr: SocketAddr, num_threads: usize, read_timeout: u64) -> Self {
-        Self {
-            addr,
-            num_threads,
-            read_timeout,
-        }
+    /// Send this header with every response, such as `X-Robots-Tag: noindex`, unless the
+    /// handler sets it.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
     }
-}
 
-pub struct App {
-    config: AppConfig,
-    resources: Vec<Resource>,
-    resource_404: Option<Resource>,
-    resource_500: Option<Resource>,
-}
+    /// The handler serves the file at `path`, so that `AppConfig::with_strict_files` can check
+    /// it exists when the resource is registered.
+    pub fn with_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some(path.into());
+        self
+    }
 
-impl App {
-    /// If the stop flag is set, the server will shut down after processing the next request.
-    /// Implemented for testing purposes.
-    pub fn new(config: AppConfig) -> Self {
-        Self {
-            config,
-            resources: vec![],
-            resource_404: None,
-            resource_500: None,
-        }
+    /// Serve `variant` instead of the handler to a percentage of visitors, see `Variant`.
+    pub fn with_variant(mut self, variant: Variant) -> Self {
+        self.variant = Some(variant);
+        self
     }
 
-    pub fn run(self, stop_flag: Option<Arc<AtomicBool>>) {
-        let addr = self.config.addr;
-        let listener = match TcpListener::bind(self.config.addr) {
-            Ok(listener) => listener,
-            Err(e) => panic!("Failed to bind to {addr}: {e:?}\n"),
-        };
-        let pool = ThreadPool::new(self.config.num_threads);
-        let app = Arc::new(self);
+    /// Answer with a 503 if the handler hasn't returned after `timeout`, rather than keeping
+    /// the worker until it does. The handler runs on a thread of its own for this, which a
+    /// handler that never returns keeps. Doesn't apply to a `Variant`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        let handler = Arc::new(self.handler);
+        self.handler = Box::new(move |request| run_with_timeout(&handler, request, timeout));
+        self
+    }
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let app_clone = Arc::clone(&app);
+    /// List the resource in the sitemap with this priority, from 0.0 to 1.0, even if it is not
+    /// a page. See `App::enable_sitemap`.
+    pub fn with_sitemap_priority(mut self, priority: f32) -> Self {
+        self.sitemap = SitemapEntry::Priority(priority);
+        self
+    }
 
-                    pool.execute(move || app_clone.handle_request(stream));
-                }
-                Err(e) => {
-                    print!("Connection Failed: {e:?}")
-                }
-            }
+    /// Leave the resource out of the sitemap.
+    pub fn without_sitemap(mut self) -> Self {
+        self.sitemap = SitemapEntry::Excluded;
+        self
+    }
 
-            if let Some(stop_flag) = &stop_flag {
-                if stop_flag.load(Ordering::SeqCst) {
-                    break;
-                }
+    /// The priority the resource is listed in the sitemap with, if it is listed. Unless set,
+    /// only GET pages are listed: text resources without an extension in their path and
+    /// without a `noindex` robots directive.
+    fn sitemap_priority(&self) -> Option<f32> {
+        if self.request_type != RequestType::GET {
+            return None;
+        }
+        match self.sitemap {
+            SitemapEntry::Priority(priority) => Some(priority),
+            SitemapEntry::Excluded => None,
+            SitemapEntry::Auto => {
+                let is_page = matches!(self.resource_type, ResourceType::TEXT)
+                    && !self
+                        .path
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or_default()
+                        .contains('.');
+                let noindex = self
+                    .meta
+                    .robots
+                    .as_deref()
+                    .is_some_and(|robots| robots.contains("noindex"));
+                (is_page && !noindex).then_some(DEFAULT_PRIORITY)
             }
         }
     }
 
-    pub fn register_resource(&mut self, resource: Resource) {
-        self.resources.push(resource);
+    pub fn handle(&self, request: &Request) -> Result<Response, Error> {
+        let mut response = match &self.variant {
+            Some(variant) => variant.handle(&self.handler, request)?,
+            None => (self.handler)(request)?,
+        };
+        self.meta.apply(&mut response);
+        add_missing_headers(&mut response, &self.headers);
+        if let Some(policy) = &self.cache {
+            policy.apply(&mut response, SystemTime::now());
+        }
+        response.apply_last_modified(request);
+        Ok(response)
     }
+}
 
-    pub fn register_resource_404(&mut self, resource: Resource) {
-        self.resource_404 = Some(resource);
+/// Run `handler` on its own thread, giving up on it after `timeout`.
+fn run_with_timeout(
+    handler: &Arc<ResourceHandler>,
+    request: &Request,
+    timeout: Duration,
+) -> Result<Response, Error> {
+    let (sender, receiver) = mpsc::channel();
+    let handler = Arc::clone(handler);
+    let owned = request.clone();
+    thread::Builder::new()
+        .name("handler".to_string())
+        .spawn(move || {
+            let _ = sender.send(handler(&owned));
+        })
+        .map_err(Error::internal)?;
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => {
+            log!(
+                "Handler for {:?} {} still running after {timeout:?}",
+                request.request_type(),
+                request.path()
+            );
+            Err(Error::Status(
+                StatusCode::ServiceUnavailable,
+                "Handler timed out".to_string(),
+            ))
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(Error::internal("Handler panicked")),
     }
+}
 
-    pub fn register_resource_500(&mut self, resource: Resource) {
-        self.resource_500 = Some(resource);
+#[derive(Clone)]
+pub struct Request {
+    request_type: RequestType,
+    version: HttpVersion,
+    path: String,
+    query: Option<String>,
+    headers: Vec<(String, String)>,
+    /// From the client's `X-Request-Id` header if it sent a usable one, see `trace`.
+    id: String,
+    body: Vec<u8>,
+    state: Arc<State>,
+    session: Option<Arc<Session>>,
+    extensions: Extensions,
+    remote_addr: Option<SocketAddr>,
+    forwarded_for: Option<IpAddr>,
+    received: Instant,
+    /// Whether the server closes the connection after the response, see `set_closing`.
+    closing: bool,
+}
+
+impl Request {
+    /// Read a request within the default limits, such as from a string in tests.
+    pub(crate) fn from_reader(reader: &mut impl BufRead) -> Result<Self, String> {
+        let limits = RequestLimits::default();
+        Self::read_head(reader, &limits)
+            .and_then(|mut request| request.read_body(reader, &limits).map(|_| request))
+            .map_err(|e| match e {
+                ReadError::Incomplete(e)
+                | ReadError::Malformed(_, e)
+                | ReadError::TooLarge(_, e)
+                | ReadError::Denied(_, e) => e,
+            })
     }
 
-    fn handle_request(&self, mut stream: TcpStream) {
-        stream
-            .set_read_timeout(Some(std::time::Duration::from_secs(
-                self.config.read_timeout,
-            )))
-            .unwrap();
-        let buf_reader = BufReader::new(&stream);
-        let request_line = match buf_reader.lines().next() {
-            Some(line) => match line {
-                Ok(line) => line,
-                Err(e) => {
-                    print!("Failed to read request line: {e:?}\n");
-                    return;
-                }
-            },
-            None => {
-                print!("Empty request\n");
-                return;
-            }
-        };
+    /// Read the request line and headers from the reader, and log the request line along with
+    /// the request ID.
+    pub(crate) fn read_head(
+        reader: &mut impl BufRead,
+        limits: &RequestLimits,
+    ) -> Result<Self, ReadError> {
+        let mut request_line = None;
+        let mut line = buffers::string();
+        let result = Self::parse_head(reader, limits, &mut request_line, &mut line);
+        buffers::recycle_string(line);
+        match (&result, request_line) {
+            _ if !trace::enabled(LogLevel::Info) => {}
+            (Ok(request), Some(line)) => println!("[{}] Request: {line}", request.id),
+            // The headers were invalid, so there is no ID.
+            (Err(_), Some(line)) => println!("Request: {line}"),
+            _ => {}
+        }
+        result
+    }
 
-        print!("Request: {request_line}\n");
+    /// Read the request line and headers, leaving the request line in `logged_line` once it is
+    /// known to be short enough to log. Every line is read into `line`.
+    fn parse_head(
+        reader: &mut impl BufRead,
+        limits: &RequestLimits,
+        logged_line: &mut Option<String>,
+        line: &mut String,
+    ) -> Result<Self, ReadError> {
+        let received = Instant::now();
+        match reader
+            .by_ref()
+            .take(limits.max_request_line as u64 + 1)
+            .read_line(line)
+        {
+            Ok(0) => return Err(ReadError::Incomplete("Empty request".to_string())),
+            Ok(length) if length > limits.max_request_line => {
+                return Err(ReadError::TooLarge(
+                    StatusCode::UriTooLong,
+                    "Request line too long".to_string(),
+                ))
+            }
+            Ok(_) => {}
+            Err(e) => {
+                return Err(ReadError::Incomplete(format!(
+                    "Failed to read request line: {e:?}"
+                )))
+            }
+        }
+        let request_line = line.trim_end();
+        *logged_line = Some(request_line.to_string());
 
         let parts = request_line.split_whitespace().collect::<Vec<&str>>();
 
-        if parts.len() < 2 {
-            print!("Malformed request\n");
-            return;
+        if parts.len() != 3 {
+            return Err(ReadError::Malformed(
+                StatusCode::BadRequest,
+                "Malformed request".to_string(),
+            ));
         }
+        // Checked first, so an HTTP/2 preface gets a 505 rather than a 501 for its method.
+        let version = parse_version(parts[2])?;
 
         let request_type = match parts[0] {
             "GET" => RequestType::GET,
             "POST" => RequestType::POST,
             "PUT" => RequestType::PUT,
             "DELETE" => RequestType::DELETE,
+            "OPTIONS" => RequestType::OPTIONS,
+            "PROPFIND" => RequestType::PROPFIND,
+            "MKCOL" => RequestType::MKCOL,
+            "PATCH" => RequestType::PATCH,
+            "TRACE" => RequestType::TRACE,
             _ => {
-                print!("Unsupported request\n");
-                return;
+                return Err(ReadError::Malformed(
+                    StatusCode::NotImplemented,
+                    "Unsupported request".to_string(),
+                ))
             }
         };
 
-        let path = parts[1];
+        let (path, query) = match parts[1].split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (parts[1].to_string(), None),
+        };
 
-        let resource = self.get_resource(request_type, path);
-        match resource {
-            Some(resource) => self.handle_resource(resource, &mut stream),
-            None => self.handle_not_found(&mut stream),
+        let mut headers = vec![];
+        let mut header_bytes = 0;
+        loop {
+            line.clear();
+            let remaining = limits.max_header_bytes - header_bytes;
+            match reader.by_ref().take(remaining as u64 + 1).read_line(line) {
+                Ok(0) => {
+                    return Err(ReadError::Incomplete(
+                        "Connection closed while reading headers".to_string(),
+                    ))
+                }
+                Ok(length) if length > remaining => {
+                    return Err(ReadError::TooLarge(
+                        StatusCode::RequestHeaderFieldsTooLarge,
+                        "Request headers too large".to_string(),
+                    ))
+                }
+                Ok(length) => header_bytes += length,
+                Err(e) => {
+                    return Err(ReadError::Incomplete(format!(
+                        "Failed to read header: {e:?}"
+                    )))
+                }
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            match line.split_once(':') {
+                // Whitespace before the colon, or at the start of a folded line, could have a
+                // proxy in front read the header differently (RFC 9112 section 5).
+                Some((name, value)) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+                    headers.push((name.to_string(), value.trim().to_string()))
+                }
+                _ => {
+                    return Err(ReadError::Malformed(
+                        StatusCode::BadRequest,
+                        format!("Malformed header: {line}"),
+                    ))
+                }
+            }
         }
-    }
 
-    fn get_resource(&self, request_type: RequestType, path: &str) -> Option<&Resource> {
-        self.resources
+        let id = headers
             .iter()
-            .find(|resource| resource.request_type == request_type && resource.path == path)
+            .find(|(name, _)| name.eq_ignore_ascii_case(trace::HEADER))
+            .map(|(_, id)| id.clone())
+            .filter(|id| trace::is_valid(id))
+            .unwrap_or_else(trace::generate);
+
+        Ok(Self {
+            request_type,
+            version,
+            path,
+            query,
+            headers,
+            id,
+            body: vec![],
+            state: Arc::default(),
+            session: None,
+            extensions: Extensions::default(),
+            remote_addr: None,
+            forwarded_for: None,
+            received,
+            closing: false,
+        })
     }
 
-    fn handle_resource(&self, resource: &Resource, stream: &mut TcpStream) {
-        let response = match resource.handle() {
-            Ok(response) => response,
-            Err(_) => match &self.resource_500 {
-                Some(resource) => match resource.handle() {
-                    Ok(response) => response,
-                    Err(_) => {
-                        self.handle_error(stream);
-                        return;
-                    }
-                },
-                None => {
-                    self.handle_error(stream);
-                    return;
-                }
-            },
-        };
+    /// Read the body, as given by Content-Length or chunked transfer coding, from the reader.
+    pub(crate) fn read_body(
+        &mut self,
+        reader: &mut impl BufRead,
+        limits: &RequestLimits,
+    ) -> Result<(), ReadError> {
+        if self.is_chunked()? {
+            self.body = read_chunked(reader, limits)?;
+            return Ok(());
+        }
+        let length = self.content_length(limits)?;
+        if length > 0 {
+            self.body = vec![0; length];
+            if let Err(e) = reader.read_exact(&mut self.body) {
+                return Err(ReadError::Incomplete(format!("Failed to read body: {e:?}")));
+            }
+        }
 
-        let path = response.path;
-        let status = response.status_code;
+        Ok(())
+    }
 
-        match resource.resource_type {
-            ResourceType::TEXT => self.handle_text(path, status, stream),
-            ResourceType::BINARY => self.handle_binary(path, status, stream),
-            ResourceType::REDIRECT => self.handle_redirect(path, status, stream),
+    /// Whether the body is sent with chunked transfer coding. Other transfer codings aren't
+    /// supported, and a Content-Length along with it is rejected, as the two could be used to
+    /// make a proxy and this server disagree on where the request ends.
+    pub(crate) fn is_chunked(&self) -> Result<bool, ReadError> {
+        let Some(encoding) = self.header("Transfer-Encoding") else {
+            return Ok(false);
+        };
+        if !encoding.trim().eq_ignore_ascii_case("chunked") {
+            return Err(ReadError::Malformed(
+                StatusCode::NotImplemented,
+                format!("Unsupported Transfer-Encoding: {encoding}"),
+            ));
+        }
+        if self.header("Content-Length").is_some() {
+            return Err(ReadError::Malformed(
+                StatusCode::BadRequest,
+                "Both Content-Length and Transfer-Encoding".to_string(),
+            ));
         }
+        Ok(true)
     }
 
-    fn handle_text(&self, path: String, status: StatusCode, stream: &mut TcpStream) {
-        let content = match fs::read_to_string(path) {
-            Ok(content) => content,
+    /// The length of the body from the Content-Length header, 0 without one.
+    pub(crate) fn content_length(&self, limits: &RequestLimits) -> Result<usize, ReadError> {
+        // Repeated lengths have to agree, or a proxy in front could go by another one than
+        // this server does (RFC 9112 section 6.3).
+        let mut lengths = self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim);
+        let Some(length) = lengths.next() else {
+            return Ok(0);
+        };
+        if lengths.any(|other| other != length) {
+            return Err(ReadError::Malformed(
+                StatusCode::BadRequest,
+                "Conflicting Content-Length headers".to_string(),
+            ));
+        }
+        let length = match length.parse::<usize>() {
+            Ok(length) => length,
             Err(_) => {
-                let resource = &self.resource_404;
-                match resource {
-                    Some(resource) => {
-                        self.handle_resource(&resource, stream);
-                        return;
-                    }
-                    None => {
-                        self.handle_not_found(stream);
-                        return;
-                    }
-                }
+                return Err(ReadError::Malformed(
+                    StatusCode::BadRequest,
+                    format!("Invalid Content-Length: {length}"),
+                ))
             }
         };
-        let length = content.len();
-        let response = format!("{status}\r\nContent-Length: {length}\r\n\r\n{content}");
-
-        print!("Response: {response}\n");
-        match stream.write_all(response.as_bytes()) {
-            Err(e) => print!("Failed to write to stream: {e:?}\n"),
-            _ => {}
+        if length > limits.max_body_bytes {
+            return Err(ReadError::TooLarge(
+                StatusCode::PayloadTooLarge,
+                format!("Request body of {length} bytes too large"),
+            ));
         }
+        Ok(length)
     }
 
-    fn handle_binary(&self, path: String, status: StatusCode, stream: &mut TcpStream) {
-        let content = match fs::read(path) {
-            Ok(content) => content,
-            Err(_) => {
-                let resource = &self.resource_404;
-                match resource {
-                    Some(resource) => {
-                        self.handle_resource(&resource, stream);
-                        return;
-                    }
-                    None => {
-                        self.handle_not_found(stream);
-                        return;
-                    }
-                }
-            }
-        };
+    pub fn request_type(&self) -> RequestType {
+        self.request_type
+    }
 
-        let length = content.len();
-        let response = format!("{status}\r\nContent-Length: {length}\r\n\r\n");
+    /// The request path, without the query string.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
 
-        print!("Response: {response}<snip>\n");
-        match stream.write_all(&[response.as_bytes(), &content].concat()) {
-            Err(e) => print!("Failed to write to stream: {e:?}\n"),
-            _ => {}
-        }
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
     }
 
-    fn handle_redirect(&self, path: String, status: StatusCode, stream: &mut TcpStream) {
-        let response = format!("{status}\r\nLocation: {path}\r\nContent-Length: 0\r\n\r\n");
+    /// The raw (not percent-decoded) value of the first query parameter with this name.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
 
-        print!("Response: {response}\n");
-        match stream.write_all(response.as_bytes()) {
-            Err(e) => print!("Failed to write to stream: {e:?}\n"),
-            _ => {}
-        }
+    /// The percent-decoded value of the first query parameter with this name.
+    pub fn query_param_decoded(&self, name: &str) -> Option<String> {
+        self.query_param(name).map(percent_decode)
     }
 
-    fn handle_not_found(&self, stream: &mut TcpStream) {
-        let resource = &self.resource_404;
-        match resource {
-            Some(resource) => self.handle_resource(&resource, stream),
-            None => {
-                let response = format!("{}\r\nContent-Length: 0\r\n\r\n", StatusCode::NotFound);
-                print!("Response: {response}\n");
-                match stream.write_all(response.as_bytes()) {
-                    Err(e) => print!("Failed to write to stream: {e:?}\n"),
-                    _ => {}
-                }
+    /// The value of the first header with this name, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn version(&self) -> HttpVersion {
+        self.version
+    }
+
+    /// Whether the client wants the connection kept open after the response. That is the
+    /// default from HTTP/1.1 on, unless it sends `Connection: close`, while HTTP/1.0 clients
+    /// have to ask for it with `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        let connection_has = |option: &str| {
+            self.header("Connection").is_some_and(|connection| {
+                connection
+                    .split(',')
+                    .any(|value| value.trim().eq_ignore_ascii_case(option))
+            })
+        };
+        match self.version {
+            HttpVersion::Http10 => connection_has("keep-alive"),
+            HttpVersion::Http11 => !connection_has("close"),
+        }
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The address the request was sent from, which is that of the proxy if there is one in
+    /// front of the server. See `client_ip` for the client behind it.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// The ID the request is logged with and the response is sent with, see `trace`.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// When the request started arriving, to measure how long it took to answer.
+    pub fn received(&self) -> Instant {
+        self.received
+    }
+
+    /// The IP of the client, which a trusted proxy may have forwarded the request for. See
+    /// `AppConfig::with_trusted_proxy`.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.forwarded_for
+            .or(self.remote_addr.map(|addr| addr.ip()))
+    }
+
+    /// The value of the cookie with this name, from any `Cookie` header.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case("Cookie"))
+            .find_map(|(_, value)| cookie::find(value, name))
+    }
+
+    /// The session of this request, if the `Sessions` middleware is registered.
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_deref()
+    }
+
+    /// Values attached by the middleware that ran before, see `Extensions`.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// For middleware to attach values to the request, such as in `Middleware::before`, for the
+    /// middleware and handlers after it.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    pub(crate) fn set_request_type(&mut self, request_type: RequestType) {
+        self.request_type = request_type;
+    }
+
+    /// Have the response say `Connection: close`, as the server won't read another request
+    /// from the connection even though the client may want it kept open.
+    pub(crate) fn set_closing(&mut self, closing: bool) {
+        self.closing = closing;
+    }
+
+    pub(crate) fn set_session(&mut self, session: Session) {
+        self.session = Some(Arc::new(session));
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Parse the body as JSON.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| Error::BadRequest(format!("Invalid JSON body: {e}")))
+    }
+
+    /// Parse an `application/x-www-form-urlencoded` body, as posted by HTML forms, into its
+    /// percent-decoded fields. For fields sent more than once the first value is kept, and
+    /// fields without a `=` have an empty value.
+    pub fn form(&self) -> Result<HashMap<String, String>, Error> {
+        let content_type = self.header("Content-Type").unwrap_or_default();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if !media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return Err(Error::BadRequest(format!(
+                "Not a form body: {content_type}"
+            )));
+        }
+        let body = std::str::from_utf8(&self.body)
+            .map_err(|_| Error::BadRequest("Invalid UTF-8 in form body".to_string()))?;
+        let mut fields = HashMap::new();
+        for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            fields
+                .entry(percent_decode(name))
+                .or_insert_with(|| percent_decode(value));
+        }
+        Ok(fields)
+    }
+
+    /// The application state of this type, as added with `App::with_state`.
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.state.get::<T>()
+    }
+
+    /// Check the body against any `Content-MD5`, `Repr-Digest` or `Digest` headers sent along.
+    /// Headers for unsupported algorithms are ignored.
+    fn verify_digests(&self) -> bool {
+        self.headers
+            .iter()
+            .all(|(name, value)| digest::verify_header(name, value, &self.body).unwrap_or(true))
+    }
+}
+
+/// The body of a response.
+pub enum Body {
+    /// The contents of a file, read when the response is written.
+    File(PathBuf),
+    Text(String),
+    Bytes(Vec<u8>),
+    /// Read while the response is written, for bodies whose length isn't known upfront. Sent
+    /// with chunked transfer coding, or to HTTP/1.0 clients as it is, ended by closing the
+    /// connection.
+    Stream(Box<dyn Read + Send>),
+    Empty,
+}
+
+pub struct Response {
+    pub(crate) status_code: StatusCode,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Body,
+}
+
+impl Response {
+    pub fn new(status_code: StatusCode, body: Body) -> Self {
+        Self {
+            status_code,
+            headers: vec![],
+            body,
+        }
+    }
+
+    pub fn file(status_code: StatusCode, path: impl Into<PathBuf>) -> Self {
+        Self::new(status_code, Body::File(path.into()))
+    }
+
+    pub fn text(status_code: StatusCode, text: impl Into<String>) -> Self {
+        Self::new(status_code, Body::Text(text.into()))
+    }
+
+    pub fn bytes(status_code: StatusCode, bytes: Vec<u8>) -> Self {
+        Self::new(status_code, Body::Bytes(bytes))
+    }
+
+    pub fn empty(status_code: StatusCode) -> Self {
+        Self::new(status_code, Body::Empty)
+    }
+
+    /// A server-sent event stream, along with the sender to push events to it. See
+    /// `sse::event_stream`, which can set how often keep-alive comments are sent.
+    pub fn event_stream() -> (Self, EventSender) {
+        sse::event_stream(sse::DEFAULT_KEEP_ALIVE)
+    }
+
+    /// A response with the body read from `reader` as it is sent, see `Body::Stream`.
+    pub fn stream(status_code: StatusCode, reader: impl Read + Send + 'static) -> Self {
+        Self::new(status_code, Body::Stream(Box::new(reader)))
+    }
+
+    /// Serialize `value` as the body, with a `Content-Type: application/json` header.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::Serialize>(status_code: StatusCode, value: &T) -> Result<Self, Error> {
+        let body =
+            serde_json::to_vec(value).map_err(|e| format!("Failed to serialize JSON: {e}"))?;
+        Ok(Self::bytes(status_code, body).with_header("Content-Type", "application/json"))
+    }
+
+    /// Create an empty response that points the client to `location`.
+    pub fn redirect(status_code: StatusCode, location: impl Into<String>) -> Self {
+        Self::empty(status_code).with_header("Location", location)
+    }
+
+    /// Add a header to the response. Content-Length is always set by the server.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Add a header, such as from middleware that only has a reference.
+    pub fn add_header(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.headers.push((name.into(), value.into()));
+    }
+
+    /// Add `Last-Modified` to a 200 with a file body from when the file was modified, unless the
+    /// handler set one. A GET that wasn't modified since its `If-Modified-Since` is answered
+    /// with a 304 without the body instead. That header is ignored with `If-None-Match`, which
+    /// takes precedence.
+    pub(crate) fn apply_last_modified(&mut self, request: &Request) {
+        if self.status_code.code() != 200 {
+            return;
+        }
+        let last_modified = match (self.header("Last-Modified"), &self.body) {
+            (Some(value), _) => parse_http_date(value),
+            (None, Body::File(path)) => {
+                let Ok(modified) = fs::metadata(path).and_then(|m| m.modified()) else {
+                    return;
+                };
+                self.add_header("Last-Modified", http_date(modified));
+                Some(modified)
+            }
+            (None, _) => None,
+        };
+        let since = request
+            .header("If-Modified-Since")
+            .filter(|_| request.header("If-None-Match").is_none())
+            .and_then(parse_http_date);
+        let (Some(last_modified), Some(since), RequestType::GET) =
+            (last_modified, since, request.request_type())
+        else {
+            return;
+        };
+        // HTTP dates have whole seconds, so compare in those.
+        let seconds = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        if seconds(last_modified) <= seconds(since) {
+            self.status_code = StatusCode::NotModified;
+            self.body = Body::Empty;
+        }
+    }
+
+    /// Tag the response for purging from caches, with both `Surrogate-Key` and `Cache-Tag`
+    /// headers for CDNs, see `ResponseCache`.
+    pub fn with_surrogate_keys(self, keys: &[&str]) -> Self {
+        self.with_header("Surrogate-Key", keys.join(" "))
+            .with_header("Cache-Tag", keys.join(","))
+    }
+
+    pub fn with_cookie(mut self, cookie: Cookie) -> Self {
+        self.add_cookie(cookie);
+        self
+    }
+
+    /// Add a `Set-Cookie` header, such as from middleware that only has a reference.
+    pub fn add_cookie(&mut self, cookie: Cookie) {
+        self.add_header("Set-Cookie", cookie.header_value());
+    }
+
+    /// The value of the first header with this name, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Code that runs around every request, registered with `App::register_middleware`.
+///
+/// Middleware runs in registration order.
+pub trait Middleware: Send + Sync {
+    /// Called before the request is routed. Returning a response skips routing and the handler.
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    /// Called with every response before it is written, including error responses.
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+
+    /// The names of the middleware running for requests to `path`, as listed by `App::routes`.
+    /// That is the name of the type, unless it only runs for some paths, such as `Auth`.
+    fn names(&self, _path: &str) -> Vec<String> {
+        vec![short_type_name(std::any::type_name::<Self>()).to_string()]
+    }
+}
+
+/// `Auth` for `wwwdaanlubbersnl::auth::Auth`.
+fn short_type_name(name: &str) -> &str {
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// Middleware that is also used elsewhere, such as by a resource, can be registered through an `Arc`.
+impl<T: Middleware> Middleware for Arc<T> {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        T::before(self, request)
+    }
+
+    fn names(&self, path: &str) -> Vec<String> {
+        T::names(self, path)
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        T::after(self, request, response)
+    }
+}
+
+/// Why a request could not be read.
+pub(crate) enum ReadError {
+    /// The connection closed or failed before the whole request arrived, so no response is sent.
+    Incomplete(String),
+    /// The request can't be parsed, or uses a method that isn't supported, and is answered with
+    /// this status unless `AppConfig::with_bad_request_responses` turns that off.
+    Malformed(StatusCode, String),
+    /// The request is over one of the `RequestLimits`, and is answered with this status.
+    TooLarge(StatusCode, String),
+    /// The client address isn't allowed by the `IpFilter`, answered with this status, or by
+    /// closing the connection without a response.
+    Denied(Option<StatusCode>, String),
+}
+
+/// Longest chunk size line accepted in a chunked request body, extensions included.
+pub(crate) const MAX_CHUNK_LINE: usize = 1024;
+/// Hex digits of the largest chunk size accepted, leading zeros aside. Chunks of 4 GiB and up
+/// are too large for any body limit that makes sense, and larger sizes could overflow.
+const MAX_CHUNK_SIZE_DIGITS: usize = 8;
+
+/// Read a chunked body, dropping chunk extensions and trailers. The trailers count towards the
+/// header limit.
+fn read_chunked(reader: &mut impl BufRead, limits: &RequestLimits) -> Result<Vec<u8>, ReadError> {
+    let mut body = vec![];
+    loop {
+        let line = read_chunk_line(reader, MAX_CHUNK_LINE)?;
+        let size = chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_bytes - body.len() {
+            return Err(ReadError::TooLarge(
+                StatusCode::PayloadTooLarge,
+                "Chunked request body too large".to_string(),
+            ));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        if let Err(e) = reader.read_exact(&mut body[start..]) {
+            return Err(ReadError::Incomplete(format!("Failed to read body: {e:?}")));
+        }
+        if !read_chunk_line(reader, 2)?.is_empty() {
+            return Err(ReadError::Malformed(
+                StatusCode::BadRequest,
+                "Chunk longer than its size".to_string(),
+            ));
+        }
+    }
+    let mut trailer_bytes = 0;
+    loop {
+        let line = read_chunk_line(
+            reader,
+            limits.max_header_bytes.saturating_sub(trailer_bytes),
+        )?;
+        if line.is_empty() {
+            return Ok(body);
+        }
+        trailer_bytes += line.len() + 2;
+    }
+}
+
+/// Read a line of a chunked body of at most `max` bytes, without its line break.
+fn read_chunk_line(reader: &mut impl BufRead, max: usize) -> Result<String, ReadError> {
+    let mut line = String::new();
+    match reader.by_ref().take(max as u64 + 2).read_line(&mut line) {
+        Ok(length) if length <= max + 1 && !line.ends_with('\n') => Err(ReadError::Incomplete(
+            "Chunked body ended early".to_string(),
+        )),
+        Ok(_) if !line.ends_with('\n') => Err(ReadError::TooLarge(
+            StatusCode::PayloadTooLarge,
+            "Chunk line too long".to_string(),
+        )),
+        Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+        Err(e) => Err(ReadError::Incomplete(format!("Failed to read body: {e:?}"))),
+    }
+}
+
+/// The size in a chunk size line, ignoring any extensions after it.
+pub(crate) fn chunk_size(line: &str) -> Result<usize, ReadError> {
+    let size = line.split(';').next().unwrap_or_default().trim();
+    if size.is_empty() || !size.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ReadError::Malformed(
+            StatusCode::BadRequest,
+            format!("Invalid chunk size: {line}"),
+        ));
+    }
+    let digits = size.trim_start_matches('0');
+    if digits.len() > MAX_CHUNK_SIZE_DIGITS {
+        return Err(ReadError::TooLarge(
+            StatusCode::PayloadTooLarge,
+            format!("Chunk size too large: {line}"),
+        ));
+    }
+    Ok(usize::from_str_radix(size, 16).unwrap_or_default())
+}
+
+/// Size limits for incoming requests, so a client can't tie up a worker with an endless request.
+pub(crate) struct RequestLimits {
+    pub(crate) max_request_line: usize,
+    pub(crate) max_header_bytes: usize,
+    pub(crate) max_body_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_bytes: 32 * 1024,
+            max_body_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// A connection requests are served on, over TCP or a Unix domain socket.
+pub(crate) trait Connection: Read + Write + Send + 'static {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    /// The address of the client, which clients of a Unix domain socket don't have.
+    fn remote_addr(&self) -> Option<SocketAddr>;
+    /// Send the first `length` bytes of the file with `sendfile(2)`, see `sendfile::send`.
+    #[cfg(target_os = "linux")]
+    fn send_file(&self, file: &File, length: u64) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_file(&self, file: &File, length: u64) -> io::Result<bool> {
+        sendfile::send(self, file, length)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_file(&self, file: &File, length: u64) -> io::Result<bool> {
+        sendfile::send(self, file, length)
+    }
+}
+
+/// A socket `run` accepts connections on.
+enum Listener {
+    Tcp(TcpListener),
+    /// Answers every request with a redirect to HTTPS, see `AppConfig::with_https_redirect`.
+    HttpsRedirect(TcpListener),
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, Arc<rustls::ServerConfig>),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Where connections are accepted, for the startup summary.
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => format!("http://{}", local_addr(listener)),
+            Listener::HttpsRedirect(listener) => {
+                format!("http://{} (redirecting to HTTPS)", local_addr(listener))
+            }
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, _) => format!("https://{}", local_addr(listener)),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    /// Connect to the listener, to wake up its accept loop.
+    fn wake(&self) {
+        match self {
+            Listener::Tcp(listener) | Listener::HttpsRedirect(listener) => wake_tcp(listener),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, _) => wake_tcp(listener),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => {
+                let _ = UnixStream::connect(path);
+            }
+        }
+    }
+}
+
+/// The address a listener is bound to, which has the actual port if it was bound to port 0.
+fn local_addr(listener: &TcpListener) -> String {
+    listener.local_addr().map_or_else(
+        |e| format!("unknown address ({e})"),
+        |addr| addr.to_string(),
+    )
+}
+
+/// Connect to a TCP listener, on the loopback address if it listens on every address.
+fn wake_tcp(listener: &TcpListener) {
+    if let Ok(mut addr) = listener.local_addr() {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(addr);
+    }
+}
+
+/// Reads from a connection until a deadline, so a client that keeps trickling in data can't
+/// keep a worker busy past it. Every read also fails after `read_timeout` without data.
+struct DeadlineReader<'a, C> {
+    stream: &'a mut C,
+    read_timeout: Duration,
+    deadline: Instant,
+}
+
+impl<C: Connection> Read for DeadlineReader<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Deadline passed"));
+        }
+        self.stream
+            .set_read_timeout(Some(remaining.min(self.read_timeout)))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Rejected connections waiting for their 503, see `App::spawn_rejecter`.
+const REJECT_QUEUE: usize = 64;
+
+/// Files are streamed to the client in chunks of this size.
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    /// The buffer every file streamed from this thread goes through.
+    static CHUNK: RefCell<Vec<u8>> = RefCell::new(vec![0; CHUNK_SIZE]);
+}
+
+/// Append a `name: value` header line to a response head.
+fn push_header(head: &mut String, name: &str, value: &str) {
+    head.push_str(name);
+    head.push_str(": ");
+    head.push_str(value);
+    head.push_str("\r\n");
+}
+
+/// Open a file to stream, along with its length.
+fn open_file(path: &Path) -> io::Result<(File, u64)> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Not a file"));
+    }
+    Ok((file, metadata.len()))
+}
+
+/// End a connection after its last response: flush it and shut down writing, so the client
+/// sees the response end rather than a reset. If the client sent more than was read, such as
+/// pipelined requests, a little of that is read first, as closing with data unread resets the
+/// connection, which can lose the response.
+fn close(stream: &mut impl Connection, unread: bool) {
+    if let Err(e) = stream.flush() {
+        log!("Failed to write to stream: {e:?}");
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+    if unread {
+        let _ = stream.set_read_timeout(Some(Duration::from_millis(100)));
+        let _ = io::copy(&mut (&mut *stream).take(64 * 1024), &mut io::sink());
+    }
+}
+
+/// A serialized response. The body of a file response is left in the file, and that of a
+/// stream response in the stream, to be streamed.
+pub(crate) struct Output {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) file: Option<(File, u64)>,
+    pub(crate) stream: Option<Box<dyn Read + Send>>,
+    /// Whether the stream is sent with chunked transfer coding, rather than ended by closing
+    /// the connection.
+    pub(crate) chunked: bool,
+    /// Runs the connection after a WebSocket handshake, once the response is written.
+    pub(crate) upgrade: Option<WebSocketHandler>,
+}
+
+impl Output {
+    /// Whether the connection can be used for another request afterwards.
+    pub(crate) fn keeps_alive(&self) -> bool {
+        self.upgrade.is_none() && (self.stream.is_none() || self.chunked)
+    }
+
+    /// Write the response. With `zero_copy`, files larger than a chunk are sent with
+    /// `sendfile(2)` on Linux, so they don't have to be copied through the chunk buffer.
+    /// The buffer of the head goes back to the thread's buffers afterwards.
+    pub(crate) fn write_to(
+        mut self,
+        stream: &mut impl Connection,
+        zero_copy: bool,
+    ) -> io::Result<()> {
+        let bytes = std::mem::take(&mut self.bytes);
+        let result = self.write_with(&bytes, stream, zero_copy);
+        buffers::recycle_bytes(bytes);
+        result
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn write_with(
+        self,
+        bytes: &[u8],
+        stream: &mut impl Connection,
+        zero_copy: bool,
+    ) -> io::Result<()> {
+        if let Some(mut body) = self.stream {
+            stream.write_all(bytes)?;
+            return match self.chunked {
+                true => write_chunked(stream, body),
+                false => io::copy(&mut body, stream).map(|_| ()),
+            };
+        }
+        let Some((file, length)) = self.file else {
+            return stream.write_all(bytes);
+        };
+        #[cfg(target_os = "linux")]
+        if zero_copy && length > CHUNK_SIZE as u64 {
+            stream.write_all(bytes)?;
+            if stream.send_file(&file, length)? {
+                return Ok(());
+            }
+            return copy_chunks(stream, &[], file, length);
+        }
+        copy_chunks(stream, bytes, file, length)
+    }
+}
+
+/// Write the head and then `length` bytes of the file through the chunk buffer.
+fn copy_chunks(stream: &mut impl Write, head: &[u8], file: File, length: u64) -> io::Result<()> {
+    CHUNK.with_borrow_mut(|chunk| {
+        // Send the head along with the start of the file, rather than in a packet of its own.
+        let mut filled = 0;
+        if head.len() < chunk.len() {
+            chunk[..head.len()].copy_from_slice(head);
+            filled = head.len();
+        } else {
+            stream.write_all(head)?;
+        }
+        let mut file = file.take(length);
+        let mut written = 0;
+        loop {
+            let read = file.read(&mut chunk[filled..])?;
+            if filled + read == 0 {
+                break;
+            }
+            stream.write_all(&chunk[..filled + read])?;
+            written += read as u64;
+            filled = 0;
+            if read == 0 {
+                break;
             }
         }
+        // The client would wait for the rest of a file that shrunk, so fail to close the
+        // connection.
+        match written {
+            written if written == length => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "File shrunk while streaming",
+            )),
+        }
+    })
+}
+
+/// Write `body` with chunked transfer coding, a chunk per read, through the chunk buffer.
+/// A failing read leaves the body unfinished, so the client can tell it is incomplete.
+fn write_chunked(stream: &mut impl Write, mut body: Box<dyn Read + Send>) -> io::Result<()> {
+    CHUNK.with_borrow_mut(|chunk| loop {
+        let read = match body.read(chunk) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if read == 0 {
+            return stream.write_all(b"0\r\n\r\n");
+        }
+        stream.write_all(format!("{read:x}\r\n").as_bytes())?;
+        stream.write_all(&chunk[..read])?;
+        stream.write_all(b"\r\n")?;
+    })
+}
+
+/// Builds an `AppConfig`, see `AppConfig::builder`.
+pub struct AppConfigBuilder {
+    addr: SocketAddr,
+    threads: usize,
+    read_timeout: u64,
+}
+
+impl Default for AppConfigBuilder {
+    /// Listen on `127.0.0.1:8080` with a worker per CPU and reads waiting at most 5 seconds.
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 8080)),
+            threads: thread::available_parallelism().map_or(4, |threads| threads.get()),
+            read_timeout: 5,
+        }
+    }
+}
+
+impl AppConfigBuilder {
+    /// The first address to listen on. More are added with `AppConfig::with_addr`.
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// The number of workers handling requests.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Seconds a single read may wait for data.
+    pub fn read_timeout(mut self, seconds: u64) -> Self {
+        self.read_timeout = seconds;
+        self
+    }
+
+    /// Fails for settings the server can't run with, rather than once it starts.
+    pub fn build(self) -> Result<AppConfig, String> {
+        if self.threads == 0 {
+            return Err("At least one thread is needed".to_string());
+        }
+        if self.read_timeout == 0 {
+            return Err("The read timeout must be at least a second".to_string());
+        }
+        Ok(AppConfig::new(self.addr, self.threads, self.read_timeout))
+    }
+}
+
+pub struct AppConfig {
+    /// Every address gets its own listener, all served by the same app and thread pool.
+    pub(crate) addrs: Vec<SocketAddr>,
+    pub(crate) num_threads: usize,
+    /// Seconds a single read may wait for data.
+    pub(crate) read_timeout: u64,
+    pub(crate) limits: RequestLimits,
+    pub(crate) header_timeout: Duration,
+    pub(crate) body_timeout: Duration,
+    pub(crate) request_timeout: Duration,
+    zero_copy: bool,
+    bad_request_responses: bool,
+    trace: bool,
+    trailing_slash: TrailingSlash,
+    case_insensitive_paths: bool,
+    strict_files: bool,
+    /// The address to redirect to HTTPS from, and the port HTTPS is served on.
+    https_redirect: Option<(SocketAddr, u16)>,
+    /// The address to serve HTTPS on, and the certificates to present there.
+    #[cfg(feature = "tls")]
+    tls: Option<(SocketAddr, Arc<TlsCertificates>)>,
+    #[cfg(feature = "tls")]
+    http2: bool,
+    pub(crate) shutdown_signals: bool,
+    server_header: Option<String>,
+    date_header: bool,
+    request_id_header: bool,
+    trusted_proxies: Vec<IpAddr>,
+    pub(crate) proxy_protocol: bool,
+    ip_filter: Option<IpFilter>,
+    max_connections: Option<(usize, OverLimit)>,
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+}
+
+impl AppConfig {
+    /// A builder with defaults for everything, so arguments can't be mixed up like they can
+    /// with `new`. Everything else is set with the `with_` methods on what it builds.
+    pub fn builder() -> AppConfigBuilder {
+        AppConfigBuilder::default()
+    }
+
+    pub fn new(addr: SocketAddr, num_threads: usize, read_timeout: u64) -> Self {
+        Self {
+            addrs: vec![addr],
+            num_threads,
+            read_timeout,
+            limits: RequestLimits::default(),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(60),
+            request_timeout: Duration::from_secs(120),
+            zero_copy: true,
+            bad_request_responses: true,
+            trace: false,
+            trailing_slash: TrailingSlash::Strict,
+            case_insensitive_paths: false,
+            strict_files: false,
+            https_redirect: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            http2: false,
+            shutdown_signals: false,
+            server_header: Some(format!("wwwdaanlubbersnl/{}", env!("CARGO_PKG_VERSION"))),
+            date_header: true,
+            request_id_header: true,
+            trusted_proxies: vec![],
+            ip_filter: None,
+            max_connections: None,
+            proxy_protocol: false,
+            #[cfg(unix)]
+            unix_socket: None,
+        }
+    }
+
+    /// The addresses listened on.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// The worker threads requests are answered on.
+    pub fn num_threads(&self) -> usize {
+        self.num_threads
+    }
+
+    /// Also listen on `addr`, such as another port or `[::]` next to `0.0.0.0`. On Linux `[::]`
+    /// accepts IPv4 connections as well, unless `net.ipv6.bindv6only` is set, so binding both to
+    /// the same port fails there.
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Also listen on a Unix domain socket at `path`, such as for a reverse proxy on the same
+    /// machine. A socket file left behind at `path` is replaced, and the file is removed again
+    /// once the server stops. Only `run` serves it, not `run_evented` or `run_async`.
+    #[cfg(unix)]
+    pub fn with_unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.unix_socket = Some(path.into());
+        self
+    }
+
+    /// Also listen on `addr` for plain HTTP, such as port 80, answering every request with a 301
+    /// to the same path and query on `https://`, at `https_port` and the host the client asked
+    /// for. For when HTTPS is served on the other addresses, or by a proxy in front of them.
+    /// Only `run` serves it, not `run_evented` or `run_async`.
+    pub fn with_https_redirect(mut self, addr: SocketAddr, https_port: u16) -> Self {
+        self.https_redirect = Some((addr, https_port));
+        self
+    }
+
+    /// Also serve HTTPS on `addr`, such as port 443, presenting the certificate from
+    /// `certificates` for the hostname the client asks for. Only `run` serves it.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, addr: SocketAddr, certificates: Arc<TlsCertificates>) -> Self {
+        self.tls = Some((addr, certificates));
+        self
+    }
+
+    /// Offer HTTP/2 to clients of the TLS listener, which they pick during the handshake. The
+    /// requests on a connection are answered at the same time, each on a thread of its own,
+    /// so pages with many assets load over one connection. Off by default.
+    ///
+    /// There's no HTTP/2 without TLS: requests asking to upgrade with `Upgrade: h2c` are answered
+    /// over HTTP/1.1, and connections starting with the HTTP/2 preface get a 505.
+    #[cfg(feature = "tls")]
+    pub fn with_http2(mut self, http2: bool) -> Self {
+        self.http2 = http2;
+        self
+    }
+
+    /// Close connections that haven't sent the request line and headers this long after
+    /// connecting. Defaults to 10 seconds.
+    pub fn with_header_timeout(mut self, timeout: Duration) -> Self {
+        self.header_timeout = timeout;
+        self
+    }
+
+    /// Close connections that haven't sent the whole body this long after connecting.
+    /// Defaults to 60 seconds.
+    pub fn with_body_timeout(mut self, timeout: Duration) -> Self {
+        self.body_timeout = timeout;
+        self
+    }
+
+    /// Close connections that haven't sent the request, or accepted the response, this long
+    /// after connecting. Handlers aren't interrupted. Defaults to 120 seconds.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Send large files with `sendfile(2)` on Linux, rather than copying them through a buffer.
+    /// Defaults to true.
+    pub fn with_zero_copy(mut self, zero_copy: bool) -> Self {
+        self.zero_copy = zero_copy;
+        self
+    }
+
+    /// Answer requests that can't be parsed with 400, and unsupported methods with 501, along
+    /// with a small HTML page. Without this, such connections are closed without a response,
+    /// which gives scanners less to go on. Defaults to true.
+    pub fn with_bad_request_responses(mut self, respond: bool) -> Self {
+        self.bad_request_responses = respond;
+        self
+    }
+
+    /// Answer TRACE requests without a resource by echoing the request as `message/http`, for
+    /// debugging what reaches the server through proxies. `Authorization`, `Cookie` and
+    /// `Proxy-Authorization` are left out, so scripts can't use it to read them. Defaults to
+    /// false, answering them with 405.
+    pub fn with_trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// How requests for a resource's path with a trailing slash added or removed are answered,
+    /// such as `/about/` for `/about`. Only applies to resources, not static directories or
+    /// uploads. Defaults to `TrailingSlash::Strict`.
+    pub fn with_trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    /// Find resources ignoring ASCII case, so `/About` is answered by `/about`. Resources whose
+    /// paths only differ in case are then registered twice, so only the first is kept.
+    /// Defaults to false.
+    pub fn with_case_insensitive_paths(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive_paths = case_insensitive;
+        self
+    }
+
+    /// Check that the files of resources, see `Resource::with_file`, and static directories can
+    /// be read when they are registered, rather than find out from 404s once the server runs.
+    /// `try_register_resource` and `try_serve_dir` return what's wrong, the other ways of
+    /// registering panic. Defaults to false.
+    pub fn with_strict_files(mut self, strict: bool) -> Self {
+        self.strict_files = strict;
+        self
+    }
+
+    /// Stop `run` and `run_evented` on `SIGINT` or `SIGTERM`, as with Ctrl-C or a service
+    /// manager stopping the server. Requests being handled are finished first, and a second
+    /// signal exits right away. Only on Unix. Defaults to false, so embedding applications keep
+    /// their own handling of these signals.
+    pub fn with_shutdown_signals(mut self, shutdown_signals: bool) -> Self {
+        self.shutdown_signals = shutdown_signals;
+        self
+    }
+
+    /// The `Server` header sent with every response, or `None` to leave it out. Defaults to
+    /// `wwwdaanlubbersnl/<version>`.
+    pub fn with_server_header(mut self, server: Option<&str>) -> Self {
+        self.server_header = server.map(str::to_string);
+        self
+    }
+
+    /// Send a `Date` header with every response. Defaults to true, as HTTP requires of servers
+    /// with a clock, so only turn it off on machines without a reliable one.
+    pub fn with_date_header(mut self, date: bool) -> Self {
+        self.date_header = date;
+        self
+    }
+
+    /// Take the client address of requests from `ip`, such as a reverse proxy on the same
+    /// machine, from their `X-Forwarded-For` or `X-Real-IP` header. See `Request::client_ip`.
+    pub fn with_trusted_proxy(mut self, ip: IpAddr) -> Self {
+        self.trusted_proxies.push(ip.to_canonical());
+        self
+    }
+
+    /// Turn away clients by their address, see `IpFilter`.
+    pub fn with_ip_filter(mut self, filter: IpFilter) -> Self {
+        self.ip_filter = Some(filter);
+        self
+    }
+
+    /// Keep at most `max` connections open, counting those still waiting for a worker, and
+    /// handle the ones over it as `over_limit` says. Only `run` limits connections.
+    pub fn with_max_connections(mut self, max: usize, over_limit: OverLimit) -> Self {
+        self.max_connections = Some((max, over_limit));
+        self
+    }
+
+    /// Expect connections to start with a PROXY protocol (v1 or v2) header, as sent by
+    /// HAProxy or nginx with `proxy_protocol on`, and take the client address from it.
+    /// Connections without one are closed, so only turn this on if every client is such a
+    /// proxy. Not read by `run_evented`. Defaults to false.
+    pub fn with_proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// Send the request ID with every response, in an `X-Request-Id` header, so a response can
+    /// be found in the logs. Defaults to true.
+    pub fn with_request_id_header(mut self, request_id: bool) -> Self {
+        self.request_id_header = request_id;
+        self
+    }
+
+    /// The `Date` and `Server` header lines every response starts with.
+    pub(crate) fn common_headers(&self) -> String {
+        let mut headers = String::new();
+        self.write_common_headers(&mut headers);
+        headers
+    }
+
+    /// Append the headers of `common_headers` to `head`.
+    pub(crate) fn write_common_headers(&self, head: &mut String) {
+        if self.date_header {
+            push_header(head, "Date", &http_date(SystemTime::now()));
+        }
+        if let Some(server) = &self.server_header {
+            push_header(head, "Server", server);
+        }
+    }
+
+    /// Longer request lines are answered with 414. Defaults to 8 KiB.
+    pub fn with_max_request_line(mut self, bytes: usize) -> Self {
+        self.limits.max_request_line = bytes;
+        self
+    }
+
+    /// Requests with more header bytes are answered with 431. Defaults to 32 KiB.
+    pub fn with_max_header_bytes(mut self, bytes: usize) -> Self {
+        self.limits.max_header_bytes = bytes;
+        self
+    }
+
+    /// Requests with a larger body are answered with 413 before the body is read. Defaults to
+    /// 16 MiB, so raise it to accept larger uploads.
+    pub fn with_max_body_bytes(mut self, bytes: usize) -> Self {
+        self.limits.max_body_bytes = bytes;
+        self
+    }
+}
+
+/// A route as listed by `App::routes`.
+#[derive(Debug, Clone)]
+pub struct RouteInfo {
+    pub method: RequestType,
+    /// The path of the resource, which may have parameters and wildcards, or `<prefix>/*` for
+    /// the files of a static directory.
+    pub pattern: String,
+    pub resource_type: ResourceType,
+    /// The middleware running for requests to the pattern, in order.
+    pub middleware: Vec<String>,
+}
+
+impl Display for RouteInfo {
+    /// A line of the routing table, such as `GET     /admin TEXT [Auth, Metrics]`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let method = format!("{:?}", self.method);
+        write!(f, "{method:<8}{} {:?}", self.pattern, self.resource_type)?;
+        if !self.middleware.is_empty() {
+            write!(f, " [{}]", self.middleware.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// What `App::on_request` and `App::on_response` hooks are told about a request.
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    /// The address of the client, which clients of a Unix domain socket don't have.
+    pub remote_addr: Option<SocketAddr>,
+    pub method: RequestType,
+    pub path: String,
+}
+
+/// What `App::on_response` hooks are told about the response.
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseInfo {
+    pub status: u16,
+    /// From reading the request until the response was ready to send, so without writing it.
+    pub duration: Duration,
+}
+
+type RequestHook = Box<dyn Fn(&RequestInfo) + Send + Sync>;
+type ResponseHook = Box<dyn Fn(&RequestInfo, &ResponseInfo) + Send + Sync>;
+
+pub struct App {
+    pub(crate) config: AppConfig,
+    resources: Vec<Resource>,
+    /// Indexes into `resources`.
+    routes: RouteTrie<usize>,
+    resource_404: Option<Resource>,
+    resource_500: Option<Resource>,
+    digests: Vec<DigestAlgorithm>,
+    middleware: Vec<Box<dyn Middleware>>,
+    state: Arc<State>,
+    uploads: Vec<UploadMount>,
+    static_dirs: Vec<StaticDir>,
+    sitemap: Option<Sitemap>,
+    /// The path the routing table is served at, see `enable_route_table`.
+    route_table: Option<String>,
+    error_pages: Option<Arc<ErrorPages>>,
+    request_hooks: Vec<RequestHook>,
+    response_hooks: Vec<ResponseHook>,
+    headers: Vec<(String, String)>,
+    #[cfg(feature = "async")]
+    async_resources: Vec<AsyncResource>,
+}
+
+impl App {
+    /// If the stop flag is set, the server will shut down after processing the next request.
+    /// Implemented for testing purposes.
+    pub fn new(config: AppConfig) -> Self {
+        let mut state = State::default();
+        state.insert(PoolCounters::default());
+        state.insert(ConnectionCounter::default());
+        state.insert(GracefulShutdown::default());
+        state.insert(RouteList::default());
+        let routes = RouteTrie::new().with_case_insensitive(config.case_insensitive_paths);
+        Self {
+            config,
+            resources: vec![],
+            routes,
+            resource_404: None,
+            resource_500: None,
+            digests: vec![],
+            middleware: vec![],
+            state: Arc::new(state),
+            uploads: vec![],
+            static_dirs: vec![],
+            sitemap: None,
+            route_table: None,
+            error_pages: None,
+            request_hooks: vec![],
+            response_hooks: vec![],
+            headers: vec![],
+            #[cfg(feature = "async")]
+            async_resources: vec![],
+        }
+    }
+
+    /// Serve requests until the stop flag is set. Fails if an address can't be bound to, so
+    /// the caller can try another one, or if the workers are gone.
+    pub fn run(self, stop_flag: Option<Arc<AtomicBool>>) -> Result<(), ServerError> {
+        self.check_setup()?;
+        let listeners = self.bind()?;
+        let addrs: Vec<String> = listeners.iter().map(Listener::describe).collect();
+        log!("{}", self.startup_summary(&addrs));
+        self.install_signal_handlers()?;
+        // Always there, so `GracefulShutdown` can stop the server without the caller setting one.
+        let stop_flag = Some(stop_flag.unwrap_or_default());
+        let counters = self.state.get::<PoolCounters>().unwrap();
+        let pool = ThreadPool::with_counters(self.config.num_threads, counters);
+        let app = Arc::new(self);
+
+        // An accept loop per listener, all handing connections to the same pool. Once one of
+        // them stops, it wakes up the others so they stop as well.
+        let serve = |listener: &Listener| {
+            let stop_flag = stop_flag.as_deref();
+            let result = match listener {
+                Listener::Tcp(tcp) => {
+                    app.accept(tcp.incoming(), &pool, stop_flag, Self::handle_request)
+                }
+                Listener::HttpsRedirect(tcp) => {
+                    app.accept(tcp.incoming(), &pool, stop_flag, Self::redirect_to_https)
+                }
+                #[cfg(feature = "tls")]
+                Listener::Tls(tcp, config) => {
+                    let incoming = tcp
+                        .incoming()
+                        .map(|stream| stream.and_then(|stream| TlsStream::new(stream, config)));
+                    app.accept(incoming, &pool, stop_flag, Self::handle_tls)
+                }
+                #[cfg(unix)]
+                Listener::Unix(unix, _) => {
+                    app.accept(unix.incoming(), &pool, stop_flag, Self::handle_request)
+                }
+            };
+            for other in listeners
+                .iter()
+                .filter(|other| !std::ptr::eq(*other, listener))
+            {
+                other.wake();
+            }
+            result
+        };
+        let stopped = AtomicBool::new(false);
+        let result = thread::scope(|scope| {
+            if let Some(stop_flag) = stop_flag.as_deref() {
+                scope.spawn(|| {
+                    while !stopped.load(Ordering::SeqCst) {
+                        if app.shutdown_requested() {
+                            log!("Shutting down");
+                            stop_flag.store(true, Ordering::SeqCst);
+                            listeners[0].wake();
+                            break;
+                        }
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                });
+            }
+            let others = listeners[1..]
+                .iter()
+                .map(|listener| scope.spawn(|| serve(listener)))
+                .collect::<Vec<_>>();
+            let result = serve(&listeners[0]);
+            let result = others
+                .into_iter()
+                .map(|other| other.join().unwrap())
+                .fold(result, Result::and);
+            stopped.store(true, Ordering::SeqCst);
+            result
+        });
+
+        #[cfg(unix)]
+        for listener in &listeners {
+            if let Listener::Unix(_, path) = listener {
+                let _ = fs::remove_file(path);
+            }
+        }
+        result
+    }
+
+    /// Fail on a setup that can't work before binding, rather than answer requests with errors.
+    fn check_setup(&self) -> Result<(), ServerError> {
+        for dir in &self.static_dirs {
+            if !dir.dir().is_dir() {
+                return Err(ServerError::Setup(format!(
+                    "The static directory {} served at {} does not exist, create it or fix the \
+                     path given to serve_dir",
+                    dir.dir().display(),
+                    dir.prefix()
+                )));
+            }
+        }
+        for mount in &self.uploads {
+            if mount.dir().exists() && !mount.dir().is_dir() {
+                return Err(ServerError::Setup(format!(
+                    "The upload directory {} for {} is not a directory",
+                    mount.dir().display(),
+                    mount.prefix()
+                )));
+            }
+        }
+        // Certificates may still be on their way, such as from ACME, so this isn't fatal.
+        #[cfg(feature = "tls")]
+        if let Some((addr, certificates)) = &self.config.tls {
+            if certificates.is_empty() {
+                warn!("No TLS certificates for {addr} yet, so handshakes fail until one is added");
+            }
+        }
+        Ok(())
+    }
+
+    /// What the server is about to serve, once it listens on `addrs`.
+    fn startup_summary(&self, addrs: &[String]) -> String {
+        #[cfg(feature = "tls")]
+        let tls = match &self.config.tls {
+            Some(_) if self.config.http2 => "on, with HTTP/2",
+            Some(_) => "on",
+            None => "off",
+        };
+        #[cfg(not(feature = "tls"))]
+        let tls = "off";
+        let static_dirs: Vec<String> = self
+            .static_dirs
+            .iter()
+            .map(|dir| format!("{} -> {}", dir.prefix(), dir.dir().display()))
+            .collect();
+        #[cfg(feature = "async")]
+        let routes = self.resources.len() + self.async_resources.len();
+        #[cfg(not(feature = "async"))]
+        let routes = self.resources.len();
+        format!(
+            "Starting server\n  listen: {}\n  tls: {tls}\n  workers: {}\n  routes: {routes}\n  \
+             static: {}",
+            addrs.join(", "),
+            self.config.num_threads,
+            match static_dirs.is_empty() {
+                true => "none".to_string(),
+                false => static_dirs.join(", "),
+            }
+        )
+    }
+
+    fn install_signal_handlers(&self) -> Result<(), ServerError> {
+        #[cfg(unix)]
+        if self.config.shutdown_signals {
+            signals::install(signals::SIGINT)?;
+            signals::install(signals::SIGTERM)?;
+        }
+        Ok(())
+    }
+
+    fn bind(&self) -> Result<Vec<Listener>, ServerError> {
+        #[allow(unused_mut)]
+        let mut listeners: Vec<Listener> =
+            self.bind_tcp()?.into_iter().map(Listener::Tcp).collect();
+        if let Some((addr, _)) = self.config.https_redirect {
+            match TcpListener::bind(addr) {
+                Ok(listener) => listeners.push(Listener::HttpsRedirect(listener)),
+                Err(e) => return Err(ServerError::Bind(addr.to_string(), e)),
+            }
+        }
+        #[cfg(feature = "tls")]
+        if let Some((addr, certificates)) = &self.config.tls {
+            let config = tls::server_config(Arc::clone(certificates), self.config.http2);
+            match TcpListener::bind(addr) {
+                Ok(listener) => listeners.push(Listener::Tls(listener, config)),
+                Err(e) => return Err(ServerError::Bind(addr.to_string(), e)),
+            }
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.config.unix_socket {
+            // A socket left behind by a server that didn't stop cleanly would make binding fail.
+            if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                let _ = fs::remove_file(path);
+            }
+            match UnixListener::bind(path) {
+                Ok(listener) => listeners.push(Listener::Unix(listener, path.clone())),
+                Err(e) => return Err(ServerError::Bind(path.display().to_string(), e)),
+            }
+        }
+        Ok(listeners)
+    }
+
+    fn bind_tcp(&self) -> Result<Vec<TcpListener>, ServerError> {
+        self.config
+            .addrs
+            .iter()
+            .map(|addr| TcpListener::bind(addr).map_err(|e| ServerError::Bind(addr.to_string(), e)))
+            .collect()
+    }
+
+    /// Hand the incoming connections of a listener to the pool until the stop flag is set, to
+    /// be handled by `handle`. Connections count as open from here on, see `ConnectionCounter`.
+    fn accept<C: Connection>(
+        self: &Arc<Self>,
+        mut incoming: impl Iterator<Item = io::Result<C>>,
+        pool: &ThreadPool,
+        stop_flag: Option<&AtomicBool>,
+        handle: fn(&Self, C),
+    ) -> Result<(), ServerError> {
+        let stopped = || stop_flag.is_some_and(|stop_flag| stop_flag.load(Ordering::SeqCst));
+        let connections = self.state.get::<ConnectionCounter>().unwrap();
+        let rejecter = match self.config.max_connections {
+            Some((max, OverLimit::Reject)) => Some((max, self.spawn_rejecter())),
+            _ => None,
+        };
+        loop {
+            if let Some((max, OverLimit::Wait)) = self.config.max_connections {
+                // Woken as soon as a connection closes, and now and then to check the flag.
+                while !connections.wait_below(max, Duration::from_millis(100)) && !stopped() {}
+            }
+            let Some(stream) = incoming.next() else {
+                break;
+            };
+            // Read the flag once the connection is accepted, so the request that follows setting
+            // the flag is always the last one handled.
+            let stop = stopped();
+
+            match stream {
+                Ok(stream) => {
+                    let connection = self.track_connection();
+                    if let Some((max, rejecter)) = &rejecter {
+                        if connections.open() > *max {
+                            drop(connection);
+                            let _ = rejecter.try_send(stream);
+                            continue;
+                        }
+                    }
+                    let app_clone = Arc::clone(self);
+
+                    pool.execute(move || {
+                        let _connection = connection;
+                        handle(&app_clone, stream)
+                    })?;
+                }
+                Err(e) => {
+                    log!("Connection Failed: {e:?}")
+                }
+            }
+
+            if stop {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `run`, but waits for requests on one thread with mio (epoll or kqueue), so idle
+    /// connections don't take up a worker. Connections are kept open between requests, unless
+    /// the client sends `Connection: close`, and closed after the read timeout without a request.
+    #[cfg(feature = "evented")]
+    pub fn run_evented(self, stop_flag: Option<Arc<AtomicBool>>) -> Result<(), ServerError> {
+        self.check_setup()?;
+        let listeners = self.bind_tcp()?;
+        let addrs: Vec<String> = listeners
+            .iter()
+            .map(|listener| format!("http://{}", local_addr(listener)))
+            .collect();
+        log!("{}", self.startup_summary(&addrs));
+        self.install_signal_handlers()?;
+        let counters = self.state.get::<PoolCounters>().unwrap();
+        let pool = ThreadPool::with_counters(self.config.num_threads, counters);
+        let idle_timeout = Duration::from_secs(self.config.read_timeout);
+
+        evented::run(Arc::new(self), listeners, pool, idle_timeout, stop_flag)
+    }
+
+    /// Serve requests on the tokio runtime this is awaited on, rather than on the thread pool.
+    /// Connections are kept open between requests if the client wants that, see
+    /// `Request::keep_alive`.
+    ///
+    /// Async resources run on the runtime. Everything else, such as resources and uploads,
+    /// runs with `spawn_blocking`, so blocking handlers don't hold up other connections.
+    #[cfg(feature = "async")]
+    pub async fn run_async(self, stop_flag: Option<Arc<AtomicBool>>) -> Result<(), ServerError> {
+        self.check_setup()?;
+        let mut listeners = vec![];
+        let mut addrs = vec![];
+        for addr in &self.config.addrs {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(|e| ServerError::Bind(addr.to_string(), e))?;
+            addrs.push(format!("http://{}", listener.local_addr()?));
+            listeners.push(listener);
+        }
+        log!("{}", self.startup_summary(&addrs));
+        // Stop accepting on every listener once one of them stops, with the error it stopped
+        // with, if any.
+        let app = Arc::new(self);
+        let mut loops = tokio::task::JoinSet::new();
+        for listener in listeners {
+            loops.spawn(async_server::run(
+                Arc::clone(&app),
+                listener,
+                stop_flag.clone(),
+            ));
+        }
+        match loops.join_next().await {
+            Some(Ok(result)) => result.map_err(ServerError::Io),
+            Some(Err(e)) => Err(ServerError::Io(io::Error::other(format!(
+                "Accept loop failed: {e}"
+            )))),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub fn register_async_resource(&mut self, resource: AsyncResource) {
+        self.async_resources.push(resource);
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn get_async_resource(&self, request: &Request) -> Option<&AsyncResource> {
+        self.async_resources
+            .iter()
+            .find(|resource| resource.matches(request.request_type(), request.path()))
+    }
+
+    /// Every registered resource, along with the static directories, in registration order.
+    /// The middleware is what is registered so far, so call this once the app is set up, such
+    /// as to print the routing table.
+    pub fn routes(&self) -> impl Iterator<Item = RouteInfo> + '_ {
+        let resources = self.resources.iter().map(|resource| {
+            (
+                resource.request_type,
+                resource.path.clone(),
+                resource.resource_type,
+            )
+        });
+        #[cfg(feature = "async")]
+        let resources = resources.chain(self.async_resources.iter().map(|resource| {
+            (
+                resource.request_type,
+                resource.path.clone(),
+                resource.resource_type,
+            )
+        }));
+        let static_dirs = self.static_dirs.iter().map(|dir| {
+            let pattern = format!("{}/*", dir.prefix().trim_end_matches('/'));
+            (RequestType::GET, pattern, ResourceType::BINARY)
+        });
+        resources
+            .chain(static_dirs)
+            .map(|(method, pattern, resource_type)| RouteInfo {
+                middleware: self
+                    .middleware
+                    .iter()
+                    .flat_map(|middleware| middleware.names(&pattern))
+                    .collect(),
+                method,
+                pattern,
+                resource_type,
+            })
+    }
+
+    /// Panics if the file of the resource can't be read, with `AppConfig::with_strict_files`.
+    pub fn register_resource(&mut self, resource: Resource) {
+        if let Err(e) = self.try_register_resource(resource) {
+            panic!("{e}");
+        }
+    }
+
+    /// Register `resource`, or with `AppConfig::with_strict_files`, fail if its file can't be
+    /// read.
+    pub fn try_register_resource(&mut self, resource: Resource) -> Result<(), ServerError> {
+        if let Some(path) = resource.file.as_ref().filter(|_| self.config.strict_files) {
+            let readable = fs::metadata(path)
+                .and_then(|metadata| match metadata.is_file() {
+                    true => File::open(path).map(drop),
+                    false => Err(io::Error::other("not a file")),
+                })
+                .map_err(|e| {
+                    ServerError::Setup(format!(
+                        "{:?} {} serves {}, which can't be read: {e}",
+                        resource.request_type,
+                        resource.path,
+                        path.display()
+                    ))
+                });
+            readable?;
+        }
+        let index = self.resources.len();
+        if self
+            .routes
+            .insert(resource.request_type, &resource.path, index)
+        {
+            let list = self.state.get::<RouteList>().unwrap();
+            list.push(resource.request_type, &resource.path);
+            self.resources.push(resource);
+        } else {
+            log!(
+                "Ignoring resource registered twice: {:?} {}",
+                resource.request_type,
+                resource.path
+            );
+        }
+        Ok(())
+    }
+
+    /// Register a resource for every redirect in the file at `path`, which has a
+    /// `/from -> /to` line per redirect, optionally followed by the status, 301 or 302. Returns
+    /// how many there are. See `redirects::resources_from_file`.
+    pub fn register_redirects_from(&mut self, path: impl AsRef<Path>) -> Result<usize, String> {
+        let resources = redirects::resources_from_file(path)?;
+        let count = resources.len();
+        for resource in resources {
+            self.register_resource(resource);
+        }
+        Ok(count)
+    }
+
+    pub fn register_resource_404(&mut self, resource: Resource) {
+        self.resource_404 = Some(resource);
+    }
+
+    pub fn register_resource_500(&mut self, resource: Resource) {
+        self.resource_500 = Some(resource);
+    }
+
+    pub fn register_middleware(&mut self, middleware: Box<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    /// Call `hook` for every request that was read, before the middleware runs. Unlike
+    /// middleware, hooks only get to look, such as to collect statistics.
+    pub fn on_request(&mut self, hook: impl Fn(&RequestInfo) + Send + Sync + 'static) {
+        self.request_hooks.push(Box::new(hook));
+    }
+
+    /// Call `hook` for every response to a request, once the `after` middleware has run.
+    pub fn on_response(
+        &mut self,
+        hook: impl Fn(&RequestInfo, &ResponseInfo) + Send + Sync + 'static,
+    ) {
+        self.response_hooks.push(Box::new(hook));
+    }
+
+    fn request_info(request: &Request) -> RequestInfo {
+        RequestInfo {
+            remote_addr: request.remote_addr(),
+            method: request.request_type(),
+            path: request.path().to_string(),
+        }
+    }
+
+    /// Register the resources and middleware that `routes` adds to a `Router` under `prefix`,
+    /// such as an API, or admin pages behind `Auth`. The middleware only runs for requests
+    /// under the prefix, in order with the middleware registered before and after this.
+    pub fn scope(&mut self, prefix: &str, routes: impl FnOnce(&mut Router)) {
+        let mut router = Router::new(prefix);
+        routes(&mut router);
+        let (resources, group) = router.finish();
+        for resource in resources {
+            self.register_resource(resource);
+        }
+        if let Some(group) = group {
+            self.register_middleware(Box::new(group));
+        }
+    }
+
+    /// Share `state` with every handler, which can read it through `Request::state`.
+    /// Only one value is kept per type, so wrap values in a newtype to store several of the same type.
+    pub fn with_state<T: Send + Sync + 'static>(mut self, state: T) -> Self {
+        Arc::make_mut(&mut self.state).insert(state);
+        self
+    }
+
+    /// Send this header with every response, including error pages and those of middleware,
+    /// unless it's already set. Runs before the `after` middleware, so they see it.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Only serve requests under `prefix` when they carry a valid signed URL, see `UrlSigner`.
+    /// The signer is added to the application state, so handlers can create links with it.
+    pub fn enable_signed_urls(&mut self, prefix: &str, signer: UrlSigner) {
+        self.register_middleware(Box::new(SignedUrls::new(prefix, signer.clone())));
+        Arc::make_mut(&mut self.state).insert(signer);
+    }
+
+    /// The signer given to `enable_signed_urls`, for creating links to protected files.
+    pub fn url_signer(&self) -> Option<Arc<UrlSigner>> {
+        self.state.get::<UrlSigner>()
+    }
+
+    /// Share `flags` with every handler, which can read them through
+    /// `request.state::<FeatureFlags>()`. The returned handle is for `watch` and `admin_resources`.
+    pub fn enable_feature_flags(&mut self, flags: FeatureFlags) -> Arc<FeatureFlags> {
+        Arc::make_mut(&mut self.state).insert(flags);
+        self.feature_flags().unwrap()
+    }
+
+    /// The flags given to `enable_feature_flags`.
+    pub fn feature_flags(&self) -> Option<Arc<FeatureFlags>> {
+        self.state.get::<FeatureFlags>()
+    }
+
+    /// Answer the HTTP-01 challenges of an ACME server, here and on the listener given to
+    /// `AppConfig::with_https_redirect`. The returned handle is for `AcmeClient::issue`.
+    pub fn enable_acme_challenges(&mut self) -> Arc<AcmeChallenges> {
+        Arc::make_mut(&mut self.state).insert(AcmeChallenges::new());
+        let challenges = self.state.get::<AcmeChallenges>().unwrap();
+        self.register_middleware(Box::new(Arc::clone(&challenges)));
+        challenges
+    }
+
+    /// For stopping the server gracefully from a handler, which can get the same handle from
+    /// `request.state::<GracefulShutdown>()`.
+    pub fn shutdown(&self) -> Arc<GracefulShutdown> {
+        self.state.get::<GracefulShutdown>().unwrap()
+    }
+
+    /// Whether `GracefulShutdown` was requested, or `SIGINT` or `SIGTERM` came in while the server
+    /// handles them, see `AppConfig::with_shutdown_signals`.
+    pub(crate) fn shutdown_requested(&self) -> bool {
+        #[cfg(unix)]
+        if self.config.shutdown_signals && signals::shutdown_requested() {
+            return true;
+        }
+        self.shutdown().requested()
+    }
+
+    /// How busy the worker threads are. Handlers can get the same numbers from
+    /// `request.state::<PoolCounters>()`, such as for a metrics endpoint.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.state.get::<PoolCounters>().unwrap().stats()
+    }
+
+    /// Answer `/healthz` with 200 for as long as the server is up, and `/readyz` with 200 while
+    /// `ready` passes and the thread pool keeps up, for uptime monitors and container
+    /// orchestrators. See `health::readiness_resource`.
+    pub fn enable_health_endpoints(
+        &mut self,
+        ready: impl Fn() -> Result<(), Error> + Send + Sync + 'static,
+    ) {
+        self.register_resource(health::liveness_resource("/healthz"));
+        let max_queued = self.config.num_threads;
+        self.register_resource(health::readiness_resource("/readyz", max_queued, ready));
+    }
+
+    /// Count a connection as open in the `ConnectionCounter` until the returned guard is dropped.
+    pub(crate) fn track_connection(&self) -> OpenConnection {
+        self.state.get::<ConnectionCounter>().unwrap().track()
+    }
+
+    /// Accept PUT and POST uploads to `<prefix>/<file name>`, stored in `dir`.
+    /// The returned mount can be used to set size limits and authorization.
+    pub fn serve_upload(&mut self, prefix: &str, dir: impl Into<PathBuf>) -> &mut UploadMount {
+        self.uploads.push(UploadMount::new(prefix, dir));
+        self.uploads.last_mut().unwrap()
+    }
+
+    /// Serve the files in `dir` for GET requests to `<prefix>/<path>`, unless a resource has the
+    /// same path. The returned directory can be used to enable listings. Panics if `dir` can't
+    /// be read, with `AppConfig::with_strict_files`.
+    pub fn serve_dir(&mut self, prefix: &str, dir: impl Into<PathBuf>) -> &mut StaticDir {
+        match self.try_serve_dir(prefix, dir) {
+            Ok(dir) => dir,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Like `serve_dir`, but with `AppConfig::with_strict_files`, fail if `dir` can't be read.
+    pub fn try_serve_dir(
+        &mut self,
+        prefix: &str,
+        dir: impl Into<PathBuf>,
+    ) -> Result<&mut StaticDir, ServerError> {
+        let dir = dir.into();
+        if self.config.strict_files {
+            fs::read_dir(&dir).map_err(|e| {
+                ServerError::Setup(format!(
+                    "The static directory {} for {prefix} can't be read: {e}",
+                    dir.display()
+                ))
+            })?;
+        }
+        self.static_dirs.push(StaticDir::new(prefix, dir));
+        Ok(self.static_dirs.last_mut().unwrap())
+    }
+
+    /// Compress the files of the static directories ahead of time with `precompress_dir`, and
+    /// serve those compressed versions, so serving them costs no compressing. Call it once the
+    /// directories are registered. Returns how many files were compressed.
+    #[cfg(feature = "gzip")]
+    pub fn precompress_static_dirs(&mut self) -> io::Result<usize> {
+        let mut written = 0;
+        for dir in &mut self.static_dirs {
+            written += compression::precompress_dir(dir.dir())?;
+            dir.precompressed();
+        }
+        log!("Precompressed {written} static files");
+        Ok(written)
+    }
+
+    /// Serve `/sitemap.xml` listing the GET resources under `base_url`, such as
+    /// `https://www.daanlubbers.nl`, unless a resource has the same path. The pages are listed
+    /// at their canonical URL if they have one, see `Resource::with_sitemap_priority` for which
+    /// are listed.
+    pub fn enable_sitemap(&mut self, base_url: &str) {
+        self.sitemap = Some(Sitemap::new(base_url));
+    }
+
+    /// Answer GET requests for `path` with the routing table of `routes` as plain text, one
+    /// route per line, for debugging. It shows what the app serves, so protect the path with
+    /// `Auth`.
+    pub fn enable_route_table(&mut self, path: &str) {
+        self.route_table = Some(path.to_string());
+    }
+
+    /// Serve `robots` at `/robots.txt`. If the sitemap is enabled before and `robots` does not
+    /// refer to a sitemap, it refers to this one.
+    pub fn enable_robots_txt(&mut self, mut robots: RobotsTxt) {
+        if let Some(sitemap) = self.sitemap.as_ref().filter(|_| !robots.has_sitemap()) {
+            robots = robots.with_sitemap(&sitemap.url());
+        }
+        self.register_resource(robots.resource("/robots.txt"));
+    }
+
+    /// Serve the file at `path` as `/favicon.ico`, cached for a week. The content type follows
+    /// from its extension, so it can be a PNG or SVG as well.
+    pub fn serve_favicon(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        let content_type = icon_content_type(&path);
+        self.serve_file(
+            "/favicon.ico",
+            path,
+            content_type,
+            CachePolicy::MaxAge(ONE_WEEK),
+        );
+    }
+
+    /// Serve the PNG at `path` as `/apple-touch-icon.png`, which iOS and others ask for when a
+    /// page is added to the home screen, cached for a week.
+    pub fn serve_apple_touch_icon(&mut self, path: impl Into<PathBuf>) {
+        let cache = CachePolicy::MaxAge(ONE_WEEK);
+        self.serve_file("/apple-touch-icon.png", path, "image/png", cache);
+    }
+
+    /// Serve a hand-written `robots.txt` from `path`, rather than one generated by
+    /// `enable_robots_txt`, cached for a day.
+    pub fn serve_robots_txt(&mut self, path: impl Into<PathBuf>) {
+        let cache = CachePolicy::MaxAge(ONE_DAY);
+        self.serve_file("/robots.txt", path, "text/plain; charset=utf-8", cache);
+    }
+
+    /// Answer GET requests for `url_path` with the file at `path`, left out of the sitemap. A
+    /// file that's missing when it's requested is answered with a 404, while with
+    /// `AppConfig::with_strict_files` one that's missing now panics.
+    pub fn serve_file(
+        &mut self,
+        url_path: &str,
+        path: impl Into<PathBuf>,
+        content_type: &str,
+        cache: CachePolicy,
+    ) {
+        let path = path.into();
+        let file = path.clone();
+        let content_type = content_type.to_string();
+        let resource = Resource::new(
+            RequestType::GET,
+            url_path.to_string(),
+            ResourceType::BINARY,
+            Box::new(move |_| {
+                Ok(Response::file(StatusCode::OK, path.clone())
+                    .with_header("Content-Type", content_type.as_str()))
+            }),
+        );
+        self.register_resource(resource.with_cache(cache).with_file(file).without_sitemap());
+    }
+
+    /// Send error responses without a body, such as a 404 for an unknown path or the 500 after
+    /// a handler failed, with a page from `pages`. The resources given to
+    /// `register_resource_404` and `register_resource_500` still answer those first.
+    ///
+    /// The returned handle is for `ErrorPages::replace`, such as after the configuration changed.
+    pub fn enable_error_pages(&mut self, pages: ErrorPages) -> Arc<ErrorPages> {
+        let pages = Arc::new(pages);
+        self.error_pages = Some(Arc::clone(&pages));
+        pages
+    }
+
+    /// Send a digest header for each of these algorithms with every `Body::File` response.
+    pub fn enable_digests(&mut self, algorithms: Vec<DigestAlgorithm>) {
+        self.digests = algorithms;
+    }
+
+    /// Answer connections over `AppConfig::with_max_connections` with a 503 on a thread of
+    /// their own, so the accept loop never waits on a client. Connections sent while
+    /// `REJECT_QUEUE` others are waiting to be answered are closed without a response.
+    fn spawn_rejecter<C: Connection>(self: &Arc<Self>) -> mpsc::SyncSender<C> {
+        let (sender, receiver) = mpsc::sync_channel::<C>(REJECT_QUEUE);
+        let app = Arc::clone(self);
+        thread::spawn(move || {
+            for mut stream in receiver {
+                app.reject_connection(&mut stream);
+            }
+        });
+        sender
+    }
+
+    /// Answer a connection with a 503 and close it. Every step only gets a moment, as the
+    /// other rejected connections wait for it.
+    fn reject_connection(&self, stream: &mut impl Connection) {
+        log!("Too many connections, answering with a 503");
+        let response = format!(
+            "{}\r\n{}Retry-After: 1\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            StatusCode::ServiceUnavailable,
+            self.config.common_headers()
+        );
+        let moment = Some(Duration::from_millis(100));
+        let _ = stream.set_read_timeout(moment);
+        let _ = stream.set_write_timeout(moment);
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            log!("Failed to write to stream: {e:?}");
+        }
+        // Closing with the request unread could reset the connection before the response is
+        // read, so take in what already arrived.
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = stream.read(&mut [0; 4096]);
+    }
+
+    fn handle_request(&self, mut stream: impl Connection) {
+        if !self.config.proxy_protocol {
+            self.serve(&mut stream, false);
+            return;
+        }
+        let mut reader = DeadlineReader {
+            stream: &mut stream,
+            read_timeout: Duration::from_secs(self.config.read_timeout),
+            deadline: Instant::now() + self.config.header_timeout,
+        };
+        match proxy::read_header(&mut reader) {
+            Ok(client) => {
+                let remote_addr = client.or(stream.remote_addr());
+                self.serve(
+                    &mut Proxied {
+                        stream,
+                        remote_addr,
+                    },
+                    false,
+                );
+            }
+            Err(e) => log!("{e}"),
+        }
+    }
+
+    /// Serve a TLS connection over HTTP/2 if the client picks it, or like any other otherwise.
+    #[cfg(feature = "tls")]
+    fn handle_tls(&self, mut stream: TlsStream) {
+        if self.config.http2 && !self.config.proxy_protocol {
+            let _ = stream.set_read_timeout(Some(self.config.header_timeout));
+            match stream.handshake() {
+                Ok(true) => return http2::serve(self, stream),
+                Ok(false) => {}
+                Err(e) => return log!("TLS handshake failed: {e}"),
+            }
+        }
+        self.handle_request(stream)
+    }
+
+    /// Answer one request with a 301 to the same URL on HTTPS, without running middleware or
+    /// handlers. Requests without a usable `Host` header get a 400, as there's nowhere to go.
+    fn redirect_to_https(&self, mut stream: TcpStream) {
+        let mut buf_reader = buffers::Reader::new(DeadlineReader {
+            stream: &mut stream,
+            read_timeout: Duration::from_secs(self.config.read_timeout),
+            deadline: Instant::now() + self.config.header_timeout,
+        });
+        let response = Request::read_head(&mut buf_reader, &self.config.limits)
+            .and_then(|request| self.https_redirect_response(&request));
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => match self.rejection(e) {
+                Some(response) => response,
+                None => return,
+            },
+        };
+        drop(buf_reader);
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            log!("Failed to write to stream: {e:?}");
+        }
+        let _ = stream.shutdown(Shutdown::Write);
+    }
+
+    /// The redirect for a request to the plain HTTP listener, or the answer to an ACME
+    /// challenge, which has to be served over plain HTTP.
+    fn https_redirect_response(&self, request: &Request) -> Result<String, ReadError> {
+        let key_authorization = self
+            .state
+            .get::<AcmeChallenges>()
+            .and_then(|challenges| challenges.key_authorization(request.path()));
+        if let Some(key_authorization) = key_authorization {
+            log!("Answered ACME challenge: {}", request.path());
+            return Ok(format!(
+                "{}\r\n{}Content-Type: application/octet-stream\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{key_authorization}",
+                StatusCode::OK,
+                self.config.common_headers(),
+                key_authorization.len()
+            ));
+        }
+        let https_port = self.config.https_redirect.map_or(443, |(_, port)| port);
+        let location = request
+            .header("Host")
+            .and_then(|host| https_url(host, https_port, request))
+            .ok_or(ReadError::Malformed(
+                StatusCode::BadRequest,
+                "Missing or invalid Host header".to_string(),
+            ))?;
+        log!("Redirecting to {location}");
+        Ok(format!(
+            "{}\r\n{}Location: {location}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            StatusCode::PermanentRedirect,
+            self.config.common_headers()
+        ))
+    }
+
+    /// Read and answer one request from the connection. Returns whether the connection can be
+    /// kept open for the next request, which only `run_evented` does by passing `keep_alive`.
+    /// Otherwise the response says `Connection: close` and the connection is half-closed once
+    /// it's written, so the client reads it to the end rather than running into a reset.
+    pub(crate) fn serve(&self, stream: &mut impl Connection, keep_alive: bool) -> bool {
+        let start = Instant::now();
+        let request_deadline = start + self.config.request_timeout;
+        let remote_addr = stream.remote_addr();
+        let mut buf_reader = buffers::Reader::new(DeadlineReader {
+            stream,
+            read_timeout: Duration::from_secs(self.config.read_timeout),
+            deadline: request_deadline.min(start + self.config.header_timeout),
+        });
+        let limits = &self.config.limits;
+        let result = self.check_ip(remote_addr, None).and_then(|_| {
+            let mut request = Request::read_head(&mut buf_reader, limits)?;
+            self.check_ip(remote_addr, Some(request.path()))?;
+            buf_reader.get_mut().deadline = request_deadline.min(start + self.config.body_timeout);
+            request.read_body(&mut buf_reader, limits).map(|_| request)
+        });
+        let mut request = match result {
+            Ok(request) => request,
+            Err(e) => {
+                let Some(response) = self.rejection(e) else {
+                    return false;
+                };
+                let stream = &mut buf_reader.get_mut().stream;
+                if let Err(e) = stream.write_all(response.as_bytes()) {
+                    log!("Failed to write to stream: {e:?}");
+                }
+                // Closing with unread data resets the connection, which can lose the response,
+                // so read a little of what the client is still sending first.
+                let _ = stream.shutdown(Shutdown::Write);
+                let _ = io::copy(&mut buf_reader.take(64 * 1024), &mut io::sink());
+                return false;
+            }
+        };
+        let _scope = Scope::enter(request.id());
+        // Bytes of a pipelined request left in the buffer would be lost with it, so only keep
+        // the connection open if there are none.
+        let keep_alive = keep_alive && buf_reader.buffer().is_empty() && request.keep_alive();
+        // A WebSocket client may send its first frames right after the handshake.
+        let buffered = buf_reader.buffer().to_vec();
+        drop(buf_reader);
+        self.attach(&mut request, remote_addr);
+        request.set_closing(!keep_alive);
+
+        // Writing the response has to finish within the request timeout as well.
+        let remaining = request_deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_write_timeout(Some(remaining)).is_err() {
+            log!("Request timed out");
+            return false;
+        }
+
+        let mut response = self.respond(&mut request);
+        let keep_alive = keep_alive && response.keeps_alive();
+        let upgrade = response.upgrade.take();
+        if let Err(e) = response.write_to(stream, self.config.zero_copy) {
+            log!("Failed to write to stream: {e:?}");
+            return false;
+        }
+        if let Some(handler) = upgrade {
+            websocket::run(&handler, &request, stream, buffered);
+            return false;
+        }
+        if !keep_alive {
+            close(stream, !buffered.is_empty());
+        }
+        keep_alive
+    }
+
+    /// The response to a request that couldn't be read, if it gets one. There is no request for
+    /// middleware and handlers, so it is sent as it is, after which the connection is closed.
+    pub(crate) fn rejection(&self, error: ReadError) -> Option<String> {
+        let (status_code, body) = match error {
+            ReadError::Incomplete(e) => {
+                log!("{e}");
+                return None;
+            }
+            ReadError::Malformed(status_code, e) => {
+                log!("{e}");
+                if !self.config.bad_request_responses {
+                    return None;
+                }
+                let page = format!(
+                    "<!DOCTYPE html><html lang=\"en\"><head><title>{0}</title></head>\
+                     <body><h1>{0}</h1></body></html>",
+                    status_code.reason()
+                );
+                (status_code, page)
+            }
+            ReadError::TooLarge(status_code, e) => {
+                log!("{e}");
+                (status_code, String::new())
+            }
+            ReadError::Denied(status_code, e) => {
+                log!("{e}");
+                (status_code?, String::new())
+            }
+        };
+        let mut response = format!(
+            "{status_code}\r\n{}Connection: close\r\n",
+            self.config.common_headers()
+        );
+        if !body.is_empty() {
+            response.push_str("Content-Type: text/html; charset=utf-8\r\n");
+        }
+        response.push_str(&format!("Content-Length: {}\r\n\r\n{body}", body.len()));
+        log!("Response: {response}");
+        Some(response)
+    }
+
+    /// Check the client address against the `IpFilter`, for connecting at all without a `path`.
+    pub(crate) fn check_ip(
+        &self,
+        remote_addr: Option<SocketAddr>,
+        path: Option<&str>,
+    ) -> Result<(), ReadError> {
+        let (Some(filter), Some(addr)) = (&self.config.ip_filter, remote_addr) else {
+            return Ok(());
+        };
+        let ip = addr.ip();
+        let denied = match path {
+            Some(path) if !filter.allows_path(ip, path) => format!("Denied {path} to {ip}"),
+            None if !filter.allows_connection(ip) => format!("Denied connection from {ip}"),
+            _ => return Ok(()),
+        };
+        let status_code =
+            (filter.action() == FilterAction::Forbidden).then_some(StatusCode::Forbidden);
+        Err(ReadError::Denied(status_code, denied))
+    }
+
+    /// Give the request access to the application state and the client address.
+    pub(crate) fn attach(&self, request: &mut Request, remote_addr: Option<SocketAddr>) {
+        request.state = Arc::clone(&self.state);
+        request.remote_addr = remote_addr;
+        request.forwarded_for = remote_addr.and_then(|addr| {
+            proxy::forwarded_client(request, addr.ip(), &self.config.trusted_proxies)
+        });
+    }
+
+    /// Check the request and run the `before` middleware, which may answer it right away.
+    pub(crate) fn preflight(&self, request: &mut Request) -> Option<Output> {
+        if !self.request_hooks.is_empty() {
+            let info = Self::request_info(request);
+            for hook in &self.request_hooks {
+                hook(&info);
+            }
+        }
+        if !request.verify_digests() {
+            log!("Request body does not match its digest");
+            return Some(self.handle_bad_request(request));
+        }
+
+        for middleware in &self.middleware {
+            if let Some(response) = middleware.before(request) {
+                return Some(self.serialize_response(&ResourceType::BINARY, request, response));
+            }
+        }
+        None
+    }
+
+    /// Answer the request with its upload mount, resource or static directory, as the bytes to
+    /// send.
+    pub(crate) fn respond(&self, request: &mut Request) -> Output {
+        let _scope = Scope::enter(request.id());
+        let method = request.request_type();
+        let (mut route, mut redirect) = self.route(request);
+        if let Some(response) = self.preflight(request) {
+            return response;
+        }
+        // Middleware such as `MethodOverride` may have changed the method.
+        if request.request_type() != method {
+            (route, redirect) = self.route(request);
+        }
+
+        if let Some(mount) = self.uploads.iter().find(|mount| mount.matches(request)) {
+            return self.handle_upload(mount, request);
+        }
+
+        match route {
+            Some((resource, _)) if redirect => {
+                let location = match request.query() {
+                    Some(query) => format!("{}?{query}", resource.path),
+                    None => resource.path.clone(),
+                };
+                let response = Response::redirect(StatusCode::PermanentRedirect, location);
+                return self.handle_result(&ResourceType::REDIRECT, request, Ok(response));
+            }
+            Some((resource, _)) => return self.handle_resource(resource, request),
+            None if request.request_type() == RequestType::TRACE => {
+                return self.handle_trace(request)
+            }
+            None => {}
+        }
+        if let Some(sitemap) = &self.sitemap {
+            if request.request_type() == RequestType::GET && request.path() == SITEMAP_PATH {
+                let pages = self
+                    .resources
+                    .iter()
+                    .filter_map(|resource| {
+                        let page = resource.meta.canonical.as_ref().unwrap_or(&resource.path);
+                        Some((page.clone(), resource.sitemap_priority()?))
+                    })
+                    .collect::<Vec<_>>();
+                return self.handle_result(
+                    &ResourceType::TEXT,
+                    request,
+                    Ok(sitemap.response(&pages)),
+                );
+            }
+        }
+        if self.route_table.as_deref() == Some(request.path())
+            && request.request_type() == RequestType::GET
+        {
+            let table: String = self.routes().map(|route| format!("{route}\n")).collect();
+            let response = Response::text(StatusCode::OK, table)
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_header("Cache-Control", "no-store");
+            return self.handle_result(&ResourceType::TEXT, request, Ok(response));
+        }
+        match self.static_dirs.iter().find(|dir| dir.matches(request)) {
+            Some(dir) => self.handle_static_dir(dir, request),
+            None => self.handle_not_found(request),
+        }
+    }
+
+    /// The resource for the request and whether to redirect to its path, which differs by a
+    /// trailing slash. Otherwise the path becomes that of the resource, so middleware and
+    /// handlers see it, and a prefix such as that of `Auth` can't be avoided by changing the case.
+    fn route(&self, request: &mut Request) -> (Option<(&Resource, bool)>, bool) {
+        let route = self.get_resource(request.request_type(), request.path());
+        let redirect = route.is_some_and(|(_, slash_differs)| {
+            slash_differs && self.config.trailing_slash == TrailingSlash::Redirect
+        });
+        if let Some((resource, _)) = route.filter(|_| !redirect) {
+            if request.path != resource.path {
+                request.path.clone_from(&resource.path);
+            }
+        }
+        (route, redirect)
+    }
+
+    /// The resource for `path`, and whether its path differs from it by a trailing slash.
+    fn get_resource(&self, request_type: RequestType, path: &str) -> Option<(&Resource, bool)> {
+        if let Some(index) = self.routes.get(request_type, path) {
+            return Some((&self.resources[*index], false));
+        }
+        if self.config.trailing_slash == TrailingSlash::Strict {
+            return None;
+        }
+        let other = match path.strip_suffix('/') {
+            Some("") => return None,
+            Some(path) => path.to_string(),
+            None => format!("{path}/"),
+        };
+        let index = self.routes.get(request_type, &other)?;
+        Some((&self.resources[*index], true))
+    }
+
+    /// Echo the request line and headers, see `AppConfig::with_trace`.
+    fn handle_trace(&self, request: &Request) -> Output {
+        if !self.config.trace {
+            let response = Response::empty(StatusCode::MethodNotAllowed);
+            return self.handle_result(&ResourceType::TEXT, request, Ok(response));
+        }
+        let mut echo = match request.query() {
+            Some(query) => format!("TRACE {}?{query} {}\r\n", request.path(), request.version()),
+            None => format!("TRACE {} {}\r\n", request.path(), request.version()),
+        };
+        for (name, value) in request.headers() {
+            if !["Authorization", "Cookie", "Proxy-Authorization"]
+                .iter()
+                .any(|sensitive| name.eq_ignore_ascii_case(sensitive))
+            {
+                echo.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        echo.push_str("\r\n");
+        let response =
+            Response::text(StatusCode::OK, echo).with_header("Content-Type", "message/http");
+        self.handle_result(&ResourceType::BINARY, request, Ok(response))
+    }
+
+    fn handle_resource(&self, resource: &Resource, request: &Request) -> Output {
+        let result = resource.handle(request);
+        let upgrade = match &result {
+            Ok(response) if response.status_code.code() == 101 => resource.websocket.clone(),
+            _ => None,
+        };
+        let mut output = self.handle_result(&resource.resource_type, request, result);
+        output.upgrade = upgrade;
+        output
+    }
+
+    /// Serialize what a handler returned, answering errors with the 500 resource.
+    pub(crate) fn handle_result(
+        &self,
+        resource_type: &ResourceType,
+        request: &Request,
+        result: Result<Response, Error>,
+    ) -> Output {
+        let response = match result {
+            Ok(response) => response,
+            Err(Error::NotFound) => return self.handle_not_found(request),
+            Err(e @ (Error::BadRequest(_) | Error::Status(..))) => {
+                log!("Handler answered {}: {e}", e.status_code().code());
+                Response::empty(e.status_code())
+            }
+            Err(e @ Error::Internal(_)) => match &self.resource_500 {
+                Some(resource) => match resource.handle(request) {
+                    Ok(response) => response,
+                    Err(_) => return self.handle_error(request),
+                },
+                None => {
+                    log!("Handler failed: {e}");
+                    return self.handle_error(request);
+                }
+            },
+        };
+
+        self.serialize_response(resource_type, request, response)
+    }
+
+    fn handle_upload(&self, mount: &UploadMount, request: &Request) -> Output {
+        match mount.handle(request) {
+            Ok(response) => self.serialize_response(&ResourceType::BINARY, request, response),
+            Err(e) => {
+                log!("Upload failed: {e}");
+                self.handle_error(request)
+            }
+        }
+    }
+
+    fn handle_static_dir(&self, dir: &StaticDir, request: &Request) -> Output {
+        match dir.handle(request) {
+            Ok(Some(response)) => self.serialize_response(&ResourceType::BINARY, request, response),
+            Ok(None) => self.handle_not_found(request),
+            Err(e) => {
+                log!("Failed to serve {}: {e}", request.path());
+                self.handle_error(request)
+            }
+        }
+    }
+
+    /// Run the `after` middleware and serialize the response. A missing `Body::File` is
+    /// answered with a 404. Files are streamed, unless digests have to be computed over them.
+    fn serialize_response(
+        &self,
+        resource_type: &ResourceType,
+        request: &Request,
+        mut response: Response,
+    ) -> Output {
+        if let Body::File(path) = &response.body {
+            if response.header("Content-Type").is_none() {
+                if let Some(content_type) = file_content_type_header(path) {
+                    response.add_header("Content-Type", content_type);
+                }
+            }
+        }
+        add_missing_headers(&mut response, &self.headers);
+        for middleware in &self.middleware {
+            middleware.after(request, &mut response);
+        }
+        if let Some(pages) = &self.error_pages {
+            if response.status_code.code() >= 400 && matches!(response.body, Body::Empty) {
+                pages.apply(request, &mut response);
+            }
+        }
+
+        let mut headers = response.headers;
+        let mut file = None;
+        let mut stream = None;
+        let content = match response.body {
+            Body::File(path) if self.digests.is_empty() => match open_file(&path) {
+                Ok(opened) => {
+                    file = Some(opened);
+                    vec![]
+                }
+                Err(_) => return self.handle_not_found(request),
+            },
+            // Text is sent as bytes too, so it can be in any encoding.
+            Body::File(path) => match fs::read(path) {
+                Ok(content) => {
+                    for algorithm in &self.digests {
+                        headers.push(algorithm.header(&content));
+                    }
+                    content
+                }
+                Err(_) => return self.handle_not_found(request),
+            },
+            Body::Text(text) => text.into_bytes(),
+            Body::Bytes(bytes) => bytes,
+            Body::Stream(body) => {
+                stream = Some(body);
+                vec![]
+            }
+            Body::Empty => vec![],
+        };
+
+        // Assembled in buffers kept by the thread, as every response needs them.
+        let mut head = buffers::string();
+        let _ = write!(
+            head,
+            "{} {}\r\n",
+            request.version(),
+            response.status_code.reason()
+        );
+        self.config.write_common_headers(&mut head);
+        if self.config.request_id_header {
+            push_header(&mut head, trace::HEADER, request.id());
+        }
+        for (name, value) in &headers {
+            push_header(&mut head, name, value);
+        }
+        // HTTP/1.0 clients only keep the connection open if the response says so. They don't
+        // know chunked transfer coding, so the end of a stream is marked by closing it. Later
+        // clients are told when the connection closes, unless it's switching protocols.
+        let chunked = request.version() != HttpVersion::Http10;
+        let closing = request.closing || !request.keep_alive();
+        if request.version() == HttpVersion::Http10 {
+            match !closing && stream.is_none() {
+                true => head.push_str("Connection: keep-alive\r\n"),
+                false => head.push_str("Connection: close\r\n"),
+            }
+        } else if closing && response.status_code.code() != 101 {
+            head.push_str("Connection: close\r\n");
+        }
+        let file_length = file.as_ref().map_or(0, |(_, length)| *length);
+        // A 304 may only have the Content-Length of the full response, so it gets none, and
+        // after a 101 the connection no longer speaks HTTP.
+        if stream.is_some() {
+            if chunked {
+                head.push_str("Transfer-Encoding: chunked\r\n");
+            }
+        } else if !matches!(response.status_code.code(), 101 | 304) {
+            let length = content.len() as u64 + file_length;
+            let _ = write!(head, "Content-Length: {length}\r\n");
+        }
+        head.push_str("\r\n");
+
+        match resource_type {
+            _ if file.is_some() => log!("Response: {head}<file>"),
+            _ if stream.is_some() => log!("Response: {head}<stream>"),
+            ResourceType::BINARY => log!("Response: {head}<snip>"),
+            _ => log!("Response: {head}{}", String::from_utf8_lossy(&content)),
+        }
+        if !self.response_hooks.is_empty() {
+            let info = Self::request_info(request);
+            let response_info = ResponseInfo {
+                status: response.status_code.code(),
+                duration: request.received().elapsed(),
+            };
+            for hook in &self.response_hooks {
+                hook(&info, &response_info);
+            }
+        }
+        let mut bytes = buffers::bytes();
+        bytes.extend_from_slice(head.as_bytes());
+        bytes.extend_from_slice(&content);
+        buffers::recycle_string(head);
+        Output {
+            bytes,
+            file,
+            stream,
+            chunked,
+            upgrade: None,
+        }
+    }
+
+    fn handle_not_found(&self, request: &Request) -> Output {
+        let resource = &self.resource_404;
+        match resource {
+            Some(resource) => self.handle_resource(resource, request),
+            None => self.serialize_response(
+                &ResourceType::TEXT,
+                request,
+                Response::empty(StatusCode::NotFound),
+            ),
+        }
+    }
+
+    fn handle_bad_request(&self, request: &Request) -> Output {
+        self.serialize_response(
+            &ResourceType::TEXT,
+            request,
+            Response::empty(StatusCode::BadRequest),
+        )
+    }
+
+    fn handle_error(&self, request: &Request) -> Output {
+        let resource = &self.resource_500;
+        match resource {
+            Some(resource) => self.handle_resource(resource, request),
+            None => self.serialize_response(
+                &ResourceType::TEXT,
+                request,
+                Response::empty(StatusCode::InternalServerError),
+            ),
+        }
+    }
+}
+
+pub fn create_app(config: AppConfig) -> App {
+    App::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::ParsedResponse;
+    use crate::ip_filter::Cidr;
+    use crate::testing::TestClient;
+    use std::{
+        io::{BufReader, Read},
+        net::{Ipv4Addr, SocketAddrV4},
+        thread, time,
+    };
+
+    /// Each test binds its own port so tests can run in parallel.
+    const fn test_addr(port: u16) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port))
+    }
+    const STARTUP_TIME: u64 = 100;
+
+    /// Apps without the `Date` and `Server` headers, so responses can be compared exactly.
+    fn create_app(config: AppConfig) -> App {
+        App::new(
+            config
+                .with_date_header(false)
+                .with_server_header(None)
+                .with_request_id_header(false),
+        )
+    }
+
+    /// The `Last-Modified` header line sent for a file.
+    fn last_modified_header(path: impl AsRef<Path>) -> String {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        format!("Last-Modified: {}\r\n", http_date(modified))
+    }
+
+    fn send_request(addr: SocketAddr, request_type: RequestType, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        let request = format!("{request_type:?} {path} HTTP/1.1\r\n\r\n");
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut buf_reader = BufReader::new(&stream);
+        let mut str = String::new();
+        buf_reader.read_to_string(&mut str).unwrap();
+        str
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("hello+world%21"), "hello world!");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%+1%2"), "%zz% 1%2");
+        assert_eq!(
+            percent_encode("https://daanlubbers.nl/a b?c=é"),
+            "https%3A%2F%2Fdaanlubbers.nl%2Fa%20b%3Fc%3D%C3%A9"
+        );
+        assert_eq!(percent_decode(&percent_encode("a+b c/é")), "a+b c/é");
+        assert_eq!(
+            html_escape("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn http_date_format() {
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            http_date(UNIX_EPOCH + time::Duration::from_secs(784111777)),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        assert_eq!(
+            http_date(UNIX_EPOCH + time::Duration::from_secs(951782400)),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn http_date_parse() {
+        for secs in [0, 784111777, 951782400, 4102444799] {
+            let time = UNIX_EPOCH + time::Duration::from_secs(secs);
+            assert_eq!(parse_http_date(&http_date(time)), Some(time));
+        }
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 CET"), None);
+        assert_eq!(parse_http_date("not a date"), None);
+    }
+
+    #[test]
+    fn http_version() {
+        let request = |text: &str| {
+            let text = format!("{text}\r\n");
+            Request::from_reader(&mut BufReader::new(text.as_bytes())).unwrap()
+        };
+        let http_1_0 = request("GET / HTTP/1.0\r\n");
+        assert_eq!(http_1_0.version(), HttpVersion::Http10);
+        assert!(!http_1_0.keep_alive());
+        assert!(request("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n").keep_alive());
+        let http_1_1 = request("GET / HTTP/1.1\r\n");
+        assert_eq!(http_1_1.version(), HttpVersion::Http11);
+        assert!(http_1_1.keep_alive());
+        assert!(!request("GET / HTTP/1.1\r\nConnection: Upgrade, close\r\n").keep_alive());
+        assert_eq!(request("GET / HTTP/1.2\r\n").version(), HttpVersion::Http11);
+        let status = |line: &str| {
+            let text = format!("{line}\r\n\r\n");
+            match Request::read_head(&mut text.as_bytes(), &RequestLimits::default()) {
+                Err(ReadError::Malformed(status, _)) => status.code(),
+                _ => 0,
+            }
+        };
+        assert_eq!(status("PRI * HTTP/2.0"), 505);
+        assert_eq!(status("GET / HTTP/0.9"), 505);
+        assert_eq!(status("GET / HTTP/1"), 400);
+        assert_eq!(status("GET / HTTP/1.10"), 400);
+        assert_eq!(status("GET / HTTPS/1.1"), 400);
+        assert_eq!(status("BREW / HTTP/1.1"), 501);
+        // Upgrading to HTTP/2 is optional, so the request is answered over HTTP/1.1.
+        let mut app = create_app(AppConfig::new(test_addr(0), 1, 5));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "hi"))),
+        ));
+        let h2c = [
+            ("Connection", "Upgrade, HTTP2-Settings"),
+            ("Upgrade", "h2c"),
+            ("HTTP2-Settings", "AAMAAABkAAQCAAAAAAIAAAAA"),
+        ];
+        let response = TestClient::new(app).request("GET", "/", &h2c, &[]);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text(), "hi");
+        assert_eq!(
+            StatusCode::NotFound.status_line(HttpVersion::Http10),
+            "HTTP/1.0 404 NOT FOUND"
+        );
+        assert_eq!(StatusCode::NotFound.to_string(), "HTTP/1.1 404 NOT FOUND");
+    }
+
+    #[test]
+    fn form_body() {
+        let request = |content_type: &str, body: &str| {
+            let text = format!(
+                "POST /contact HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            Request::from_reader(&mut BufReader::new(text.as_bytes())).unwrap()
+        };
+        let form = request(
+            "application/x-www-form-urlencoded; charset=UTF-8",
+            "name=Daan+Lubbers&message=Hi%21%20%26%20bye&name=other&empty=&flag&&caf%C3%A9=%E2%98%95",
+        )
+        .form()
+        .unwrap();
+        assert_eq!(form.len(), 5);
+        assert_eq!(form["name"], "Daan Lubbers");
+        assert_eq!(form["message"], "Hi! & bye");
+        assert_eq!(form["empty"], "");
+        assert_eq!(form["flag"], "");
+        assert_eq!(form["café"], "☕");
+        assert!(request("application/x-www-form-urlencoded", "")
+            .form()
+            .unwrap()
+            .is_empty());
+        assert!(request("application/json", "{}").form().is_err());
+    }
+
+    #[test]
+    fn app_config_builder() {
+        let addr = test_addr(8000);
+        let config = AppConfig::builder()
+            .addr(addr)
+            .threads(2)
+            .read_timeout(30)
+            .build()
+            .unwrap();
+        assert_eq!(config.addrs, vec![addr]);
+        assert_eq!((config.num_threads, config.read_timeout), (2, 30));
+
+        let config = AppConfig::builder().build().unwrap();
+        assert_eq!(config.addrs, vec!["127.0.0.1:8080".parse().unwrap()]);
+        assert!(config.num_threads > 0);
+        assert_eq!(config.read_timeout, 5);
+
+        assert!(AppConfig::builder().threads(0).build().is_err());
+        assert!(AppConfig::builder().read_timeout(0).build().is_err());
+    }
+
+    #[test]
+    fn common_headers() {
+        let mut request = Request::from_reader(&mut BufReader::new(
+            "GET /missing HTTP/1.1\r\n\r\n".as_bytes(),
+        ))
+        .unwrap();
+        let head = |app: App, request: &mut Request| {
+            String::from_utf8(app.respond(request).bytes).unwrap()
+        };
+
+        let config = AppConfig::new(test_addr(0), 1, 1);
+        let response = head(App::new(config), &mut request);
+        let date = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Date: "))
+            .unwrap();
+        assert!(parse_http_date(date).is_some());
+        let server = format!("Server: wwwdaanlubbersnl/{}\r\n", env!("CARGO_PKG_VERSION"));
+        assert!(response.starts_with("HTTP/1.1 404 NOT FOUND\r\nDate: "));
+        assert!(response.contains(&server));
+        assert!(response.contains(&format!("\r\nX-Request-Id: {}\r\n", request.id())));
+
+        let mut traced = Request::from_reader(&mut BufReader::new(
+            "GET /missing HTTP/1.1\r\nX-Request-Id: abc-123\r\n\r\n".as_bytes(),
+        ))
+        .unwrap();
+        let config = AppConfig::new(test_addr(0), 1, 1);
+        assert!(head(App::new(config), &mut traced).contains("\r\nX-Request-Id: abc-123\r\n"));
+        let config = AppConfig::new(test_addr(0), 1, 1).with_server_header(Some("test/1.0"));
+        assert!(head(App::new(config), &mut request).contains("\r\nServer: test/1.0\r\n"));
+        let config = AppConfig::new(test_addr(0), 1, 1)
+            .with_server_header(None)
+            .with_date_header(false)
+            .with_request_id_header(false);
+        assert_eq!(
+            head(App::new(config), &mut request),
+            "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn handler_timeout() {
+        let request =
+            Request::from_reader(&mut BufReader::new("GET /slow HTTP/1.1\r\n\r\n".as_bytes()))
+                .unwrap();
+        let resource = |handler: ResourceHandler| {
+            Resource::new(
+                RequestType::GET,
+                "/slow".to_string(),
+                ResourceType::TEXT,
+                handler,
+            )
+            .with_timeout(Duration::from_millis(50))
+        };
+        let fast = resource(Box::new(|request| {
+            Ok(Response::text(StatusCode::OK, request.path()))
+        }));
+        assert_eq!(fast.handle(&request).unwrap().status_code.code(), 200);
+
+        let start = Instant::now();
+        let slow = resource(Box::new(|_| {
+            thread::sleep(Duration::from_secs(2));
+            Ok(Response::text(StatusCode::OK, "late"))
+        }));
+        let error = slow.handle(&request).err().unwrap();
+        assert_eq!(error.status_code().code(), 503);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let panics = resource(Box::new(|_| panic!("handler failed")));
+        let error = panics.handle(&request).err().unwrap();
+        assert_eq!(error.status_code().code(), 500);
+    }
+
+    #[test]
+    fn lifecycle_hooks() {
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut app = create_app(AppConfig::new(test_addr(0), 1, 5));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "ok"))),
+        ));
+        let seen_clone = seen.clone();
+        app.on_request(move |info| {
+            let entry = format!("{:?} {}", info.method, info.path);
+            seen_clone.lock().unwrap().push(entry);
+        });
+        let seen_clone = seen.clone();
+        app.on_response(move |info, response| {
+            assert!(response.duration < Duration::from_secs(1));
+            let entry = format!("{} {}", info.path, response.status);
+            seen_clone.lock().unwrap().push(entry);
+        });
+        let client = TestClient::new(app);
+        client.get("/");
+        client.get("/missing");
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["GET /", "/ 200", "GET /missing", "/missing 404"]
+        );
+    }
+
+    #[test]
+    fn patch_and_trace() {
+        let app = |trace: bool| {
+            let mut app = create_app(AppConfig::new(test_addr(0), 1, 5).with_trace(trace));
+            app.register_resource(Resource::new(
+                RequestType::PATCH,
+                "/note".to_string(),
+                ResourceType::TEXT,
+                Box::new(|request| Ok(Response::bytes(StatusCode::OK, request.body().to_vec()))),
+            ));
+            TestClient::new(app)
+        };
+        let client = app(false);
+        let response = client.patch("/note", "text/plain", b"edited");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), b"edited");
+        assert_eq!(client.get("/note").status(), 404);
+        assert_eq!(client.request("TRACE", "/", &[], &[]).status(), 405);
+
+        let response = app(true).request(
+            "TRACE",
+            "/debug?x=1",
+            &[
+                ("X-Forwarded-For", "10.0.0.1"),
+                ("Cookie", "session=secret"),
+            ],
+            &[],
+        );
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("Content-Type"), Some("message/http"));
+        assert_eq!(
+            response.text(),
+            "TRACE /debug?x=1 HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn route_table() {
+        let mut app = create_app(AppConfig::new(test_addr(0), 1, 5));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "home"))),
+        ));
+        app.scope("/admin", |router| {
+            router.register_middleware(Box::new(
+                crate::auth::Auth::new("/admin", "Admin").with_users(&[("admin", "secret")]),
+            ));
+            router.post("/reload", |_| Ok(Response::empty(StatusCode::OK)));
+        });
+        app.register_middleware(Box::new(Arc::new(crate::metrics::Metrics::new())));
+        app.serve_dir("/static/", "static_test");
+        app.enable_route_table("/routes");
+
+        let routes: Vec<RouteInfo> = app.routes().collect();
+        assert_eq!(routes.len(), 3);
+        assert_eq!(
+            (routes[1].method, routes[1].resource_type),
+            (RequestType::POST, ResourceType::TEXT)
+        );
+        assert_eq!(routes[1].pattern, "/admin/reload");
+        assert_eq!(routes[1].middleware, ["Auth", "Metrics"]);
+        let client = TestClient::new(app);
+        let response = client.get("/routes");
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.text(),
+            "GET     / TEXT [Metrics]\n\
+             POST    /admin/reload TEXT [Auth, Metrics]\n\
+             GET     /static/* BINARY [Metrics]\n"
+        );
+        assert_eq!(client.delete("/routes").status(), 404);
+    }
+
+    #[test]
+    fn startup_checks() {
+        let mut app = create_app(AppConfig::new(test_addr(0), 3, 5));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "home"))),
+        ));
+        app.serve_dir("/static", "static_test");
+        assert_eq!(
+            app.startup_summary(&["http://127.0.0.1:8080".to_string()]),
+            "Starting server\n  listen: http://127.0.0.1:8080\n  tls: off\n  workers: 3\n  \
+             routes: 1\n  static: /static -> static_test"
+        );
+
+        // Fails before binding, so nothing listens on the port.
+        app.serve_dir("/missing", "static_test/missing");
+        match app.run(None) {
+            Err(ServerError::Setup(message)) => assert!(
+                message.starts_with("The static directory static_test/missing served at /missing"),
+                "{message}"
+            ),
+            other => panic!("expected a setup error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn text_file_encodings() {
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_encodings");
+        fs::create_dir_all(&dir).unwrap();
+        let utf8 = "<p>Caf\u{e9} \u{2013} \u{1f600}</p>";
+        fs::write(dir.join("utf8.html"), utf8).unwrap();
+        let latin1 = b"<meta charset=\"iso-8859-1\"><p>Caf\xe9</p>";
+        fs::write(dir.join("latin1.html"), latin1).unwrap();
+        fs::write(dir.join("style.css"), "p::before { content: \"\u{2713}\" }").unwrap();
+        // A UTF-8 character cut in two by how much is looked at for the charset.
+        let mut long = "a".repeat(CHARSET_SNIFF_BYTES as usize - 1);
+        long.push('\u{e9}');
+        fs::write(dir.join("long.txt"), &long).unwrap();
+
+        for digests in [vec![], vec![DigestAlgorithm::Sha256]] {
+            let mut app = create_app(AppConfig::new(test_addr(0), 1, 5));
+            app.enable_digests(digests);
+            app.serve_dir("/files", &dir);
+            let client = TestClient::new(app);
+
+            let response = client.get("/files/utf8.html");
+            assert_eq!(
+                response.header("Content-Type"),
+                Some("text/html; charset=utf-8")
+            );
+            // The bytes of the 15 characters.
+            assert_eq!(response.header("Content-Length"), Some("21"));
+            assert_eq!(response.text(), utf8);
+            let response = client.get("/files/latin1.html");
+            assert_eq!(response.header("Content-Type"), Some("text/html"));
+            assert_eq!(response.body(), latin1);
+            let response = client.get("/files/style.css");
+            assert_eq!(
+                response.header("Content-Type"),
+                Some("text/css; charset=utf-8")
+            );
+            let response = client.get("/files/long.txt");
+            assert_eq!(
+                response.header("Content-Type"),
+                Some("text/plain; charset=utf-8")
+            );
+            assert_eq!(response.body(), long.as_bytes());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn strict_files() {
+        let file = |path: &str| {
+            let handler_path = path.to_string();
+            Resource::new(
+                RequestType::GET,
+                format!("/{path}"),
+                ResourceType::BINARY,
+                Box::new(move |_| Ok(Response::file(StatusCode::OK, handler_path.clone()))),
+            )
+            .with_file(path)
+        };
+        // Only checked when asked for.
+        let mut app = create_app(AppConfig::new(test_addr(0), 1, 5));
+        app.try_register_resource(file("static_test/missing.jpg"))
+            .unwrap();
+        assert!(app.try_serve_dir("/missing", "static_test/missing").is_ok());
+
+        let mut app = create_app(AppConfig::new(test_addr(0), 1, 5).with_strict_files(true));
+        app.try_register_resource(file("static_test/test.jpg"))
+            .unwrap();
+        app.try_serve_dir("/static", "static_test").unwrap();
+        match app.try_register_resource(file("static_test/missing.jpg")) {
+            Err(ServerError::Setup(message)) => assert!(
+                message.starts_with(
+                    "GET /static_test/missing.jpg serves static_test/missing.jpg, which can't be \
+                     read: "
+                ),
+                "{message}"
+            ),
+            other => panic!("expected a setup error, got {other:?}"),
+        }
+        assert!(matches!(
+            app.try_register_resource(file("static_test")),
+            Err(ServerError::Setup(_))
+        ));
+        assert!(matches!(
+            app.try_serve_dir("/missing", "static_test/missing"),
+            Err(ServerError::Setup(_))
+        ));
+        // Nothing is registered for what failed.
+        assert_eq!(app.routes().count(), 2);
+        assert_eq!(
+            TestClient::new(app).get("/static_test/test.jpg").status(),
+            200
+        );
+    }
+
+    #[test]
+    fn configured_headers() {
+        let mut app = create_app(AppConfig::new(test_addr(0), 1, 5))
+            .with_header("X-Site", "daanlubbers.nl")
+            .with_header("Cache-Control", "no-cache");
+        app.register_resource(
+            Resource::new(
+                RequestType::GET,
+                "/drafts".to_string(),
+                ResourceType::TEXT,
+                Box::new(|_| {
+                    Ok(Response::text(StatusCode::OK, "drafts")
+                        .with_header("Cache-Control", "no-store"))
+                }),
+            )
+            .with_header("X-Robots-Tag", "noindex"),
+        );
+        let client = TestClient::new(app);
+        let response = client.get("/drafts");
+        assert_eq!(response.header("X-Robots-Tag"), Some("noindex"));
+        assert_eq!(response.header("X-Site"), Some("daanlubbers.nl"));
+        // Set by the handler, so kept.
+        assert_eq!(response.header("Cache-Control"), Some("no-store"));
+        let response = client.get("/missing");
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.header("X-Site"), Some("daanlubbers.nl"));
+        assert_eq!(response.header("Cache-Control"), Some("no-cache"));
+        assert_eq!(response.header("X-Robots-Tag"), None);
+    }
+
+    #[test]
+    fn common_files() {
+        let mut app = create_app(AppConfig::new(test_addr(0), 1, 5));
+        app.serve_favicon("static_test/test.jpg");
+        app.serve_apple_touch_icon("static_test/test.jpg");
+        app.serve_robots_txt("static_test/missing.txt");
+        app.enable_sitemap("https://daanlubbers.nl");
+        let client = TestClient::new(app);
+        for (path, content_type) in [
+            ("/favicon.ico", "image/jpeg"),
+            ("/apple-touch-icon.png", "image/png"),
+        ] {
+            let response = client.get(path);
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.header("Content-Type"), Some(content_type));
+            assert_eq!(
+                response.header("Cache-Control"),
+                Some("public, max-age=604800")
+            );
+            assert_eq!(response.body(), fs::read("static_test/test.jpg").unwrap());
+        }
+        assert_eq!(client.get("/robots.txt").status(), 404);
+        assert!(!client.get("/sitemap.xml").text().contains("favicon"));
+        assert_eq!(icon_content_type(Path::new("icon.ICO")), "image/x-icon");
+        assert_eq!(icon_content_type(Path::new("icon.svg")), "image/svg+xml");
+    }
+
+    #[test]
+    fn last_modified() {
+        let path = "static_test/test.html";
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        let request = |headers: &str| {
+            let text = format!("GET /test HTTP/1.1\r\n{headers}\r\n");
+            Request::from_reader(&mut BufReader::new(text.as_bytes())).unwrap()
+        };
+        let respond = |request: &Request| {
+            let mut response = Response::file(StatusCode::OK, path.to_string());
+            response.apply_last_modified(request);
+            response
+        };
+
+        let response = respond(&request(""));
+        assert_eq!(response.status_code.code(), 200);
+        assert_eq!(
+            response.header("Last-Modified"),
+            Some(http_date(modified).as_str())
+        );
+
+        let since = format!("If-Modified-Since: {}\r\n", http_date(modified));
+        let response = respond(&request(&since));
+        assert_eq!(response.status_code.code(), 304);
+        assert!(matches!(response.body, Body::Empty));
+        assert_eq!(
+            respond(&request(&format!("{since}If-None-Match: \"x\"\r\n")))
+                .status_code
+                .code(),
+            200
+        );
+        let response = respond(&request(
+            "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n",
+        ));
+        assert_eq!(response.status_code.code(), 200);
+        let response = respond(&request("If-Modified-Since: yesterday\r\n"));
+        assert_eq!(response.status_code.code(), 200);
+    }
+
+    #[test]
+    fn app_request_404() {
+        const TEST_ADDR: SocketAddr = test_addr(7676);
+        // Default 400 handler
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let app = create_app(config);
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        let response = send_request(TEST_ADDR, RequestType::POST, "/nonexistent");
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        let response = send_request(TEST_ADDR, RequestType::PUT, "/im/not/real");
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::DELETE, "deletemeplease");
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+
+        // Custom 404 handler
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.register_resource_404(Resource::new(
+            RequestType::GET,
+            "/404".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::file(StatusCode::NotFound, "static_test/404.html"))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(response, "HTTP/1.1 404 NOT FOUND\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>404</body></html>");
+
+        let response = send_request(TEST_ADDR, RequestType::POST, "/nonexistent");
+        assert_eq!(response, "HTTP/1.1 404 NOT FOUND\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>404</body></html>");
+
+        let response = send_request(TEST_ADDR, RequestType::PUT, "/im/not/real");
+        assert_eq!(response, "HTTP/1.1 404 NOT FOUND\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>404</body></html>");
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::DELETE, "deletemeplease");
+        assert_eq!(response, "HTTP/1.1 404 NOT FOUND\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>404</body></html>");
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_invalid() {
+        const TEST_ADDR: SocketAddr = test_addr(7677);
+        let config = AppConfig::new(TEST_ADDR, 4, 1);
+        let app = create_app(config);
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let send = |request: &str| {
+            let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            BufReader::new(&stream)
+                .read_to_string(&mut response)
+                .unwrap();
+            response
+        };
+        let page = |status: &str| {
+            let page = format!(
+                "<!DOCTYPE html><html lang=\"en\"><head><title>{status}</title></head>\
+                 <body><h1>{status}</h1></body></html>"
+            );
+            format!(
+                "HTTP/1.1 {status}\r\nConnection: close\r\nContent-Type: text/html; \
+                 charset=utf-8\r\nContent-Length: {}\r\n\r\n{page}",
+                page.len()
+            )
+        };
+
+        // Connections closed before the request is complete get no response.
+        assert_eq!(send(""), "");
+        assert_eq!(send("GET / HTTP/1.1"), "");
+        assert_eq!(send("\n"), page("400 BAD REQUEST"));
+        assert_eq!(send("request\n"), page("400 BAD REQUEST"));
+        assert_eq!(send("some text here\n"), page("400 BAD REQUEST"));
+        assert_eq!(
+            send("GET / HTTP/1.1\r\nNo colon\r\n\r\n"),
+            page("400 BAD REQUEST")
+        );
+        assert_eq!(
+            send("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            page("400 BAD REQUEST")
+        );
+        // Requests a proxy could read differently.
+        for headers in [
+            "Content-Length: 1\r\nContent-Length: 2",
+            "Content-Length: 1, 2",
+            "Content-Length : 1",
+            "Host: localhost\r\n Content-Length: 1",
+        ] {
+            assert_eq!(
+                send(&format!("POST / HTTP/1.1\r\n{headers}\r\n\r\nab")),
+                page("400 BAD REQUEST")
+            );
+        }
+        assert_eq!(send("FOO / HTTP/1.1\r\n\r\n"), page("501 NOT IMPLEMENTED"));
+
+        let quiet = create_app(AppConfig::new(TEST_ADDR, 1, 1).with_bad_request_responses(false));
+        let malformed = ReadError::Malformed(StatusCode::BadRequest, "Malformed".to_string());
+        assert!(quiet.rejection(malformed).is_none());
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let stream = TcpStream::connect(TEST_ADDR).unwrap();
+        drop(stream);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_500() {
+        const TEST_ADDR: SocketAddr = test_addr(7678);
+        // Default 500 handler
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Err("Failed".into())),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(
+            response,
+            "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+
+        // Custom 500 handler
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Err("Failed".into())),
+        ));
+        app.register_resource_500(Resource::new(
+            RequestType::GET,
+            "/500".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| {
+                Ok(Response::file(
+                    StatusCode::InternalServerError,
+                    "static_test/500.html",
+                ))
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(response, "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nContent-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>500</body></html>");
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_handler_errors() {
+        const TEST_ADDR: SocketAddr = test_addr(7710);
+        let mut app = create_app(AppConfig::new(TEST_ADDR, 2, 5));
+        fn fail(request: &Request) -> Result<Response, Error> {
+            Err(match request.path() {
+                "/missing" => Error::NotFound,
+                "/invalid" => Error::BadRequest("Invalid".to_string()),
+                "/forbidden" => Error::Status(StatusCode::Forbidden, "No".to_string()),
+                _ => io::Error::other("Disk on fire").into(),
+            })
+        }
+        for path in ["/missing", "/invalid", "/forbidden", "/io"] {
+            app.register_resource(Resource::new(
+                RequestType::GET,
+                path.to_string(),
+                ResourceType::TEXT,
+                Box::new(fail),
+            ));
+        }
+        app.register_resource_404(Resource::new(
+            RequestType::GET,
+            "/404".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::NotFound, "gone"))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        // Not found goes to the registered 404 resource, like an unknown path does.
+        assert_eq!(
+            send_request(TEST_ADDR, RequestType::GET, "/missing"),
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 4\r\n\r\ngone"
+        );
+        assert_eq!(
+            send_request(TEST_ADDR, RequestType::GET, "/invalid"),
+            "HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            send_request(TEST_ADDR, RequestType::GET, "/forbidden"),
+            "HTTP/1.1 403 FORBIDDEN\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        stop_flag.store(true, Ordering::SeqCst);
+        assert_eq!(
+            send_request(TEST_ADDR, RequestType::GET, "/io"),
+            "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request() {
+        const TEST_ADDR: SocketAddr = test_addr(7679);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/html".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.html"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/html".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.html"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::PUT,
+            "/html".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.html"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::DELETE,
+            "/html".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.html"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/image".to_string(),
+            ResourceType::BINARY,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.jpg"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/image".to_string(),
+            ResourceType::BINARY,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.jpg"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::PUT,
+            "/image".to_string(),
+            ResourceType::BINARY,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.jpg"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::DELETE,
+            "/image".to_string(),
+            ResourceType::BINARY,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.jpg"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/redirect".to_string(),
+            ResourceType::REDIRECT,
+            Box::new(|_| {
+                Ok(Response::redirect(
+                    StatusCode::OK,
+                    "static_test/redirect.html",
+                ))
+            }),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/redirect".to_string(),
+            ResourceType::REDIRECT,
+            Box::new(|_| {
+                Ok(Response::redirect(
+                    StatusCode::OK,
+                    "static_test/redirect.html",
+                ))
+            }),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::PUT,
+            "/redirect".to_string(),
+            ResourceType::REDIRECT,
+            Box::new(|_| {
+                Ok(Response::redirect(
+                    StatusCode::OK,
+                    "static_test/redirect.html",
+                ))
+            }),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::DELETE,
+            "/redirect".to_string(),
+            ResourceType::REDIRECT,
+            Box::new(|_| {
+                Ok(Response::redirect(
+                    StatusCode::OK,
+                    "static_test/redirect.html",
+                ))
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let html_modified = last_modified_header("static_test/test.html");
+        let image_modified = last_modified_header("static_test/test.jpg");
+        let response = send_request(TEST_ADDR, RequestType::GET, "/html");
+        assert_eq!(response, format!("HTTP/1.1 200 OK\r\n{html_modified}Content-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: 55\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>test</body></html>"));
+        let response = send_request(TEST_ADDR, RequestType::POST, "/html");
+        assert_eq!(response, format!("HTTP/1.1 200 OK\r\n{html_modified}Content-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: 55\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>test</body></html>"));
+        let response = send_request(TEST_ADDR, RequestType::PUT, "/html");
+        assert_eq!(response, format!("HTTP/1.1 200 OK\r\n{html_modified}Content-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: 55\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>test</body></html>"));
+        let response = send_request(TEST_ADDR, RequestType::DELETE, "/html");
+        assert_eq!(response, format!("HTTP/1.1 200 OK\r\n{html_modified}Content-Type: text/html; charset=utf-8\r\nConnection: close\r\nContent-Length: 55\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>test</body></html>"));
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/image");
+        assert_eq!(
+            response,
+            format!("HTTP/1.1 200 OK\r\n{image_modified}Content-Type: image/jpeg\r\nConnection: close\r\nContent-Length: 12\r\n\r\n\\x01\\x02\\x03")
+        );
+        let response = send_request(TEST_ADDR, RequestType::POST, "/image");
+        assert_eq!(
+            response,
+            format!("HTTP/1.1 200 OK\r\n{image_modified}Content-Type: image/jpeg\r\nConnection: close\r\nContent-Length: 12\r\n\r\n\\x01\\x02\\x03")
+        );
+        let response = send_request(TEST_ADDR, RequestType::PUT, "/image");
+        assert_eq!(
+            response,
+            format!("HTTP/1.1 200 OK\r\n{image_modified}Content-Type: image/jpeg\r\nConnection: close\r\nContent-Length: 12\r\n\r\n\\x01\\x02\\x03")
+        );
+        let response = send_request(TEST_ADDR, RequestType::DELETE, "/image");
+        assert_eq!(
+            response,
+            format!("HTTP/1.1 200 OK\r\n{image_modified}Content-Type: image/jpeg\r\nConnection: close\r\nContent-Length: 12\r\n\r\n\\x01\\x02\\x03")
+        );
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/redirect");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nLocation: static_test/redirect.html\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        let response = send_request(TEST_ADDR, RequestType::POST, "/redirect");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nLocation: static_test/redirect.html\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        let response = send_request(TEST_ADDR, RequestType::PUT, "/redirect");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nLocation: static_test/redirect.html\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::DELETE, "/redirect");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nLocation: static_test/redirect.html\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_body() {
+        const TEST_ADDR: SocketAddr = test_addr(7680);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/text".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, format!("{}", 6 * 7)))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/bytes".to_string(),
+            ResourceType::BINARY,
+            Box::new(|_| {
+                Ok(Response::bytes(
+                    StatusCode::OK,
+                    vec![0xde, 0xad, 0xbe, 0xef],
+                ))
+            }),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/empty".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::empty(StatusCode::OK).with_header("X-Test", "yes"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/missing".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/missing.html"))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/text");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n42"
+        );
+
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream.write_all(b"GET /bytes HTTP/1.1\r\n\r\n").unwrap();
+        let mut bytes = vec![];
+        stream.read_to_end(&mut bytes).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 4\r\n\r\n".as_slice(),
+                &[0xde, 0xad, 0xbe, 0xef]
+            ]
+            .concat()
+        );
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/empty");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nX-Test: yes\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/missing");
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_digest() {
+        const TEST_ADDR: SocketAddr = test_addr(7681);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.enable_digests(vec![DigestAlgorithm::Md5, DigestAlgorithm::Sha256]);
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/html".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::file(StatusCode::OK, "static_test/test.html"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::PUT,
+            "/upload".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::empty(StatusCode::OK))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/html");
+        let html_modified = last_modified_header("static_test/test.html");
+        assert_eq!(response, format!("HTTP/1.1 200 OK\r\n{html_modified}Content-Type: text/html; charset=utf-8\r\nContent-MD5: 3m3k4JKXX00/gPVVOak+ZA==\r\nRepr-Digest: sha-256=:31Z35tc10l2gKNybMMqVtDo9EH5bSffZ73uvOzrvdYE=:\r\nConnection: close\r\nContent-Length: 55\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>test</body></html>"));
+
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream
+            .write_all(b"PUT /upload HTTP/1.1\r\nContent-Length: 5\r\nContent-MD5: XUFAKrxLKna5cZ2REBfFkg==\r\n\r\nhello")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream
+            .write_all(b"PUT /upload HTTP/1.1\r\nContent-Length: 5\r\nRepr-Digest: sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:\r\n\r\nhellp")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_handler_state() {
+        const TEST_ADDR: SocketAddr = test_addr(7682);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        let counter = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter_clone = counter.clone();
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/count".to_string(),
+            ResourceType::TEXT,
+            Box::new(move |request| {
+                let count = counter_clone.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok(Response::text(
+                    StatusCode::OK,
+                    format!(
+                        "{count} {} {} {}",
+                        request.path(),
+                        request.query().unwrap_or_default(),
+                        String::from_utf8_lossy(request.body())
+                    ),
+                ))
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::POST, "/count?a=b");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 13\r\n\r\n1 /count a=b "
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream
+            .write_all(b"POST /count HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 14\r\n\r\n2 /count  body"
+        );
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_signed_url() {
+        const TEST_ADDR: SocketAddr = test_addr(7683);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.enable_signed_urls("/download/", UrlSigner::new(b"secret"));
+        let url = app
+            .url_signer()
+            .unwrap()
+            .sign_for("/download/file", time::Duration::from_secs(60));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/download/file".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "secret file"))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/download/file");
+        assert_eq!(
+            response,
+            "HTTP/1.1 403 FORBIDDEN\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, &url);
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 11\r\n\r\nsecret file"
+        );
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_state() {
+        const TEST_ADDR: SocketAddr = test_addr(7684);
+        struct Visits(std::sync::atomic::AtomicUsize);
+
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config)
+            .with_state(Visits(std::sync::atomic::AtomicUsize::new(0)))
+            .with_state("site name".to_string());
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/visits".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| {
+                let visits = request.state::<Visits>().ok_or("Missing state")?;
+                let name = request.state::<String>().ok_or("Missing state")?;
+                let count = visits.0.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok(Response::text(StatusCode::OK, format!("{name} {count}")))
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/visits");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 11\r\n\r\nsite name 1"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/visits");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 11\r\n\r\nsite name 2"
+        );
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_upload() {
+        const TEST_ADDR: SocketAddr = test_addr(7685);
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_upload");
+        let _ = fs::remove_dir_all(&dir);
+
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.serve_upload("/drop", &dir)
+            .max_file_size(8)
+            .quota(12)
+            .auth(Box::new(|request| request.header("X-Key") == Some("key")));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let upload = |method: &str, path: &str, key: &str, body: &str| {
+            let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+            let request = format!(
+                "{method} {path} HTTP/1.1\r\nX-Key: {key}\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        assert_eq!(
+            upload("PUT", "/drop/a.txt", "wrong", "hello"),
+            "HTTP/1.1 403 FORBIDDEN\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            upload("PUT", "/drop/a.txt", "key", "hello"),
+            "HTTP/1.1 201 CREATED\r\nLocation: /drop/a.txt\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+        assert_eq!(
+            upload("POST", "/drop/../b.txt", "key", "bye"),
+            "HTTP/1.1 201 CREATED\r\nLocation: /drop/_b.txt\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(fs::read_to_string(dir.join("_b.txt")).unwrap(), "bye");
+        assert_eq!(
+            upload("PUT", "/drop/c.txt", "key", "too large"),
+            "HTTP/1.1 413 PAYLOAD TOO LARGE\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            upload("PUT", "/drop/c.txt", "key", "quota"),
+            "HTTP/1.1 507 INSUFFICIENT STORAGE\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        // Replacing a file only counts the difference in size
+        assert_eq!(
+            upload("PUT", "/drop/a.txt", "key", "hello!!"),
+            "HTTP/1.1 201 CREATED\r\nLocation: /drop/a.txt\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/drop/a.txt");
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn app_request_json() {
+        const TEST_ADDR: SocketAddr = test_addr(7686);
+        #[derive(serde::Deserialize, serde::Serialize)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/flip".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| {
+                let point: Point = request.json()?;
+                Response::json(
+                    StatusCode::OK,
+                    &Point {
+                        x: point.y,
+                        y: point.x,
+                    },
+                )
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream
+            .write_all(b"POST /flip HTTP/1.1\r\nContent-Length: 13\r\n\r\n{\"x\":1,\"y\":2}")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\nContent-Length: 13\r\n\r\n{\"x\":2,\"y\":1}"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream
+            .write_all(b"POST /flip HTTP/1.1\r\nContent-Length: 3\r\n\r\nnop")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_webdav() {
+        const TEST_ADDR: SocketAddr = test_addr(7687);
+        let dir = std::env::temp_dir().join("wwwdaanlubbersnl_test_webdav");
+        let _ = fs::remove_dir_all(&dir);
+
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.serve_upload("/dav", &dir)
+            .webdav()
+            .auth(Box::new(|request| request.header("X-Key") == Some("key")));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let dav = |head: &str, body: &str| {
+            let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+            let request = format!(
+                "{head} HTTP/1.1\r\nX-Key: key\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = send_request(TEST_ADDR, RequestType::OPTIONS, "/dav/");
+        assert_eq!(
+            response,
+            "HTTP/1.1 403 FORBIDDEN\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            dav("OPTIONS /dav/", ""),
+            "HTTP/1.1 200 OK\r\nDAV: 1\r\nAllow: OPTIONS, PROPFIND, GET, PUT, DELETE, MKCOL\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            dav("PUT /dav/docs/a.txt", "hello"),
+            "HTTP/1.1 409 CONFLICT\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            dav("MKCOL /dav/docs", ""),
+            "HTTP/1.1 201 CREATED\r\nLocation: /dav/docs/\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            dav("PUT /dav/docs/a.txt", "hello"),
+            "HTTP/1.1 201 CREATED\r\nLocation: /dav/docs/a.txt\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        assert_eq!(
+            dav("PUT /dav/docs/..%2f..%2fetc", "hello"),
+            "HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\nContent-Length: 12\r\n\r\nInvalid path"
+        );
+        assert_eq!(
+            dav("GET /dav/docs/a.txt", ""),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello"
+        );
+
+        let response = dav("PROPFIND /dav/", "");
+        assert!(response.starts_with("HTTP/1.1 207 MULTI-STATUS\r\n"));
+        assert!(response.contains("<D:href>/dav/</D:href>"));
+        assert!(response.contains("<D:href>/dav/docs/</D:href>"));
+        assert!(!response.contains("a.txt"));
+        let response = dav("PROPFIND /dav/docs/a.txt", "");
+        assert!(response.contains("<D:href>/dav/docs/a.txt</D:href>"));
+        assert!(response.contains("<D:getcontentlength>5</D:getcontentlength>"));
+
+        assert_eq!(
+            dav("DELETE /dav/docs", ""),
+            "HTTP/1.1 204 NO CONTENT\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        stop_flag.store(true, Ordering::SeqCst);
+        assert_eq!(
+            dav("PROPFIND /dav/docs", ""),
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn app_request_static_dir() {
+        const TEST_ADDR: SocketAddr = test_addr(7699);
+        let mut app = create_app(AppConfig::new(TEST_ADDR, 4, 5));
+        app.serve_dir("/static", "static_test").autoindex();
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/static/test.html".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "resource"))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/static/test.jpg");
+        assert_eq!(
+            response,
+            format!(
+                "HTTP/1.1 200 OK\r\n{}Content-Type: image/jpeg\r\nConnection: close\r\nContent-Length: \
+                 12\r\n\r\n\\x01\\x02\\x03",
+                last_modified_header("static_test/test.jpg")
+            )
+        );
+        let response = send_request(TEST_ADDR, RequestType::GET, "/static/test.html");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 8\r\n\r\nresource"
+        );
+        let response = send_request(TEST_ADDR, RequestType::GET, "/static");
+        assert_eq!(
+            response,
+            "HTTP/1.1 301 PERMANENT REDIRECT\r\nLocation: /static/\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        let response = send_request(TEST_ADDR, RequestType::GET, "/static/");
+        assert!(response.contains("<a href=\"test.jpg\">test.jpg</a>"));
+        for path in ["/static/../Cargo.toml", "/static/%2e%2e/Cargo.toml"] {
+            let response = send_request(TEST_ADDR, RequestType::GET, path);
+            assert_eq!(
+                response,
+                "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+            );
+        }
+        let response = send_request(TEST_ADDR, RequestType::POST, "/static/test.jpg");
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/static/missing.txt");
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        thread.join().unwrap();
+    }
+
+    #[cfg(feature = "qr")]
+    #[test]
+    fn app_request_qr() {
+        const TEST_ADDR: SocketAddr = test_addr(7688);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.register_resource(crate::qr::resource("/qr"));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/qr?data=hello");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: image/svg+xml\r\nETag: \""));
+        assert!(response.contains("Cache-Control: public, max-age=31536000, immutable\r\n"));
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/qr?data=hello&format=gif");
+        assert_eq!(
+            response,
+            "HTTP/1.1 400 BAD REQUEST\r\nConnection: close\r\nContent-Length: 14\r\n\r\nUnknown format"
+        );
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_rate_limit() {
+        const TEST_ADDR: SocketAddr = test_addr(7689);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        app.register_middleware(Box::new(crate::ratelimit::RateLimit::new(0.1, 1)));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| {
+                Ok(Response::text(
+                    StatusCode::OK,
+                    request.remote_addr().unwrap().ip().to_string(),
+                ))
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 9\r\n\r\n127.0.0.1"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(
+            response,
+            "HTTP/1.1 429 TOO MANY REQUESTS\r\nRetry-After: 10\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_search_notify() {
+        const TEST_ADDR: SocketAddr = test_addr(7690);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        let received = Arc::new(std::sync::Mutex::new(vec![]));
+        let received_clone = received.clone();
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/indexnow".to_string(),
+            ResourceType::TEXT,
+            Box::new(move |request| {
+                received_clone
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(request.body()).into_owned());
+                Ok(Response::empty(StatusCode::OK))
+            }),
+        ));
+        let received_clone = received.clone();
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/ping".to_string(),
+            ResourceType::TEXT,
+            Box::new(move |request| {
+                received_clone
+                    .lock()
+                    .unwrap()
+                    .push(request.query_param_decoded("sitemap").unwrap());
+                Ok(Response::text(StatusCode::OK, "pong"))
+            }),
+        ));
+        let notifier = crate::search_notify::SearchNotifier::new("www.daanlubbers.nl")
+            .with_indexnow("0123456789abcdef")
+            .with_indexnow_endpoint(&format!("http://{TEST_ADDR}/indexnow"))
+            .with_sitemap_ping(
+                "https://www.daanlubbers.nl/sitemap.xml",
+                &format!("http://{TEST_ADDR}/ping?sitemap="),
+            );
+        app.register_resource(notifier.key_file_resource().unwrap());
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        notifier
+            .notify(vec!["https://www.daanlubbers.nl/".to_string()])
+            .join()
+            .unwrap();
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                "{\"host\":\"www.daanlubbers.nl\",\"key\":\"0123456789abcdef\",\"keyLocation\":\"https://www.daanlubbers.nl/0123456789abcdef.txt\",\"urlList\":[\"https://www.daanlubbers.nl/\"]}",
+                "https://www.daanlubbers.nl/sitemap.xml"
+            ]
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response =
+            crate::http_client::get(&format!("http://{TEST_ADDR}/0123456789abcdef.txt")).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text(), "0123456789abcdef");
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_feature_flags() {
+        const TEST_ADDR: SocketAddr = test_addr(7691);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        let flags = app.enable_feature_flags(FeatureFlags::new(&[("new_nav", false)]));
+        for resource in flags.admin_resources("/admin/flags") {
+            app.register_resource(resource);
+        }
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| {
+                let flags = request.state::<FeatureFlags>().unwrap();
+                Ok(Response::text(
+                    StatusCode::OK,
+                    flags.enabled("new_nav").to_string(),
+                ))
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let url = format!("http://{TEST_ADDR}/admin/flags");
+        let page = crate::http_client::get(&url).unwrap().text();
+        assert!(page.contains("<td>new_nav</td><td>off</td>"));
+
+        let response = crate::http_client::post(
+            &url,
+            "application/x-www-form-urlencoded",
+            b"name=new_nav&enabled=true",
+        )
+        .unwrap();
+        assert_eq!(response.status(), 303);
+        assert_eq!(response.header("Location"), Some("/admin/flags"));
+
+        let response = crate::http_client::post(
+            &url,
+            "application/x-www-form-urlencoded",
+            b"name=new+nav&enabled=true",
+        )
+        .unwrap();
+        assert_eq!(response.status(), 400);
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 4\r\n\r\ntrue"
+        );
+
+        thread.join().unwrap();
     }
 
-    fn handle_error(&self, stream: &mut TcpStream) {
-        let resource = &self.resource_500;
-        match resource {
-            Some(resource) => self.handle_resource(&resource, stream),
-            None => {
-                let response = format!(
-                    "{}\r\nContent-Length: 0\r\n\r\n",
-                    StatusCode::InternalServerError
-                );
-                print!("Response: {response}\n");
-                match stream.write_all(response.as_bytes()) {
-                    Err(e) => print!("Failed to write to stream: {e:?}\n"),
-                    _ => {}
+    #[test]
+    fn app_request_limits() {
+        const TEST_ADDR: SocketAddr = test_addr(7692);
+        let config = AppConfig::new(TEST_ADDR, 4, 5)
+            .with_max_request_line(32)
+            .with_max_header_bytes(64)
+            .with_max_body_bytes(4);
+        let mut app = create_app(config);
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| Ok(Response::bytes(StatusCode::OK, request.body().to_vec()))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let send = |request: &str| {
+            let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            BufReader::new(&stream)
+                .read_to_string(&mut response)
+                .unwrap();
+            response
+        };
+
+        let response = send("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody");
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 4\r\n\r\nbody"
+        );
+
+        let response = send(&format!("POST /{} HTTP/1.1\r\n\r\n", "a".repeat(32)));
+        assert_eq!(
+            response,
+            "HTTP/1.1 414 URI TOO LONG\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        let response = send(&format!(
+            "POST / HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(64)
+        ));
+        assert_eq!(
+            response,
+            "HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n");
+        assert_eq!(
+            response,
+            "HTTP/1.1 413 PAYLOAD TOO LARGE\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_cache() {
+        const TEST_ADDR: SocketAddr = test_addr(7693);
+        let config = AppConfig::new(TEST_ADDR, 4, 5);
+        let mut app = create_app(config);
+        let cache = Arc::new(crate::cache::ResponseCache::new(time::Duration::from_secs(
+            60,
+        )));
+        app.register_middleware(Box::new(Arc::clone(&cache)));
+        app.register_resource(cache.purge_resource("/purge"));
+        let counter = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter_clone = counter.clone();
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(move |_| {
+                let count = counter_clone.fetch_add(1, Ordering::SeqCst) + 1;
+                Ok(
+                    Response::text(StatusCode::OK, count.to_string())
+                        .with_surrogate_keys(&["home"]),
+                )
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nSurrogate-Key: home\r\nCache-Tag: home\r\nX-Cache: MISS\r\nConnection: close\r\nContent-Length: 1\r\n\r\n1");
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nSurrogate-Key: home\r\nCache-Tag: home\r\nX-Cache: HIT\r\nConnection: close\r\nContent-Length: 1\r\n\r\n1");
+
+        let response =
+            crate::http_client::post(&format!("http://{TEST_ADDR}/purge"), "text/plain", b"home")
+                .unwrap();
+        assert_eq!(response.text(), "Purged 1\n");
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/");
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nSurrogate-Key: home\r\nCache-Tag: home\r\nX-Cache: MISS\r\nConnection: close\r\nContent-Length: 1\r\n\r\n2");
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_request_slow_client() {
+        const TEST_ADDR: SocketAddr = test_addr(7694);
+        let config = AppConfig::new(TEST_ADDR, 4, 5)
+            .with_header_timeout(time::Duration::from_millis(300))
+            .with_body_timeout(time::Duration::from_millis(600));
+        let mut app = create_app(config);
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| Ok(Response::bytes(StatusCode::OK, request.body().to_vec()))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        // Send the request a byte at a time, stopping once the server closes the connection.
+        let trickle = |request: &str| {
+            let start = time::Instant::now();
+            let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+            for byte in request.bytes() {
+                if stream.write_all(&[byte]).is_err() {
+                    break;
                 }
+                thread::sleep(time::Duration::from_millis(50));
             }
-        }
+            let mut response = String::new();
+            let _ = BufReader::new(&stream).read_to_string(&mut response);
+            (response, start.elapsed())
+        };
+
+        let (response, elapsed) = trickle("POST / HTTP/1.1\r\nX-Padding: aaaaaaaaaa\r\n\r\n");
+        assert_eq!(response, "");
+        assert!(elapsed < time::Duration::from_secs(5));
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 20\r\n\r\nslow")
+            .unwrap();
+        let mut response = String::new();
+        let _ = BufReader::new(&stream).read_to_string(&mut response);
+        assert_eq!(response, "");
+
+        thread.join().unwrap();
     }
-}
 
-pub fn create_app(config: AppConfig) -> App {
-    App::new(config)
-}
+    #[cfg(feature = "evented")]
+    #[test]
+    fn app_run_evented_keep_alive() {
+        const TEST_ADDR: SocketAddr = test_addr(7695);
+        const OTHER_ADDR: SocketAddr = test_addr(7702);
+        // A single worker, which an idle connection would block with `run`.
+        let mut app = create_app(AppConfig::new(TEST_ADDR, 1, 5).with_addr(OTHER_ADDR));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "hello"))),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run_evented(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::{
-        io::Read,
-        net::{Ipv4Addr, SocketAddrV4},
-        thread, time,
-    };
+        let expected = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let mut idle = TcpStream::connect(TEST_ADDR).unwrap();
+        let mut stream = TcpStream::connect(OTHER_ADDR).unwrap();
+        for _ in 0..2 {
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = vec![0; expected.len()];
+            stream.read_exact(&mut response).unwrap();
+            assert_eq!(String::from_utf8(response).unwrap(), expected);
+        }
 
-    const TEST_ADDR: SocketAddr =
-        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 7676));
-    const STARTUP_TIME: u64 = 100;
+        // HTTP/1.0 connections are only kept open when the client asks for it.
+        let expected_1_0 =
+            "HTTP/1.0 200 OK\r\nConnection: keep-alive\r\nContent-Length: 5\r\n\r\nhello";
+        stream
+            .write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let mut response = vec![0; expected_1_0.len()];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), expected_1_0);
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.0 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello"
+        );
+
+        idle.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        idle.read_to_string(&mut response).unwrap();
+        let closed = "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(response, closed);
 
-    fn send_request(request_type: RequestType, path: &str) -> String {
+        stop_flag.store(true, Ordering::SeqCst);
         let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, closed);
+        thread.join().unwrap();
+    }
 
-        let request = format!("{request_type:?} {path} HTTP/1.1\r\n");
-        stream.write_all(request.as_bytes()).unwrap();
+    #[cfg(feature = "async")]
+    #[test]
+    fn app_run_async() {
+        const TEST_ADDR: SocketAddr = test_addr(7696);
+        const OTHER_ADDR: SocketAddr = test_addr(7703);
+        let mut app = create_app(AppConfig::new(TEST_ADDR, 1, 5).with_addr(OTHER_ADDR));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/sync".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "sync"))),
+        ));
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/echo".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| {
+                let body = io::Cursor::new(request.body().to_vec());
+                Ok(Response::stream(StatusCode::OK, body))
+            }),
+        ));
+        app.register_async_resource(AsyncResource::new(
+            RequestType::GET,
+            "/async".to_string(),
+            ResourceType::TEXT,
+            |request: Request| async move {
+                let query = request.query().unwrap_or_default().to_string();
+                Ok(Response::text(StatusCode::OK, format!("async {query}")))
+            },
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(app.run_async(Some(stop_flag_clone)))
+                .unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
-        let mut buf_reader = BufReader::new(&stream);
-        let mut str = String::new();
-        buf_reader.read_to_string(&mut str).unwrap();
-        str
+        // Both requests are answered, in order, on the same connection.
+        let expected = "HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nasync x\
+                        HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nsync";
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream
+            .write_all(b"GET /async?x HTTP/1.1\r\n\r\nGET /sync HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut response = vec![0; expected.len()];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), expected);
+
+        // A chunked request body is decoded, and a stream response sent in chunks.
+        let expected = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        7\r\nabcdefg\r\n0\r\n\r\n";
+        stream
+            .write_all(
+                b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                         3\r\nabc\r\n4;x=y\r\ndefg\r\n0\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = vec![0; expected.len()];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), expected);
+
+        stop_flag.store(true, Ordering::SeqCst);
+        let mut stream = TcpStream::connect(OTHER_ADDR).unwrap();
+        stream.write_all(b"GET /missing HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+        );
+        thread.join().unwrap();
     }
 
     #[test]
-    fn app_request_404() {
-        // Default 400 handler
-        let config = AppConfig::new(TEST_ADDR, 4, 5);
-        let app = create_app(config);
+    fn app_run_connection_close() {
+        const TEST_ADDR: SocketAddr = test_addr(7724);
+        let mut app = create_app(AppConfig::new(TEST_ADDR, 2, 5));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "hello"))),
+        ));
         let stop_flag = Arc::new(AtomicBool::new(false));
         let stop_flag_clone = stop_flag.clone();
         let thread = thread::spawn(move || {
-            app.run(Some(stop_flag_clone));
+            app.run(Some(stop_flag_clone)).unwrap();
         });
         thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
-        let response = send_request(RequestType::GET, "/");
+        // `run` answers one request per connection, so it says it closes, and the rest of what
+        // was sent is taken in rather than reset the connection under the response.
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        let mut requests =
+            b"GET / HTTP/1.1\r\n\r\nPOST / HTTP/1.1\r\nContent-Length: 32768\r\n\r\n".to_vec();
+        requests.resize(requests.len() + 32768, b'x');
+        stream.write_all(&requests).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
         assert_eq!(
             response,
-            "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 0\r\n\r\n"
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello"
         );
 
-        let response = send_request(RequestType::POST, "/nonexistent");
+        stop_flag.store(true, Ordering::SeqCst);
+        send_request(TEST_ADDR, RequestType::GET, "/");
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_run_chunked() {
+        const TEST_ADDR: SocketAddr = test_addr(7706);
+        let config = AppConfig::new(TEST_ADDR, 2, 5).with_max_body_bytes(16);
+        let mut app = create_app(config);
+        // Echoes the body back, two bytes per chunk.
+        app.register_resource(Resource::new(
+            RequestType::POST,
+            "/echo".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| {
+                let (first, rest) = request.body().split_at(request.body().len().min(2));
+                let body = io::Cursor::new(first.to_vec()).chain(io::Cursor::new(rest.to_vec()));
+                Ok(Response::stream(StatusCode::OK, body))
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let send = |request: &str| {
+            let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let chunked =
+            "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+                       4;ext=1\r\nWiki\r\n6\r\npedia \r\n0\r\nExpires: never\r\n\r\n";
         assert_eq!(
-            response,
-            "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 0\r\n\r\n"
+            send(chunked),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n\
+             2\r\nWi\r\n8\r\nkipedia \r\n0\r\n\r\n"
+        );
+        assert_eq!(
+            send("POST /echo HTTP/1.0\r\nConnection: keep-alive\r\nContent-Length: 5\r\n\r\nhello"),
+            "HTTP/1.0 200 OK\r\nConnection: close\r\n\r\nhello"
+        );
+        let status = |request: &str| send(request).lines().next().unwrap().to_string();
+        assert_eq!(
+            status("POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n11\r\n"),
+            "HTTP/1.1 413 PAYLOAD TOO LARGE"
+        );
+        // Sizes that would overflow when added to the body so far, or on their own.
+        for size in ["ffffffffffffffff", "fffffffff", "10000000000000000000"] {
+            assert_eq!(
+                status(&format!(
+                    "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     1\r\na\r\n{size}\r\n"
+                )),
+                "HTTP/1.1 413 PAYLOAD TOO LARGE"
+            );
+        }
+        assert_eq!(
+            status("POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nx\r\n"),
+            "HTTP/1.1 400 BAD REQUEST"
+        );
+        assert_eq!(
+            status("POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nab\r\n"),
+            "HTTP/1.1 400 BAD REQUEST"
+        );
+        assert_eq!(
+            status(
+                "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n"
+            ),
+            "HTTP/1.1 400 BAD REQUEST"
+        );
+        stop_flag.store(true, Ordering::SeqCst);
+        assert_eq!(
+            status("POST /echo HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            "HTTP/1.1 501 NOT IMPLEMENTED"
         );
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_run_event_stream() {
+        const TEST_ADDR: SocketAddr = test_addr(7707);
+        let config = AppConfig::new(TEST_ADDR, 2, 5);
+        let mut app = create_app(config);
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/events".to_string(),
+            ResourceType::TEXT,
+            Box::new(|request| {
+                let (response, events) = sse::event_stream(time::Duration::from_millis(100));
+                let last_id = request.header("Last-Event-ID").unwrap_or("0").to_string();
+                thread::spawn(move || {
+                    events
+                        .send(&sse::Event::new("up").with_id(&last_id))
+                        .unwrap();
+                    thread::sleep(time::Duration::from_millis(150));
+                    events.send(&sse::Event::new("still up")).unwrap();
+                });
+                Ok(response)
+            }),
+        ));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
-        let response = send_request(RequestType::PUT, "/im/not/real");
+        stop_flag.store(true, Ordering::SeqCst);
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nLast-Event-ID: 7\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
         assert_eq!(
             response,
-            "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 0\r\n\r\n"
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+             X-Accel-Buffering: no\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n\
+             10\r\nid: 7\ndata: up\n\n\r\n3\r\n:\n\n\r\n10\r\ndata: still up\n\n\r\n0\r\n\r\n"
         );
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn app_run_websocket() {
+        const TEST_ADDR: SocketAddr = test_addr(7708);
+        let config = AppConfig::new(TEST_ADDR, 2, 5);
+        let mut app = create_app(config);
+        app.register_resource(Resource::websocket("/ws", |_, socket| loop {
+            match socket.receive()? {
+                websocket::Message::Text(text) => socket.send_text(&text.to_uppercase())?,
+                websocket::Message::Close(_) => return Ok(()),
+                _ => {}
+            }
+        }));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
         stop_flag.store(true, Ordering::SeqCst);
-        let response = send_request(RequestType::DELETE, "deletemeplease");
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        // The frames are sent along with the handshake, and are masked as from a browser.
+        stream
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n\
+                  \x81\x82\x01\x02\x03\x04\x69\x6b\x88\x82\x00\x00\x00\x00\x03\xe8",
+            )
+            .unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
         assert_eq!(
             response,
-            "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 0\r\n\r\n"
+            b"HTTP/1.1 101 SWITCHING PROTOCOLS\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n\
+              \x81\x02HI\x88\x02\x03\xe8"
         );
-
         thread.join().unwrap();
+    }
 
-        // Custom 404 handler
-        let config = AppConfig::new(TEST_ADDR, 4, 5);
+    #[test]
+    fn app_run_proxy_protocol() {
+        const TEST_ADDR: SocketAddr = test_addr(7709);
+        let config = AppConfig::new(TEST_ADDR, 2, 5)
+            .with_proxy_protocol(true)
+            .with_trusted_proxy("203.0.113.1".parse().unwrap());
         let mut app = create_app(config);
-        app.register_resource_404(Resource::new(
+        app.register_resource(Resource::new(
             RequestType::GET,
-            "/404".to_string(),
+            "/".to_string(),
             ResourceType::TEXT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::NotFound,
-                    "static_test/404.html".to_string(),
-                ))
+            Box::new(|request| {
+                let client = format!(
+                    "{} {}",
+                    request.client_ip().unwrap(),
+                    request.remote_addr().unwrap()
+                );
+                Ok(Response::text(StatusCode::OK, client))
             }),
         ));
         let stop_flag = Arc::new(AtomicBool::new(false));
         let stop_flag_clone = stop_flag.clone();
         let thread = thread::spawn(move || {
-            app.run(Some(stop_flag_clone));
+            app.run(Some(stop_flag_clone)).unwrap();
         });
         thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
-        let response = send_request(RequestType::GET, "/");
-        assert_eq!(response, "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>404</body></html>");
-
-        let response = send_request(RequestType::POST, "/nonexistent");
-        assert_eq!(response, "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>404</body></html>");
-
-        let response = send_request(RequestType::PUT, "/im/not/real");
-        assert_eq!(response, "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>404</body></html>");
-
         stop_flag.store(true, Ordering::SeqCst);
-        let response = send_request(RequestType::DELETE, "deletemeplease");
-        assert_eq!(response, "HTTP/1.1 404 NOT FOUND\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>404</body></html>");
-
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream
+            .write_all(
+                b"PROXY TCP4 203.0.113.1 127.0.0.1 5000 80\r\n\
+                  GET / HTTP/1.1\r\nX-Forwarded-For: 10.9.9.9, 198.51.100.7\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 29\r\n\r\n198.51.100.7 203.0.113.1:5000"
+        );
         thread.join().unwrap();
     }
 
     #[test]
-    fn app_request_invalid() {
-        let config = AppConfig::new(TEST_ADDR, 4, 1);
-        let app = create_app(config);
+    fn app_run_multiple_addrs() {
+        const TEST_ADDR: SocketAddr = test_addr(7700);
+        const OTHER_ADDR: SocketAddr = test_addr(7701);
+        let mut app = create_app(AppConfig::new(TEST_ADDR, 2, 5).with_addr(OTHER_ADDR));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "hello"))),
+        ));
         let stop_flag = Arc::new(AtomicBool::new(false));
         let stop_flag_clone = stop_flag.clone();
         let thread = thread::spawn(move || {
-            app.run(Some(stop_flag_clone));
+            app.run(Some(stop_flag_clone)).unwrap();
         });
         thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
-        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
-        let mut str = String::new();
-
-        stream.write_all("".as_bytes()).unwrap();
-        let mut buf_reader = BufReader::new(&stream);
-        buf_reader.read_to_string(&mut str).unwrap();
-        assert_eq!(str, "");
-
-        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
-        stream.write_all("\n".as_bytes()).unwrap();
-        let mut buf_reader = BufReader::new(&stream);
-        buf_reader.read_to_string(&mut str).unwrap();
-        assert_eq!(str, "");
+        let expected = "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(send_request(TEST_ADDR, RequestType::GET, "/"), expected);
+        assert_eq!(send_request(OTHER_ADDR, RequestType::GET, "/"), expected);
 
-        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
-        stream.write_all("request\n".as_bytes()).unwrap();
-        let mut buf_reader = BufReader::new(&stream);
-        buf_reader.read_to_string(&mut str).unwrap();
-        assert_eq!(str, "");
+        // Stopping on one listener stops the other as well.
+        stop_flag.store(true, Ordering::SeqCst);
+        assert_eq!(send_request(OTHER_ADDR, RequestType::GET, "/"), expected);
+        thread.join().unwrap();
+        assert!(TcpStream::connect(TEST_ADDR).is_err());
+    }
 
-        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
-        stream.write_all("some text here\n".as_bytes()).unwrap();
-        let mut buf_reader = BufReader::new(&stream);
-        buf_reader.read_to_string(&mut str).unwrap();
-        assert_eq!(str, "");
+    #[test]
+    fn app_run_bind_error() {
+        const TEST_ADDR: SocketAddr = test_addr(7711);
+        let _taken = TcpListener::bind(TEST_ADDR).unwrap();
+        let app = create_app(AppConfig::new(TEST_ADDR, 1, 5));
+        match app.run(None) {
+            Err(ServerError::Bind(addr, e)) => {
+                assert_eq!(addr, TEST_ADDR.to_string());
+                assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+            }
+            result => panic!("Expected a bind error, got {result:?}"),
+        }
+    }
 
-        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
-        stream
-            .write_all(format!("FOO / HTTP/1.1\r\n").as_bytes())
-            .unwrap();
-        let mut buf_reader = BufReader::new(&stream);
-        buf_reader.read_to_string(&mut str).unwrap();
-        assert_eq!(str, "");
+    #[cfg(unix)]
+    #[test]
+    fn app_run_shutdown_signal() {
+        extern "C" {
+            fn raise(signum: i32) -> i32;
+        }
+        const TEST_ADDR: SocketAddr = test_addr(7712);
+        let mut app = create_app(AppConfig::new(TEST_ADDR, 2, 5).with_shutdown_signals(true));
+        app.register_resource(Resource::new(
+            RequestType::GET,
+            "/slow".to_string(),
+            ResourceType::TEXT,
+            Box::new(|_| {
+                thread::sleep(time::Duration::from_millis(300));
+                Ok(Response::text(StatusCode::OK, "done"))
+            }),
+        ));
+        let thread = thread::spawn(move || app.run(None));
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
         let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
         stream
-            .write_all(format!("GET / HTTP/1.1").as_bytes())
+            .write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
             .unwrap();
-        let mut buf_reader = BufReader::new(&stream);
-        buf_reader.read_to_string(&mut str).unwrap();
-        assert_eq!(str, "");
-
-        stop_flag.store(true, Ordering::SeqCst);
-        let stream = TcpStream::connect(TEST_ADDR).unwrap();
-        drop(stream);
-
-        thread.join().unwrap();
+        thread::sleep(time::Duration::from_millis(50));
+        // No other test turns on shutdown signals, so this only stops this server.
+        assert_eq!(unsafe { raise(signals::SIGTERM) }, 0);
+        // The request being handled is finished first.
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\ndone"));
+        thread.join().unwrap().unwrap();
+        assert!(TcpStream::connect(TEST_ADDR).is_err());
     }
 
     #[test]
-    fn app_request_500() {
-        // Default 500 handler
-        let config = AppConfig::new(TEST_ADDR, 4, 5);
+    fn app_run_https_redirect() {
+        const TEST_ADDR: SocketAddr = test_addr(7713);
+        const REDIRECT_ADDR: SocketAddr = test_addr(7714);
+        let config = AppConfig::new(TEST_ADDR, 2, 5).with_https_redirect(REDIRECT_ADDR, 8443);
         let mut app = create_app(config);
         app.register_resource(Resource::new(
             RequestType::GET,
             "/".to_string(),
             ResourceType::TEXT,
-            Box::new(|| Err("Failed".to_string())),
+            Box::new(|_| Ok(Response::text(StatusCode::OK, "secure"))),
         ));
+        app.enable_acme_challenges()
+            .insert("token", "token.thumbprint");
         let stop_flag = Arc::new(AtomicBool::new(false));
         let stop_flag_clone = stop_flag.clone();
-        let thread = thread::spawn(move || {
-            app.run(Some(stop_flag_clone));
-        });
+        let thread = thread::spawn(move || app.run(Some(stop_flag_clone)).unwrap());
         thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
-        stop_flag.store(true, Ordering::SeqCst);
-        let response = send_request(RequestType::GET, "/");
+        let get = |path: &str, host: &str| {
+            let mut stream = TcpStream::connect(REDIRECT_ADDR).unwrap();
+            let request = format!("GET {path} HTTP/1.1\r\n{host}\r\n");
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let request = |host: &str| get("/blog/post?page=2", host);
         assert_eq!(
-            response,
-            "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nContent-Length: 0\r\n\r\n"
+            request("Host: www.daanlubbers.nl:80\r\n"),
+            "HTTP/1.1 301 PERMANENT REDIRECT\r\n\
+             Location: https://www.daanlubbers.nl:8443/blog/post?page=2\r\n\
+             Connection: close\r\nContent-Length: 0\r\n\r\n"
         );
+        assert!(request("Host: [::1]\r\n").contains("Location: https://[::1]:8443/blog/post"));
+        assert!(request("").starts_with("HTTP/1.1 400 "));
+        assert!(request("Host: evil.com/\r\n").starts_with("HTTP/1.1 400 "));
+        // ACME challenges are answered over plain HTTP.
+        let challenge = get("/.well-known/acme-challenge/token", "");
+        assert!(challenge.starts_with("HTTP/1.1 200 "));
+        assert!(challenge.ends_with("\r\n\r\ntoken.thumbprint"));
+        assert!(send_request(TEST_ADDR, RequestType::GET, "/").ends_with("secure"));
 
+        stop_flag.store(true, Ordering::SeqCst);
+        send_request(TEST_ADDR, RequestType::GET, "/");
         thread.join().unwrap();
+    }
 
-        // Custom 500 handler
-        let config = AppConfig::new(TEST_ADDR, 4, 5);
+    #[test]
+    fn app_run_ip_filter() {
+        const TEST_ADDR: SocketAddr = test_addr(7718);
+        const DROP_ADDR: SocketAddr = test_addr(7719);
+        let localhost: Cidr = "127.0.0.1".parse().unwrap();
+        let start = |addr, filter| {
+            let mut app = create_app(AppConfig::new(addr, 2, 5).with_ip_filter(filter));
+            for path in ["/", "/admin"] {
+                app.register_resource(Resource::new(
+                    RequestType::GET,
+                    path.to_string(),
+                    ResourceType::TEXT,
+                    Box::new(|_| Ok(Response::text(StatusCode::OK, "ok"))),
+                ));
+            }
+            let stop_flag = Arc::new(AtomicBool::new(false));
+            let stop_flag_clone = stop_flag.clone();
+            let thread = thread::spawn(move || app.run(Some(stop_flag_clone)).unwrap());
+            (stop_flag, thread)
+        };
+        let allow = IpFilter::new().with_allow("/admin", &["10.0.0.0/8".parse().unwrap()]);
+        let drop = IpFilter::new()
+            .with_deny(localhost)
+            .with_action(FilterAction::Drop);
+        let servers = [start(TEST_ADDR, allow), start(DROP_ADDR, drop)];
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        assert!(send_request(TEST_ADDR, RequestType::GET, "/").ends_with("ok"));
+        assert!(send_request(TEST_ADDR, RequestType::GET, "/admin")
+            .starts_with("HTTP/1.1 403 FORBIDDEN\r\n"));
+        // Closed without reading the request, which may reset the connection.
+        let mut stream = TcpStream::connect(DROP_ADDR).unwrap();
+        let _ = stream.write_all(b"GET / HTTP/1.1\r\n\r\n");
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert_eq!(response, "");
+
+        for ((stop_flag, thread), addr) in servers.into_iter().zip([TEST_ADDR, DROP_ADDR]) {
+            stop_flag.store(true, Ordering::SeqCst);
+            let _ = TcpStream::connect(addr);
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn app_run_max_connections() {
+        const REJECT_ADDR: SocketAddr = test_addr(7720);
+        const WAIT_ADDR: SocketAddr = test_addr(7721);
+        let start = |addr, over_limit| {
+            let config = AppConfig::new(addr, 2, 5).with_max_connections(1, over_limit);
+            let mut app = create_app(config);
+            app.register_resource(Resource::new(
+                RequestType::GET,
+                "/".to_string(),
+                ResourceType::TEXT,
+                Box::new(|_| Ok(Response::text(StatusCode::OK, "ok"))),
+            ));
+            let stop_flag = Arc::new(AtomicBool::new(false));
+            let stop_flag_clone = stop_flag.clone();
+            let thread = thread::spawn(move || app.run(Some(stop_flag_clone)).unwrap());
+            (stop_flag, thread)
+        };
+        let servers = [
+            start(REJECT_ADDR, OverLimit::Reject),
+            start(WAIT_ADDR, OverLimit::Wait),
+        ];
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        // An idle connection takes up the only one allowed.
+        let idle = TcpStream::connect(REJECT_ADDR).unwrap();
+        thread::sleep(time::Duration::from_millis(50));
+        let response = send_request(REJECT_ADDR, RequestType::GET, "/");
+        let response = ParsedResponse::from_reader(&mut response.as_bytes()).unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.header("Retry-After"), Some("1"));
+        // Rejected clients that send nothing don't hold up accepting the next connection,
+        // as waiting for each of them for a moment would.
+        let rejected: Vec<TcpStream> = (0..10)
+            .map(|_| TcpStream::connect(REJECT_ADDR).unwrap())
+            .collect();
+        thread::sleep(time::Duration::from_millis(50));
+        drop(idle);
+        thread::sleep(time::Duration::from_millis(50));
+        let start = Instant::now();
+        assert!(send_request(REJECT_ADDR, RequestType::GET, "/").ends_with("ok"));
+        assert!(start.elapsed() < time::Duration::from_millis(500));
+        drop(rejected);
+
+        let idle = TcpStream::connect(WAIT_ADDR).unwrap();
+        thread::sleep(time::Duration::from_millis(50));
+        let waiting = thread::spawn(|| send_request(WAIT_ADDR, RequestType::GET, "/"));
+        thread::sleep(time::Duration::from_millis(100));
+        assert!(!waiting.is_finished());
+        drop(idle);
+        assert!(waiting.join().unwrap().ends_with("ok"));
+
+        for ((stop_flag, thread), addr) in servers.into_iter().zip([REJECT_ADDR, WAIT_ADDR]) {
+            stop_flag.store(true, Ordering::SeqCst);
+            let _ = TcpStream::connect(addr);
+            thread.join().unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn app_run_unix_socket() {
+        const TEST_ADDR: SocketAddr = test_addr(7704);
+        let path = std::env::temp_dir().join("wwwdaanlubbersnl_test.sock");
+        // A socket file left behind is replaced.
+        let _ = fs::remove_file(&path);
+        drop(UnixListener::bind(&path).unwrap());
+        let config = AppConfig::new(TEST_ADDR, 2, 5).with_unix_socket(&path);
         let mut app = create_app(config);
         app.register_resource(Resource::new(
             RequestType::GET,
             "/".to_string(),
             ResourceType::TEXT,
-            Box::new(|| Err("Failed".to_string())),
-        ));
-        app.register_resource_500(Resource::new(
-            RequestType::GET,
-            "/500".to_string(),
-            ResourceType::TEXT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::InternalServerError,
-                    "static_test/500.html".to_string(),
-                ))
+            Box::new(|request| {
+                let remote = request
+                    .remote_addr()
+                    .map_or("none".to_string(), |addr| addr.to_string());
+                Ok(Response::text(StatusCode::OK, remote))
             }),
         ));
         let stop_flag = Arc::new(AtomicBool::new(false));
         let stop_flag_clone = stop_flag.clone();
         let thread = thread::spawn(move || {
-            app.run(Some(stop_flag_clone));
+            app.run(Some(stop_flag_clone)).unwrap();
         });
         thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
-        stop_flag.store(true, Ordering::SeqCst);
-        let response = send_request(RequestType::GET, "/");
-        assert_eq!(response, "HTTP/1.1 500 INTERNAL SERVER ERROR\r\nContent-Length: 54\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>500</body></html>");
+        let request_unix = || {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let expected = "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 4\r\n\r\nnone";
+        assert_eq!(request_unix(), expected);
+        assert!(send_request(TEST_ADDR, RequestType::GET, "/").contains("\r\n\r\n127.0.0.1:"));
 
+        stop_flag.store(true, Ordering::SeqCst);
+        assert_eq!(request_unix(), expected);
         thread.join().unwrap();
+        assert!(!path.exists());
     }
 
     #[test]
-    fn app_request() {
-        let config = AppConfig::new(TEST_ADDR, 4, 5);
-        let mut app = create_app(config);
+    fn app_request_stream_file() {
+        const TEST_ADDR: SocketAddr = test_addr(7697);
+        // Larger than a few chunks, and not a multiple of the chunk size.
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join("wwwdaanlubbersnl_stream_test");
+        fs::write(&path, &content).unwrap();
+        let mut app = create_app(AppConfig::new(TEST_ADDR, 4, 5));
+        let path_clone = path.clone();
         app.register_resource(Resource::new(
             RequestType::GET,
-            "/html".to_string(),
-            ResourceType::TEXT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/test.html".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::POST,
-            "/html".to_string(),
-            ResourceType::TEXT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/test.html".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::PUT,
-            "/html".to_string(),
-            ResourceType::TEXT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/test.html".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::DELETE,
-            "/html".to_string(),
-            ResourceType::TEXT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/test.html".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::GET,
-            "/image".to_string(),
-            ResourceType::BINARY,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/test.jpg".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::POST,
-            "/image".to_string(),
-            ResourceType::BINARY,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/test.jpg".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::PUT,
-            "/image".to_string(),
-            ResourceType::BINARY,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/test.jpg".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::DELETE,
-            "/image".to_string(),
+            "/download".to_string(),
             ResourceType::BINARY,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/test.jpg".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::GET,
-            "/redirect".to_string(),
-            ResourceType::REDIRECT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/redirect.html".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::POST,
-            "/redirect".to_string(),
-            ResourceType::REDIRECT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/redirect.html".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::PUT,
-            "/redirect".to_string(),
-            ResourceType::REDIRECT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/redirect.html".to_string(),
-                ))
-            }),
-        ));
-        app.register_resource(Resource::new(
-            RequestType::DELETE,
-            "/redirect".to_string(),
-            ResourceType::REDIRECT,
-            Box::new(|| {
-                Ok(Response::new(
-                    StatusCode::OK,
-                    "static_test/redirect.html".to_string(),
-                ))
-            }),
+            Box::new(move |_| Ok(Response::file(StatusCode::OK, path_clone.clone()))),
         ));
-        let stop_flag = Arc::new(AtomicBool::new(false));
-        let stop_flag_clone = stop_flag.clone();
+        let stop_flag = Arc::new(AtomicBool::new(true));
         let thread = thread::spawn(move || {
-            app.run(Some(stop_flag_clone));
+            app.run(Some(stop_flag)).unwrap();
         });
         thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
 
-        let response = send_request(RequestType::GET, "/html");
-        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 55\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>test</body></html>");
-        let response = send_request(RequestType::POST, "/html");
-        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 55\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>test</body></html>");
-        let response = send_request(RequestType::PUT, "/html");
-        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 55\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>test</body></html>");
-        let response = send_request(RequestType::DELETE, "/html");
-        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 55\r\n\r\n<!DOCTYPE html><html lang=\"en\"><body>test</body></html>");
-
-        let response = send_request(RequestType::GET, "/image");
-        assert_eq!(
-            response,
-            "HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n\\x01\\x02\\x03"
-        );
-        let response = send_request(RequestType::POST, "/image");
-        assert_eq!(
-            response,
-            "HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n\\x01\\x02\\x03"
+        let mut stream = TcpStream::connect(TEST_ADDR).unwrap();
+        stream.write_all(b"GET /download HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let head = format!(
+            "HTTP/1.1 200 OK\r\n{}Connection: close\r\nContent-Length: 200000\r\n\r\n",
+            last_modified_header(&path)
         );
-        let response = send_request(RequestType::PUT, "/image");
-        assert_eq!(
-            response,
-            "HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n\\x01\\x02\\x03"
+        assert_eq!(&response[..head.len()], head.as_bytes());
+        assert!(response[head.len()..] == content);
+        thread.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sitemap_and_robots_txt() {
+        const TEST_ADDR: SocketAddr = test_addr(7705);
+        let config = AppConfig::new(TEST_ADDR, 2, 5);
+        let mut app = create_app(config);
+        let page = |request_type, path: &str, resource_type| {
+            Resource::new(
+                request_type,
+                path.to_string(),
+                resource_type,
+                Box::new(|_| Ok(Response::text(StatusCode::OK, ""))),
+            )
+        };
+        app.register_resource(
+            page(RequestType::GET, "/", ResourceType::TEXT).with_sitemap_priority(1.0),
         );
-        let response = send_request(RequestType::DELETE, "/image");
-        assert_eq!(
-            response,
-            "HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n\\x01\\x02\\x03"
+        app.register_resource(page(RequestType::GET, "/about", ResourceType::TEXT));
+        app.register_resource(
+            page(RequestType::GET, "/blog", ResourceType::TEXT)
+                .with_canonical("https://blog.daanlubbers.nl/"),
         );
-
-        let response = send_request(RequestType::GET, "/redirect");
-        assert_eq!(
-            response,
-            "HTTP/1.1 200 OK\r\nLocation: static_test/redirect.html\r\nContent-Length: 0\r\n\r\n"
+        app.register_resource(
+            page(RequestType::GET, "/404", ResourceType::TEXT).with_robots("noindex"),
         );
-        let response = send_request(RequestType::POST, "/redirect");
-        assert_eq!(
-            response,
-            "HTTP/1.1 200 OK\r\nLocation: static_test/redirect.html\r\nContent-Length: 0\r\n\r\n"
+        app.register_resource(
+            page(RequestType::GET, "/drafts", ResourceType::TEXT).without_sitemap(),
         );
-        let response = send_request(RequestType::PUT, "/redirect");
-        assert_eq!(
-            response,
-            "HTTP/1.1 200 OK\r\nLocation: static_test/redirect.html\r\nContent-Length: 0\r\n\r\n"
+        app.register_resource(page(RequestType::GET, "/style.css", ResourceType::TEXT));
+        app.register_resource(page(RequestType::GET, "/photo", ResourceType::BINARY));
+        app.register_resource(
+            page(RequestType::GET, "/cv.pdf", ResourceType::BINARY).with_sitemap_priority(0.3),
         );
-        stop_flag.store(true, Ordering::SeqCst);
-        let response = send_request(RequestType::DELETE, "/redirect");
+        app.register_resource(page(RequestType::POST, "/contact", ResourceType::TEXT));
+        app.enable_sitemap("https://www.daanlubbers.nl/");
+        app.enable_robots_txt(RobotsTxt::new().disallow("/drafts"));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let thread = thread::spawn(move || {
+            app.run(Some(stop_flag_clone)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(STARTUP_TIME)); // Give the app time to start up
+
+        let response = send_request(TEST_ADDR, RequestType::GET, "/sitemap.xml");
+        assert!(response.contains("Content-Type: application/xml; charset=utf-8\r\n"));
+        let urls = response
+            .lines()
+            .filter_map(|line| line.strip_prefix("<url><loc>"))
+            .collect::<Vec<_>>();
         assert_eq!(
-            response,
-            "HTTP/1.1 200 OK\r\nLocation: static_test/redirect.html\r\nContent-Length: 0\r\n\r\n"
+            urls,
+            [
+                "https://blog.daanlubbers.nl/</loc><priority>0.5</priority></url>",
+                "https://www.daanlubbers.nl/</loc><priority>1.0</priority></url>",
+                "https://www.daanlubbers.nl/about</loc><priority>0.5</priority></url>",
+                "https://www.daanlubbers.nl/cv.pdf</loc><priority>0.3</priority></url>",
+            ]
         );
 
+        stop_flag.store(true, Ordering::SeqCst);
+        let response = send_request(TEST_ADDR, RequestType::GET, "/robots.txt");
+        assert!(response.ends_with(
+            "\r\n\r\nUser-agent: *\nDisallow: /drafts\n\nSitemap: https://www.daanlubbers.nl/sitemap.xml\n"
+        ));
         thread.join().unwrap();
     }
 }
@@ -0,0 +1,466 @@
+use crate::digest::{base64_decode, base64_encode, sha1};
+use crate::log;
+use crate::webserver::{Connection, Error, Request, Response, StatusCode};
+use std::{
+    io::{self, Cursor, Read, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`, from RFC 6455.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Runs a WebSocket connection after the handshake, registered with `Resource::websocket`.
+/// The connection is closed when it returns.
+pub type WebSocketHandler =
+    Arc<dyn Fn(&Request, &mut WebSocket) -> Result<(), Error> + Send + Sync>;
+
+/// Status codes sent in close frames, from RFC 6455.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Answered with a pong by `WebSocket::receive` before it is returned.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The status code and reason the other side closed the connection with, if it sent one.
+    Close(Option<(u16, String)>),
+}
+
+/// A WebSocket connection (RFC 6455), handed to the handler of a `Resource::websocket`.
+///
+/// Messages are received whole, with fragmented messages put back together. The connection
+/// has no read timeout, so a handler that wants to notice idle clients should set one and ping.
+pub struct WebSocket<'a> {
+    /// Bytes the client sent right after the handshake, before the rest of the connection.
+    stream: io::Chain<Cursor<Vec<u8>>, &'a mut dyn Connection>,
+    max_message_size: usize,
+    /// The opcode and payload of a fragmented message received so far. Control frames may
+    /// come between its fragments, and are returned from `receive` on their own.
+    fragmented: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<'a> WebSocket<'a> {
+    pub(crate) fn new(stream: &'a mut dyn Connection, buffered: Vec<u8>) -> Self {
+        Self {
+            stream: Cursor::new(buffered).chain(stream),
+            max_message_size: 16 * 1024 * 1024,
+            fragmented: None,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// Close the connection with `CLOSE_TOO_BIG` when a message is larger than this many bytes.
+    /// Defaults to 16 MiB.
+    pub fn set_max_message_size(&mut self, bytes: usize) {
+        self.max_message_size = bytes;
+    }
+
+    /// Make `receive` fail after waiting this long for a frame.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), String> {
+        self.stream
+            .get_ref()
+            .1
+            .set_read_timeout(timeout)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Wait for the next message. Pings are answered and close frames confirmed, after which
+    /// there is nothing more to receive. Frames breaking the protocol close the connection with
+    /// an error.
+    pub fn receive(&mut self) -> Result<Message, String> {
+        if self.close_received {
+            return Err("WebSocket is closed".to_string());
+        }
+        loop {
+            let frame = match read_frame(&mut self.stream, self.max_message_size) {
+                Ok(frame) => frame,
+                Err(FrameError::Io(e)) => return Err(format!("Failed to read frame: {e}")),
+                Err(FrameError::Protocol(code, e)) => return self.fail(code, e),
+            };
+            if !frame.masked {
+                return self.fail(CLOSE_PROTOCOL_ERROR, "Unmasked client frame");
+            }
+            match (frame.opcode, &mut self.fragmented) {
+                (OPCODE_TEXT | OPCODE_BINARY, None) => {
+                    self.fragmented = Some((frame.opcode, frame.payload))
+                }
+                (OPCODE_CONTINUATION, Some((_, payload))) => {
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return self.fail(CLOSE_TOO_BIG, "Message too large");
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                }
+                (OPCODE_PING, _) => {
+                    self.send(&Message::Pong(frame.payload.clone()))?;
+                    return Ok(Message::Ping(frame.payload));
+                }
+                (OPCODE_PONG, _) => return Ok(Message::Pong(frame.payload)),
+                (OPCODE_CLOSE, _) => {
+                    self.close_received = true;
+                    let close = match frame.payload.as_slice() {
+                        [] => None,
+                        [high, low, reason @ ..] => {
+                            let reason = String::from_utf8_lossy(reason).into_owned();
+                            Some((u16::from_be_bytes([*high, *low]), reason))
+                        }
+                        [_] => return self.fail(CLOSE_PROTOCOL_ERROR, "Invalid close frame"),
+                    };
+                    if !self.close_sent {
+                        let code = close.as_ref().map_or(CLOSE_NORMAL, |(code, _)| *code);
+                        self.close(code, "")?;
+                    }
+                    return Ok(Message::Close(close));
+                }
+                _ => return self.fail(CLOSE_PROTOCOL_ERROR, "Unexpected frame"),
+            }
+            if frame.fin {
+                let (opcode, payload) = self.fragmented.take().unwrap();
+                return match opcode {
+                    OPCODE_TEXT => match String::from_utf8(payload) {
+                        Ok(text) => Ok(Message::Text(text)),
+                        Err(_) => self.fail(CLOSE_INVALID_DATA, "Invalid UTF-8 in text message"),
+                    },
+                    _ => Ok(Message::Binary(payload)),
+                };
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<(), String> {
+        let frame = match message {
+            Message::Text(text) => encode_frame(OPCODE_TEXT, text.as_bytes(), None),
+            Message::Binary(bytes) => encode_frame(OPCODE_BINARY, bytes, None),
+            Message::Ping(payload) => encode_frame(OPCODE_PING, payload, None),
+            Message::Pong(payload) => encode_frame(OPCODE_PONG, payload, None),
+            Message::Close(close) => {
+                self.close_sent = true;
+                let payload = close.as_ref().map_or(vec![], |(code, reason)| {
+                    // Control frames are at most 125 bytes.
+                    let reason = truncate(reason, 123);
+                    [&code.to_be_bytes()[..], reason.as_bytes()].concat()
+                });
+                encode_frame(OPCODE_CLOSE, &payload, None)
+            }
+        };
+        self.stream
+            .get_mut()
+            .1
+            .write_all(&frame)
+            .map_err(|e| format!("Failed to send frame: {e}"))
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), String> {
+        self.send(&Message::Text(text.to_string()))
+    }
+
+    /// Start closing the connection. The handler can return right away, or wait for the
+    /// client to confirm with `receive`.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), String> {
+        self.send(&Message::Close(Some((code, reason.to_string()))))
+    }
+
+    /// Close the connection because the client broke the protocol.
+    fn fail<T>(&mut self, code: u16, reason: &str) -> Result<T, String> {
+        self.close_received = true;
+        let _ = self.close(code, reason);
+        Err(reason.to_string())
+    }
+}
+
+/// Answer a WebSocket handshake with a 101, or with a 426 or 400 if the request isn't a valid
+/// one. This is the handler of every `Resource::websocket`.
+pub fn handshake(request: &Request) -> Result<Response, Error> {
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Ok(
+            Response::text(StatusCode::UpgradeRequired, "WebSocket connections only")
+                .with_header("Upgrade", "websocket")
+                .with_header("Connection", "Upgrade"),
+        );
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Ok(
+            Response::empty(StatusCode::UpgradeRequired).with_header("Sec-WebSocket-Version", "13")
+        );
+    }
+    let Some(key) = request
+        .header("Sec-WebSocket-Key")
+        .filter(|key| base64_decode(key).is_some_and(|nonce| nonce.len() == 16))
+    else {
+        return Ok(Response::text(
+            StatusCode::BadRequest,
+            "Invalid Sec-WebSocket-Key",
+        ));
+    };
+    Ok(Response::empty(StatusCode::SwitchingProtocols)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key)))
+}
+
+/// The `Sec-WebSocket-Accept` answering a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    base64_encode(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// Hand a connection that completed the handshake to the handler. The connection is closed
+/// with `CLOSE_INTERNAL_ERROR` if the handler fails without closing it.
+pub(crate) fn run(
+    handler: &WebSocketHandler,
+    request: &Request,
+    stream: &mut dyn Connection,
+    buffered: Vec<u8>,
+) {
+    if stream.set_read_timeout(None).is_err() || stream.set_write_timeout(None).is_err() {
+        return;
+    }
+    let mut websocket = WebSocket::new(stream, buffered);
+    let result = handler(request, &mut websocket);
+    if let Err(e) = &result {
+        log!("WebSocket {} failed: {e}", request.path());
+    }
+    if !websocket.close_sent {
+        let code = match result {
+            Ok(()) => CLOSE_NORMAL,
+            Err(_) => CLOSE_INTERNAL_ERROR,
+        };
+        let _ = websocket.close(code, "");
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    masked: bool,
+    /// Already unmasked.
+    payload: Vec<u8>,
+}
+
+enum FrameError {
+    Io(io::Error),
+    /// Closes the connection with this status code.
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+fn read_frame(reader: &mut impl Read, max_size: usize) -> Result<Frame, FrameError> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    if head[0] & 0x70 != 0 {
+        return Err(FrameError::Protocol(
+            CLOSE_PROTOCOL_ERROR,
+            "Reserved bits set without an extension",
+        ));
+    }
+    let masked = head[1] & 0x80 != 0;
+    let length = match head[1] & 0x7f {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if opcode >= OPCODE_CLOSE && (!fin || length > 125) {
+        return Err(FrameError::Protocol(
+            CLOSE_PROTOCOL_ERROR,
+            "Invalid control frame",
+        ));
+    }
+    if length > max_size as u64 {
+        return Err(FrameError::Protocol(CLOSE_TOO_BIG, "Message too large"));
+    }
+    let mut mask = [0; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    if masked {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        masked,
+        payload,
+    })
+}
+
+/// A single, final frame. Servers send unmasked frames, clients masked ones.
+fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(mask_bit | length as u8),
+        length @ 126..=0xffff => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    let start = frame.len();
+    frame.extend_from_slice(payload);
+    if let Some(mask) = mask {
+        frame.splice(start..start, mask);
+        apply_mask(&mut frame[start + 4..], mask);
+    }
+    frame
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// At most `max` bytes of `text`, cut at a character boundary.
+fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept() {
+        // The example from RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let answer = |headers: &str| {
+            let request = format!("GET /ws HTTP/1.1\r\n{headers}\r\n");
+            let request = Request::from_reader(&mut request.as_bytes()).unwrap();
+            handshake(&request).unwrap()
+        };
+        let upgrade = "Upgrade: WebSocket\r\nConnection: keep-alive, upgrade\r\n";
+        let response = answer(&format!(
+            "{upgrade}Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"
+        ));
+        assert_eq!(response.status_code.code(), 101);
+        assert_eq!(
+            response.header("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(answer("").status_code.code(), 426);
+        let response = answer(&format!("{upgrade}Sec-WebSocket-Version: 8\r\n"));
+        assert_eq!(response.status_code.code(), 426);
+        assert_eq!(response.header("Sec-WebSocket-Version"), Some("13"));
+        let response = answer(&format!(
+            "{upgrade}Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n"
+        ));
+        assert_eq!(response.status_code.code(), 400);
+    }
+
+    #[test]
+    fn frames() {
+        // Examples from RFC 6455, section 5.7.
+        assert_eq!(
+            encode_frame(OPCODE_TEXT, b"Hello", None),
+            [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]
+        );
+        let masked = encode_frame(OPCODE_TEXT, b"Hello", Some([0x37, 0xfa, 0x21, 0x3d]));
+        assert_eq!(
+            masked,
+            [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]
+        );
+        let frame = read_frame(&mut masked.as_slice(), 1024).ok().unwrap();
+        assert!(frame.fin && frame.masked);
+        assert_eq!(
+            (frame.opcode, frame.payload.as_slice()),
+            (OPCODE_TEXT, &b"Hello"[..])
+        );
+
+        for length in [125, 126, 0xffff, 0x10000] {
+            let payload = vec![7; length];
+            let encoded = encode_frame(OPCODE_BINARY, &payload, Some([1, 2, 3, 4]));
+            let frame = read_frame(&mut encoded.as_slice(), 1 << 20).ok().unwrap();
+            assert_eq!(frame.payload, payload);
+        }
+        assert_eq!(
+            encode_frame(OPCODE_BINARY, &[0; 256], None)[..4],
+            [0x82, 126, 1, 0]
+        );
+
+        let too_large = encode_frame(OPCODE_BINARY, &[0; 11], None);
+        assert!(matches!(
+            read_frame(&mut too_large.as_slice(), 10),
+            Err(FrameError::Protocol(CLOSE_TOO_BIG, _))
+        ));
+        let long_ping = encode_frame(OPCODE_PING, &[0; 126], None);
+        assert!(matches!(
+            read_frame(&mut long_ping.as_slice(), 1024),
+            Err(FrameError::Protocol(CLOSE_PROTOCOL_ERROR, _))
+        ));
+        assert!(matches!(
+            read_frame(&mut [0x81].as_slice(), 1024),
+            Err(FrameError::Io(_))
+        ));
+        assert_eq!(truncate("caf\u{e9}", 4), "caf");
+    }
+
+    #[test]
+    fn ping_between_fragments() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let mask = Some([1, 2, 3, 4]);
+        // "Hel" without FIN, a ping, and then "lo" as the last fragment.
+        let mut first = encode_frame(OPCODE_TEXT, b"Hel", mask);
+        first[0] &= 0x7f;
+        let mut frames = first;
+        frames.extend(encode_frame(OPCODE_PING, b"hi", mask));
+        frames.extend(encode_frame(OPCODE_CONTINUATION, b"lo", mask));
+
+        let mut websocket = WebSocket::new(&mut server, frames);
+        assert_eq!(websocket.receive(), Ok(Message::Ping(b"hi".to_vec())));
+        assert_eq!(websocket.receive(), Ok(Message::Text("Hello".to_string())));
+        let mut pong = [0; 4];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x8a, 0x02, b'h', b'i']);
+    }
+}